@@ -1,6 +1,7 @@
-use super::rsdt::SDT;
+use super::rsdt::{SDTBuilder, SDT};
 use super::GenericAddressStructure;
 use crate::error::Result;
+use alloc::vec::Vec;
 use byteorder::{ByteOrder, NativeEndian};
 use core::convert::TryFrom;
 use core::fmt;
@@ -95,6 +96,32 @@ impl<'a> HPET<'a> {
             page_protection,
         })
     }
+
+    /// Build an HPET table for the first timer block.
+    ///
+    /// The `event_timer_block_id` is the low 32 bits of the General
+    /// Capabilities and ID Register of the timer block.
+    pub fn build(
+        event_timer_block_id: u32,
+        address: &GenericAddressStructure,
+        minimum_tick: u16,
+    ) -> Vec<u8> {
+        let mut sdt = SDTBuilder::new(b"HPET", 1);
+        let mut table = [0u8; offsets::PAGE_PROTECTION + 1];
+        NativeEndian::write_u32(
+            &mut table[offsets::EVENT_TIMER_BLOCK_ID],
+            event_timer_block_id,
+        );
+        table[offsets::BASE_ADDRESS].copy_from_slice(&address.to_bytes());
+        table[offsets::HPET_NUMBER] = 0;
+        NativeEndian::write_u16(
+            &mut table[offsets::MIN_CLOCK_TICK],
+            minimum_tick,
+        );
+        table[offsets::PAGE_PROTECTION] = PageProtection::NoProtection as u8;
+        sdt.append(&table);
+        sdt.finish()
+    }
 }
 
 impl<'a> fmt::Debug for HPET<'a> {
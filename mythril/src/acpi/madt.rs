@@ -1,5 +1,6 @@
-use super::rsdt::SDT;
+use super::rsdt::{SDTBuilder, SDT};
 use crate::error::{Error, Result};
+use alloc::vec::Vec;
 use bitflags::bitflags;
 use byteorder::{ByteOrder, NativeEndian};
 use core::convert::TryFrom;
@@ -251,6 +252,68 @@ impl Ics {
         }
     }
 
+    /// Encode the structure as a slice of bytes (including the type and
+    /// length).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let ty = self.ics_type();
+        let mut bytes = vec![0u8; ty.expected_len()];
+        bytes[0] = ty as u8;
+        bytes[1] = ty.expected_len() as u8;
+
+        // The fields are at the same offsets used by `Ics::parse` (after
+        // the type and length)
+        let data = &mut bytes[2..];
+        match self {
+            &Ics::LocalApic {
+                apic_uid,
+                apic_id,
+                flags,
+            } => {
+                data[0] = apic_uid;
+                data[1] = apic_id;
+                NativeEndian::write_u32(&mut data[2..6], flags.bits());
+            }
+            &Ics::IoApic {
+                ioapic_id,
+                ioapic_addr,
+                gsi_base,
+            } => {
+                data[0] = ioapic_id;
+                NativeEndian::write_u32(&mut data[2..6], ioapic_addr as u32);
+                NativeEndian::write_u32(&mut data[6..10], gsi_base);
+            }
+            &Ics::InterruptSourceOverride { source, gsi, flags } => {
+                // Bus 0 is ISA
+                data[1] = source;
+                NativeEndian::write_u32(&mut data[2..6], gsi);
+                NativeEndian::write_u16(&mut data[6..8], flags.bits());
+            }
+            &Ics::NmiSource { flags, gsi } => {
+                NativeEndian::write_u16(&mut data[0..2], flags.bits());
+                NativeEndian::write_u32(&mut data[2..6], gsi);
+            }
+            &Ics::LocalApicNmi {
+                acpi_proc_uid,
+                flags,
+                local_apic_lint,
+            } => {
+                data[0] = acpi_proc_uid;
+                NativeEndian::write_u16(&mut data[1..3], flags.bits());
+                data[3] = local_apic_lint;
+            }
+            &Ics::LocalX2Apic {
+                x2apic_id,
+                flags,
+                apic_proc_uid,
+            } => {
+                NativeEndian::write_u32(&mut data[2..6], x2apic_id);
+                NativeEndian::write_u32(&mut data[6..10], flags.bits());
+                NativeEndian::write_u32(&mut data[10..14], apic_proc_uid);
+            }
+        }
+        bytes
+    }
+
     /// The controll structure type for the value.
     pub fn ics_type(&self) -> IcsType {
         match self {
@@ -308,6 +371,20 @@ impl<'a> MADT<'a> {
         }
     }
 
+    /// Build a MADT with the given Local Interrupt Controller Address,
+    /// flags and Interrupt Controller Structures.
+    pub fn build(ica: u32, flags: MultipleApicFlags, ics: &[Ics]) -> Vec<u8> {
+        let mut sdt = SDTBuilder::new(b"APIC", 5);
+        let mut table = [0u8; offsets::INT_CTRL_STRUCTS];
+        NativeEndian::write_u32(&mut table[offsets::LOCAL_INT_CTRL_ADDR], ica);
+        NativeEndian::write_u32(&mut table[offsets::FLAGS], flags.bits());
+        sdt.append(&table);
+        for structure in ics {
+            sdt.append(&structure.to_bytes());
+        }
+        sdt.finish()
+    }
+
     /// Interrupt Controller Structures.
     pub fn structures<'c, 'd: 'c>(&'d self) -> IcsIterator<'c> {
         IcsIterator { bytes: self.ics }
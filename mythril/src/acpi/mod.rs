@@ -22,6 +22,10 @@ pub mod madt;
 pub mod rsdp;
 /// Support for the Root System Descriptor Table (RSDT).
 pub mod rsdt;
+/// Support for the System Resource Affinity Table (SRAT).
+pub mod srat;
+/// Generation of the ACPI tables of a guest.
+pub mod tables;
 
 mod offsets {
     use core::ops::Range;
@@ -51,6 +55,15 @@ pub(self) fn verify_checksum(bytes: &[u8], cksum_idx: usize) -> Result<()> {
     }
 }
 
+/// Calculate the one byte checksum for a given slice.
+///
+/// This is the value of the checksum field (assuming the field is currently
+/// zero) that makes the sum of the bytes zero.
+pub(self) fn calculate_checksum(bytes: &[u8]) -> u8 {
+    let sum = bytes.iter().fold(0u8, |acc, val| acc.wrapping_add(*val));
+    0u8.wrapping_sub(sum)
+}
+
 /// The size of a Generic Address Structure in bytes.
 pub const GAS_SIZE: usize = 12;
 
@@ -171,4 +184,15 @@ impl GenericAddressStructure {
             address,
         })
     }
+
+    /// Encode the GAS as a slice of bytes.
+    pub fn to_bytes(&self) -> [u8; GAS_SIZE] {
+        let mut bytes = [0u8; GAS_SIZE];
+        bytes[offsets::GAS_ADDRESS_SPACE] = self.address_space as u8;
+        bytes[offsets::GAS_BIT_WIDTH] = self.bit_width;
+        bytes[offsets::GAS_BIT_OFFSET] = self.bit_offset;
+        bytes[offsets::GAS_ACCESS_SIZE] = self.access_size as u8;
+        NativeEndian::write_u64(&mut bytes[offsets::GAS_ADDRESS], self.address);
+        bytes
+    }
 }
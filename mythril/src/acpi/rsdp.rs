@@ -1,6 +1,7 @@
 use super::rsdt::RSDT;
-use super::verify_checksum;
+use super::{calculate_checksum, verify_checksum};
 use crate::error::{Error, Result};
+use alloc::vec::Vec;
 use byteorder::{ByteOrder, NativeEndian};
 use core::fmt;
 use core::ops::Range;
@@ -18,7 +19,7 @@ const MAIN_BIOS_DATA_SIZE: usize = 0x20000;
 const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
 
 /// Offsets from `ACPI § 5.2.5.3`
-mod offsets {
+pub(super) mod offsets {
     use super::*;
     /// Well known bytes, "RST PTR ".
     pub const SIGNATURE: Range<usize> = 0..8;
@@ -30,6 +31,8 @@ mod offsets {
     pub const REVISION: usize = 15;
     /// 32-bit physical address of the RSDT.
     pub const RSDT_ADDR: Range<usize> = 16..20;
+    /// Length of the table (ACPI 2.0 only).
+    pub const LENGTH: Range<usize> = 20..24;
     /// 64-bit physical address of the XSDT (ACPI 2.0 only).
     pub const XSDT_ADDR: Range<usize> = 24..32;
     /// Checksum of entire structure (ACPI 2.0 only).
@@ -192,6 +195,44 @@ impl RSDP {
         }
     }
 
+    /// Encode the RSDP as a slice of bytes (with valid checksums).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (oemid, size) = match self {
+            RSDP::V1 { oemid, .. } => (oemid, RSDP_V1_SIZE),
+            RSDP::V2 { oemid, .. } => (oemid, RSDP_V2_SIZE),
+        };
+        let mut bytes = vec![0u8; size];
+        bytes[offsets::SIGNATURE].copy_from_slice(RSDP_SIGNATURE);
+        bytes[offsets::OEMID].copy_from_slice(oemid);
+
+        match self {
+            &RSDP::V1 { rsdt_addr, .. } => {
+                NativeEndian::write_u32(
+                    &mut bytes[offsets::RSDT_ADDR],
+                    rsdt_addr,
+                );
+            }
+            // The revision two structure only points to the XSDT
+            &RSDP::V2 { xsdt_addr, .. } => {
+                bytes[offsets::REVISION] = 2;
+                NativeEndian::write_u32(
+                    &mut bytes[offsets::LENGTH],
+                    RSDP_V2_SIZE as u32,
+                );
+                NativeEndian::write_u64(
+                    &mut bytes[offsets::XSDT_ADDR],
+                    xsdt_addr,
+                );
+            }
+        }
+
+        bytes[offsets::CHECKSUM] = calculate_checksum(&bytes[..RSDP_V1_SIZE]);
+        if size == RSDP_V2_SIZE {
+            bytes[offsets::EXT_CHECKSUM] = calculate_checksum(&bytes);
+        }
+        bytes
+    }
+
     /// Return the RSDT pointed to by this structure.
     pub fn rsdt(&self) -> Result<RSDT> {
         match self {
@@ -1,5 +1,6 @@
-use super::verify_checksum;
+use super::{calculate_checksum, verify_checksum};
 use crate::error::{Error, Result};
+use alloc::vec::Vec;
 use byteorder::{ByteOrder, NativeEndian};
 use core::fmt;
 use core::ops::Range;
@@ -7,7 +8,7 @@ use core::slice;
 use core::str;
 
 /// Offsets from `ACPI § 5.2.6`
-mod offsets {
+pub(super) mod offsets {
     use super::*;
     /// Well known bytes, "RST PTR ".
     pub const SIGNATURE: Range<usize> = 0..4;
@@ -17,6 +18,14 @@ mod offsets {
     pub const REVISION: usize = 8;
     /// The checksum of the entire table.
     pub const CHECKSUM: usize = 9;
+    /// OEM-supplied ID string.
+    pub const OEMID: Range<usize> = 10..16;
+    /// OEM-supplied string identifying the table.
+    pub const OEM_TABLE_ID: Range<usize> = 16..24;
+    /// OEM-supplied revision of the table.
+    pub const OEM_REVISION: Range<usize> = 24..28;
+    /// Vendor ID of utility that created the table.
+    pub const CREATOR_ID: Range<usize> = 28..32;
     /// Revision of utility that created the structure
     pub const CREATOR_REVISION: Range<usize> = 32..36;
 }
@@ -101,6 +110,50 @@ impl<'a> SDT<'a> {
     }
 }
 
+/// The OEM ID of the tables generated by mythril.
+pub const MYTHRIL_OEMID: &[u8; 6] = b"MYTHRL";
+
+/// Builder for a System Descriptor Table.
+///
+/// The header is filled in when the builder is created, except for the
+/// length and checksum which are set by `SDTBuilder::finish`.
+pub struct SDTBuilder {
+    bytes: Vec<u8>,
+}
+
+impl SDTBuilder {
+    /// Create a builder for a table with the given signature and revision.
+    pub fn new(signature: &[u8; 4], revision: u8) -> SDTBuilder {
+        let mut bytes = vec![0u8; offsets::CREATOR_REVISION.end];
+        bytes[offsets::SIGNATURE].copy_from_slice(signature);
+        bytes[offsets::REVISION] = revision;
+        bytes[offsets::OEMID].copy_from_slice(MYTHRIL_OEMID);
+        bytes[offsets::OEM_TABLE_ID].copy_from_slice(b"MYTHRIL ");
+        NativeEndian::write_u32(&mut bytes[offsets::OEM_REVISION], 1);
+        bytes[offsets::CREATOR_ID].copy_from_slice(b"MYTH");
+        NativeEndian::write_u32(&mut bytes[offsets::CREATOR_REVISION], 1);
+        SDTBuilder { bytes }
+    }
+
+    /// The offset (from the start of the header) of the next appended byte.
+    pub fn offset(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Append data to the table.
+    pub fn append(&mut self, data: &[u8]) {
+        self.bytes.extend_from_slice(data);
+    }
+
+    /// Set the length and checksum of the table and return its bytes.
+    pub fn finish(mut self) -> Vec<u8> {
+        let length = self.bytes.len() as u32;
+        NativeEndian::write_u32(&mut self.bytes[offsets::LENGTH], length);
+        self.bytes[offsets::CHECKSUM] = calculate_checksum(&self.bytes);
+        self.bytes
+    }
+}
+
 /// The Root System Description Table.
 /// Two variants to support 32 bit RSDT and 64 bit XSDT.
 pub enum RSDT<'a> {
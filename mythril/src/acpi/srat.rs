@@ -0,0 +1,222 @@
+use super::rsdt::SDT;
+use crate::error::{Error, Result};
+use alloc::vec::Vec;
+use byteorder::{ByteOrder, NativeEndian};
+use core::fmt;
+use core::ops::Range;
+
+/// See Table 5-76 in the ACPI specification.
+///
+/// Note that these offsets are relative to the end of the
+/// SDT (the end of the Creator Revision at offset 36).
+mod offsets {
+    /// The affinity structures follow 12 reserved bytes.
+    pub const AFFINITY_STRUCTS: usize = 12;
+}
+
+/// Offsets of the fields of the affinity structures (relative to the
+/// start of each structure).
+///
+/// See `ACPI § 5.2.16`.
+mod affinity_offsets {
+    use super::*;
+    pub const LAPIC_DOMAIN_LOW: usize = 2;
+    pub const LAPIC_ID: usize = 3;
+    pub const LAPIC_FLAGS: Range<usize> = 4..8;
+    pub const LAPIC_DOMAIN_HIGH: Range<usize> = 9..12;
+
+    pub const MEMORY_DOMAIN: Range<usize> = 2..6;
+    pub const MEMORY_BASE: Range<usize> = 8..16;
+    pub const MEMORY_LENGTH: Range<usize> = 16..24;
+    pub const MEMORY_FLAGS: Range<usize> = 28..32;
+
+    pub const X2APIC_DOMAIN: Range<usize> = 4..8;
+    pub const X2APIC_ID: Range<usize> = 8..12;
+    pub const X2APIC_FLAGS: Range<usize> = 12..16;
+}
+
+const PROCESSOR_LOCAL_APIC_AFFINITY: u8 = 0x00;
+const MEMORY_AFFINITY: u8 = 0x01;
+const PROCESSOR_LOCAL_X2APIC_AFFINITY: u8 = 0x02;
+
+/// All affinity structures use bit 0 of their flags to mark them enabled.
+const AFFINITY_ENABLED: u32 = 1 << 0;
+
+/// The proximity domain (NUMA node) of a processor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProcessorAffinity {
+    /// The (x2)APIC ID of the processor.
+    pub apic_id: u32,
+    /// The proximity domain of the processor.
+    pub domain: u32,
+}
+
+/// The proximity domain (NUMA node) of a range of memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryAffinity {
+    /// The start address of the memory range.
+    pub base: u64,
+    /// The length of the memory range in bytes.
+    pub length: u64,
+    /// The proximity domain of the memory range.
+    pub domain: u32,
+}
+
+/// System Resource Affinity Table.
+///
+/// See `ACPI § 5.2.16`.
+pub struct SRAT<'a> {
+    /// System Descriptor Table Header for this structure.
+    sdt: &'a SDT<'a>,
+    /// The enabled processor affinity structures.
+    pub processors: Vec<ProcessorAffinity>,
+    /// The enabled memory affinity structures.
+    pub memory: Vec<MemoryAffinity>,
+}
+
+impl<'a> SRAT<'a> {
+    /// Create a new SRAT given a SDT.
+    pub fn new(sdt: &'a SDT<'a>) -> Result<SRAT<'a>> {
+        let mut processors = vec![];
+        let mut memory = vec![];
+
+        let mut bytes = sdt
+            .table
+            .get(offsets::AFFINITY_STRUCTS..)
+            .ok_or_else(|| Error::InvalidValue("SRAT is too short".into()))?;
+        while bytes.len() >= 2 {
+            let len = bytes[1] as usize;
+            if len < 2 || len > bytes.len() {
+                return Err(Error::InvalidValue(format!(
+                    "Invalid SRAT structure length {}",
+                    len
+                )));
+            }
+            let entry = &bytes[..len];
+            match (entry[0], len) {
+                (PROCESSOR_LOCAL_APIC_AFFINITY, 16) => {
+                    let flags = NativeEndian::read_u32(
+                        &entry[affinity_offsets::LAPIC_FLAGS],
+                    );
+                    let high = NativeEndian::read_u24(
+                        &entry[affinity_offsets::LAPIC_DOMAIN_HIGH],
+                    );
+                    if flags & AFFINITY_ENABLED != 0 {
+                        processors.push(ProcessorAffinity {
+                            apic_id: entry[affinity_offsets::LAPIC_ID] as u32,
+                            domain: high << 8
+                                | entry[affinity_offsets::LAPIC_DOMAIN_LOW]
+                                    as u32,
+                        });
+                    }
+                }
+                (MEMORY_AFFINITY, 40) => {
+                    let flags = NativeEndian::read_u32(
+                        &entry[affinity_offsets::MEMORY_FLAGS],
+                    );
+                    if flags & AFFINITY_ENABLED != 0 {
+                        memory.push(MemoryAffinity {
+                            base: NativeEndian::read_u64(
+                                &entry[affinity_offsets::MEMORY_BASE],
+                            ),
+                            length: NativeEndian::read_u64(
+                                &entry[affinity_offsets::MEMORY_LENGTH],
+                            ),
+                            domain: NativeEndian::read_u32(
+                                &entry[affinity_offsets::MEMORY_DOMAIN],
+                            ),
+                        });
+                    }
+                }
+                (PROCESSOR_LOCAL_X2APIC_AFFINITY, 24) => {
+                    let flags = NativeEndian::read_u32(
+                        &entry[affinity_offsets::X2APIC_FLAGS],
+                    );
+                    if flags & AFFINITY_ENABLED != 0 {
+                        processors.push(ProcessorAffinity {
+                            apic_id: NativeEndian::read_u32(
+                                &entry[affinity_offsets::X2APIC_ID],
+                            ),
+                            domain: NativeEndian::read_u32(
+                                &entry[affinity_offsets::X2APIC_DOMAIN],
+                            ),
+                        });
+                    }
+                }
+
+                // Other structures (like GICC affinity) are not used
+                _ => (),
+            }
+            bytes = &bytes[len..];
+        }
+
+        Ok(Self {
+            sdt: sdt,
+            processors: processors,
+            memory: memory,
+        })
+    }
+}
+
+impl<'a> fmt::Debug for SRAT<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.sdt)?;
+        write!(
+            f,
+            " SRAT processors={} memory ranges={}",
+            self.processors.len(),
+            self.memory.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_srat_parse() {
+        let mut buf = vec![
+            // Header
+            0x53, 0x52, 0x41, 0x54, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x4d,
+            0x59, 0x54, 0x48, 0x52, 0x4c, 0x4d, 0x59, 0x54, 0x48, 0x52, 0x49,
+            0x4c, 0x20, 0x01, 0x00, 0x00, 0x00, 0x4d, 0x59, 0x54, 0x48, 0x01,
+            0x00, 0x00, 0x00, // Reserved
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, // Local APIC 2 in domain 1
+            0x00, 0x10, 0x01, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00,
+            // Disabled local APIC 3 in domain 1
+            0x00, 0x10, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00,
+            // 1GB of memory at 4GB in domain 1
+            0x01, 0x28, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        let len = buf.len();
+        buf[4] = len as u8;
+        let sum = buf.iter().fold(0u8, |acc, val| acc.wrapping_add(*val));
+        buf[9] = 0u8.wrapping_sub(sum);
+
+        let srat_sdt = unsafe { SDT::new(buf.as_ptr()).unwrap() };
+        let srat = SRAT::new(&srat_sdt).unwrap();
+
+        assert_eq!(
+            srat.processors,
+            vec![ProcessorAffinity {
+                apic_id: 2,
+                domain: 1
+            }]
+        );
+        assert_eq!(
+            srat.memory,
+            vec![MemoryAffinity {
+                base: 0x1_0000_0000,
+                length: 0x4000_0000,
+                domain: 1
+            }]
+        );
+    }
+}
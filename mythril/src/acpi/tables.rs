@@ -0,0 +1,611 @@
+use super::hpet::HPET;
+use super::madt::{Ics, LocalApicFlags, MpsIntiFlags, MultipleApicFlags, MADT};
+use super::rsdp::{self, RSDP};
+use super::rsdt::{self, SDTBuilder, MYTHRIL_OEMID};
+use super::{AccessSize, AddressSpaceID, GenericAddressStructure};
+use crate::device::acpi::{
+    AcpiRuntime, PM1A_CNT_OFFSET, PM1A_EVT_OFFSET, PMTIMER_OFFSET,
+};
+use crate::device::hpet::Hpet;
+use crate::device::qemu_fw_cfg::{
+    QemuFwCfgBuilder, TableLoader, TableLoaderZone,
+};
+use crate::device::Port;
+use crate::error::Result;
+use crate::memory_layout::{
+    GuestRegion, HPET_BASE, IOAPIC_BASE, LOCAL_APIC_BASE,
+};
+use crate::rtc::RTC_CENTURY;
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use byteorder::{ByteOrder, NativeEndian};
+use core::ops::Range;
+
+/// The fw_cfg file containing the RSDP (loaded in the F segment).
+const RSDP_FILE: &str = "etc/acpi/rsdp";
+/// The fw_cfg file containing all other tables.
+const TABLES_FILE: &str = "etc/acpi/tables";
+/// The fw_cfg file with the commands used by the BIOS to load the tables.
+const LOADER_FILE: &str = "etc/table-loader";
+
+/// The size of the SDT header that precedes each table.
+const SDT_HEADER_SIZE: usize = rsdt::offsets::CREATOR_REVISION.end;
+
+/// See Table 5-34 in the ACPI specification.
+///
+/// Note that these offsets are relative to the end of the
+/// SDT (the end of the Creator Revision at offset 36).
+mod fadt_offsets {
+    use super::*;
+    pub const FIRMWARE_CTRL: Range<usize> = 0..4;
+    pub const DSDT: Range<usize> = 4..8;
+    pub const SCI_INT: Range<usize> = 10..12;
+    pub const PM1A_EVT_BLK: Range<usize> = 20..24;
+    pub const PM1A_CNT_BLK: Range<usize> = 28..32;
+    pub const PM_TMR_BLK: Range<usize> = 40..44;
+    pub const GPE0_BLK: Range<usize> = 44..48;
+    pub const PM1_EVT_LEN: usize = 52;
+    pub const PM1_CNT_LEN: usize = 53;
+    pub const PM_TMR_LEN: usize = 55;
+    pub const GPE0_BLK_LEN: usize = 56;
+    pub const P_LVL2_LAT: Range<usize> = 60..62;
+    pub const P_LVL3_LAT: Range<usize> = 62..64;
+    pub const CENTURY: usize = 72;
+    pub const IAPC_BOOT_ARCH: Range<usize> = 73..75;
+    pub const FLAGS: Range<usize> = 76..80;
+
+    /// The size of a revision 3 FADT (ending with the X_GPE1_BLK).
+    pub const SIZE: usize = 208;
+}
+
+bitflags! {
+    /// Fixed Feature Flags of the FADT.
+    ///
+    /// See ACPI Table 5-35.
+    struct FadtFlags: u32 {
+        const WBINVD = 1 << 0;
+        const PROC_C1 = 1 << 2;
+        const PWR_BUTTON = 1 << 4;
+        const SLP_BUTTON = 1 << 5;
+        const TMR_VAL_EXT = 1 << 8;
+    }
+}
+
+bitflags! {
+    /// IA-PC Boot Architecture Flags of the FADT.
+    ///
+    /// See ACPI Table 5-36.
+    struct BootArchFlags: u16 {
+        const LEGACY_DEVICES = 1 << 0;
+        const I8042 = 1 << 1;
+    }
+}
+
+/// The size of the FACS (which has no SDT header).
+const FACS_SIZE: usize = 64;
+
+/// The SCI is routed to the legacy IRQ 9 (as on the PIIX4 and ICH9).
+pub const SCI_IRQ: u8 = 9;
+
+/// The AML of the DSDT, which only defines the S3 (suspend to RAM) and S5
+/// (soft off) sleep states used by `AcpiRuntime`:
+///
+/// ```text
+/// Name (\_S3, Package (0x04) { One, One, Zero, Zero })
+/// Name (\_S5, Package (0x04) { Zero, Zero, Zero, Zero })
+/// ```
+const DSDT_AML: &[u8] = &[
+    0x08, 0x5c, 0x5f, 0x53, 0x33, 0x5f, 0x12, 0x06, 0x04, 0x01, 0x01, 0x00,
+    0x00, 0x08, 0x5c, 0x5f, 0x53, 0x35, 0x5f, 0x12, 0x06, 0x04, 0x00, 0x00,
+    0x00, 0x00,
+];
+
+/// Builder for the ACPI tables of a guest.
+///
+/// The tables are installed by the BIOS from the fw_cfg files
+/// `etc/acpi/rsdp` and `etc/acpi/tables`, as directed by the commands in
+/// `etc/table-loader` (which also patch the pointers between tables).
+pub struct AcpiTablesBuilder {
+    cpus: usize,
+    pm_base: Port,
+    pic: bool,
+    ioapic: bool,
+    hpet: bool,
+    keyboard: bool,
+    pci_ecam: Option<GuestRegion>,
+}
+
+impl AcpiTablesBuilder {
+    /// Create a builder for a VM with `cpus` processors, where the ACPI
+    /// fixed hardware (see `AcpiRuntime`) starts at `pm_base`.
+    pub fn new(cpus: usize, pm_base: Port) -> AcpiTablesBuilder {
+        AcpiTablesBuilder {
+            cpus: cpus,
+            pm_base: pm_base,
+            pic: false,
+            ioapic: false,
+            hpet: false,
+            keyboard: false,
+            pci_ecam: None,
+        }
+    }
+
+    /// Report a legacy (8259) PIC.
+    pub fn add_pic(&mut self) {
+        self.pic = true;
+    }
+
+    /// Report an IOAPIC at `IOAPIC_BASE`.
+    pub fn add_ioapic(&mut self) {
+        self.ioapic = true;
+    }
+
+    /// Report an HPET at `HPET_BASE`.
+    pub fn add_hpet(&mut self) {
+        self.hpet = true;
+    }
+
+    /// Report an 8042 (PS/2) controller.
+    pub fn add_keyboard(&mut self) {
+        self.keyboard = true;
+    }
+
+    /// Report the PCI Express configuration space (ECAM) window, starting
+    /// with bus 0.
+    pub fn add_pci_ecam(&mut self, ecam: GuestRegion) {
+        self.pci_ecam = Some(ecam);
+    }
+
+    /// Build the tables and add them (and the loader commands) to the given
+    /// fw_cfg device.
+    pub fn build(&self, fw_cfg: &mut QemuFwCfgBuilder) -> Result<()> {
+        let mut loader = TableLoader::new();
+        loader.allocate(RSDP_FILE, 16, TableLoaderZone::FSeg)?;
+        loader.allocate(TABLES_FILE, 64, TableLoaderZone::High)?;
+
+        // The FACS must be 64 byte aligned, so it goes first
+        let mut tables = vec![0u8; FACS_SIZE];
+        Self::write_facs(&mut tables);
+
+        // The position of each table with a checksum
+        let mut checksums: Vec<Range<usize>> = vec![];
+        let mut append = |tables: &mut Vec<u8>, table: Vec<u8>| {
+            let start = tables.len();
+            tables.extend_from_slice(&table);
+            checksums.push(start..tables.len());
+            start
+        };
+
+        let dsdt = append(&mut tables, self.dsdt());
+        let fadt = append(&mut tables, self.fadt(0, dsdt as u32));
+        let mut entries = vec![fadt];
+        entries.push(append(&mut tables, self.madt()));
+        if self.hpet {
+            entries.push(append(&mut tables, self.hpet()));
+        }
+        if let Some(ref ecam) = self.pci_ecam {
+            entries.push(append(&mut tables, self.mcfg(ecam)));
+        }
+        let xsdt = append(&mut tables, self.xsdt(&entries));
+
+        // Patch the pointers now that all tables are placed
+        let fadt_field = |field: usize| (fadt + SDT_HEADER_SIZE + field) as u32;
+        loader.add_pointer(
+            TABLES_FILE,
+            TABLES_FILE,
+            fadt_field(fadt_offsets::FIRMWARE_CTRL.start),
+            4,
+        )?;
+        loader.add_pointer(
+            TABLES_FILE,
+            TABLES_FILE,
+            fadt_field(fadt_offsets::DSDT.start),
+            4,
+        )?;
+        for i in 0..entries.len() {
+            loader.add_pointer(
+                TABLES_FILE,
+                TABLES_FILE,
+                (xsdt + SDT_HEADER_SIZE + i * 8) as u32,
+                8,
+            )?;
+        }
+        loader.add_pointer(
+            RSDP_FILE,
+            TABLES_FILE,
+            rsdp::offsets::XSDT_ADDR.start as u32,
+            8,
+        )?;
+
+        // And then update the checksums of the patched tables
+        for table in checksums {
+            loader.add_checksum(
+                TABLES_FILE,
+                (table.start + rsdt::offsets::CHECKSUM) as u32,
+                table.start as u32,
+                table.len() as u32,
+            )?;
+        }
+        let rsdp = RSDP::V2 {
+            oemid: *MYTHRIL_OEMID,
+            xsdt_addr: xsdt as u64,
+        }
+        .to_bytes();
+        loader.add_checksum(
+            RSDP_FILE,
+            rsdp::offsets::EXT_CHECKSUM as u32,
+            0,
+            rsdp.len() as u32,
+        )?;
+
+        fw_cfg.add_file(RSDP_FILE, rsdp)?;
+        fw_cfg.add_file(TABLES_FILE, tables)?;
+        fw_cfg.add_file(LOADER_FILE, loader.as_bytes().to_vec())?;
+        Ok(())
+    }
+
+    /// Firmware ACPI Control Structure.
+    ///
+    /// See `ACPI § 5.2.10`.
+    fn write_facs(bytes: &mut [u8]) {
+        bytes[0..4].copy_from_slice(b"FACS");
+        NativeEndian::write_u32(&mut bytes[4..8], FACS_SIZE as u32);
+
+        // Version
+        bytes[32] = 1;
+    }
+
+    /// Differentiated System Description Table.
+    ///
+    /// See `ACPI § 5.2.11.1`.
+    fn dsdt(&self) -> Vec<u8> {
+        let mut sdt = SDTBuilder::new(b"DSDT", 1);
+        sdt.append(DSDT_AML);
+        sdt.finish()
+    }
+
+    /// Fixed ACPI Description Table, which points to the FACS and DSDT at
+    /// the given offsets in the tables file.
+    ///
+    /// See `ACPI § 5.2.9`.
+    fn fadt(&self, facs: u32, dsdt: u32) -> Vec<u8> {
+        let mut table = [0u8; fadt_offsets::SIZE];
+        NativeEndian::write_u32(&mut table[fadt_offsets::FIRMWARE_CTRL], facs);
+        NativeEndian::write_u32(&mut table[fadt_offsets::DSDT], dsdt);
+        NativeEndian::write_u16(
+            &mut table[fadt_offsets::SCI_INT],
+            SCI_IRQ as u16,
+        );
+
+        // The SMI command port is left as zero, so the guest knows the
+        // hardware is always in ACPI mode
+        NativeEndian::write_u32(
+            &mut table[fadt_offsets::PM1A_EVT_BLK],
+            (self.pm_base + PM1A_EVT_OFFSET) as u32,
+        );
+        table[fadt_offsets::PM1_EVT_LEN] = 4;
+        NativeEndian::write_u32(
+            &mut table[fadt_offsets::PM1A_CNT_BLK],
+            (self.pm_base + PM1A_CNT_OFFSET) as u32,
+        );
+        table[fadt_offsets::PM1_CNT_LEN] = 2;
+        NativeEndian::write_u32(
+            &mut table[fadt_offsets::PM_TMR_BLK],
+            (self.pm_base + PMTIMER_OFFSET) as u32,
+        );
+        table[fadt_offsets::PM_TMR_LEN] = 4;
+        NativeEndian::write_u32(
+            &mut table[fadt_offsets::GPE0_BLK],
+            AcpiRuntime::GPE_BLOCK_START as u32,
+        );
+        table[fadt_offsets::GPE0_BLK_LEN] = (AcpiRuntime::GPE_BLOCK_END
+            - AcpiRuntime::GPE_BLOCK_START
+            + 1) as u8;
+
+        // Latencies above 100 and 1000 mean C2 and C3 are not supported
+        NativeEndian::write_u16(&mut table[fadt_offsets::P_LVL2_LAT], 101);
+        NativeEndian::write_u16(&mut table[fadt_offsets::P_LVL3_LAT], 1001);
+        table[fadt_offsets::CENTURY] = RTC_CENTURY;
+
+        let mut boot_arch = BootArchFlags::LEGACY_DEVICES;
+        if self.keyboard {
+            boot_arch |= BootArchFlags::I8042;
+        }
+        NativeEndian::write_u16(
+            &mut table[fadt_offsets::IAPC_BOOT_ARCH],
+            boot_arch.bits(),
+        );
+
+        // The PM timer returns all 32 bits of the counter, and the power
+        // button is a fixed feature (so PWR_BUTTON is clear)
+        let flags = FadtFlags::WBINVD
+            | FadtFlags::PROC_C1
+            | FadtFlags::SLP_BUTTON
+            | FadtFlags::TMR_VAL_EXT;
+        NativeEndian::write_u32(&mut table[fadt_offsets::FLAGS], flags.bits());
+
+        let mut sdt = SDTBuilder::new(b"FACP", 3);
+        sdt.append(&table);
+        sdt.finish()
+    }
+
+    /// Multiple APIC Description Table.
+    ///
+    /// See `ACPI § 5.2.12`.
+    fn madt(&self) -> Vec<u8> {
+        // The guest APIC ids are the indices of the cores of the VM
+        let mut ics: Vec<Ics> = (0..self.cpus)
+            .map(|cpu| Ics::LocalApic {
+                apic_uid: cpu as u8,
+                apic_id: cpu as u8,
+                flags: LocalApicFlags::ENABLED,
+            })
+            .collect();
+
+        if self.ioapic {
+            ics.push(Ics::IoApic {
+                ioapic_id: 0,
+                ioapic_addr: IOAPIC_BASE as *mut u8,
+                gsi_base: 0,
+            });
+
+            // The PIT is connected to pin 2 of the IOAPIC (see `IrqLine`)
+            ics.push(Ics::InterruptSourceOverride {
+                source: 0,
+                gsi: 2,
+                flags: MpsIntiFlags::empty(),
+            });
+            ics.push(Ics::InterruptSourceOverride {
+                source: SCI_IRQ,
+                gsi: SCI_IRQ as u32,
+                flags: MpsIntiFlags::ACTIVE_HIGH
+                    | MpsIntiFlags::LEVEL_TRIGGERED,
+            });
+        }
+
+        // LINT1 of all processors is connected to NMI
+        ics.push(Ics::LocalApicNmi {
+            acpi_proc_uid: 0xff,
+            flags: MpsIntiFlags::empty(),
+            local_apic_lint: 1,
+        });
+
+        let flags = if self.pic {
+            MultipleApicFlags::PCAT_COMPAT
+        } else {
+            MultipleApicFlags::empty()
+        };
+        MADT::build(LOCAL_APIC_BASE as u32, flags, &ics)
+    }
+
+    /// IA-PC High Precision Event Timer Table.
+    fn hpet(&self) -> Vec<u8> {
+        let address = GenericAddressStructure {
+            address_space: AddressSpaceID::SystemMemory,
+            bit_width: 64,
+            bit_offset: 0,
+            access_size: AccessSize::Undefined,
+            address: HPET_BASE,
+        };
+        HPET::build(Hpet::capabilities() as u32, &address, 0x80)
+    }
+
+    /// PCI Express Memory-mapped Configuration Space base address
+    /// description table.
+    ///
+    /// See `PCI Firmware Specification § 4.1.2`.
+    fn mcfg(&self, ecam: &GuestRegion) -> Vec<u8> {
+        let mut sdt = SDTBuilder::new(b"MCFG", 1);
+        sdt.append(&[0u8; 8]);
+
+        // Each bus has 1MB of configuration space
+        let mut allocation = [0u8; 16];
+        NativeEndian::write_u64(&mut allocation[0..8], ecam.start.as_u64());
+        allocation[11] = ((ecam.size >> 20) - 1) as u8;
+        sdt.append(&allocation);
+        sdt.finish()
+    }
+
+    /// Extended System Description Table, with entries pointing to the
+    /// given offsets in the tables file.
+    ///
+    /// See `ACPI § 5.2.8`.
+    fn xsdt(&self, entries: &[usize]) -> Vec<u8> {
+        let mut sdt = SDTBuilder::new(b"XSDT", 1);
+        for entry in entries {
+            let mut bytes = [0u8; 8];
+            NativeEndian::write_u64(&mut bytes, *entry as u64);
+            sdt.append(&bytes);
+        }
+        sdt.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::acpi::calculate_checksum;
+    use crate::acpi::rsdt::SDT;
+    use crate::device::qemu_fw_cfg::TABLE_LOADER_COMMAND_SIZE;
+
+    #[test]
+    fn test_madt_build() {
+        let mut builder = AcpiTablesBuilder::new(2, 0xb000);
+        builder.add_ioapic();
+        builder.add_pic();
+
+        let bytes = builder.madt();
+        let sdt = unsafe { SDT::new(bytes.as_ptr()).unwrap() };
+        let madt = MADT::new(&sdt);
+        assert_eq!(madt.ica as u64, LOCAL_APIC_BASE);
+        assert_eq!(madt.flags, MultipleApicFlags::PCAT_COMPAT);
+
+        let ics = madt.structures().collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(ics.len(), 6);
+        match ics[1] {
+            Ics::LocalApic { apic_id, flags, .. } => {
+                assert_eq!(apic_id, 1);
+                assert_eq!(flags, LocalApicFlags::ENABLED);
+            }
+            _ => panic!("Expected a local APIC, found {:?}", ics[1]),
+        }
+        match ics[2] {
+            Ics::IoApic { ioapic_addr, .. } => {
+                assert_eq!(ioapic_addr as u64, IOAPIC_BASE)
+            }
+            _ => panic!("Expected an IOAPIC, found {:?}", ics[2]),
+        }
+    }
+
+    #[test]
+    fn test_fadt_build() {
+        let builder = AcpiTablesBuilder::new(1, 0xb000);
+        let bytes = builder.fadt(0, 0x40);
+        let sdt = unsafe { SDT::new(bytes.as_ptr()).unwrap() };
+        assert_eq!(&sdt.signature, b"FACP");
+        assert_eq!(sdt.len(), fadt_offsets::SIZE);
+        assert_eq!(
+            NativeEndian::read_u32(&sdt.table[fadt_offsets::DSDT]),
+            0x40
+        );
+        assert_eq!(
+            NativeEndian::read_u32(&sdt.table[fadt_offsets::PM1A_CNT_BLK]),
+            0xb004
+        );
+    }
+
+    // Where the simulated BIOS loads the RSDP and the tables
+    const RSDP_BASE: u64 = 0xf_5a40;
+    const TABLES_BASE: u64 = 0x7ffe_0000;
+
+    fn file_name(bytes: &[u8]) -> &str {
+        let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        core::str::from_utf8(&bytes[..len]).unwrap()
+    }
+
+    /// Run the table loader commands like the BIOS, returning the loaded
+    /// RSDP and tables files and the type of each command
+    fn run_loader(fw_cfg: &QemuFwCfgBuilder) -> (Vec<u8>, Vec<u8>, Vec<u32>) {
+        let mut files = [
+            (
+                RSDP_FILE,
+                RSDP_BASE,
+                fw_cfg.file(RSDP_FILE).unwrap().to_vec(),
+            ),
+            (
+                TABLES_FILE,
+                TABLES_BASE,
+                fw_cfg.file(TABLES_FILE).unwrap().to_vec(),
+            ),
+        ];
+        let find = |name: &str| {
+            [RSDP_FILE, TABLES_FILE]
+                .iter()
+                .position(|file| *file == name)
+                .unwrap()
+        };
+
+        let loader = fw_cfg.file(LOADER_FILE).unwrap();
+        assert_eq!(loader.len() % TABLE_LOADER_COMMAND_SIZE, 0);
+
+        let mut commands = vec![];
+        for cmd in loader.chunks(TABLE_LOADER_COMMAND_SIZE) {
+            let command = NativeEndian::read_u32(&cmd[0..4]);
+            let file = find(file_name(&cmd[4..60]));
+            match command {
+                // Allocate (the files are loaded at fixed addresses)
+                1 => (),
+
+                // AddPointer
+                2 => {
+                    let src = files[find(file_name(&cmd[60..116]))].1;
+                    let offset =
+                        NativeEndian::read_u32(&cmd[116..120]) as usize;
+                    let size = cmd[120] as usize;
+                    let data = &mut files[file].2[offset..offset + size];
+                    let mut ptr = [0u8; 8];
+                    ptr[..size].copy_from_slice(data);
+                    let ptr = u64::from_le_bytes(ptr) + src;
+                    data.copy_from_slice(&ptr.to_le_bytes()[..size]);
+                }
+
+                // AddChecksum
+                3 => {
+                    let offset = NativeEndian::read_u32(&cmd[60..64]) as usize;
+                    let start = NativeEndian::read_u32(&cmd[64..68]) as usize;
+                    let len = NativeEndian::read_u32(&cmd[68..72]) as usize;
+                    let data = &mut files[file].2;
+                    data[offset] = 0;
+                    let checksum =
+                        calculate_checksum(&data[start..start + len]);
+                    data[offset] = checksum;
+                }
+                _ => panic!("Unknown table loader command {}", command),
+            }
+            commands.push(command);
+        }
+
+        (files[0].2.clone(), files[1].2.clone(), commands)
+    }
+
+    /// The table at the given guest address in the loaded tables file
+    fn table_at(tables: &[u8], addr: u64) -> SDT {
+        let offset = (addr - TABLES_BASE) as usize;
+        unsafe { SDT::new(tables[offset..].as_ptr()).unwrap() }
+    }
+
+    #[test]
+    fn test_build_tables() {
+        let mut builder = AcpiTablesBuilder::new(1, 0xb000);
+        builder.add_hpet();
+        let mut fw_cfg = QemuFwCfgBuilder::new();
+        builder.build(&mut fw_cfg).unwrap();
+
+        let (rsdp, tables, commands) = run_loader(&fw_cfg);
+
+        // The files are allocated first, then the pointers are patched (in
+        // the FADT, the three XSDT entries and the RSDP) before the
+        // checksums of the five tables and the RSDP are updated
+        let mut expected = vec![1, 1];
+        expected.extend_from_slice(&[2; 6]);
+        expected.extend_from_slice(&[3; 6]);
+        assert_eq!(commands, expected);
+
+        // The RSDP points to the XSDT (and has valid checksums)
+        let xsdt_addr = match RSDP::from_bytes(&rsdp).unwrap() {
+            RSDP::V2 { xsdt_addr, .. } => xsdt_addr,
+            _ => panic!("Expected a revision two RSDP"),
+        };
+
+        // The tables are checked by SDT::new, which fails for a bad
+        // checksum
+        let xsdt = table_at(&tables, xsdt_addr);
+        assert_eq!(&xsdt.signature, b"XSDT");
+        let entries: Vec<_> = xsdt
+            .table
+            .chunks(8)
+            .map(|entry| table_at(&tables, NativeEndian::read_u64(entry)))
+            .collect();
+        let signatures: Vec<_> =
+            entries.iter().map(|table| &table.signature).collect();
+        assert_eq!(signatures, vec![b"FACP", b"APIC", b"HPET"]);
+
+        // The FACS is at the start of the tables file
+        let fadt = &entries[0];
+        assert_eq!(
+            NativeEndian::read_u32(&fadt.table[fadt_offsets::FIRMWARE_CTRL])
+                as u64,
+            TABLES_BASE
+        );
+        assert_eq!(&tables[0..4], b"FACS");
+
+        let dsdt = NativeEndian::read_u32(&fadt.table[fadt_offsets::DSDT]);
+        let dsdt = table_at(&tables, dsdt as u64);
+        assert_eq!(&dsdt.signature, b"DSDT");
+        assert_eq!(dsdt.table, DSDT_AML);
+
+        let hpet = HPET::new(&entries[2]).unwrap();
+        assert_eq!(hpet.address.address, HPET_BASE);
+        assert_eq!(hpet.pci_vendor_id, 0x8086);
+    }
+}
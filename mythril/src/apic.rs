@@ -59,7 +59,7 @@ pub enum DstMode {
     Logical = 0x01,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 #[repr(u8)]
 /// ICR delivery mode
 pub enum DeliveryMode {
@@ -295,10 +295,9 @@ impl LocalApic {
     /// the requested vector at the requested time. This will clear any outstanding
     /// apic interrupt.
     pub fn schedule_interrupt(&mut self, when: time::Instant, vector: u8) {
-        //TODO: always round _up_ here to avoid the timer not actually being
-        // expired when we receive the interrupt
-        let micros = (when - time::now()).as_micros();
-        let ticks = micros * self.ticks_per_ms as u128 / 1000;
+        // A time in the past fires as soon as possible
+        let micros = when.saturating_duration_since(time::now()).as_micros();
+        let ticks = timer_ticks(micros, self.ticks_per_ms);
         unsafe {
             msr::wrmsr(msr::IA32_X2APIC_DIV_CONF, 0x3); // timer divisor = 16
             msr::wrmsr(msr::IA32_X2APIC_LVT_TIMER, vector as u64);
@@ -306,3 +305,29 @@ impl LocalApic {
         }
     }
 }
+
+/// The initial count of a timer that fires after `micros` microseconds
+///
+/// This rounds up, so the time has always passed when the interrupt
+/// arrives, and is at least one tick, as a count of zero stops the timer.
+fn timer_ticks(micros: u128, ticks_per_ms: u64) -> u32 {
+    let ticks = (micros * ticks_per_ms as u128 + 999) / 1000;
+    ticks.max(1).min(u32::MAX as u128) as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_timer_ticks() {
+        assert_eq!(timer_ticks(1000, 62_500), 62_500);
+        assert_eq!(timer_ticks(1, 62_500), 63);
+
+        // Deadlines that have passed (or are less than a tick away) must
+        // still fire, and long ones are limited to the largest count
+        assert_eq!(timer_ticks(0, 62_500), 1);
+        assert_eq!(timer_ticks(1, 100), 1);
+        assert_eq!(timer_ticks(u64::MAX as u128, 62_500), u32::MAX);
+    }
+}
@@ -9,6 +9,13 @@ use alloc::{string::String, vec::Vec};
 pub struct BootInfo {
     pub modules: Vec<BootModule>,
     pub rsdp: Option<acpi::rsdp::RSDP>,
+
+    /// The usable host memory
+    pub memory_map: Vec<MemoryRegion>,
+
+    /// The usable host memory that is already in use (by the hypervisor
+    /// image, the boot modules and the kernel heap)
+    pub reserved: Vec<MemoryRegion>,
 }
 
 impl BootInfo {
@@ -26,6 +33,22 @@ impl BootInfo {
     }
 }
 
+/// A range of host physical memory
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryRegion {
+    pub start: HostPhysAddr,
+    pub end: HostPhysAddr,
+}
+
+impl MemoryRegion {
+    pub fn new(start: u64, end: u64) -> Self {
+        Self {
+            start: HostPhysAddr::new(start),
+            end: HostPhysAddr::new(end),
+        }
+    }
+}
+
 pub struct BootModule {
     pub identifier: Option<String>,
     pub address: HostPhysAddr,
@@ -33,7 +56,11 @@ pub struct BootModule {
 }
 
 impl BootModule {
-    pub fn data(&self) -> &[u8] {
+    /// The contents of the module
+    ///
+    /// The modules are reserved memory, so they remain valid for as long
+    /// as the hypervisor runs.
+    pub fn data(&self) -> &'static [u8] {
         unsafe {
             core::slice::from_raw_parts(
                 self.address.as_u64() as *const u8,
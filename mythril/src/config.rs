@@ -0,0 +1,895 @@
+//! Support for the declarative mythril configuration
+//!
+//! The configuration is passed to mythril as a multiboot module named
+//! `mythril.cfg`. It uses a small subset of TOML, where each virtual
+//! machine is described by a `[[vm]]` section. For example:
+//!
+//! ```text
+//! [[vm]]
+//! cpus = [0]
+//! memory = 256 # in MB
+//! pci_hole = 3072 # RAM beyond the first 3072MB is placed above 4GB
+//! bios = "seabios.bin"
+//! kernel = "kernel"
+//! initramfs = "initramfs"
+//! cmdline = "console=ttyS0 earlyprintk=serial,0x3f8,115200"
+//! devices = ["acpi", "com1", "pic", "pit", "rtc", "pci"]
+//! cpuid_max_leaf = 0xd
+//! cpuid = ["0x7.0:ebx:0:0x20"] # hide AVX2
+//! exit_trace = 64 # keep the last 64 exits of each vcpu
+//! rtc_base = "host" # or a fixed time in seconds since the UNIX epoch
+//! rtc_nvram = "cmos.bin" # the initial CMOS contents (128 bytes)
+//! tsc_khz = 2_000_000 # the guest TSC frequency (requires TSC scaling)
+//! ```
+//!
+//! Any key that is not present in a section takes the value used by
+//! `UserVmConfig::default`.
+
+use crate::acpi::tables::{AcpiTablesBuilder, SCI_IRQ};
+use crate::boot_info::BootInfo;
+use crate::device::qemu_fw_cfg::FwCfgSelector;
+use crate::device::{self, EmulatedDevice, Port};
+use crate::emulate::cpuid::CpuidOverride;
+use crate::error::{Error, Result};
+use crate::linux;
+use crate::memory_layout::{self, GuestMemoryLayout};
+use crate::rtc;
+use crate::serial;
+use crate::time;
+use crate::vm::VirtualMachineConfig;
+use crate::vmx;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::iter::Peekable;
+use core::str::Chars;
+use spin::Mutex;
+
+/// The name of the multiboot module containing the mythril configuration
+pub const CONFIG_MODULE_NAME: &str = "mythril.cfg";
+
+/// The devices given to a virtual machine that does not list any
+pub const DEFAULT_DEVICES: &[&str] = &[
+    "acpi", "com1", "com2", "com3", "com4", "debugcon", "vga", "dma", "ignore",
+    "pci", "pic", "keyboard", "pit", "pos", "rtc", "ioapic", "hpet",
+];
+
+// The base port of the ACPI fixed hardware (see `AcpiRuntime`)
+const ACPI_PM_BASE: Port = 0xb000;
+
+// The smallest amount of VM memory (in MB). The BIOS, the boot images and
+// the guest's page tables need more than the first 1MB.
+const MIN_MEMORY: u64 = 16;
+
+// The largest guest TSC frequency (in kHz). This keeps the TSC multiplier
+// (a 16.48 fixed point value) in range for any real host frequency.
+const MAX_TSC_KHZ: u64 = 100_000_000;
+
+const DEFAULT_CMDLINE: &str = core::concat!(
+    "rodata=0 nopti ",
+    "earlyprintk=serial,0x3f8,115200 ",
+    "console=ttyS0 debug nokaslr mitigations=off ",
+    "root=/dev/ram0 rdinit=/init"
+);
+
+/// The mythril configuration (i.e., the set of virtual machines to run)
+#[derive(Debug, Default, PartialEq)]
+pub struct UserConfig {
+    /// The virtual machines described by the configuration
+    pub vms: Vec<UserVmConfig>,
+}
+
+impl UserConfig {
+    /// Read the configuration from the `mythril.cfg` boot module, if present
+    pub fn from_boot_info(info: &BootInfo) -> Result<Option<Self>> {
+        match info.find_module(CONFIG_MODULE_NAME) {
+            Some(module) => Ok(Some(Self::parse(module.data())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Parse a configuration from the contents of a `mythril.cfg` module
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        let text = core::str::from_utf8(data).map_err(|_| {
+            Error::InvalidValue(format!(
+                "{} is not valid UTF-8",
+                CONFIG_MODULE_NAME
+            ))
+        })?;
+
+        let mut vms: Vec<UserVmConfig> = vec![];
+        for (idx, line) in text.lines().enumerate() {
+            let invalid = |msg: String| {
+                Error::InvalidValue(format!(
+                    "{}:{}: {}",
+                    CONFIG_MODULE_NAME,
+                    idx + 1,
+                    msg
+                ))
+            };
+
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                if line != "[[vm]]" {
+                    return Err(invalid(format!("unknown section '{}'", line)));
+                }
+                vms.push(UserVmConfig::default());
+                continue;
+            }
+
+            let vm = vms.last_mut().ok_or_else(|| {
+                invalid("key outside of a [[vm]] section".into())
+            })?;
+
+            let eq = line.find('=').ok_or_else(|| {
+                invalid(format!("expected '=' in '{}'", line))
+            })?;
+            let key = line[..eq].trim();
+
+            let mut parser = ValueParser {
+                chars: line[eq + 1..].chars().peekable(),
+            };
+            let value = parser.parse_value().map_err(&invalid)?;
+            parser.expect_end().map_err(&invalid)?;
+
+            vm.set(key, value).map_err(&invalid)?;
+        }
+
+        let mut used_cpus = vec![];
+        let mut used_gdb_ports = vec![];
+        for (i, vm) in vms.iter().enumerate() {
+            if let Some(port) = vm.gdb {
+                if used_gdb_ports.contains(&port) {
+                    return Err(Error::InvalidValue(format!(
+                        "{}: gdb port 0x{:x} is used by multiple vms",
+                        CONFIG_MODULE_NAME, port
+                    )));
+                }
+                used_gdb_ports.push(port);
+            }
+
+            if vm.cpus.is_empty() {
+                return Err(Error::InvalidValue(format!(
+                    "{}: vm {} has no cpus",
+                    CONFIG_MODULE_NAME, i
+                )));
+            }
+            if vm.memory < MIN_MEMORY || vm.memory > u64::MAX >> 20 {
+                return Err(Error::InvalidValue(format!(
+                    "{}: vm {} has an invalid memory size of {}MB",
+                    CONFIG_MODULE_NAME, i, vm.memory
+                )));
+            }
+            if let Some(khz) = vm.tsc_khz {
+                if khz == 0 || khz > MAX_TSC_KHZ {
+                    return Err(Error::InvalidValue(format!(
+                        "{}: vm {} has an invalid tsc_khz of {}",
+                        CONFIG_MODULE_NAME, i, khz
+                    )));
+                }
+            }
+
+            for cpu in vm.cpus.iter() {
+                if used_cpus.contains(cpu) {
+                    return Err(Error::InvalidValue(format!(
+                        "{}: cpu {} is assigned to multiple vms",
+                        CONFIG_MODULE_NAME, cpu
+                    )));
+                }
+                used_cpus.push(*cpu);
+            }
+        }
+
+        Ok(UserConfig { vms })
+    }
+
+    /// Check that every cpu used by the VMs is one of the host cores (by
+    /// APIC ID)
+    pub fn check_cpus(&self, apic_ids: &[u32]) -> Result<()> {
+        for (i, vm) in self.vms.iter().enumerate() {
+            if let Some(cpu) = vm
+                .cpus
+                .iter()
+                .find(|cpu| !apic_ids.contains(&(**cpu as u32)))
+            {
+                return Err(Error::InvalidValue(format!(
+                    "{}: vm {} uses missing cpu {}",
+                    CONFIG_MODULE_NAME, i, cpu
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The time the emulated RTC of a VM starts from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RtcBase {
+    /// The current time of the host RTC
+    Host,
+
+    /// A fixed time (in seconds since the UNIX epoch), so every boot of the
+    /// VM starts at the same time
+    Fixed(u64),
+}
+
+/// The configuration of a single virtual machine
+#[derive(Clone, Debug, PartialEq)]
+pub struct UserVmConfig {
+    /// The cores used by the VM (by APIC id)
+    pub cpus: Vec<u8>,
+
+    /// The amount of VM memory (in MB)
+    pub memory: u64,
+
+    /// The start of the PCI hole (in MB). Any memory that doesn't fit below
+    /// the hole is placed above 4GB.
+    pub pci_hole: u64,
+
+    /// The name of the BIOS module
+    pub bios: Option<String>,
+
+    /// The name of the linux kernel module (if a kernel should be loaded)
+    pub kernel: Option<String>,
+
+    /// The name of the initramfs module
+    pub initramfs: String,
+
+    /// The kernel command line
+    pub cmdline: String,
+
+    /// The names of the emulated devices available to the VM
+    pub devices: Vec<String>,
+
+    /// The highest basic CPUID leaf reported to the guest
+    pub cpuid_max_leaf: Option<u32>,
+
+    /// The highest extended CPUID leaf reported to the guest
+    pub cpuid_max_extended_leaf: Option<u32>,
+
+    /// Additional modifications of the CPUID values reported to the guest
+    /// (see `CpuidOverride` for the format)
+    pub cpuid: Vec<CpuidOverride>,
+
+    /// The host serial port (by base I/O port) used to debug the VM's BSP
+    /// with GDB, given as a port name like "com2" in the configuration
+    pub gdb: Option<u16>,
+
+    /// The number of recent exits to keep for each vcpu (zero to disable
+    /// exit tracing)
+    pub exit_trace: usize,
+
+    /// The time the emulated RTC starts from
+    pub rtc_base: RtcBase,
+
+    /// The name of the module containing the initial contents of the CMOS
+    /// memory (e.g., a dump from the monitor's `nvram` command)
+    pub rtc_nvram: Option<String>,
+
+    /// The frequency of the guest TSC (in kHz), if it should differ from
+    /// the host's
+    pub tsc_khz: Option<u64>,
+}
+
+impl Default for UserVmConfig {
+    fn default() -> Self {
+        Self {
+            cpus: vec![],
+            memory: 256,
+            pci_hole: memory_layout::DEFAULT_PCI_HOLE >> 20,
+            bios: Some("seabios.bin".into()),
+            kernel: Some("kernel".into()),
+            initramfs: "initramfs".into(),
+            cmdline: DEFAULT_CMDLINE.into(),
+            devices: DEFAULT_DEVICES.iter().map(|d| d.to_string()).collect(),
+            cpuid_max_leaf: None,
+            cpuid_max_extended_leaf: None,
+            cpuid: vec![],
+            gdb: None,
+            exit_trace: 0,
+            rtc_base: RtcBase::Host,
+            rtc_nvram: None,
+            tsc_khz: None,
+        }
+    }
+}
+
+impl UserVmConfig {
+    fn set(
+        &mut self,
+        key: &str,
+        value: Value,
+    ) -> core::result::Result<(), String> {
+        match key {
+            "cpus" => {
+                self.cpus = value
+                    .into_array()?
+                    .into_iter()
+                    .map(|cpu| {
+                        let cpu = cpu.into_integer()?;
+                        u8::try_from(cpu)
+                            .map_err(|_| format!("invalid cpu '{}'", cpu))
+                    })
+                    .collect::<core::result::Result<_, _>>()?
+            }
+            "memory" => self.memory = value.into_integer()?,
+            "pci_hole" => self.pci_hole = value.into_integer()?,
+            "bios" => self.bios = Some(value.into_string()?),
+            "kernel" => self.kernel = Some(value.into_string()?),
+            "initramfs" => self.initramfs = value.into_string()?,
+            "cmdline" => self.cmdline = value.into_string()?,
+            "devices" => {
+                self.devices = value
+                    .into_array()?
+                    .into_iter()
+                    .map(Value::into_string)
+                    .collect::<core::result::Result<_, _>>()?
+            }
+            "cpuid_max_leaf" => self.cpuid_max_leaf = Some(value.into_u32()?),
+            "cpuid_max_extended_leaf" => {
+                self.cpuid_max_extended_leaf = Some(value.into_u32()?)
+            }
+            "cpuid" => {
+                self.cpuid = value
+                    .into_array()?
+                    .into_iter()
+                    .map(|over| {
+                        over.into_string()?
+                            .parse::<CpuidOverride>()
+                            .map_err(|e| format!("{:?}", e))
+                    })
+                    .collect::<core::result::Result<_, _>>()?
+            }
+            "gdb" => {
+                let name = value.into_string()?;
+                let port = serial::com_port_base(&name)
+                    .ok_or_else(|| format!("unknown serial port '{}'", name))?;
+                if port == serial::LOG_PORT_BASE {
+                    return Err(format!(
+                        "{} is used for the mythril log",
+                        name
+                    ));
+                }
+                self.gdb = Some(port);
+            }
+            "exit_trace" => self.exit_trace = value.into_integer()? as usize,
+            "rtc_base" => {
+                self.rtc_base = match value {
+                    Value::Integer(time) => RtcBase::Fixed(time),
+                    value => match value.into_string()?.as_str() {
+                        "host" => RtcBase::Host,
+                        base => {
+                            return Err(format!("invalid rtc base '{}'", base))
+                        }
+                    },
+                }
+            }
+            "rtc_nvram" => self.rtc_nvram = Some(value.into_string()?),
+            "tsc_khz" => self.tsc_khz = Some(value.into_integer()?),
+            _ => return Err(format!("unknown key '{}'", key)),
+        }
+        Ok(())
+    }
+
+    /// Build the `VirtualMachineConfig` described by this configuration
+    ///
+    /// # Arguments
+    ///
+    /// * `vmid` - The identifier of the resulting VM (used for logging)
+    /// * `info` - The `BootInfo` containing the referenced modules
+    pub fn build(
+        &self,
+        vmid: u64,
+        info: &BootInfo,
+    ) -> Result<VirtualMachineConfig> {
+        let layout = GuestMemoryLayout::new(self.memory, self.pci_hole << 20)?;
+        let mut config =
+            VirtualMachineConfig::new(self.cpus.clone(), layout.clone());
+
+        if let Some(ref bios) = self.bios {
+            config.map_bios(bios.clone())?;
+        }
+
+        if let Some(leaf) = self.cpuid_max_leaf {
+            config.cpuid_mut().set_max_basic_leaf(leaf);
+        }
+        if let Some(leaf) = self.cpuid_max_extended_leaf {
+            config.cpuid_mut().set_max_extended_leaf(leaf);
+        }
+        for over in self.cpuid.iter() {
+            config.cpuid_mut().add_override(over.clone());
+        }
+
+        if let Some(port) = self.gdb {
+            config.set_gdb_port(port);
+        }
+        config.set_exit_trace_size(self.exit_trace);
+
+        if let Some(khz) = self.tsc_khz {
+            let frequency = khz.checked_mul(1000).ok_or_else(|| {
+                Error::InvalidValue(format!("Invalid tsc_khz {}", khz))
+            })?;
+            let clock = config.lifecycle().clock().clone();
+            clock.set_frequency(frequency, time::frequency());
+
+            // Fail now, instead of when the vcpus are created
+            if clock.tsc_controls().multiplier.is_some()
+                && !vmx::Vmx::supports_tsc_scaling()
+            {
+                return Err(Error::InvalidValue(format!(
+                    "{}: vm {} sets tsc_khz, but the processor does not \
+                     support TSC scaling",
+                    CONFIG_MODULE_NAME, vmid
+                )));
+            }
+        }
+
+        for name in self.devices.iter() {
+            let dev = self.device(name, vmid, info, &mut config)?;
+            config.device_map().register_device(dev)?;
+        }
+
+        let mut fw_cfg_builder = device::qemu_fw_cfg::QemuFwCfgBuilder::new();
+
+        // The BIOS uses the cpu count to know how many APs to wait for
+        let nb_cpus = (self.cpus.len() as u16).to_le_bytes();
+        fw_cfg_builder.add_bytes(FwCfgSelector::NB_CPUS, nb_cpus.to_vec());
+        fw_cfg_builder.add_bytes(FwCfgSelector::MAX_CPUS, nb_cpus.to_vec());
+
+        // The BIOS builds the guest's memory map from the e820 file
+        fw_cfg_builder.add_bytes(
+            FwCfgSelector::RAM_SIZE,
+            layout.ram_size().to_le_bytes().to_vec(),
+        );
+        fw_cfg_builder.add_file("etc/e820", layout.e820_table())?;
+
+        // The PIT is connected to pin 2 of the IOAPIC, so have the BIOS
+        // report the interrupt source override.
+        if self.devices.iter().any(|d| d == "ioapic") {
+            fw_cfg_builder.add_i32(FwCfgSelector::X86_IRQ0_OVERRIDES, 1);
+        }
+
+        if self.devices.iter().any(|d| d == "acpi") {
+            self.acpi_tables(config.layout())
+                .build(&mut fw_cfg_builder)?;
+        }
+
+        if let Some(ref kernel) = self.kernel {
+            // The 'linuxboot' file is an option rom that loads the linux kernel
+            // via qemu_fw_cfg
+            let linuxboot =
+                info.find_module("linuxboot_dma.bin").ok_or_else(|| {
+                    Error::MissingFile("linuxboot_dma.bin".into())
+                })?;
+            fw_cfg_builder
+                .add_file("genroms/linuxboot_dma.bin", linuxboot.data())?;
+
+            // Passing the bootorder file automatically selects the option rom
+            // as the default boot device
+            fw_cfg_builder.add_file(
+                "bootorder",
+                "/rom@genroms/linuxboot_dma.bin\nHALT".as_bytes(),
+            )?;
+
+            let mut cmdline = self.cmdline.clone().into_bytes();
+            cmdline.push(b'\0');
+
+            linux::load_linux(
+                kernel,
+                &self.initramfs,
+                &cmdline,
+                &layout,
+                &mut fw_cfg_builder,
+                info,
+            )?;
+        }
+        config
+            .device_map()
+            .register_device(fw_cfg_builder.build())?;
+
+        Ok(config)
+    }
+
+    fn acpi_tables(&self, layout: &GuestMemoryLayout) -> AcpiTablesBuilder {
+        let mut tables = AcpiTablesBuilder::new(self.cpus.len(), ACPI_PM_BASE);
+        for name in self.devices.iter() {
+            match name.as_str() {
+                "hpet" => tables.add_hpet(),
+                "ioapic" => tables.add_ioapic(),
+                "keyboard" => tables.add_keyboard(),
+                "pci" => tables.add_pci_ecam(layout.pci_ecam()),
+                "pic" => tables.add_pic(),
+                _ => (),
+            }
+        }
+        tables
+    }
+
+    fn device(
+        &self,
+        name: &str,
+        vmid: u64,
+        info: &BootInfo,
+        config: &mut VirtualMachineConfig,
+    ) -> Result<Box<dyn EmulatedDevice>> {
+        let dev: Box<dyn EmulatedDevice> = match name {
+            "acpi" => {
+                let acpi = device::acpi::AcpiRuntime::new(
+                    ACPI_PM_BASE,
+                    config.irq_line(SCI_IRQ),
+                    config.lifecycle().clone(),
+                )?;
+                let acpi = Arc::new(Mutex::new(*acpi));
+                config.set_acpi(acpi.clone());
+                Box::new(acpi)
+            }
+            "com1" => Self::com_device(vmid, 0x3F8, 4, config),
+            "com2" => Self::com_device(vmid, 0x2F8, 3, config),
+            "com3" => Self::com_device(vmid, 0x3E8, 4, config),
+            "com4" => Self::com_device(vmid, 0x2E8, 3, config),
+            "debugcon" => device::debug::DebugPort::new(vmid, 0x402),
+            "dma" => device::dma::Dma8237::new(),
+            "hpet" => device::hpet::Hpet::new(
+                (0..16).map(|irq| config.irq_line(irq)).collect(),
+                config.lifecycle().clock().clone(),
+            ),
+            "ignore" => device::ignore::IgnoredDevice::new(),
+            "keyboard" => {
+                device::keyboard::Keyboard8042::new(config.lifecycle().clone())
+            }
+            "ioapic" => Box::new(config.ioapic().clone()),
+            "pci" => device::pci::PciRootComplex::new(
+                config.lifecycle().clone(),
+                config.layout().pci_ecam().start,
+            ),
+            "pic" => Box::new(config.pic().clone()),
+            "pit" => device::pit::Pit8254::new(
+                config.irq_line(0),
+                config.lifecycle().clock().clone(),
+            ),
+            "pos" => device::pos::ProgrammableOptionSelect::new(),
+            "rtc" => {
+                let nvram = match self.rtc_nvram {
+                    Some(ref name) => Some(
+                        info.find_module(name)
+                            .ok_or_else(|| Error::MissingFile(name.clone()))?
+                            .data(),
+                    ),
+                    None => None,
+                };
+                let base_time = match self.rtc_base {
+                    RtcBase::Host => rtc::read_host_time(),
+                    RtcBase::Fixed(time) => time,
+                };
+                let rtc = device::rtc::CmosRtc::new(
+                    config.layout(),
+                    nvram,
+                    base_time,
+                    config.irq_line(8),
+                    config.lifecycle().clone(),
+                )?;
+                let rtc = Arc::new(Mutex::new(*rtc));
+                config.set_rtc(rtc.clone());
+                Box::new(rtc)
+            }
+            "vga" => device::vga::VgaController::new(),
+            _ => {
+                return Err(Error::InvalidValue(format!(
+                    "Unknown device '{}'",
+                    name
+                )))
+            }
+        };
+        Ok(dev)
+    }
+
+    fn com_device(
+        vmid: u64,
+        port: u16,
+        irq: u8,
+        config: &mut VirtualMachineConfig,
+    ) -> Box<dyn EmulatedDevice> {
+        let com = device::com::ComDevice::new(vmid, port, config.irq_line(irq));
+        let com = Arc::new(Mutex::new(*com));
+        config.add_com_port(com.clone());
+        Box::new(com)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    Integer(u64),
+    String(String),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn into_integer(self) -> core::result::Result<u64, String> {
+        match self {
+            Value::Integer(val) => Ok(val),
+            val => Err(format!("expected an integer, found {:?}", val)),
+        }
+    }
+
+    fn into_u32(self) -> core::result::Result<u32, String> {
+        let val = self.into_integer()?;
+        u32::try_from(val).map_err(|_| format!("invalid value '{}'", val))
+    }
+
+    fn into_string(self) -> core::result::Result<String, String> {
+        match self {
+            Value::String(val) => Ok(val),
+            val => Err(format!("expected a string, found {:?}", val)),
+        }
+    }
+
+    fn into_array(self) -> core::result::Result<Vec<Value>, String> {
+        match self {
+            Value::Array(val) => Ok(val),
+            val => Err(format!("expected an array, found {:?}", val)),
+        }
+    }
+}
+
+struct ValueParser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl<'a> ValueParser<'a> {
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.chars.peek() {
+            if !c.is_whitespace() {
+                break;
+            }
+            self.chars.next();
+        }
+    }
+
+    fn expect_end(&mut self) -> core::result::Result<(), String> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(c) => Err(format!("unexpected character '{}'", c)),
+            None => Ok(()),
+        }
+    }
+
+    fn parse_value(&mut self) -> core::result::Result<Value, String> {
+        self.skip_whitespace();
+        match self.chars.peek() {
+            Some('"') => self.parse_string(),
+            Some('[') => self.parse_array(),
+            Some(c) if c.is_ascii_digit() => self.parse_integer(),
+            Some(c) => Err(format!("unexpected character '{}'", c)),
+            None => Err("missing value".into()),
+        }
+    }
+
+    fn parse_integer(&mut self) -> core::result::Result<Value, String> {
+        let mut digits = String::new();
+        while let Some(c) = self.chars.peek() {
+            if !c.is_ascii_alphanumeric() && *c != '_' {
+                break;
+            }
+            if *c != '_' {
+                digits.push(*c);
+            }
+            self.chars.next();
+        }
+
+        let val = if digits.starts_with("0x") {
+            u64::from_str_radix(&digits[2..], 16)
+        } else {
+            digits.parse::<u64>()
+        };
+        val.map(Value::Integer)
+            .map_err(|_| format!("invalid integer '{}'", digits))
+    }
+
+    fn parse_string(&mut self) -> core::result::Result<Value, String> {
+        // Skip the opening quote
+        self.chars.next();
+
+        let mut val = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(Value::String(val)),
+                Some('\\') => match self.chars.next() {
+                    Some('"') => val.push('"'),
+                    Some('\\') => val.push('\\'),
+                    Some('n') => val.push('\n'),
+                    Some('t') => val.push('\t'),
+                    Some(c) => return Err(format!("invalid escape '\\{}'", c)),
+                    None => return Err("unterminated string".into()),
+                },
+                Some(c) => val.push(c),
+                None => return Err("unterminated string".into()),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> core::result::Result<Value, String> {
+        // Skip the opening bracket
+        self.chars.next();
+
+        let mut values = vec![];
+        loop {
+            self.skip_whitespace();
+            if let Some(']') = self.chars.peek() {
+                self.chars.next();
+                return Ok(Value::Array(values));
+            }
+
+            values.push(self.parse_value()?);
+
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some(',') => continue,
+                Some(']') => return Ok(Value::Array(values)),
+                Some(c) => return Err(format!("unexpected character '{}'", c)),
+                None => return Err("unterminated array".into()),
+            }
+        }
+    }
+}
+
+/// Remove any trailing comment from a line (ignoring '#' inside strings)
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_vm() {
+        let config = UserConfig::parse(
+            br#"
+            # A comment
+            [[vm]]
+            cpus = [0, 1]
+            memory = 0x100 # in MB
+            pci_hole = 2048
+            kernel = "vmlinuz"
+            cmdline = "console=ttyS0 \"quoted # not a comment\""
+            devices = ["com1", "pic"]
+            gdb = "com2"
+            exit_trace = 32
+            rtc_base = 951827696
+            rtc_nvram = "cmos.bin"
+            tsc_khz = 1_000_000
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.vms.len(), 1);
+        let vm = &config.vms[0];
+        assert_eq!(vm.cpus, vec![0, 1]);
+        assert_eq!(vm.memory, 256);
+        assert_eq!(vm.pci_hole, 2048);
+        assert_eq!(vm.kernel, Some("vmlinuz".into()));
+        assert_eq!(vm.initramfs, "initramfs");
+        assert_eq!(vm.cmdline, "console=ttyS0 \"quoted # not a comment\"");
+        assert_eq!(vm.devices, vec!["com1", "pic"]);
+        assert_eq!(vm.gdb, Some(0x2f8));
+        assert_eq!(vm.exit_trace, 32);
+        assert_eq!(vm.rtc_base, RtcBase::Fixed(951827696));
+        assert_eq!(vm.rtc_nvram, Some("cmos.bin".into()));
+        assert_eq!(vm.tsc_khz, Some(1_000_000));
+    }
+
+    #[test]
+    fn test_parse_multiple_vms() {
+        let config = UserConfig::parse(
+            b"[[vm]]\ncpus = [0]\n[[vm]]\ncpus = [1, 2,]\nmemory = 512\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.vms.len(), 2);
+        assert_eq!(config.vms[0].memory, 256);
+        assert_eq!(config.vms[1].cpus, vec![1, 2]);
+        assert_eq!(config.vms[1].memory, 512);
+    }
+
+    #[test]
+    fn test_check_cpus() {
+        let config =
+            UserConfig::parse(b"[[vm]]\ncpus = [0]\n[[vm]]\ncpus = [1, 2]\n")
+                .unwrap();
+        assert!(config.check_cpus(&[0, 1, 2, 3]).is_ok());
+        assert!(config.check_cpus(&[0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_parse_cpuid() {
+        let config = UserConfig::parse(
+            b"[[vm]]\ncpus = [0]\ncpuid_max_leaf = 0xd\ncpuid = [\"1:ecx:0:0x20\"]\n",
+        )
+        .unwrap();
+
+        let vm = &config.vms[0];
+        assert_eq!(vm.cpuid_max_leaf, Some(0xd));
+        assert_eq!(vm.cpuid.len(), 1);
+        assert_eq!(vm.cpuid[0].mask, 0x20);
+
+        assert!(UserConfig::parse(b"[[vm]]\ncpus = [0]\ncpuid = [\"1\"]\n")
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        // Keys must be in a vm section
+        assert!(UserConfig::parse(b"memory = 10\n").is_err());
+
+        // Unknown keys and sections are rejected
+        assert!(UserConfig::parse(b"[[vm]]\ncpus = [0]\nfoo = 1\n").is_err());
+        assert!(UserConfig::parse(b"[vm]\ncpus = [0]\n").is_err());
+
+        // Values must have the right type
+        assert!(UserConfig::parse(b"[[vm]]\ncpus = 0\n").is_err());
+        assert!(UserConfig::parse(b"[[vm]]\ncpus = [0]\nbios = 1\n").is_err());
+        assert!(UserConfig::parse(b"[[vm]]\ncpus = [\"0\"]\n").is_err());
+        assert!(UserConfig::parse(b"[[vm]]\ncpus = [256]\n").is_err());
+
+        // Malformed values
+        assert!(UserConfig::parse(b"[[vm]]\ncpus = [0\n").is_err());
+        assert!(UserConfig::parse(b"[[vm]]\nkernel = \"abc\n").is_err());
+        assert!(UserConfig::parse(b"[[vm]]\ncpus = [0] 1\n").is_err());
+
+        // The GDB stub needs a free host serial port
+        assert!(
+            UserConfig::parse(b"[[vm]]\ncpus = [0]\ngdb = \"com1\"\n").is_err()
+        );
+        assert!(
+            UserConfig::parse(b"[[vm]]\ncpus = [0]\ngdb = \"com9\"\n").is_err()
+        );
+        assert!(UserConfig::parse(
+            b"[[vm]]\ncpus = [0]\ngdb = \"com2\"\n[[vm]]\ncpus = [1]\ngdb = \"com2\"\n"
+        )
+        .is_err());
+
+        // Every vm needs some memory
+        assert!(UserConfig::parse(b"[[vm]]\ncpus = [0]\nmemory = 0\n").is_err());
+        assert!(
+            UserConfig::parse(b"[[vm]]\ncpus = [0]\nmemory = 15\n").is_err()
+        );
+        assert!(UserConfig::parse(b"[[vm]]\ncpus = [0]\nmemory = 16\n").is_ok());
+
+        // The guest TSC frequency cannot be zero or absurdly high
+        assert!(
+            UserConfig::parse(b"[[vm]]\ncpus = [0]\ntsc_khz = 0\n").is_err()
+        );
+        assert!(UserConfig::parse(
+            b"[[vm]]\ncpus = [0]\ntsc_khz = 18446744073709551\n"
+        )
+        .is_err());
+        assert!(
+            UserConfig::parse(b"[[vm]]\ncpus = [0]\ntsc_khz = 3000000\n")
+                .is_ok()
+        );
+
+        // Every vm needs a cpu, and cpus cannot be shared
+        assert!(UserConfig::parse(b"[[vm]]\nmemory = 10\n").is_err());
+        assert!(
+            UserConfig::parse(b"[[vm]]\ncpus = [0]\n[[vm]]\ncpus = [0]\n")
+                .is_err()
+        );
+    }
+}
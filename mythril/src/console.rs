@@ -0,0 +1,355 @@
+//! The host serial console multiplexer
+//!
+//! The host serial port (the port also used for the log) shows the console
+//! of one VM at a time. Output from the guest serial ports of the focused
+//! VM is written to the host as it is produced, while the output of every
+//! VM is kept in a scrollback buffer that is replayed when the VM gets the
+//! focus. Host input goes to the first serial port of the focused VM.
+//!
+//! The operator switches the focus with escape sequences, which start with
+//! Ctrl-A:
+//!
+//! * `Ctrl-A <n>` - Focus the console of VM `n` (0-9)
+//! * `Ctrl-A n` - Focus the console of the next VM
+//! * `Ctrl-A m` - Enter (or leave) the hypervisor monitor
+//! * `Ctrl-A h` - Show the available escape sequences
+//! * `Ctrl-A Ctrl-A` - Send a Ctrl-A to the guest
+
+use crate::device::com::ComDevice;
+use crate::logger;
+use crate::monitor::Monitor;
+use crate::serial::{self, SerialPort};
+use crate::vm::VirtualMachine;
+use alloc::collections::vec_deque::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::{Mutex, RwLock};
+
+/// The first byte of a console escape sequence (Ctrl-A)
+pub const ESCAPE: u8 = 0x01;
+
+/// The number of bytes of output kept for each VM
+pub const SCROLLBACK_SIZE: usize = 8192;
+
+/// The number of bytes of input held for each VM until its serial port has
+/// room for them
+pub const INPUT_QUEUE_SIZE: usize = 256;
+
+const HELP: &str = "\r\n[console] Ctrl-A <n>: focus VM n, Ctrl-A n: focus \
+                    the next VM, Ctrl-A m: monitor, Ctrl-A Ctrl-A: send \
+                    Ctrl-A\r\n";
+
+/// The most recent output of a VM console
+pub struct Scrollback {
+    buff: VecDeque<u8>,
+}
+
+impl Scrollback {
+    pub fn new() -> Self {
+        Self {
+            buff: VecDeque::with_capacity(SCROLLBACK_SIZE),
+        }
+    }
+
+    /// Add output to the buffer, dropping the oldest output if it is full
+    pub fn push(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            if self.buff.len() == SCROLLBACK_SIZE {
+                self.buff.pop_front();
+            }
+            self.buff.push_back(*byte);
+        }
+    }
+
+    /// The buffered output (oldest first)
+    pub fn contents(&self) -> Vec<u8> {
+        self.buff.iter().cloned().collect()
+    }
+}
+
+/// Host input waiting for room in a guest serial port
+///
+/// The host UART is always drained (so the escape sequences keep working
+/// when a guest does not read its serial port), and the input for the
+/// guest waits here instead.
+pub struct InputQueue {
+    buff: VecDeque<u8>,
+}
+
+impl InputQueue {
+    pub fn new() -> Self {
+        Self {
+            buff: VecDeque::with_capacity(INPUT_QUEUE_SIZE),
+        }
+    }
+
+    /// Queue a byte for the guest (the byte is dropped if the queue is full)
+    pub fn push(&mut self, byte: u8) {
+        if self.buff.len() < INPUT_QUEUE_SIZE {
+            self.buff.push_back(byte);
+        }
+    }
+
+    /// Move as much of the queued input as possible to the guest
+    pub fn deliver(&mut self, com: &mut ComDevice) {
+        while com.can_receive() {
+            match self.buff.pop_front() {
+                Some(byte) => com.receive(byte),
+                None => return,
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buff.is_empty()
+    }
+}
+
+/// The result of a byte of host input
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum InputAction {
+    /// The byte was part of an incomplete escape sequence
+    None,
+
+    /// Send the byte to the focused guest
+    Guest(u8),
+
+    /// Focus the console of the given VM
+    Focus(usize),
+
+    /// Focus the console of the next VM
+    FocusNext,
+
+    /// Enter or leave the monitor
+    Monitor,
+
+    /// Show the available escape sequences
+    Help,
+}
+
+#[derive(Default)]
+struct EscapeParser {
+    escaped: bool,
+}
+
+impl EscapeParser {
+    fn parse(&mut self, byte: u8) -> InputAction {
+        if !self.escaped {
+            if byte == ESCAPE {
+                self.escaped = true;
+                return InputAction::None;
+            }
+            return InputAction::Guest(byte);
+        }
+
+        self.escaped = false;
+        match byte {
+            ESCAPE => InputAction::Guest(ESCAPE),
+            b'0'..=b'9' => InputAction::Focus((byte - b'0') as usize),
+            b'n' => InputAction::FocusNext,
+            b'm' => InputAction::Monitor,
+            b'h' | b'?' => InputAction::Help,
+            _ => InputAction::None,
+        }
+    }
+}
+
+struct HostInput {
+    port: SerialPort,
+    parser: EscapeParser,
+    monitor: Monitor,
+
+    // The serial port of each VM that receives input (indexed by vmid)
+    targets: Vec<Option<Arc<Mutex<ComDevice>>>>,
+
+    // The input for each VM that its serial port has no room for yet
+    queues: Vec<InputQueue>,
+}
+
+impl HostInput {
+    fn deliver_queued(&mut self) {
+        for (com, queue) in self.targets.iter().zip(self.queues.iter_mut()) {
+            if let Some(com) = com {
+                if !queue.is_empty() {
+                    queue.deliver(&mut com.lock());
+                }
+            }
+        }
+    }
+}
+
+// NOTE: to avoid deadlocks, `HOST_INPUT` must be locked before any guest
+// serial port, which must be locked before `SCROLLBACK`.
+static HOST_INPUT: Mutex<Option<HostInput>> = Mutex::new(None);
+static SCROLLBACK: Mutex<Vec<Scrollback>> = Mutex::new(Vec::new());
+static FOCUS: AtomicUsize = AtomicUsize::new(0);
+
+// Guest output is not shown while the monitor is in use
+static IN_MONITOR: AtomicBool = AtomicBool::new(false);
+
+/// Start multiplexing the host console between the given VMs
+///
+/// The VMs are indexed by vmid, and the first VM initially has the focus.
+pub fn init(vms: &[Arc<RwLock<VirtualMachine>>]) {
+    let targets = vms
+        .iter()
+        .map(|vm| vm.read().config.com_ports().first().cloned())
+        .collect();
+
+    *SCROLLBACK.lock() = vms.iter().map(|_| Scrollback::new()).collect();
+    *HOST_INPUT.lock() = Some(HostInput {
+        port: SerialPort::new(serial::LOG_PORT_BASE),
+        parser: EscapeParser::default(),
+        monitor: Monitor::new(vms.to_vec()),
+        targets: targets,
+        queues: vms.iter().map(|_| InputQueue::new()).collect(),
+    });
+    logger::write_console(HELP);
+}
+
+/// The vmid of the VM whose console is shown on the host
+pub fn focus() -> usize {
+    FOCUS.load(Ordering::Relaxed)
+}
+
+/// Write output from a guest serial port of the given VM
+pub fn write(vmid: u64, bytes: &[u8]) {
+    let mut scrollback = SCROLLBACK.lock();
+    match scrollback.get_mut(vmid as usize) {
+        Some(buff) => buff.push(bytes),
+
+        // Before the multiplexer is ready, output goes to the log
+        None => {
+            let s = String::from_utf8_lossy(bytes);
+            logger::write_console(&format!("GUEST{}: {}", vmid, s));
+            return;
+        }
+    }
+
+    //FIXME: bytes are written one at a time by the guest, so non-ASCII
+    //       UTF-8 characters are not shown correctly
+    if vmid as usize == focus() && !IN_MONITOR.load(Ordering::Relaxed) {
+        logger::write_console(&String::from_utf8_lossy(bytes));
+    }
+}
+
+fn set_focus(vmid: usize) {
+    let scrollback = SCROLLBACK.lock();
+    let buff = match scrollback.get(vmid) {
+        Some(buff) => buff,
+        None => {
+            logger::write_console(&format!("\r\n[console] no VM {}\r\n", vmid));
+            return;
+        }
+    };
+
+    // Holding the scrollback lock ensures no output is lost or repeated
+    // while the buffer is replayed
+    FOCUS.store(vmid, Ordering::Relaxed);
+    logger::write_console(&format!("\r\n[console] VM {}\r\n", vmid));
+    logger::write_console(&String::from_utf8_lossy(&buff.contents()));
+}
+
+fn leave_monitor() {
+    IN_MONITOR.store(false, Ordering::Relaxed);
+    set_focus(focus());
+}
+
+/// Handle any pending host console input
+///
+/// This is called on every VM exit, so it returns immediately if another
+/// core is already polling the console.
+pub fn poll_input() {
+    let mut input = match HOST_INPUT.try_lock() {
+        Some(input) => input,
+        None => return,
+    };
+    let input = match input.as_mut() {
+        Some(input) => input,
+        None => return,
+    };
+
+    // Input for the guests is held until their serial ports have room for
+    // it, but the host UART is always drained so the escape sequences work
+    input.deliver_queued();
+    loop {
+        let byte = match input.port.try_read() {
+            Some(byte) => byte,
+            None => return,
+        };
+
+        match input.parser.parse(byte) {
+            InputAction::None => (),
+            InputAction::Guest(byte) if IN_MONITOR.load(Ordering::Relaxed) => {
+                if input.monitor.input(byte, focus()) {
+                    leave_monitor();
+                }
+            }
+            InputAction::Guest(byte) => {
+                // Input for a VM without a serial port is dropped
+                let vmid = focus();
+                if let Some(Some(_)) = input.targets.get(vmid) {
+                    input.queues[vmid].push(byte);
+                    input.deliver_queued();
+                }
+            }
+            InputAction::Monitor if IN_MONITOR.load(Ordering::Relaxed) => {
+                leave_monitor()
+            }
+            InputAction::Monitor => {
+                IN_MONITOR.store(true, Ordering::Relaxed);
+                input.monitor.enter();
+            }
+            InputAction::Focus(vmid) => set_focus(vmid),
+            InputAction::FocusNext => {
+                set_focus((focus() + 1) % input.targets.len().max(1))
+            }
+            InputAction::Help => logger::write_console(HELP),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scrollback_wraps() {
+        let mut scrollback = Scrollback::new();
+        scrollback.push(&[0u8; SCROLLBACK_SIZE]);
+        scrollback.push(b"abc");
+        let contents = scrollback.contents();
+        assert_eq!(contents.len(), SCROLLBACK_SIZE);
+        assert_eq!(&contents[SCROLLBACK_SIZE - 3..], b"abc");
+    }
+
+    #[test]
+    fn test_input_queue_limit() {
+        let mut queue = InputQueue::new();
+        for byte in 0..=INPUT_QUEUE_SIZE {
+            queue.push(byte as u8);
+        }
+        assert_eq!(queue.buff.len(), INPUT_QUEUE_SIZE);
+        assert_eq!(queue.buff.back(), Some(&((INPUT_QUEUE_SIZE - 1) as u8)));
+    }
+
+    #[test]
+    fn test_escape_sequences() {
+        let mut parser = EscapeParser::default();
+        assert_eq!(parser.parse(b'a'), InputAction::Guest(b'a'));
+        assert_eq!(parser.parse(ESCAPE), InputAction::None);
+        assert_eq!(parser.parse(b'2'), InputAction::Focus(2));
+        assert_eq!(parser.parse(b'2'), InputAction::Guest(b'2'));
+        assert_eq!(parser.parse(ESCAPE), InputAction::None);
+        assert_eq!(parser.parse(b'n'), InputAction::FocusNext);
+        assert_eq!(parser.parse(ESCAPE), InputAction::None);
+        assert_eq!(parser.parse(ESCAPE), InputAction::Guest(ESCAPE));
+
+        // Unknown escape sequences are dropped
+        assert_eq!(parser.parse(ESCAPE), InputAction::None);
+        assert_eq!(parser.parse(b'z'), InputAction::None);
+        assert_eq!(parser.parse(b'z'), InputAction::Guest(b'z'));
+    }
+}
@@ -1,16 +1,93 @@
+use crate::device::irq::IrqLine;
 use crate::device::{
     DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
 };
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::memory::GuestAddressSpaceViewMut;
-use crate::time;
+use crate::vm::{VmLifecycle, VmState};
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 
 const PMTIMER_HZ: u64 = 3579545;
 
+/// The offset of the PM1a event block (status and enable) from the PM base
+pub const PM1A_EVT_OFFSET: Port = 0x00;
+
+/// The offset of the PM1a control block from the PM base
+pub const PM1A_CNT_OFFSET: Port = 0x04;
+
+/// The offset of the PM timer from the PM base
+pub const PMTIMER_OFFSET: Port = 0x08;
+
+// The PM1 status bits, which are also the bits of the matching enable
+// register (except for WAK_STS)
+const PM1_TMR_STS: u16 = 1 << 0;
+const PM1_GBL_STS: u16 = 1 << 5;
+const PM1_PWRBTN_STS: u16 = 1 << 8;
+const PM1_SLPBTN_STS: u16 = 1 << 9;
+const PM1_RTC_STS: u16 = 1 << 10;
+const PM1_WAK_STS: u16 = 1 << 15;
+const PM1_EVENTS: u16 =
+    PM1_TMR_STS | PM1_GBL_STS | PM1_PWRBTN_STS | PM1_SLPBTN_STS | PM1_RTC_STS;
+
+const PM1_CNT_SCI_EN: u16 = 1 << 0;
+const PM1_CNT_SLP_TYP_SHIFT: u16 = 10;
+const PM1_CNT_SLP_TYP_MASK: u16 = 0b111 << PM1_CNT_SLP_TYP_SHIFT;
+const PM1_CNT_SLP_EN: u16 = 1 << 13;
+
+// The sleep types of the S3 (suspend to RAM) and S5 (soft off) states,
+// from the \_S3 and \_S5 objects in the DSDT (see acpi::tables)
+const SLP_TYP_S3: u16 = 1;
+const SLP_TYP_S5: u16 = 0;
+
+// The number of events in the GPE0 block (its 16-bit status register)
+const GPE0_COUNT: u8 = 16;
+
+/// A 16-bit status register followed by a 16-bit enable register (the
+/// layout of the PM1a event block and the GPE0 block)
+///
+/// Status bits are set by events and cleared by writing a one to them.
+#[derive(Default)]
+struct EventBlock {
+    status: u16,
+    enable: u16,
+}
+
+impl EventBlock {
+    fn read(&self, offset: Port, val: &mut PortReadRequest) {
+        let block = (self.enable as u32) << 16 | self.status as u32;
+        val.copy_from_u32(block >> (offset * 8));
+    }
+
+    fn write(&mut self, offset: Port, val: &PortWriteRequest) {
+        let bits = val.as_slice().len() * 8;
+        let mask = ((1u64 << bits) - 1) as u32;
+        let data = (val.as_u32() & mask) << (offset * 8);
+        let mask = mask << (offset * 8);
+
+        self.status &= !(data as u16);
+        self.enable =
+            ((self.enable as u32) & !(mask >> 16) | (data >> 16)) as u16;
+    }
+
+    /// Whether an enabled event is pending
+    fn pending(&self) -> bool {
+        self.status & self.enable != 0
+    }
+}
+
+/// The ACPI fixed hardware (the PM1a registers, the PM timer and the
+/// GPE0 block)
+///
+/// Any enabled PM1 event or GPE raises the SCI.
 pub struct AcpiRuntime {
     pm_base: Port,
+    pm1a_events: EventBlock,
+    pm1a_control: u16,
+    gpe0: EventBlock,
+    sci: IrqLine,
+    lifecycle: Arc<VmLifecycle>,
 }
 
 impl AcpiRuntime {
@@ -19,8 +96,8 @@ impl AcpiRuntime {
 
     // Seabios expects us to pass PCI hotplug info via ACPI like QEMU.
     // See https://github.com/qemu/qemu/blob/master/docs/specs/acpi_pci_hotplug.txt
-    const GPE_BLOCK_START: Port = 0xafe0;
-    const GPE_BLOCK_END: Port = 0xafe3;
+    pub const GPE_BLOCK_START: Port = 0xafe0;
+    pub const GPE_BLOCK_END: Port = 0xafe3;
     const PCI_SLOT_INJECTION_START: Port = 0xae00;
     const PCI_SLOT_INJECTION_END: Port = 0xae03;
     const PCI_SLOT_REMOVAL_NOTIFY_START: Port = 0xae04;
@@ -30,25 +107,108 @@ impl AcpiRuntime {
     const PCI_REMOVABILITY_STATUS_START: Port = 0xae0c;
     const PCI_REMOVABILITY_STATUS_END: Port = 0xae0f;
 
-    pub fn new(pm_base: Port) -> Result<Box<Self>> {
-        Ok(Box::new(AcpiRuntime { pm_base }))
+    /// Create the ACPI fixed hardware at `pm_base`, which raises `sci` for
+    /// ACPI events and can power off or suspend the VM with the given
+    /// lifecycle
+    pub fn new(
+        pm_base: Port,
+        sci: IrqLine,
+        lifecycle: Arc<VmLifecycle>,
+    ) -> Result<Box<Self>> {
+        Ok(Box::new(AcpiRuntime {
+            pm_base: pm_base,
+            pm1a_events: EventBlock::default(),
+            pm1a_control: PM1_CNT_SCI_EN,
+            gpe0: EventBlock::default(),
+            sci: sci,
+            lifecycle: lifecycle,
+        }))
+    }
+
+    /// Press the power button, which asks the guest to shut down (or wakes
+    /// a suspended VM)
+    pub fn press_power_button(&mut self) {
+        self.pm1a_events.status |= PM1_PWRBTN_STS;
+        if self.lifecycle.state() == VmState::Suspended {
+            info!("Power button pressed, waking the VM");
+            self.pm1a_events.status |= PM1_WAK_STS;
+            self.lifecycle.wake();
+        }
+        self.update_sci();
+    }
+
+    /// Signal the given general purpose event
+    pub fn raise_gpe(&mut self, gpe: u8) -> Result<()> {
+        if gpe >= GPE0_COUNT {
+            return Err(Error::InvalidValue(format!(
+                "Invalid GPE {} (the GPE0 block has {} events)",
+                gpe, GPE0_COUNT
+            )));
+        }
+        self.gpe0.status |= 1 << gpe;
+        self.update_sci();
+        Ok(())
+    }
+
+    fn sci_level(&self) -> bool {
+        // WAK_STS has no enable bit, so it never raises the SCI
+        let pm1_pending =
+            self.pm1a_events.status & self.pm1a_events.enable & PM1_EVENTS != 0;
+        self.pm1a_control & PM1_CNT_SCI_EN != 0
+            && (pm1_pending || self.gpe0.pending())
+    }
+
+    fn update_sci(&self) {
+        self.sci.set_level(self.sci_level());
+    }
+
+    fn enter_sleep_state(&mut self, sleep_type: u16) {
+        match sleep_type {
+            SLP_TYP_S3 => {
+                info!("Guest entered S3, suspending the VM");
+                self.lifecycle.suspend();
+            }
+            SLP_TYP_S5 => {
+                info!("Guest entered S5, shutting down the VM");
+                self.lifecycle.shutdown();
+            }
+            _ => warn!("Unsupported ACPI sleep type {}", sleep_type),
+        }
+    }
+
+    fn pm1a_evt(&self) -> Port {
+        self.pm_base + PM1A_EVT_OFFSET
     }
 
     fn pm1a_cnt(&self) -> Port {
-        self.pm_base + 0x04
+        self.pm_base + PM1A_CNT_OFFSET
     }
 
     fn pmtimer(&self) -> Port {
-        self.pm_base + 0x08
+        self.pm_base + PMTIMER_OFFSET
     }
 }
 
 impl EmulatedDevice for AcpiRuntime {
+    fn reset(&mut self) -> Result<()> {
+        // The event registers are in the resume well, so they survive a wake
+        // from S3 (and the guest can see WAK_STS)
+        if self.lifecycle.waking() {
+            return Ok(());
+        }
+        self.pm1a_events = EventBlock::default();
+        self.pm1a_control = PM1_CNT_SCI_EN;
+        self.gpe0 = EventBlock::default();
+        self.update_sci();
+        Ok(())
+    }
+
     fn services(&self) -> Vec<DeviceRegion> {
         vec![
             DeviceRegion::PortIo(
                 Self::FADT_SMI_COMMAND..=Self::FADT_SMI_COMMAND,
             ),
+            DeviceRegion::PortIo(self.pm1a_evt()..=self.pm1a_evt() + 3),
             DeviceRegion::PortIo(self.pm1a_cnt()..=self.pm1a_cnt()),
             DeviceRegion::PortIo(self.pmtimer()..=self.pmtimer()),
             DeviceRegion::PortIo(Self::GPE_BLOCK_START..=Self::GPE_BLOCK_END),
@@ -76,10 +236,15 @@ impl EmulatedDevice for AcpiRuntime {
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
         if port == self.pmtimer() {
-            let on_duration = time::now() - time::system_start_time();
-            let pm_time =
-                (on_duration.as_nanos() * PMTIMER_HZ as u128) / 1_000_000_000;
+            let on_duration = self.lifecycle.clock().now_ns() as u128;
+            let pm_time = (on_duration * PMTIMER_HZ as u128) / 1_000_000_000;
             val.copy_from_u32(pm_time as u32);
+        } else if port == self.pm1a_cnt() {
+            val.copy_from_u32(self.pm1a_control as u32);
+        } else if port >= self.pm1a_evt() && port <= self.pm1a_evt() + 3 {
+            self.pm1a_events.read(port - self.pm1a_evt(), &mut val);
+        } else if port >= Self::GPE_BLOCK_START && port <= Self::GPE_BLOCK_END {
+            self.gpe0.read(port - Self::GPE_BLOCK_START, &mut val);
         }
         Ok(())
     }
@@ -90,6 +255,28 @@ impl EmulatedDevice for AcpiRuntime {
         val: PortWriteRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
+        if port == self.pm1a_cnt() {
+            let val = val.as_u32() as u16;
+
+            // SLP_EN is write-only, and SCI_EN is always set as there is no
+            // SMI command to return to legacy mode
+            self.pm1a_control = val & !PM1_CNT_SLP_EN | PM1_CNT_SCI_EN;
+            if val & PM1_CNT_SLP_EN != 0 {
+                self.enter_sleep_state(
+                    (val & PM1_CNT_SLP_TYP_MASK) >> PM1_CNT_SLP_TYP_SHIFT,
+                );
+            }
+            return Ok(());
+        } else if port >= self.pm1a_evt() && port <= self.pm1a_evt() + 3 {
+            self.pm1a_events.write(port - self.pm1a_evt(), &val);
+            self.update_sci();
+            return Ok(());
+        } else if port >= Self::GPE_BLOCK_START && port <= Self::GPE_BLOCK_END {
+            self.gpe0.write(port - Self::GPE_BLOCK_START, &val);
+            self.update_sci();
+            return Ok(());
+        }
+
         info!(
             "Attempt to write to AcpiRuntime port=0x{:x}, val={}. Ignoring",
             port, val
@@ -97,3 +284,63 @@ impl EmulatedDevice for AcpiRuntime {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::ioapic::IoApic;
+    use crate::device::irq::IrqRouter;
+    use crate::device::pic::Pic8259;
+    use core::convert::TryFrom;
+    use spin::Mutex;
+
+    // The tests never enable the SCI, so its line is never asserted
+    fn define_test_acpi() -> Box<AcpiRuntime> {
+        let router = IrqRouter::new(
+            Arc::new(Mutex::new(Pic8259::default())),
+            Arc::new(Mutex::new(IoApic::new(vec![]))),
+            vec![],
+            0,
+        );
+        let sci = IrqLine::new(9, Arc::new(router));
+        let lifecycle = Arc::new(VmLifecycle::new(vec![0]));
+        AcpiRuntime::new(0xb000, sci, lifecycle).unwrap()
+    }
+
+    #[test]
+    fn test_raise_gpe() {
+        let mut acpi = define_test_acpi();
+        acpi.raise_gpe(3).unwrap();
+        assert_eq!(acpi.gpe0.status, 1 << 3);
+
+        // The event only raises the SCI once the guest enables it
+        assert!(!acpi.sci_level());
+        acpi.gpe0.enable = 1 << 3;
+        assert!(acpi.sci_level());
+
+        assert!(acpi.raise_gpe(16).is_err());
+    }
+
+    #[test]
+    fn test_event_block() {
+        let mut block = EventBlock::default();
+        block.status = PM1_PWRBTN_STS | PM1_TMR_STS;
+
+        // Enable the power button with a byte write to the enable register
+        let data = [0x01];
+        block.write(3, &PortWriteRequest::try_from(&data[..]).unwrap());
+        assert_eq!(block.enable, PM1_PWRBTN_STS);
+        assert!(block.pending());
+
+        let mut buff = [0u8; 4];
+        block.read(0, &mut PortReadRequest::FourBytes(&mut buff));
+        assert_eq!(u32::from_be_bytes(buff), 0x0100_0101);
+
+        // Writing a one clears a status bit, without changing the others
+        let data = [0x01, 0x00];
+        block.write(0, &PortWriteRequest::try_from(&data[..]).unwrap());
+        assert_eq!(block.status, PM1_TMR_STS);
+        assert_eq!(block.enable, PM1_PWRBTN_STS);
+        assert!(!block.pending());
+    }
+}
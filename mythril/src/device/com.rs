@@ -1,28 +1,17 @@
+use crate::console;
+use crate::device::irq::IrqLine;
 use crate::device::{
     DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
 };
 use crate::error::Result;
-use crate::logger;
 use crate::memory::GuestAddressSpaceViewMut;
 use alloc::boxed::Box;
-use alloc::string::String;
+use alloc::collections::vec_deque::VecDeque;
 use alloc::vec::Vec;
 use core::convert::TryInto;
 
-#[derive(Default)]
-pub struct ComDevice {
-    id: u64,
-    base_port: Port,
-    buff: Vec<u8>,
-    divisor: u16,
-    interrupt_enable_register: u8,
-    interrupt_identification_register: u8,
-    _line_control_register: u8,
-    _modem_control_register: u8,
-    line_status_register: u8,
-    _modem_status_register: u8,
-    _scratch_register: u8,
-}
+/// The size of the receive FIFO of the 16550A
+const FIFO_SIZE: usize = 16;
 
 #[allow(non_snake_case)]
 #[allow(dead_code)]
@@ -32,27 +21,357 @@ mod SerialOffset {
     pub const IER: u16 = 1;
     pub const DLH: u16 = 1;
     pub const IIR: u16 = 2;
+    pub const FCR: u16 = 2;
     pub const LCR: u16 = 3;
+    pub const MCR: u16 = 4;
     pub const LSR: u16 = 5;
     pub const MSR: u16 = 6;
+    pub const SCR: u16 = 7;
+}
+
+// Interrupt enable register
+const IER_RDA: u8 = 1 << 0;
+const IER_THRE: u8 = 1 << 1;
+const IER_LINE_STATUS: u8 = 1 << 2;
+const IER_MODEM_STATUS: u8 = 1 << 3;
+
+// Interrupt identification register
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_MODEM_STATUS: u8 = 0x00;
+const IIR_THRE: u8 = 0x02;
+const IIR_RDA: u8 = 0x04;
+const IIR_LINE_STATUS: u8 = 0x06;
+const IIR_CHAR_TIMEOUT: u8 = 0x0c;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+// FIFO control register
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_TRIGGER_SHIFT: u8 = 6;
+
+// Line control register
+const LCR_DLAB: u8 = 1 << 7;
+
+// Modem control register
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT1: u8 = 1 << 2;
+const MCR_OUT2: u8 = 1 << 3;
+const MCR_LOOP: u8 = 1 << 4;
+
+// Line status register
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_OVERRUN: u8 = 1 << 1;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const LSR_TRANSMITTER_EMPTY: u8 = 1 << 6;
+
+// Modem status register
+const MSR_DELTA_CTS: u8 = 1 << 0;
+const MSR_DELTA_DSR: u8 = 1 << 1;
+const MSR_TRAILING_RI: u8 = 1 << 2;
+const MSR_DELTA_DCD: u8 = 1 << 3;
+const MSR_DELTA_MASK: u8 = 0x0f;
+const MSR_CTS: u8 = 1 << 4;
+const MSR_DSR: u8 = 1 << 5;
+const MSR_RI: u8 = 1 << 6;
+const MSR_DCD: u8 = 1 << 7;
+
+/// An emulated 16550A UART
+///
+/// Transmitted bytes are sent to the VM's console on the host
+/// (transmission is instantaneous, so the transmitter is always empty).
+/// Received bytes come from the host through `receive`.
+pub struct ComDevice {
+    id: u64,
+    base_port: Port,
+    irq: IrqLine,
+    irq_level: bool,
+
+    rx_fifo: VecDeque<u8>,
+    overrun: bool,
+
+    // Set when the THR becomes empty and cleared when the guest reads the
+    // IIR (while this is the reported interrupt) or writes the THR
+    thr_empty_interrupt: bool,
+
+    divisor: u16,
+    interrupt_enable_register: u8,
+    fifo_control_register: u8,
+    line_control_register: u8,
+    modem_control_register: u8,
+    modem_status_register: u8,
+    scratch_register: u8,
 }
 
 impl ComDevice {
-    pub fn new(vmid: u64, base_port: Port) -> Box<dyn EmulatedDevice> {
-        Box::new(Self {
+    pub fn new(vmid: u64, base_port: Port, irq: IrqLine) -> Box<Self> {
+        let mut com = Self {
             id: vmid,
-            base_port,
-            buff: vec![],
+            base_port: base_port,
+            irq: irq,
+            irq_level: false,
+            rx_fifo: VecDeque::with_capacity(FIFO_SIZE),
+            overrun: false,
+            thr_empty_interrupt: false,
+            divisor: 0,
+            interrupt_enable_register: 0,
+            fifo_control_register: 0,
+            line_control_register: 0,
+            modem_control_register: 0,
+            modem_status_register: 0,
+            scratch_register: 0,
+        };
+        com.modem_status_register = com.modem_status_lines();
+        Box::new(com)
+    }
 
-            // For now, transmitter holding register is always empty
-            interrupt_identification_register: 0x02,
+    /// Returns true if the UART can accept another byte from the host
+    /// without overrunning its receive buffer
+    pub fn can_receive(&self) -> bool {
+        !self.loopback() && self.rx_fifo.len() < self.rx_capacity()
+    }
 
-            ..Default::default()
-        })
+    /// Receive a byte from the host (e.g., typed on the host console)
+    ///
+    /// Input is discarded while the UART is in loopback mode, as the
+    /// receiver is disconnected.
+    pub fn receive(&mut self, byte: u8) {
+        if self.loopback() {
+            return;
+        }
+        self.push_rx(byte);
+        self.update_irq();
     }
 
     fn divisor_latch_bit_set(&self) -> bool {
-        self.line_status_register & (1 << 7) != 0
+        self.line_control_register & LCR_DLAB != 0
+    }
+
+    fn loopback(&self) -> bool {
+        self.modem_control_register & MCR_LOOP != 0
+    }
+
+    fn fifo_enabled(&self) -> bool {
+        self.fifo_control_register & FCR_ENABLE != 0
+    }
+
+    fn rx_capacity(&self) -> usize {
+        if self.fifo_enabled() {
+            FIFO_SIZE
+        } else {
+            1
+        }
+    }
+
+    fn rx_trigger_level(&self) -> usize {
+        if !self.fifo_enabled() {
+            return 1;
+        }
+        match self.fifo_control_register >> FCR_TRIGGER_SHIFT {
+            0 => 1,
+            1 => 4,
+            2 => 8,
+            _ => 14,
+        }
+    }
+
+    fn push_rx(&mut self, byte: u8) {
+        if self.rx_fifo.len() < self.rx_capacity() {
+            self.rx_fifo.push_back(byte);
+        } else if !self.fifo_enabled() {
+            // Without the FIFO, the unread byte in the RBR is overwritten
+            self.overrun = true;
+            self.rx_fifo.clear();
+            self.rx_fifo.push_back(byte);
+        } else {
+            // With the FIFO, the new byte is lost
+            self.overrun = true;
+        }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        if self.loopback() {
+            self.push_rx(byte);
+        } else {
+            console::write(self.id, &[byte]);
+        }
+
+        // The byte is sent immediately, so the THR is empty again
+        self.thr_empty_interrupt = true;
+    }
+
+    /// The current level of the modem status inputs (CTS, DSR, RI and DCD)
+    fn modem_status_lines(&self) -> u8 {
+        if self.loopback() {
+            // The modem control outputs are connected to the inputs
+            let mcr = self.modem_control_register;
+            let mut lines = 0;
+            if mcr & MCR_RTS != 0 {
+                lines |= MSR_CTS;
+            }
+            if mcr & MCR_DTR != 0 {
+                lines |= MSR_DSR;
+            }
+            if mcr & MCR_OUT1 != 0 {
+                lines |= MSR_RI;
+            }
+            if mcr & MCR_OUT2 != 0 {
+                lines |= MSR_DCD;
+            }
+            lines
+        } else {
+            // The host console is always connected and ready
+            MSR_CTS | MSR_DSR | MSR_DCD
+        }
+    }
+
+    fn update_modem_status(&mut self) {
+        let old = self.modem_status_register;
+        let new = self.modem_status_lines();
+        let changed = old ^ new;
+
+        let mut delta = old & MSR_DELTA_MASK;
+        if changed & MSR_CTS != 0 {
+            delta |= MSR_DELTA_CTS;
+        }
+        if changed & MSR_DSR != 0 {
+            delta |= MSR_DELTA_DSR;
+        }
+        if old & MSR_RI != 0 && new & MSR_RI == 0 {
+            delta |= MSR_TRAILING_RI;
+        }
+        if changed & MSR_DCD != 0 {
+            delta |= MSR_DELTA_DCD;
+        }
+        self.modem_status_register = new | delta;
+    }
+
+    fn line_status(&self) -> u8 {
+        let mut lsr = LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY;
+        if !self.rx_fifo.is_empty() {
+            lsr |= LSR_DATA_READY;
+        }
+        if self.overrun {
+            lsr |= LSR_OVERRUN;
+        }
+        lsr
+    }
+
+    /// The highest priority pending (and enabled) interrupt
+    fn pending_interrupt(&self) -> Option<u8> {
+        let ier = self.interrupt_enable_register;
+        if ier & IER_LINE_STATUS != 0 && self.overrun {
+            Some(IIR_LINE_STATUS)
+        } else if ier & IER_RDA != 0 && !self.rx_fifo.is_empty() {
+            //TODO: this should only be reported once no byte has been
+            //      received for 4 character times
+            if self.rx_fifo.len() >= self.rx_trigger_level() {
+                Some(IIR_RDA)
+            } else {
+                Some(IIR_CHAR_TIMEOUT)
+            }
+        } else if ier & IER_THRE != 0 && self.thr_empty_interrupt {
+            Some(IIR_THRE)
+        } else if ier & IER_MODEM_STATUS != 0
+            && self.modem_status_register & MSR_DELTA_MASK != 0
+        {
+            Some(IIR_MODEM_STATUS)
+        } else {
+            None
+        }
+    }
+
+    fn update_irq(&mut self) {
+        // On the PC, OUT2 gates the interrupt output of the UART
+        let level = self.pending_interrupt().is_some()
+            && self.modem_control_register & MCR_OUT2 != 0
+            && !self.loopback();
+        if level != self.irq_level {
+            self.irq_level = level;
+            self.irq.set_level(level);
+        }
+    }
+
+    fn read_register(&mut self, offset: u16) -> u8 {
+        match offset {
+            SerialOffset::DLL if self.divisor_latch_bit_set() => {
+                self.divisor as u8
+            }
+            SerialOffset::DLH if self.divisor_latch_bit_set() => {
+                (self.divisor >> 8) as u8
+            }
+            SerialOffset::DATA => self.rx_fifo.pop_front().unwrap_or(0),
+            SerialOffset::IER => self.interrupt_enable_register,
+            SerialOffset::IIR => {
+                let id = self.pending_interrupt();
+                if id == Some(IIR_THRE) {
+                    self.thr_empty_interrupt = false;
+                }
+                let fifo = if self.fifo_enabled() {
+                    IIR_FIFO_ENABLED
+                } else {
+                    0
+                };
+                id.unwrap_or(IIR_NO_INTERRUPT) | fifo
+            }
+            SerialOffset::LCR => self.line_control_register,
+            SerialOffset::MCR => self.modem_control_register,
+            SerialOffset::LSR => {
+                let lsr = self.line_status();
+                self.overrun = false;
+                lsr
+            }
+            SerialOffset::MSR => {
+                let msr = self.modem_status_register;
+                self.modem_status_register &= !MSR_DELTA_MASK;
+                msr
+            }
+            SerialOffset::SCR => self.scratch_register,
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_register(&mut self, offset: u16, val: u8) {
+        match offset {
+            SerialOffset::DLL if self.divisor_latch_bit_set() => {
+                self.divisor = (self.divisor & 0xff00) | val as u16;
+            }
+            SerialOffset::DLH if self.divisor_latch_bit_set() => {
+                self.divisor = (self.divisor & 0xff) | (val as u16) << 8;
+            }
+            SerialOffset::DATA => self.transmit(val),
+            SerialOffset::IER => {
+                let val = val & 0x0f;
+
+                // Enabling the THRE interrupt while the THR is empty (which
+                // it always is) raises the interrupt
+                if val & IER_THRE != 0
+                    && self.interrupt_enable_register & IER_THRE == 0
+                {
+                    self.thr_empty_interrupt = true;
+                }
+                self.interrupt_enable_register = val;
+            }
+            SerialOffset::FCR => {
+                // Changing the FIFO enable bit clears the FIFOs
+                if (val ^ self.fifo_control_register) & FCR_ENABLE != 0
+                    || val & FCR_CLEAR_RX != 0
+                {
+                    self.rx_fifo.clear();
+                }
+                self.fifo_control_register = val & 0xc1;
+            }
+            SerialOffset::LCR => self.line_control_register = val,
+            SerialOffset::MCR => {
+                self.modem_control_register = val & 0x1f;
+                self.update_modem_status();
+            }
+            SerialOffset::LSR | SerialOffset::MSR => {
+                info!("Ignoring write to COM status register 0x{:x}", offset);
+            }
+            SerialOffset::SCR => self.scratch_register = val,
+            _ => unreachable!(),
+        }
     }
 }
 
@@ -61,32 +380,30 @@ impl EmulatedDevice for ComDevice {
         vec![DeviceRegion::PortIo(self.base_port..=self.base_port + 7)]
     }
 
+    fn reset(&mut self) -> Result<()> {
+        self.rx_fifo.clear();
+        self.overrun = false;
+        self.thr_empty_interrupt = false;
+        self.divisor = 0;
+        self.interrupt_enable_register = 0;
+        self.fifo_control_register = 0;
+        self.line_control_register = 0;
+        self.modem_control_register = 0;
+        self.modem_status_register = self.modem_status_lines();
+        self.scratch_register = 0;
+        self.update_irq();
+        Ok(())
+    }
+
     fn on_port_read(
         &mut self,
         port: Port,
         mut val: PortReadRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
-        if port - self.base_port == SerialOffset::DATA
-            && self.divisor_latch_bit_set()
-        {
-            val.copy_from_u32((self.divisor & 0xff).into());
-        } else if port - self.base_port == SerialOffset::DLL
-            && self.divisor_latch_bit_set()
-        {
-            val.copy_from_u32((self.divisor >> 8).into());
-        }
-
-        if port - self.base_port == SerialOffset::IIR {
-            val.copy_from_u32(self.interrupt_identification_register as u32);
-        } else if port - self.base_port == SerialOffset::IER {
-            val.copy_from_u32(self.interrupt_enable_register as u32);
-        }
-
-        if port - self.base_port == SerialOffset::LSR {
-            val.copy_from_u32(0x20);
-        }
-
+        let res = self.read_register(port - self.base_port);
+        val.copy_from_u32(res as u32);
+        self.update_irq();
         Ok(())
     }
 
@@ -97,29 +414,100 @@ impl EmulatedDevice for ComDevice {
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
         let val: u8 = val.try_into()?;
-        if port - self.base_port == SerialOffset::DATA {
-            if self.divisor_latch_bit_set() {
-                self.divisor &= 0xff00 | val as u16;
-            } else {
-                self.buff.push(val);
-                if val == 10 {
-                    let s = String::from_utf8_lossy(&self.buff);
-                    logger::write_console(&format!("GUEST{}: {}", self.id, s));
-                    self.buff.clear();
-                }
-            }
-        } else if port - self.base_port == SerialOffset::DLL
-            && self.divisor_latch_bit_set()
-        {
-            self.divisor = (self.divisor & 0xff) | (val as u16) << 8;
+        self.write_register(port - self.base_port, val);
+        self.update_irq();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::ioapic::IoApic;
+    use crate::device::irq::IrqRouter;
+    use crate::device::pic::Pic8259;
+    use alloc::sync::Arc;
+    use spin::Mutex;
+
+    // The tests only access the registers, so the irq line is never used
+    fn define_test_com() -> Box<ComDevice> {
+        let router = IrqRouter::new(
+            Arc::new(Mutex::new(Pic8259::default())),
+            Arc::new(Mutex::new(IoApic::new(vec![]))),
+            vec![],
+            0,
+        );
+        let irq = IrqLine::new(4, Arc::new(router));
+        ComDevice::new(0, 0x3f8, irq)
+    }
+
+    #[test]
+    fn test_com_divisor_latch() {
+        let mut com = define_test_com();
+        com.write_register(SerialOffset::LCR, LCR_DLAB | 0x03);
+        com.write_register(SerialOffset::DLL, 0x01);
+        com.write_register(SerialOffset::DLH, 0x02);
+        assert_eq!(com.divisor, 0x0201);
+        assert_eq!(com.interrupt_enable_register, 0);
+
+        com.write_register(SerialOffset::LCR, 0x03);
+        assert_eq!(com.read_register(SerialOffset::IER), 0);
+        assert_eq!(com.read_register(SerialOffset::LCR), 0x03);
+    }
+
+    #[test]
+    fn test_com_receive_fifo() {
+        let mut com = define_test_com();
+        com.write_register(SerialOffset::FCR, FCR_ENABLE | 0x80);
+        com.write_register(SerialOffset::IER, IER_RDA);
+        for byte in 0..FIFO_SIZE as u8 {
+            assert!(com.can_receive());
+            com.push_rx(byte);
         }
+        assert!(!com.can_receive());
+        assert_eq!(com.read_register(SerialOffset::IIR), IIR_RDA | 0xc0);
 
-        if port - self.base_port == SerialOffset::IIR {
-            self.interrupt_identification_register = val;
-        } else if port - self.base_port == SerialOffset::IER {
-            self.interrupt_enable_register = val;
+        // A byte received with a full FIFO is lost
+        com.push_rx(0xff);
+        assert_eq!(
+            com.read_register(SerialOffset::LSR),
+            LSR_DATA_READY
+                | LSR_OVERRUN
+                | LSR_THR_EMPTY
+                | LSR_TRANSMITTER_EMPTY
+        );
+        assert_eq!(com.read_register(SerialOffset::LSR) & LSR_OVERRUN, 0);
+        for byte in 0..FIFO_SIZE as u8 {
+            assert_eq!(com.read_register(SerialOffset::DATA), byte);
         }
+        assert_eq!(com.read_register(SerialOffset::LSR) & LSR_DATA_READY, 0);
+        assert_eq!(com.read_register(SerialOffset::IIR), 0xc1);
+    }
 
-        Ok(())
+    #[test]
+    fn test_com_thre_interrupt() {
+        let mut com = define_test_com();
+        assert_eq!(com.read_register(SerialOffset::IIR), IIR_NO_INTERRUPT);
+
+        // Enabling the interrupt with an empty THR raises it, and reading
+        // the IIR clears it
+        com.write_register(SerialOffset::IER, IER_THRE);
+        assert_eq!(com.read_register(SerialOffset::IIR), IIR_THRE);
+        assert_eq!(com.read_register(SerialOffset::IIR), IIR_NO_INTERRUPT);
+    }
+
+    #[test]
+    fn test_com_loopback() {
+        let mut com = define_test_com();
+        com.write_register(SerialOffset::MCR, MCR_LOOP | MCR_RTS | MCR_OUT2);
+        let msr = com.read_register(SerialOffset::MSR);
+        assert_eq!(msr & 0xf0, MSR_CTS | MSR_DCD);
+        assert_eq!(msr & MSR_DELTA_MASK, MSR_DELTA_DSR);
+
+        com.write_register(SerialOffset::DATA, 0x5a);
+        assert_eq!(com.read_register(SerialOffset::DATA), 0x5a);
+
+        // Host input is ignored in loopback mode
+        assert!(!com.can_receive());
     }
 }
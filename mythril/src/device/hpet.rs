@@ -0,0 +1,582 @@
+use crate::device::irq::IrqLine;
+use crate::device::{
+    DeviceRegion, EmulatedDevice, MemReadRequest, MemWriteRequest,
+};
+use crate::error::{Error, Result};
+use crate::memory::{GuestAddressSpaceViewMut, GuestPhysAddr};
+use crate::memory_layout::HPET_BASE;
+use crate::time;
+use crate::vtime::GuestClock;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryInto;
+use core::time::Duration;
+
+const HPET_SIZE: u64 = 0x400;
+
+const HPET_NUM_TIMERS: usize = 3;
+
+// The main counter runs at 100MHz
+const HPET_NS_PER_TICK: u64 = 10;
+const HPET_FS_PER_TICK: u64 = HPET_NS_PER_TICK * 1_000_000;
+
+const HPET_VENDOR_ID: u64 = 0x8086;
+const HPET_REVISION: u64 = 0x01;
+
+mod reg {
+    pub const CAPABILITIES: u64 = 0x000;
+    pub const CONFIG: u64 = 0x010;
+    pub const INTERRUPT_STATUS: u64 = 0x020;
+    pub const MAIN_COUNTER: u64 = 0x0f0;
+    pub const TIMER_BASE: u64 = 0x100;
+    pub const TIMER_SIZE: u64 = 0x20;
+
+    // Offsets within the registers of each timer
+    pub const TIMER_CONFIG: u64 = 0x00;
+    pub const TIMER_COMPARATOR: u64 = 0x08;
+    pub const TIMER_FSB_ROUTE: u64 = 0x10;
+}
+
+const CAP_COUNT_SIZE_64: u64 = 1 << 13;
+const CAP_LEGACY_ROUTE: u64 = 1 << 15;
+
+const CONFIG_ENABLE: u64 = 1 << 0;
+const CONFIG_LEGACY_ROUTE: u64 = 1 << 1;
+const CONFIG_WRITE_MASK: u64 = CONFIG_ENABLE | CONFIG_LEGACY_ROUTE;
+
+const TN_LEVEL_TRIGGERED: u64 = 1 << 1;
+const TN_INT_ENABLE: u64 = 1 << 2;
+const TN_PERIODIC: u64 = 1 << 3;
+const TN_PERIODIC_CAP: u64 = 1 << 4;
+const TN_SIZE_64_CAP: u64 = 1 << 5;
+const TN_VAL_SET: u64 = 1 << 6;
+const TN_32BIT_MODE: u64 = 1 << 8;
+const TN_INT_ROUTE_SHIFT: u64 = 9;
+const TN_INT_ROUTE_MASK: u64 = 0x1f << TN_INT_ROUTE_SHIFT;
+const TN_WRITE_MASK: u64 = TN_LEVEL_TRIGGERED
+    | TN_INT_ENABLE
+    | TN_PERIODIC
+    | TN_VAL_SET
+    | TN_32BIT_MODE
+    | TN_INT_ROUTE_MASK;
+
+fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos(ticks.saturating_mul(HPET_NS_PER_TICK))
+}
+
+struct HpetTimer {
+    config: u64,
+    comparator: u64,
+    period: u64,
+    timer: Option<time::TimerId>,
+}
+
+impl HpetTimer {
+    fn new(route_cap: u64) -> Self {
+        Self {
+            config: TN_PERIODIC_CAP | TN_SIZE_64_CAP | (route_cap << 32),
+            comparator: !0,
+            period: 0,
+            timer: None,
+        }
+    }
+
+    fn is_periodic(&self) -> bool {
+        self.config & TN_PERIODIC != 0
+    }
+
+    fn is_32bit(&self) -> bool {
+        self.config & TN_32BIT_MODE != 0
+    }
+
+    fn is_level_triggered(&self) -> bool {
+        self.config & TN_LEVEL_TRIGGERED != 0
+    }
+
+    fn route(&self) -> u8 {
+        ((self.config & TN_INT_ROUTE_MASK) >> TN_INT_ROUTE_SHIFT) as u8
+    }
+
+    fn truncate(&self, val: u64) -> u64 {
+        if self.is_32bit() {
+            val & 0xffffffff
+        } else {
+            val
+        }
+    }
+
+    /// The number of ticks from `counter` until the comparator matches
+    fn ticks_until_match(&self, counter: u64) -> u64 {
+        self.truncate(self.comparator.wrapping_sub(counter))
+    }
+
+    /// Returns true if the comparator matched in the `elapsed` ticks
+    /// following `last` (in which case periodic timers are advanced)
+    fn catch_up(&mut self, last: u64, elapsed: u64) -> bool {
+        let ticks = self.ticks_until_match(last);
+        if ticks == 0 || ticks > elapsed {
+            return false;
+        }
+
+        if self.is_periodic() && self.period != 0 {
+            let periods = (elapsed - ticks) / self.period + 1;
+            self.comparator = self.truncate(
+                self.comparator
+                    .wrapping_add(periods.wrapping_mul(self.period)),
+            );
+        }
+        true
+    }
+}
+
+/// An emulated High Precision Event Timer
+///
+/// Interrupts from the comparators are delivered through the legacy
+/// interrupt lines of the VM (either the routing selected by the guest or
+/// the legacy replacement routing to IRQ0 and IRQ8).
+pub struct Hpet {
+    config: u64,
+    interrupt_status: u64,
+
+    // The value of the main counter when it was last halted or written, and
+    // the guest time (in ns) when it was last started
+    counter: u64,
+    started: Option<u64>,
+
+    // The value of the main counter when the comparators were last checked
+    last_update: u64,
+
+    timers: [HpetTimer; HPET_NUM_TIMERS],
+
+    // Indexed by legacy irq number
+    lines: Vec<IrqLine>,
+    clock: Arc<GuestClock>,
+}
+
+impl Hpet {
+    /// Create a new `Hpet` that delivers interrupts through the given
+    /// legacy interrupt lines (indexed by irq), and counts the guest time
+    /// of `clock`
+    pub fn new(lines: Vec<IrqLine>, clock: Arc<GuestClock>) -> Box<Self> {
+        let route_cap =
+            lines.iter().fold(0u64, |cap, line| cap | (1 << line.gsi()));
+        Box::new(Self {
+            config: 0,
+            interrupt_status: 0,
+            counter: 0,
+            started: None,
+            last_update: 0,
+            timers: [
+                HpetTimer::new(route_cap),
+                HpetTimer::new(route_cap),
+                HpetTimer::new(route_cap),
+            ],
+            lines: lines,
+            clock: clock,
+        })
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.config & CONFIG_ENABLE != 0
+    }
+
+    /// The value of the General Capabilities and ID Register
+    pub fn capabilities() -> u64 {
+        (HPET_FS_PER_TICK << 32)
+            | (HPET_VENDOR_ID << 16)
+            | CAP_LEGACY_ROUTE
+            | CAP_COUNT_SIZE_64
+            | ((HPET_NUM_TIMERS as u64 - 1) << 8)
+            | HPET_REVISION
+    }
+
+    fn main_counter(&self) -> u64 {
+        match self.started {
+            Some(started) => {
+                let elapsed = self.clock.now_ns().saturating_sub(started);
+                self.counter.wrapping_add(elapsed / HPET_NS_PER_TICK)
+            }
+            None => self.counter,
+        }
+    }
+
+    /// Update the interrupt status and periodic comparators for any
+    /// matches since the last update
+    ///
+    /// The interrupts themselves are delivered by the host timers, so this
+    /// only needs to happen before the guest observes the registers.
+    fn update(&mut self, counter: u64) {
+        let elapsed = counter.wrapping_sub(self.last_update);
+        for (i, timer) in self.timers.iter_mut().enumerate() {
+            if timer.catch_up(self.last_update, elapsed)
+                && timer.is_level_triggered()
+                && timer.config & TN_INT_ENABLE != 0
+            {
+                self.interrupt_status |= 1 << i;
+            }
+        }
+        self.last_update = counter;
+    }
+
+    /// The interrupt line currently used by the given timer
+    fn timer_line(&self, index: usize) -> Option<IrqLine> {
+        let legacy_irq = match index {
+            0 => Some(0),
+            1 => Some(8),
+            _ => None,
+        };
+        match legacy_irq {
+            Some(irq) if self.config & CONFIG_LEGACY_ROUTE != 0 => {
+                self.lines.get(irq).cloned()
+            }
+            _ => {
+                let gsi = self.timers[index].route();
+                self.lines.iter().find(|line| line.gsi() == gsi).cloned()
+            }
+        }
+    }
+
+    fn lower_timer_line(&self, index: usize) {
+        if let Some(line) = self.timer_line(index) {
+            line.lower();
+        }
+    }
+
+    /// (Re)arm the host timer for the given comparator
+    fn schedule_timer(&mut self, index: usize, counter: u64) {
+        if let Some(id) = self.timers[index].timer.take() {
+            // The timer may have already expired, so ignore any error
+            let _ = time::cancel_timer(&id);
+        }
+
+        let timer = &self.timers[index];
+        if !self.is_enabled() || timer.config & TN_INT_ENABLE == 0 {
+            return;
+        }
+
+        let line = match self.timer_line(index) {
+            Some(line) => line,
+            None => {
+                warn!(
+                    "HPET: timer {} routed to unsupported GSI {}",
+                    index,
+                    timer.route()
+                );
+                return;
+            }
+        };
+        let interrupt = if timer.is_level_triggered() {
+            time::TimerInterruptType::RaiseIrq(line)
+        } else {
+            time::TimerInterruptType::Irq(line)
+        };
+
+        let id = if timer.is_periodic() && timer.period != 0 {
+            //FIXME: the host timer starts now, so the first interrupt will
+            // only line up with the comparator if the guest programmed it
+            // one period from the current counter (as linux does).
+            time::set_periodic_timer(ticks_to_duration(timer.period), interrupt)
+        } else {
+            // A 64 bit comparator in the past will not match until the
+            // counter wraps, so it is effectively disabled.
+            if !timer.is_32bit() && timer.comparator < counter {
+                return;
+            }
+            let ticks = timer.ticks_until_match(counter);
+            time::set_oneshot_timer(ticks_to_duration(ticks), interrupt)
+        };
+        self.timers[index].timer = Some(id);
+    }
+
+    fn read_register(&self, offset: u64, counter: u64) -> u64 {
+        match offset {
+            reg::CAPABILITIES => Self::capabilities(),
+            reg::CONFIG => self.config,
+            reg::INTERRUPT_STATUS => self.interrupt_status,
+            reg::MAIN_COUNTER => counter,
+            offset
+                if offset >= reg::TIMER_BASE
+                    && offset
+                        < reg::TIMER_BASE
+                            + reg::TIMER_SIZE * HPET_NUM_TIMERS as u64 =>
+            {
+                let index =
+                    ((offset - reg::TIMER_BASE) / reg::TIMER_SIZE) as usize;
+                let timer = &self.timers[index];
+                match (offset - reg::TIMER_BASE) % reg::TIMER_SIZE {
+                    reg::TIMER_CONFIG => timer.config,
+                    reg::TIMER_COMPARATOR => timer.comparator,
+                    _ => 0,
+                }
+            }
+            offset => {
+                info!("HPET: read of unknown offset 0x{:x}", offset);
+                0
+            }
+        }
+    }
+
+    /// Write the bits of `val` selected by `mask` to the given register
+    fn write_register(
+        &mut self,
+        offset: u64,
+        val: u64,
+        mask: u64,
+        counter: u64,
+    ) -> Result<()> {
+        match offset {
+            reg::CAPABILITIES => (),
+            reg::CONFIG => {
+                let mask = mask & CONFIG_WRITE_MASK;
+                let old = self.config;
+                self.config = (self.config & !mask) | (val & mask);
+
+                let enable = self.config & CONFIG_ENABLE != 0;
+                if enable && old & CONFIG_ENABLE == 0 {
+                    self.started = Some(self.clock.now_ns());
+                    self.last_update = counter;
+                } else if !enable && old & CONFIG_ENABLE != 0 {
+                    self.counter = counter;
+                    self.started = None;
+                }
+
+                // Changing the legacy routing moves the interrupt lines
+                for index in 0..HPET_NUM_TIMERS {
+                    if (old ^ self.config) & CONFIG_LEGACY_ROUTE != 0 {
+                        self.lower_timer_line(index);
+                    }
+                    self.schedule_timer(index, counter);
+                }
+            }
+            reg::INTERRUPT_STATUS => {
+                // The status bits are cleared by writing a 1
+                let cleared = self.interrupt_status & val & mask;
+                self.interrupt_status &= !cleared;
+                for index in 0..HPET_NUM_TIMERS {
+                    if cleared & (1 << index) != 0 {
+                        self.lower_timer_line(index);
+                    }
+                }
+            }
+            reg::MAIN_COUNTER => {
+                if self.is_enabled() {
+                    warn!("HPET: ignoring write to the running main counter");
+                } else {
+                    self.counter = (self.counter & !mask) | (val & mask);
+                    self.last_update = self.counter;
+                }
+            }
+            offset
+                if offset >= reg::TIMER_BASE
+                    && offset
+                        < reg::TIMER_BASE
+                            + reg::TIMER_SIZE * HPET_NUM_TIMERS as u64 =>
+            {
+                let index =
+                    ((offset - reg::TIMER_BASE) / reg::TIMER_SIZE) as usize;
+                match (offset - reg::TIMER_BASE) % reg::TIMER_SIZE {
+                    reg::TIMER_CONFIG => {
+                        let timer = &mut self.timers[index];
+                        let mask = mask & TN_WRITE_MASK;
+                        timer.config = (timer.config & !mask) | (val & mask);
+                        timer.comparator = timer.truncate(timer.comparator);
+                        timer.period = timer.truncate(timer.period);
+
+                        // The status bit only applies to enabled, level
+                        // triggered interrupts
+                        let level = timer.is_level_triggered()
+                            && timer.config & TN_INT_ENABLE != 0;
+                        if !level && self.interrupt_status & (1 << index) != 0 {
+                            self.interrupt_status &= !(1 << index);
+                            self.lower_timer_line(index);
+                        }
+                    }
+                    reg::TIMER_COMPARATOR => {
+                        let timer = &mut self.timers[index];
+                        let val = timer.truncate(val);
+
+                        // In periodic mode, writes set the period unless the
+                        // guest explicitly sets the comparator value.
+                        if !timer.is_periodic()
+                            || timer.config & TN_VAL_SET != 0
+                        {
+                            timer.comparator =
+                                (timer.comparator & !mask) | (val & mask);
+                        }
+                        if timer.is_periodic() {
+                            timer.period =
+                                (timer.period & !mask) | (val & mask);
+                        }
+                        timer.config &= !TN_VAL_SET;
+                    }
+                    reg::TIMER_FSB_ROUTE => {
+                        info!("HPET: FSB interrupt delivery is not supported");
+                        return Ok(());
+                    }
+                    _ => return Ok(()),
+                }
+                self.schedule_timer(index, counter);
+            }
+            offset => {
+                info!("HPET: write to unknown offset 0x{:x}", offset);
+            }
+        }
+        Ok(())
+    }
+
+    fn current_counter(&mut self) -> u64 {
+        let counter = self.main_counter();
+        if self.is_enabled() {
+            self.update(counter);
+        }
+        counter
+    }
+}
+
+impl EmulatedDevice for Hpet {
+    fn reset(&mut self) -> Result<()> {
+        for timer in self.timers.iter_mut() {
+            if let Some(id) = timer.timer.take() {
+                let _ = time::cancel_timer(&id);
+            }
+        }
+        for i in 0..HPET_NUM_TIMERS {
+            self.lower_timer_line(i);
+        }
+        *self = *Self::new(self.lines.clone());
+        Ok(())
+    }
+
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::MemIo(
+            GuestPhysAddr::new(HPET_BASE)
+                ..=GuestPhysAddr::new(HPET_BASE + HPET_SIZE - 1),
+        )]
+    }
+
+    fn on_mem_read(
+        &mut self,
+        addr: GuestPhysAddr,
+        mut data: MemReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let offset = addr.as_u64() - HPET_BASE;
+        let len = data.as_slice().len();
+        if (len != 4 && len != 8) || offset % len as u64 != 0 {
+            return Err(Error::InvalidValue(format!(
+                "Invalid HPET read of length {} at offset 0x{:x}",
+                len, offset
+            )));
+        }
+
+        let counter = self.current_counter();
+        let val =
+            self.read_register(offset & !0x7, counter) >> ((offset & 0x4) * 8);
+        let bytes = val.to_be_bytes();
+        data.as_mut_slice()
+            .copy_from_slice(&bytes[bytes.len() - len..]);
+        Ok(())
+    }
+
+    fn on_mem_write(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemWriteRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let offset = addr.as_u64() - HPET_BASE;
+        let (val, mask) = match data.as_slice().len() {
+            4 if offset % 4 == 0 => {
+                let val: u32 = data.try_into()?;
+                let shift = (offset & 0x4) * 8;
+                ((val as u64) << shift, 0xffffffff << shift)
+            }
+            8 if offset % 8 == 0 => (data.try_into()?, !0),
+            len => {
+                return Err(Error::InvalidValue(format!(
+                    "Invalid HPET write of length {} at offset 0x{:x}",
+                    len, offset
+                )))
+            }
+        };
+
+        let counter = self.current_counter();
+        self.write_register(offset & !0x7, val, mask, counter)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn timer_reg(index: u64, reg: u64) -> u64 {
+        reg::TIMER_BASE + index * reg::TIMER_SIZE + reg
+    }
+
+    #[test]
+    fn test_hpet_capabilities() {
+        let hpet = Hpet::new(vec![], Arc::new(GuestClock::new()));
+        let caps = hpet.read_register(reg::CAPABILITIES, 0);
+        assert_eq!(caps >> 32, 10_000_000);
+        assert_eq!((caps >> 8) & 0x1f, HPET_NUM_TIMERS as u64 - 1);
+        assert_ne!(caps & CAP_LEGACY_ROUTE, 0);
+    }
+
+    #[test]
+    fn test_hpet_periodic_comparator_write() {
+        let mut hpet = Hpet::new(vec![], Arc::new(GuestClock::new()));
+        let config = timer_reg(0, reg::TIMER_CONFIG);
+        let comparator = timer_reg(0, reg::TIMER_COMPARATOR);
+
+        hpet.write_register(config, TN_PERIODIC | TN_VAL_SET, !0, 0)
+            .unwrap();
+        hpet.write_register(comparator, 1000, !0, 0).unwrap();
+        assert_eq!(hpet.read_register(comparator, 0), 1000);
+        assert_eq!(hpet.timers[0].period, 1000);
+
+        // Without TN_VAL_SET, only the period is changed
+        hpet.write_register(comparator, 500, !0, 0).unwrap();
+        assert_eq!(hpet.read_register(comparator, 0), 1000);
+        assert_eq!(hpet.timers[0].period, 500);
+    }
+
+    #[test]
+    fn test_hpet_level_status() {
+        let mut hpet = Hpet::new(vec![], Arc::new(GuestClock::new()));
+        let config = timer_reg(1, reg::TIMER_CONFIG);
+        let comparator = timer_reg(1, reg::TIMER_COMPARATOR);
+        hpet.write_register(
+            config,
+            TN_PERIODIC | TN_VAL_SET | TN_LEVEL_TRIGGERED,
+            !0,
+            0,
+        )
+        .unwrap();
+        hpet.write_register(comparator, 100, !0, 0).unwrap();
+        hpet.write_register(comparator, 50, !0, 0).unwrap();
+
+        // Interrupts are not enabled, so the status is not set
+        hpet.update(120);
+        assert_eq!(hpet.read_register(reg::INTERRUPT_STATUS, 0), 0);
+        assert_eq!(hpet.read_register(comparator, 0), 150);
+
+        hpet.timers[1].config |= TN_INT_ENABLE;
+        hpet.update(260);
+        assert_eq!(hpet.read_register(reg::INTERRUPT_STATUS, 0), 0b10);
+        assert_eq!(hpet.read_register(comparator, 0), 300);
+
+        hpet.write_register(reg::INTERRUPT_STATUS, 0b10, 0xffffffff, 0)
+            .unwrap();
+        assert_eq!(hpet.read_register(reg::INTERRUPT_STATUS, 0), 0);
+    }
+
+    #[test]
+    fn test_hpet_32bit_comparator_wrap() {
+        let mut timer = HpetTimer::new(0);
+        timer.config |= TN_32BIT_MODE;
+        timer.comparator = 0x10;
+        assert!(!timer.catch_up(0xffff_fff0, 0x10));
+        assert!(timer.catch_up(0xffff_fff0, 0x20));
+    }
+}
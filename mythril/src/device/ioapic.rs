@@ -0,0 +1,345 @@
+use crate::device::irq;
+use crate::device::{
+    DeviceRegion, EmulatedDevice, MemReadRequest, MemWriteRequest,
+};
+use crate::error::{Error, Result};
+use crate::ioapic::{
+    DeliveryMode, DestinationMode, IoRedTblEntry, TriggerMode,
+};
+use crate::memory::{GuestAddressSpaceViewMut, GuestPhysAddr};
+use crate::memory_layout::IOAPIC_BASE;
+use crate::vcpu::{InterruptMessage, VCpuMailbox};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::{TryFrom, TryInto};
+
+const IOREGSEL_OFFSET: u64 = 0x00;
+const IOWIN_OFFSET: u64 = 0x10;
+
+const IOAPIC_VERSION: u32 = 0x11;
+const IOAPIC_NUM_PINS: usize = 24;
+
+const IOAPIC_REG_ID: u8 = 0x00;
+const IOAPIC_REG_VERSION: u8 = 0x01;
+const IOAPIC_REG_ARB: u8 = 0x02;
+const IOAPIC_REG_REDTBL_BASE: u8 = 0x10;
+
+// The delivery status and remote IRR bits can't be written by the guest
+const IOREDTBL_RO_MASK: u64 = (1 << 12) | (1 << 14);
+const IOREDTBL_REMOTE_IRR: u64 = 1 << 14;
+const IOREDTBL_LEVEL_TRIGGERED: u64 = 1 << 15;
+const IOREDTBL_MASKED: u64 = 1 << 16;
+
+/// An emulated I/O APIC
+///
+/// Assertions of the input pins (GSIs) are routed to the local APIC of the
+/// destination `VCpu` according to the redirection table.
+pub struct IoApic {
+    id: u8,
+    ioregsel: u8,
+
+    // The raw redirection table entries. These are only decoded when an
+    // interrupt is delivered, as the guest may write invalid intermediate
+    // values (one half at a time).
+    redirection: [u64; IOAPIC_NUM_PINS],
+
+    // The current level of each pin
+    irr: u32,
+
+    // Indexed by guest APIC id
+    mailboxes: Vec<Arc<VCpuMailbox>>,
+}
+
+impl IoApic {
+    /// Create a new `IoApic` that will deliver interrupts to the given vcpus
+    pub fn new(mailboxes: Vec<Arc<VCpuMailbox>>) -> Self {
+        Self {
+            id: 0,
+            ioregsel: 0,
+            redirection: [IOREDTBL_MASKED; IOAPIC_NUM_PINS],
+            irr: 0,
+            mailboxes: mailboxes,
+        }
+    }
+
+    /// Set the level of one of the input pins
+    pub fn set_irq(&mut self, pin: u8, level: bool) {
+        let pin = pin as usize;
+        if pin >= IOAPIC_NUM_PINS {
+            warn!("IOAPIC: invalid pin {}", pin);
+            return;
+        }
+
+        let mask = 1 << pin;
+        let asserted = level && self.irr & mask == 0;
+        if level {
+            self.irr |= mask;
+        } else {
+            self.irr &= !mask;
+        }
+
+        // Edge triggered interrupts are only delivered on the rising edge,
+        // level triggered ones are delivered as long as the pin is asserted.
+        if self.redirection[pin] & IOREDTBL_LEVEL_TRIGGERED != 0 {
+            if level {
+                self.service(pin);
+            }
+        } else if asserted {
+            self.service(pin);
+        }
+    }
+
+    /// Handle an EOI broadcast from a local APIC
+    pub fn end_of_interrupt(&mut self, vector: u8) {
+        for pin in 0..IOAPIC_NUM_PINS {
+            let entry = self.redirection[pin];
+            if entry & 0xff != vector as u64 || entry & IOREDTBL_REMOTE_IRR == 0
+            {
+                continue;
+            }
+            self.redirection[pin] &= !IOREDTBL_REMOTE_IRR;
+
+            // If the pin is still asserted, the interrupt is delivered again
+            if self.irr & (1 << pin) != 0 {
+                self.service(pin);
+            }
+        }
+    }
+
+    fn service(&mut self, pin: usize) {
+        let bits = self.redirection[pin];
+        if bits & IOREDTBL_MASKED != 0 {
+            return;
+        }
+
+        let entry = match IoRedTblEntry::try_from(bits) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!(
+                    "IOAPIC: invalid redirection entry for pin {} (0x{:x}): {:?}",
+                    pin, bits, e
+                );
+                return;
+            }
+        };
+
+        let level_triggered = entry.trigger_mode() == TriggerMode::Level;
+        if level_triggered {
+            // The interrupt has not been EOI'd yet
+            if bits & IOREDTBL_REMOTE_IRR != 0 {
+                return;
+            }
+            self.redirection[pin] |= IOREDTBL_REMOTE_IRR;
+        }
+
+        let message = match entry.delivery_mode() {
+            DeliveryMode::Fixed | DeliveryMode::LowestPriority => {
+                InterruptMessage::Fixed {
+                    vector: entry.vector(),
+                    level_triggered: level_triggered,
+                }
+            }
+            DeliveryMode::NMI => InterruptMessage::Nmi,
+
+            // The output of the PIC is delivered directly to the BSP
+            DeliveryMode::ExtINT => return,
+            mode => {
+                warn!("IOAPIC: unsupported delivery mode {:?}", mode);
+                return;
+            }
+        };
+
+        let mut targets = self.destinations(&entry);
+        if entry.delivery_mode() == DeliveryMode::LowestPriority {
+            //TODO: actually deliver to the lowest priority processor
+            targets.truncate(1);
+        }
+        for target in targets {
+            self.mailboxes[target].post(message);
+        }
+    }
+
+    /// Returns the guest APIC ids of the destinations of the given entry
+    fn destinations(&self, entry: &IoRedTblEntry) -> Vec<usize> {
+        irq::destinations(
+            &self.mailboxes,
+            entry.destination() as u32,
+            entry.destination_mode() == DestinationMode::Logical,
+        )
+    }
+
+    fn read_register(&self, reg: u8) -> u32 {
+        match reg {
+            IOAPIC_REG_ID | IOAPIC_REG_ARB => (self.id as u32) << 24,
+            IOAPIC_REG_VERSION => {
+                IOAPIC_VERSION | ((IOAPIC_NUM_PINS as u32 - 1) << 16)
+            }
+            reg if reg >= IOAPIC_REG_REDTBL_BASE => {
+                let index = (reg - IOAPIC_REG_REDTBL_BASE) as usize;
+                match self.redirection.get(index / 2) {
+                    Some(entry) if index % 2 == 0 => *entry as u32,
+                    Some(entry) => (*entry >> 32) as u32,
+                    None => 0,
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, reg: u8, val: u32) {
+        match reg {
+            IOAPIC_REG_ID => self.id = ((val >> 24) & 0xf) as u8,
+            IOAPIC_REG_VERSION | IOAPIC_REG_ARB => (),
+            reg if reg >= IOAPIC_REG_REDTBL_BASE => {
+                let index = (reg - IOAPIC_REG_REDTBL_BASE) as usize;
+                let pin = index / 2;
+                let entry = match self.redirection.get_mut(pin) {
+                    Some(entry) => entry,
+                    None => return,
+                };
+                if index % 2 == 0 {
+                    let val = val as u64 & !IOREDTBL_RO_MASK;
+                    *entry = (*entry & !0xffffffff)
+                        | (*entry & IOREDTBL_RO_MASK)
+                        | val;
+                } else {
+                    *entry = (*entry & 0xffffffff) | ((val as u64) << 32);
+                }
+
+                // A level triggered interrupt may have been unmasked
+                if *entry & IOREDTBL_LEVEL_TRIGGERED != 0
+                    && self.irr & (1 << pin) != 0
+                {
+                    self.service(pin);
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl EmulatedDevice for IoApic {
+    fn reset(&mut self) -> Result<()> {
+        *self = Self::new(self.mailboxes.clone());
+        Ok(())
+    }
+
+    fn services(&self) -> Vec<DeviceRegion> {
+        vec![DeviceRegion::MemIo(
+            GuestPhysAddr::new(IOAPIC_BASE)
+                ..=GuestPhysAddr::new(IOAPIC_BASE + 0xfff),
+        )]
+    }
+
+    fn on_mem_read(
+        &mut self,
+        addr: GuestPhysAddr,
+        mut data: MemReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        let val = match addr.as_u64() - IOAPIC_BASE {
+            IOREGSEL_OFFSET => self.ioregsel as u32,
+            IOWIN_OFFSET => self.read_register(self.ioregsel),
+            offset => {
+                info!("IOAPIC: read of unknown offset 0x{:x}", offset);
+                0
+            }
+        };
+
+        let bytes = val.to_be_bytes();
+        let len = data.as_slice().len();
+        if len > bytes.len() {
+            return Err(Error::InvalidValue(format!(
+                "Invalid IOAPIC read length: {}",
+                len
+            )));
+        }
+        data.as_mut_slice()
+            .copy_from_slice(&bytes[bytes.len() - len..]);
+        Ok(())
+    }
+
+    fn on_mem_write(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemWriteRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        match addr.as_u64() - IOAPIC_BASE {
+            IOREGSEL_OFFSET => {
+                let val: u32 = match data.as_slice().len() {
+                    1 => data.try_into().map(|val: u8| val as u32)?,
+                    _ => data.try_into()?,
+                };
+                self.ioregsel = val as u8;
+            }
+            IOWIN_OFFSET => {
+                self.write_register(self.ioregsel, data.try_into()?)
+            }
+            offset => {
+                info!("IOAPIC: write to unknown offset 0x{:x}", offset);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_entry(ioapic: &mut IoApic, pin: u8, entry: u64) {
+        let reg = IOAPIC_REG_REDTBL_BASE + pin * 2;
+        ioapic.write_register(reg + 1, (entry >> 32) as u32);
+        ioapic.write_register(reg, entry as u32);
+    }
+
+    #[test]
+    fn test_ioapic_version() {
+        let ioapic = IoApic::new(vec![]);
+        assert_eq!(ioapic.read_register(IOAPIC_REG_VERSION), 0x00170011);
+    }
+
+    #[test]
+    fn test_ioapic_redirection_entries() {
+        let mut ioapic = IoApic::new(vec![]);
+        assert_eq!(ioapic.read_register(IOAPIC_REG_REDTBL_BASE), 1 << 16);
+
+        write_entry(&mut ioapic, 2, 0x01000000_00000030 | IOREDTBL_RO_MASK);
+        assert_eq!(ioapic.read_register(IOAPIC_REG_REDTBL_BASE + 4), 0x30);
+        assert_eq!(
+            ioapic.read_register(IOAPIC_REG_REDTBL_BASE + 5),
+            0x01000000
+        );
+    }
+
+    #[test]
+    fn test_ioapic_remote_irr() {
+        let mut ioapic = IoApic::new(vec![]);
+        write_entry(&mut ioapic, 9, 0x00000000_00008041);
+
+        ioapic.set_irq(9, true);
+        assert_ne!(ioapic.redirection[9] & IOREDTBL_REMOTE_IRR, 0);
+
+        // The remote IRR is only cleared by an EOI with the right vector
+        ioapic.end_of_interrupt(0x40);
+        assert_ne!(ioapic.redirection[9] & IOREDTBL_REMOTE_IRR, 0);
+
+        ioapic.set_irq(9, false);
+        ioapic.end_of_interrupt(0x41);
+        assert_eq!(ioapic.redirection[9] & IOREDTBL_REMOTE_IRR, 0);
+    }
+
+    #[test]
+    fn test_ioapic_masked_level_irq() {
+        let mut ioapic = IoApic::new(vec![]);
+        write_entry(&mut ioapic, 9, 0x00000000_00018041);
+
+        ioapic.set_irq(9, true);
+        assert_eq!(ioapic.redirection[9] & IOREDTBL_REMOTE_IRR, 0);
+
+        // Unmasking the entry delivers the interrupt
+        write_entry(&mut ioapic, 9, 0x00000000_00008041);
+        assert_ne!(ioapic.redirection[9] & IOREDTBL_REMOTE_IRR, 0);
+    }
+}
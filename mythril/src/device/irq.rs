@@ -0,0 +1,308 @@
+use crate::device::ioapic::IoApic;
+use crate::device::pic::Pic8259;
+use crate::vcpu::{self, InterruptMessage, VCpuMailbox};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+/// The number of interrupt lines (the 16 legacy irqs, followed by the
+/// IOAPIC pins that are not connected to the PIC)
+pub const NUM_IRQ_LINES: usize = 24;
+
+// The largest number of devices that can share a line
+const MAX_LINE_SOURCES: u8 = 32;
+
+/// The IOAPIC pin (GSI) a line is connected to
+///
+/// This is an identity mapping, except for the PIT (IRQ0) which is
+/// connected to pin 2 (the BIOS reports this as an interrupt source
+/// override).
+fn irq_to_gsi(irq: u8) -> u8 {
+    match irq {
+        0 => 2,
+        irq => irq,
+    }
+}
+
+/// Returns the guest APIC ids selected by a physical or logical APIC
+/// destination
+pub fn destinations(
+    mailboxes: &[Arc<VCpuMailbox>],
+    dest: u32,
+    logical: bool,
+) -> Vec<usize> {
+    let all = 0..mailboxes.len();
+    if logical {
+        all.filter(|id| mailboxes[*id].logical_destination().matches(dest))
+            .collect()
+    } else {
+        all.filter(|id| dest == 0xff || *id as u32 == dest)
+            .collect()
+    }
+}
+
+/// A decoded message signalled interrupt
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct MsiMessage {
+    dest: u32,
+    logical: bool,
+    lowest_priority: bool,
+    message: InterruptMessage,
+}
+
+impl MsiMessage {
+    /// Decode the address and data written by a device, returning `None`
+    /// for unsupported messages
+    fn decode(address: u64, data: u32) -> Option<Self> {
+        // MSIs are writes to the local APIC address range
+        if address >> 20 != 0xfee {
+            return None;
+        }
+
+        let message = match (data >> 8) & 0b111 {
+            0b000 | 0b001 => InterruptMessage::Fixed {
+                vector: data as u8,
+                level_triggered: data & (1 << 15) != 0,
+            },
+            0b100 => InterruptMessage::Nmi,
+            _ => return None,
+        };
+        Some(Self {
+            dest: ((address >> 12) & 0xff) as u32,
+            logical: address & (1 << 2) != 0,
+            lowest_priority: (data >> 8) & 0b111 == 0b001,
+            message: message,
+        })
+    }
+}
+
+/// The levels of the interrupt lines of a VM
+///
+/// A line may be shared by several devices (e.g., COM1 and COM3), so each
+/// `IrqLine` is a separate source and the line is asserted while any of
+/// its sources is.
+#[derive(Default)]
+struct LineLevels {
+    // The number of sources of each line
+    sources: [u8; NUM_IRQ_LINES],
+
+    // The asserted sources of each line (a bit per source)
+    asserted: [u32; NUM_IRQ_LINES],
+}
+
+impl LineLevels {
+    fn add_source(&mut self, irq: u8) -> u8 {
+        let sources = &mut self.sources[irq as usize];
+        assert!(*sources < MAX_LINE_SOURCES, "Too many sources for irq");
+        *sources += 1;
+        *sources - 1
+    }
+
+    /// Set the level of a source, returning the level of its line
+    fn set(&mut self, irq: u8, source: u8, level: bool) -> bool {
+        let asserted = &mut self.asserted[irq as usize];
+        if level {
+            *asserted |= 1 << source;
+        } else {
+            *asserted &= !(1 << source);
+        }
+        *asserted != 0
+    }
+}
+
+/// Routes the interrupts of the emulated devices of a VM to its interrupt
+/// controllers
+///
+/// The legacy irqs are connected to both the PIC and the IOAPIC, and the
+/// other lines only to the IOAPIC. Message signalled interrupts are sent
+/// directly to the local APICs of the destination vcpus.
+pub struct IrqRouter {
+    pic: Arc<Mutex<Pic8259>>,
+    ioapic: Arc<Mutex<IoApic>>,
+
+    // Indexed by guest APIC id
+    mailboxes: Vec<Arc<VCpuMailbox>>,
+
+    // The host APIC id of the core running the guest BSP
+    bsp: u32,
+
+    levels: Mutex<LineLevels>,
+}
+
+impl IrqRouter {
+    /// Create a new `IrqRouter`
+    ///
+    /// # Arguments
+    ///
+    /// * `pic` - The PIC of the VM
+    /// * `ioapic` - The IOAPIC of the VM
+    /// * `mailboxes` - The mailboxes of the vcpus (by guest APIC id)
+    /// * `bsp` - The host APIC id of the core running the guest BSP
+    pub fn new(
+        pic: Arc<Mutex<Pic8259>>,
+        ioapic: Arc<Mutex<IoApic>>,
+        mailboxes: Vec<Arc<VCpuMailbox>>,
+        bsp: u32,
+    ) -> Self {
+        Self {
+            pic: pic,
+            ioapic: ioapic,
+            mailboxes: mailboxes,
+            bsp: bsp,
+            levels: Mutex::new(LineLevels::default()),
+        }
+    }
+
+    fn set_level(&self, irq: u8, source: u8, level: bool) {
+        let level = self.levels.lock().set(irq, source, level);
+        self.ioapic.lock().set_irq(irq_to_gsi(irq), level);
+        if irq >= 16 {
+            return;
+        }
+
+        let pending = {
+            let mut pic = self.pic.lock();
+            pic.set_irq(irq, level);
+            pic.has_interrupt()
+        };
+
+        // The PIC output is only checked when the BSP exits, so make sure
+        // that happens soon.
+        if pending {
+            vcpu::kick_vcpu(self.bsp);
+        }
+    }
+
+    /// Deliver a message signalled interrupt written to `address`
+    pub fn send_msi(&self, address: u64, data: u32) {
+        let msi = match MsiMessage::decode(address, data) {
+            Some(msi) => msi,
+            None => {
+                warn!(
+                    "Unsupported MSI (address=0x{:x}, data=0x{:x})",
+                    address, data
+                );
+                return;
+            }
+        };
+
+        let mut targets = destinations(&self.mailboxes, msi.dest, msi.logical);
+        if msi.lowest_priority {
+            //TODO: actually deliver to the lowest priority processor
+            targets.truncate(1);
+        }
+        for target in targets {
+            self.mailboxes[target].post(msi.message);
+        }
+    }
+}
+
+/// A handle to one of the interrupt lines of a VM
+///
+/// Emulated devices use an `IrqLine` to raise or lower their interrupt
+/// line, and the `IrqRouter` delivers the corresponding vector to the
+/// guest. Clones of a line are the same source (so a device can keep
+/// one for each of its timers).
+#[derive(Clone)]
+pub struct IrqLine {
+    irq: u8,
+    source: u8,
+    router: Arc<IrqRouter>,
+}
+
+impl fmt::Debug for IrqLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IrqLine")
+            .field("irq", &self.irq)
+            .field("source", &self.source)
+            .finish()
+    }
+}
+
+impl IrqLine {
+    /// Connect a new source to the interrupt line `irq` (0-23)
+    pub fn new(irq: u8, router: Arc<IrqRouter>) -> Self {
+        assert!((irq as usize) < NUM_IRQ_LINES, "Invalid irq {}", irq);
+        let source = router.levels.lock().add_source(irq);
+        Self {
+            irq: irq,
+            source: source,
+            router: router,
+        }
+    }
+
+    /// The legacy irq number of this line
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+
+    /// The IOAPIC pin this line is connected to
+    pub fn gsi(&self) -> u8 {
+        irq_to_gsi(self.irq)
+    }
+
+    /// Set the level of the line
+    pub fn set_level(&self, level: bool) {
+        self.router.set_level(self.irq, self.source, level);
+    }
+
+    /// Assert the line
+    pub fn raise(&self) {
+        self.set_level(true);
+    }
+
+    /// Deassert the line
+    pub fn lower(&self) {
+        self.set_level(false);
+    }
+
+    /// Generate an edge triggered interrupt on the line
+    pub fn pulse(&self) {
+        self.raise();
+        self.lower();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shared_line_levels() {
+        let mut levels = LineLevels::default();
+        let com1 = levels.add_source(4);
+        let com3 = levels.add_source(4);
+        assert_eq!((com1, com3), (0, 1));
+
+        assert!(levels.set(4, com1, true));
+        assert!(levels.set(4, com3, true));
+        assert!(levels.set(4, com1, false));
+        assert!(!levels.set(4, com3, false));
+        assert!(!levels.set(3, 0, false));
+    }
+
+    #[test]
+    fn test_msi_decode() {
+        let msi = MsiMessage::decode(0xfee0_1004, 0x8031).unwrap();
+        assert_eq!(
+            msi,
+            MsiMessage {
+                dest: 1,
+                logical: true,
+                lowest_priority: false,
+                message: InterruptMessage::Fixed {
+                    vector: 0x31,
+                    level_triggered: true,
+                },
+            }
+        );
+
+        let msi = MsiMessage::decode(0xfee0_0000, 0x0400).unwrap();
+        assert_eq!(msi.message, InterruptMessage::Nmi);
+
+        // SMIs and writes outside the local APIC range are not supported
+        assert_eq!(MsiMessage::decode(0xfee0_0000, 0x0200), None);
+        assert_eq!(MsiMessage::decode(0xfec0_0000, 0x0031), None);
+    }
+}
@@ -3,18 +3,25 @@ use crate::device::{
 };
 use crate::error::Result;
 use crate::memory::GuestAddressSpaceViewMut;
+use crate::vm::VmLifecycle;
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::convert::TryFrom;
 
-#[derive(Default, Debug)]
-pub struct Keyboard8042;
+pub struct Keyboard8042 {
+    // The controller can reset the VM by pulsing the CPU reset line
+    lifecycle: Arc<VmLifecycle>,
+}
 
 impl Keyboard8042 {
     const PS2_DATA: Port = 0x0060;
     const PS2_STATUS: Port = 0x0064;
 
-    pub fn new() -> Box<Self> {
-        Box::new(Self::default())
+    pub fn new(lifecycle: Arc<VmLifecycle>) -> Box<Self> {
+        Box::new(Self {
+            lifecycle: lifecycle,
+        })
     }
 }
 
@@ -39,10 +46,19 @@ impl EmulatedDevice for Keyboard8042 {
 
     fn on_port_write(
         &mut self,
-        _port: Port,
-        _val: PortWriteRequest,
+        port: Port,
+        val: PortWriteRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
+        // Commands 0xf0-0xff pulse the output port lines with a clear bit
+        // in the command, and line 0 is the CPU reset (e.g., 0xfe).
+        if port == Self::PS2_STATUS {
+            let cmd = u8::try_from(val)?;
+            if cmd & 0xf0 == 0xf0 && cmd & 0x01 == 0 {
+                info!("Reset requested through the keyboard controller");
+                self.lifecycle.reset();
+            }
+        }
         Ok(())
     }
 }
@@ -1,58 +1,854 @@
-use crate::device::{
-    DeviceRegion, EmulatedDevice, MemReadRequest, MemWriteRequest,
-};
-use crate::error::Result;
-use crate::memory::{GuestAddressSpaceViewMut, GuestPhysAddr};
-use alloc::boxed::Box;
+use crate::apic;
+use crate::device::ioapic::IoApic;
+use crate::device::{MemReadRequest, MemWriteRequest};
+use crate::error::{Error, Result};
+use crate::memory::GuestPhysAddr;
+use crate::memory_layout::LOCAL_APIC_BASE;
+use crate::time;
+use crate::vcpu::{InterruptMessage, VCpuMailbox};
+use crate::vtime::GuestClock;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
+use core::convert::TryInto;
+use spin::Mutex;
 
-#[derive(Default)]
-pub struct LocalApic;
+const APIC_BASE_BSP: u64 = 1 << 8;
+const APIC_BASE_EXTD: u64 = 1 << 10;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
 
-impl LocalApic {
-    pub fn new() -> Box<Self> {
-        Box::new(LocalApic::default())
+// Version 0x14 with 6 LVT entries
+const APIC_VERSION: u32 = 0x14 | (5 << 16);
+
+// The (virtual) APIC timer counts down at 1GHz before the divider
+const APIC_TIMER_NS_PER_TICK: u64 = 1;
+
+/// The IA32_APIC_BASE MSR
+pub const MSR_IA32_APIC_BASE: u32 = 0x1b;
+
+/// The IA32_TSC_DEADLINE MSR
+pub const MSR_IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+const MSR_X2APIC_FIRST: u32 = 0x800;
+const MSR_X2APIC_LAST: u32 = 0x8ff;
+
+mod reg {
+    pub const ID: u16 = 0x20;
+    pub const VERSION: u16 = 0x30;
+    pub const TPR: u16 = 0x80;
+    pub const APR: u16 = 0x90;
+    pub const PPR: u16 = 0xa0;
+    pub const EOI: u16 = 0xb0;
+    pub const LDR: u16 = 0xd0;
+    pub const DFR: u16 = 0xe0;
+    pub const SVR: u16 = 0xf0;
+    pub const ISR_BASE: u16 = 0x100;
+    pub const TMR_BASE: u16 = 0x180;
+    pub const IRR_BASE: u16 = 0x200;
+    pub const ESR: u16 = 0x280;
+    pub const ICR_LOW: u16 = 0x300;
+    pub const ICR_HIGH: u16 = 0x310;
+    pub const LVT_TIMER: u16 = 0x320;
+    pub const LVT_ERROR: u16 = 0x370;
+    pub const TIMER_INITIAL_COUNT: u16 = 0x380;
+    pub const TIMER_CURRENT_COUNT: u16 = 0x390;
+    pub const TIMER_DIVIDE_CONFIG: u16 = 0x3e0;
+    pub const SELF_IPI: u16 = 0x3f0;
+}
+
+const LVT_TIMER: usize = 0;
+const LVT_LINT0: usize = 3;
+const LVT_COUNT: usize = 6;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_MODE_MASK: u32 = 0b11 << 17;
+const LVT_TIMER_PERIODIC: u32 = 0b01 << 17;
+const LVT_TIMER_TSC_DEADLINE: u32 = 0b10 << 17;
+const LVT_DELIVERY_MODE_EXTINT: u32 = 0b111 << 8;
+
+const SVR_APIC_ENABLED: u32 = 1 << 8;
+const SVR_SUPPRESS_EOI_BROADCAST: u32 = 1 << 12;
+
+// The writable bits of each LVT entry (timer, thermal, perf, lint0,
+// lint1 and error)
+const LVT_WRITE_MASKS: [u32; LVT_COUNT] =
+    [0x700ff, 0x107ff, 0x107ff, 0x1a7ff, 0x1a7ff, 0x100ff];
+
+/// Returns true if the given MSR is emulated by the virtual local APIC
+pub fn is_local_apic_msr(msr: u32) -> bool {
+    msr == MSR_IA32_APIC_BASE
+        || msr == MSR_IA32_TSC_DEADLINE
+        || (MSR_X2APIC_FIRST..=MSR_X2APIC_LAST).contains(&msr)
+}
+
+/// The logical destination configuration of a local APIC
+///
+/// This is published through the `VCpu`s mailbox, so that the sources of
+/// interrupts with logical destinations (e.g., the IOAPIC) can determine
+/// which vcpus are targeted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LogicalDestination {
+    x2apic: bool,
+    ldr: u32,
+    dfr: u32,
+}
+
+impl Default for LogicalDestination {
+    fn default() -> Self {
+        Self {
+            x2apic: false,
+            ldr: 0,
+            dfr: 0xffffffff,
+        }
     }
 }
 
-impl EmulatedDevice for LocalApic {
-    fn services(&self) -> Vec<DeviceRegion> {
-        vec![
-            DeviceRegion::MemIo(
-                GuestPhysAddr::new(0xfee00000)..=GuestPhysAddr::new(0xfee010f0),
-            ),
-            //FIXME: this is actually the 1st HPET
-            DeviceRegion::MemIo(
-                GuestPhysAddr::new(0xfed00000)..=GuestPhysAddr::new(0xfed010f0),
-            ),
-            //FIXME: this is actually the io apic
-            DeviceRegion::MemIo(
-                GuestPhysAddr::new(0xfec00000)..=GuestPhysAddr::new(0xfec010f0),
-            ),
-        ]
+impl LogicalDestination {
+    /// Returns true if the given logical destination selects this APIC
+    pub fn matches(&self, dest: u32) -> bool {
+        if self.x2apic {
+            // Cluster in the high 16 bits, and a bitmap in the low bits
+            return dest >> 16 == self.ldr >> 16
+                && dest & self.ldr & 0xffff != 0;
+        }
+
+        let dest = dest & 0xff;
+        let ldr = self.ldr >> 24;
+        match self.dfr >> 28 {
+            // Flat model
+            0xf => dest & ldr != 0,
+
+            // Cluster model
+            _ => dest >> 4 == ldr >> 4 && dest & ldr & 0xf != 0,
+        }
+    }
+}
+
+/// The virtual local APIC of a `VCpu`
+///
+/// Unlike most emulated devices, each `VCpu` owns its local APIC, so all
+/// accesses occur on the core that runs the vcpu. Interrupts from other
+/// sources are received via the vcpu's `VCpuMailbox`.
+pub struct LocalApic {
+    id: u32,
+    apic_base: u64,
+    tpr: u32,
+    ldr: u32,
+    dfr: u32,
+    svr: u32,
+    esr: u32,
+    icr: u64,
+    isr: [u32; 8],
+    tmr: [u32; 8],
+    irr: [u32; 8],
+    lvt: [u32; LVT_COUNT],
+
+    timer_initial_count: u32,
+    timer_divide_config: u32,
+
+    // The guest time (in nanoseconds) when the timer was started
+    timer_start: Option<u64>,
+    tsc_deadline: u64,
+    timer: Option<time::TimerId>,
+
+    // The host APIC id of each vcpu in the VM (indexed by guest APIC id)
+    cpus: Vec<u8>,
+    mailboxes: Vec<Arc<VCpuMailbox>>,
+    ioapic: Arc<Mutex<IoApic>>,
+
+    // The TSC deadline and the timer count are in terms of the guest time,
+    // so the timer does not count down while the VM is paused
+    clock: Arc<GuestClock>,
+}
+
+impl LocalApic {
+    /// Create a new `LocalApic` for the vcpu with the given guest APIC id
+    pub fn new(
+        id: u32,
+        cpus: Vec<u8>,
+        mailboxes: Vec<Arc<VCpuMailbox>>,
+        ioapic: Arc<Mutex<IoApic>>,
+        clock: Arc<GuestClock>,
+    ) -> Self {
+        let mut apic_base = LOCAL_APIC_BASE | APIC_BASE_ENABLE;
+        if id == 0 {
+            apic_base |= APIC_BASE_BSP;
+        }
+
+        let mut lapic = Self {
+            id: id,
+            apic_base: apic_base,
+            tpr: 0,
+            ldr: 0,
+            dfr: 0xffffffff,
+            svr: 0xff,
+            esr: 0,
+            icr: 0,
+            isr: [0; 8],
+            tmr: [0; 8],
+            irr: [0; 8],
+            lvt: [LVT_MASKED; LVT_COUNT],
+            timer_initial_count: 0,
+            timer_divide_config: 0,
+            timer_start: None,
+            tsc_deadline: 0,
+            timer: None,
+            cpus: cpus,
+            mailboxes: mailboxes,
+            ioapic: ioapic,
+            clock: clock,
+        };
+        lapic.reset();
+        lapic
+    }
+
+    /// Return the local APIC to its state following INIT
+    ///
+    /// Note that the APIC id and IA32_APIC_BASE are not affected.
+    pub fn reset(&mut self) {
+        self.stop_timer();
+        self.tpr = 0;
+        self.ldr = 0;
+        self.dfr = 0xffffffff;
+        self.svr = 0xff;
+        self.esr = 0;
+        self.icr = 0;
+        self.isr = [0; 8];
+        self.tmr = [0; 8];
+        self.irr = [0; 8];
+        self.lvt = [LVT_MASKED; LVT_COUNT];
+        self.timer_initial_count = 0;
+        self.timer_divide_config = 0;
+        self.tsc_deadline = 0;
+
+        // Like QEMU, LINT0 of the BSP starts in ExtINT mode so interrupts
+        // from the PIC are delivered even if the BIOS doesn't set this up.
+        if self.is_bsp() {
+            self.lvt[LVT_LINT0] = LVT_DELIVERY_MODE_EXTINT;
+        }
+        self.update_logical_destination();
+    }
+
+    fn is_bsp(&self) -> bool {
+        self.apic_base & APIC_BASE_BSP != 0
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.apic_base & APIC_BASE_ENABLE != 0
+    }
+
+    fn is_software_enabled(&self) -> bool {
+        self.is_enabled() && self.svr & SVR_APIC_ENABLED != 0
+    }
+
+    fn is_x2apic(&self) -> bool {
+        self.is_enabled() && self.apic_base & APIC_BASE_EXTD != 0
+    }
+
+    /// Returns true if the given address is in the xAPIC MMIO region
+    pub fn contains(&self, addr: GuestPhysAddr) -> bool {
+        addr.as_u64() & !0xfff == self.apic_base & !0xfff
+    }
+
+    /// Returns true if interrupts from the PIC are accepted via LINT0
+    pub fn accepts_pic_interrupts(&self) -> bool {
+        let lint0 = self.lvt[LVT_LINT0];
+        !self.is_enabled()
+            || (lint0 & LVT_MASKED == 0
+                && lint0 & LVT_DELIVERY_MODE_EXTINT == LVT_DELIVERY_MODE_EXTINT)
+    }
+
+    /// The value of CR8 (the task priority class)
+    pub fn cr8(&self) -> u64 {
+        (self.tpr >> 4) as u64
+    }
+
+    /// Set the task priority via CR8
+    pub fn set_cr8(&mut self, val: u64) {
+        self.tpr = ((val & 0xf) << 4) as u32;
+    }
+
+    /// Accept a maskable interrupt (i.e., set the corresponding IRR bit)
+    pub fn accept_interrupt(&mut self, vector: u8, level_triggered: bool) {
+        // Vectors 0-15 are reserved
+        if vector < 16 {
+            self.esr |= 1 << 6;
+            return;
+        }
+
+        set_bit(&mut self.irr, vector);
+        if level_triggered {
+            set_bit(&mut self.tmr, vector);
+        } else {
+            clear_bit(&mut self.tmr, vector);
+        }
+    }
+
+    fn ppr(&self) -> u32 {
+        let isrv = highest_bit(&self.isr).unwrap_or(0) as u32;
+        if self.tpr & 0xf0 >= isrv & 0xf0 {
+            self.tpr & 0xff
+        } else {
+            isrv & 0xf0
+        }
+    }
+
+    /// Returns the highest priority interrupt that can be delivered to the
+    /// processor (if any)
+    pub fn pending_vector(&self) -> Option<u8> {
+        let vector = highest_bit(&self.irr)?;
+        if vector as u32 & 0xf0 > self.ppr() & 0xf0 {
+            Some(vector)
+        } else {
+            None
+        }
+    }
+
+    /// Acknowledge the highest priority pending interrupt and return its
+    /// vector (moving it from the IRR to the ISR)
+    pub fn acknowledge(&mut self) -> Option<u8> {
+        let vector = self.pending_vector()?;
+        clear_bit(&mut self.irr, vector);
+        set_bit(&mut self.isr, vector);
+        Some(vector)
+    }
+
+    fn end_of_interrupt(&mut self) {
+        let vector = match highest_bit(&self.isr) {
+            Some(vector) => vector,
+            None => return,
+        };
+        clear_bit(&mut self.isr, vector);
+
+        if test_bit(&self.tmr, vector)
+            && self.svr & SVR_SUPPRESS_EOI_BROADCAST == 0
+        {
+            self.ioapic.lock().end_of_interrupt(vector);
+        }
+    }
+
+    fn update_logical_destination(&mut self) {
+        let dest = LogicalDestination {
+            x2apic: self.is_x2apic(),
+            ldr: self.ldr,
+            dfr: self.dfr,
+        };
+        if let Some(mailbox) = self.mailboxes.get(self.id as usize) {
+            mailbox.set_logical_destination(dest);
+        }
+    }
+
+    /// Returns the guest APIC ids selected by the given ICR destination
+    fn ipi_destinations(&self, icr: u64) -> Vec<usize> {
+        let all = 0..self.mailboxes.len();
+        let (dest, broadcast) = if self.is_x2apic() {
+            ((icr >> 32) as u32, 0xffffffff)
+        } else {
+            ((icr >> 56) as u32, 0xff)
+        };
+
+        match (icr >> 18) & 0b11 {
+            0b00 if icr & (1 << 11) == 0 => all
+                .filter(|id| dest == broadcast || *id as u32 == dest)
+                .collect(),
+            0b00 => all
+                .filter(|id| {
+                    self.mailboxes[*id].logical_destination().matches(dest)
+                })
+                .collect(),
+            0b01 => vec![self.id as usize],
+            0b10 => all.collect(),
+            _ => all.filter(|id| *id as u32 != self.id).collect(),
+        }
     }
 
-    fn on_mem_read(
+    fn send_ipi(&mut self, icr: u64) {
+        let vector = icr as u8;
+        let targets = self.ipi_destinations(icr);
+
+        let message = match (icr >> 8) & 0b111 {
+            0b000 | 0b001 => InterruptMessage::Fixed {
+                vector: vector,
+                level_triggered: false,
+            },
+            0b100 => InterruptMessage::Nmi,
+            mode @ 0b101 | mode @ 0b110 => {
+                // An INIT level de-assert has no effect
+                if mode == 0b101 && icr & (1 << 14) == 0 {
+                    return;
+                }
+
+                // INIT and SIPI are delivered as physical IPIs, so the
+                // target vcpus will see the corresponding `InitSignal` or
+                // `StartUpIpi` exit.
+                let delivery_mode = if mode == 0b101 {
+                    apic::DeliveryMode::Init
+                } else {
+                    apic::DeliveryMode::StartUp
+                };
+                for target in targets {
+                    unsafe { apic::get_local_apic_mut() }.send_ipi(
+                        self.cpus[target] as u32,
+                        apic::DstShorthand::NoShorthand,
+                        apic::TriggerMode::Edge,
+                        apic::Level::Assert,
+                        apic::DstMode::Physical,
+                        delivery_mode,
+                        vector,
+                    );
+                }
+                return;
+            }
+            mode => {
+                info!("Unsupported guest IPI delivery mode: 0x{:x}", mode);
+                return;
+            }
+        };
+
+        for target in targets {
+            if target == self.id as usize {
+                match message {
+                    InterruptMessage::Fixed { vector, .. } => {
+                        self.accept_interrupt(vector, false)
+                    }
+                    _ => self.mailboxes[target].post(message),
+                }
+            } else {
+                self.mailboxes[target].post(message);
+            }
+        }
+    }
+
+    fn timer_divisor(&self) -> u64 {
+        let val = (self.timer_divide_config & 0b11)
+            | ((self.timer_divide_config & 0b1000) >> 1);
+        1 << ((val + 1) & 0b111)
+    }
+
+    fn timer_period(&self) -> core::time::Duration {
+        core::time::Duration::from_nanos(
+            self.timer_initial_count as u64
+                * self.timer_divisor()
+                * APIC_TIMER_NS_PER_TICK,
+        )
+    }
+
+    fn stop_timer(&mut self) {
+        if let Some(timer) = self.timer.take() {
+            // The timer may have already expired, so ignore any error
+            let _ = time::cancel_timer(&timer);
+        }
+        self.timer_start = None;
+    }
+
+    fn start_timer(&mut self) {
+        self.stop_timer();
+
+        match self.lvt[LVT_TIMER] & LVT_TIMER_MODE_MASK {
+            LVT_TIMER_TSC_DEADLINE => {
+                if self.tsc_deadline == 0 {
+                    return;
+                }
+                let ns = self.clock.ns_until_tsc(self.tsc_deadline);
+                self.timer = Some(time::set_oneshot_timer(
+                    core::time::Duration::from_nanos(ns),
+                    time::TimerInterruptType::LocalApicTimer,
+                ));
+            }
+            mode => {
+                if self.timer_initial_count == 0 {
+                    return;
+                }
+                let period = self.timer_period();
+                self.timer_start = Some(self.clock.now_ns());
+                self.timer = Some(if mode == LVT_TIMER_PERIODIC {
+                    time::set_periodic_timer(
+                        period,
+                        time::TimerInterruptType::LocalApicTimer,
+                    )
+                } else {
+                    time::set_oneshot_timer(
+                        period,
+                        time::TimerInterruptType::LocalApicTimer,
+                    )
+                });
+            }
+        }
+    }
+
+    /// Handle the expiration of the APIC timer
+    pub fn timer_expired(&mut self) {
+        match self.lvt[LVT_TIMER] & LVT_TIMER_MODE_MASK {
+            LVT_TIMER_PERIODIC => (),
+            LVT_TIMER_TSC_DEADLINE => {
+                self.timer = None;
+                self.tsc_deadline = 0;
+            }
+            _ => {
+                self.timer = None;
+                self.timer_start = None;
+            }
+        }
+
+        let lvt = self.lvt[LVT_TIMER];
+        if lvt & LVT_MASKED == 0 {
+            self.accept_interrupt(lvt as u8, false);
+        }
+    }
+
+    fn timer_current_count(&self) -> u32 {
+        let start = match self.timer_start {
+            Some(start) => start,
+            None => return 0,
+        };
+        let ticks_per_count = self.timer_divisor() * APIC_TIMER_NS_PER_TICK;
+        let mut elapsed =
+            self.clock.now_ns().saturating_sub(start) / ticks_per_count;
+
+        if self.lvt[LVT_TIMER] & LVT_TIMER_MODE_MASK == LVT_TIMER_PERIODIC {
+            elapsed %= self.timer_initial_count as u64;
+        }
+        (self.timer_initial_count as u64).saturating_sub(elapsed) as u32
+    }
+
+    fn read_register(&self, offset: u16) -> Result<u32> {
+        Ok(match offset {
+            reg::ID if self.is_x2apic() => self.id,
+            reg::ID => self.id << 24,
+            reg::VERSION => APIC_VERSION,
+            reg::TPR => self.tpr,
+            reg::APR => 0,
+            reg::PPR => self.ppr(),
+            reg::LDR => self.ldr,
+            reg::DFR if !self.is_x2apic() => self.dfr,
+            reg::SVR => self.svr,
+            reg::ISR_BASE..=0x170 => {
+                self.isr[(offset - reg::ISR_BASE) as usize >> 4]
+            }
+            reg::TMR_BASE..=0x1f0 => {
+                self.tmr[(offset - reg::TMR_BASE) as usize >> 4]
+            }
+            reg::IRR_BASE..=0x270 => {
+                self.irr[(offset - reg::IRR_BASE) as usize >> 4]
+            }
+            reg::ESR => self.esr,
+            reg::ICR_LOW => self.icr as u32,
+            reg::ICR_HIGH if !self.is_x2apic() => (self.icr >> 32) as u32,
+            reg::LVT_TIMER..=reg::LVT_ERROR if offset & 0xf == 0 => {
+                self.lvt[(offset - reg::LVT_TIMER) as usize >> 4]
+            }
+            reg::TIMER_INITIAL_COUNT => self.timer_initial_count,
+            reg::TIMER_CURRENT_COUNT => self.timer_current_count(),
+            reg::TIMER_DIVIDE_CONFIG => self.timer_divide_config,
+            _ => {
+                return Err(Error::InvalidValue(format!(
+                    "Invalid local APIC read of offset 0x{:x}",
+                    offset
+                )))
+            }
+        })
+    }
+
+    fn write_register(&mut self, offset: u16, val: u32) -> Result<()> {
+        match offset {
+            reg::ID if !self.is_x2apic() => {
+                //TODO: support changing the APIC id
+                if val >> 24 != self.id {
+                    warn!("Ignoring write to local APIC id: 0x{:x}", val);
+                }
+            }
+            reg::TPR => self.tpr = val & 0xff,
+            reg::EOI => self.end_of_interrupt(),
+            reg::LDR if !self.is_x2apic() => {
+                self.ldr = val & 0xff000000;
+                self.update_logical_destination();
+            }
+            reg::DFR if !self.is_x2apic() => {
+                self.dfr = val | 0x0fffffff;
+                self.update_logical_destination();
+            }
+            reg::SVR => {
+                self.svr = val & 0x11ff;
+                if self.svr & SVR_APIC_ENABLED == 0 {
+                    for lvt in self.lvt.iter_mut() {
+                        *lvt |= LVT_MASKED;
+                    }
+                }
+            }
+            reg::ESR => self.esr = 0,
+            reg::ICR_LOW => {
+                self.icr = (self.icr & !0xffffffff) | val as u64;
+                self.send_ipi(self.icr);
+            }
+            reg::ICR_HIGH if !self.is_x2apic() => {
+                self.icr = (self.icr & 0xffffffff) | ((val as u64) << 32);
+            }
+            reg::LVT_TIMER..=reg::LVT_ERROR if offset & 0xf == 0 => {
+                let index = (offset - reg::LVT_TIMER) as usize >> 4;
+                let mut val = val & LVT_WRITE_MASKS[index];
+                if !self.is_software_enabled() {
+                    val |= LVT_MASKED;
+                }
+
+                let old = self.lvt[index];
+                self.lvt[index] = val;
+
+                // Changing the timer mode stops the timer
+                if index == LVT_TIMER && (old ^ val) & LVT_TIMER_MODE_MASK != 0
+                {
+                    self.stop_timer();
+                    self.timer_initial_count = 0;
+                    self.tsc_deadline = 0;
+                }
+            }
+            reg::TIMER_INITIAL_COUNT => {
+                // The initial count is ignored in TSC-deadline mode
+                if self.lvt[LVT_TIMER] & LVT_TIMER_MODE_MASK
+                    != LVT_TIMER_TSC_DEADLINE
+                {
+                    self.timer_initial_count = val;
+                    self.start_timer();
+                }
+            }
+            reg::TIMER_DIVIDE_CONFIG => self.timer_divide_config = val & 0b1011,
+            reg::SELF_IPI if self.is_x2apic() => {
+                self.accept_interrupt(val as u8, false)
+            }
+            reg::ID
+            | reg::VERSION
+            | reg::APR
+            | reg::PPR
+            | reg::ISR_BASE..=0x270
+            | reg::TIMER_CURRENT_COUNT => {
+                // Read only registers
+            }
+            _ => {
+                return Err(Error::InvalidValue(format!(
+                    "Invalid local APIC write of offset 0x{:x}",
+                    offset
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a read from the xAPIC MMIO region
+    pub fn on_mem_read(
         &mut self,
         addr: GuestPhysAddr,
-        data: MemReadRequest,
-        _space: GuestAddressSpaceViewMut,
+        mut data: MemReadRequest,
     ) -> Result<()> {
-        info!(
-            "local apic read of addr = {:?} (len=0x{:x})",
-            addr,
-            data.as_slice().len()
-        );
+        // In x2APIC mode, the MMIO interface is disabled
+        if self.is_x2apic() {
+            return Ok(());
+        }
+
+        let offset = (addr.as_u64() & 0xfff) as u16;
+        let val = match self.read_register(offset & !0xf) {
+            Ok(val) => val,
+            Err(e) => {
+                info!("{:?}", e);
+                0
+            }
+        };
+
+        // Registers are 16 byte aligned, but accesses may be smaller
+        let bytes = (val >> ((offset & 0x3) * 8)).to_be_bytes();
+        let len = data.as_slice().len().min(bytes.len());
+        data.as_mut_slice()[..len].copy_from_slice(&bytes[bytes.len() - len..]);
         Ok(())
     }
 
-    fn on_mem_write(
+    /// Handle a write to the xAPIC MMIO region
+    pub fn on_mem_write(
         &mut self,
         addr: GuestPhysAddr,
         data: MemWriteRequest,
-        _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
-        info!("local apic write of addr = {:?} (data={:?})", addr, data);
+        if self.is_x2apic() {
+            return Ok(());
+        }
+
+        let offset = (addr.as_u64() & 0xfff) as u16;
+        let val: u32 = data.try_into()?;
+        if let Err(e) = self.write_register(offset, val) {
+            info!("{:?}", e);
+        }
         Ok(())
     }
+
+    /// Read one of the local APIC MSRs (see `is_local_apic_msr`)
+    pub fn read_msr(&mut self, msr: u32) -> Result<u64> {
+        match msr {
+            MSR_IA32_APIC_BASE => Ok(self.apic_base),
+            MSR_IA32_TSC_DEADLINE => Ok(self.tsc_deadline),
+            _ if !self.is_x2apic() => Err(Error::InvalidValue(format!(
+                "Read of x2APIC MSR 0x{:x} in xAPIC mode",
+                msr
+            ))),
+            _ => {
+                let offset = ((msr - MSR_X2APIC_FIRST) << 4) as u16;
+                match offset {
+                    reg::ICR_LOW => Ok(self.icr),
+                    reg::EOI | reg::SELF_IPI => Err(Error::InvalidValue(
+                        format!("Read of write-only x2APIC MSR 0x{:x}", msr),
+                    )),
+                    _ => self.read_register(offset).map(|val| val as u64),
+                }
+            }
+        }
+    }
+
+    /// Write one of the local APIC MSRs (see `is_local_apic_msr`)
+    pub fn write_msr(&mut self, msr: u32, val: u64) -> Result<()> {
+        match msr {
+            MSR_IA32_APIC_BASE => {
+                let was_x2apic = self.is_x2apic();
+
+                //TODO: support relocating the local APIC
+                self.apic_base = (val & (APIC_BASE_EXTD | APIC_BASE_ENABLE))
+                    | (self.apic_base & !(APIC_BASE_EXTD | APIC_BASE_ENABLE));
+
+                if self.is_x2apic() && !was_x2apic {
+                    // The logical id is fixed in x2APIC mode
+                    self.ldr = ((self.id >> 4) << 16) | (1 << (self.id & 0xf));
+                }
+                self.update_logical_destination();
+                Ok(())
+            }
+            MSR_IA32_TSC_DEADLINE => {
+                self.tsc_deadline = val;
+                if self.lvt[LVT_TIMER] & LVT_TIMER_MODE_MASK
+                    == LVT_TIMER_TSC_DEADLINE
+                {
+                    self.start_timer();
+                }
+                Ok(())
+            }
+            _ if !self.is_x2apic() => Err(Error::InvalidValue(format!(
+                "Write of x2APIC MSR 0x{:x} in xAPIC mode",
+                msr
+            ))),
+            _ => {
+                let offset = ((msr - MSR_X2APIC_FIRST) << 4) as u16;
+                match offset {
+                    reg::ICR_LOW => {
+                        self.icr = val;
+                        self.send_ipi(val);
+                        Ok(())
+                    }
+                    reg::LDR => Ok(()),
+                    _ => self.write_register(offset, val as u32),
+                }
+            }
+        }
+    }
+}
+
+fn set_bit(reg: &mut [u32; 8], vector: u8) {
+    reg[vector as usize / 32] |= 1 << (vector % 32);
+}
+
+fn clear_bit(reg: &mut [u32; 8], vector: u8) {
+    reg[vector as usize / 32] &= !(1 << (vector % 32));
+}
+
+fn test_bit(reg: &[u32; 8], vector: u8) -> bool {
+    reg[vector as usize / 32] & (1 << (vector % 32)) != 0
+}
+
+fn highest_bit(reg: &[u32; 8]) -> Option<u8> {
+    reg.iter()
+        .enumerate()
+        .rev()
+        .find(|(_, bits)| **bits != 0)
+        .map(|(i, bits)| (i * 32 + 31 - bits.leading_zeros() as usize) as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn local_apic() -> LocalApic {
+        let ioapic = Arc::new(Mutex::new(IoApic::new(vec![])));
+        LocalApic::new(0, vec![0], vec![], ioapic, Arc::new(GuestClock::new()))
+    }
+
+    #[test]
+    fn test_lapic_priority() {
+        let mut lapic = local_apic();
+        lapic.accept_interrupt(0x31, false);
+        lapic.accept_interrupt(0x51, false);
+
+        assert_eq!(lapic.acknowledge(), Some(0x51));
+
+        // 0x31 has a lower priority than the in service interrupt
+        assert_eq!(lapic.pending_vector(), None);
+        assert_eq!(lapic.ppr(), 0x50);
+
+        lapic.write_register(reg::EOI, 0).unwrap();
+        assert_eq!(lapic.pending_vector(), Some(0x31));
+
+        // The TPR also blocks lower priority interrupts
+        lapic.write_register(reg::TPR, 0x40).unwrap();
+        assert_eq!(lapic.pending_vector(), None);
+    }
+
+    #[test]
+    fn test_lapic_reserved_vector() {
+        let mut lapic = local_apic();
+        lapic.accept_interrupt(0x0e, false);
+        assert_eq!(lapic.pending_vector(), None);
+        assert_ne!(lapic.read_register(reg::ESR).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_lapic_lvt_masked_while_disabled() {
+        let mut lapic = local_apic();
+        lapic.write_register(reg::LVT_TIMER, 0x20).unwrap();
+        assert_eq!(lapic.read_register(reg::LVT_TIMER).unwrap(), 0x10020);
+
+        lapic.write_register(reg::SVR, 0x1ff).unwrap();
+        lapic.write_register(reg::LVT_TIMER, 0x20).unwrap();
+        assert_eq!(lapic.read_register(reg::LVT_TIMER).unwrap(), 0x20);
+    }
+
+    #[test]
+    fn test_lapic_timer_divisor() {
+        let mut lapic = local_apic();
+        for (config, divisor) in
+            [(0b0000, 2), (0b0011, 16), (0b1000, 32), (0b1011, 1)].iter()
+        {
+            lapic
+                .write_register(reg::TIMER_DIVIDE_CONFIG, *config)
+                .unwrap();
+            assert_eq!(lapic.timer_divisor(), *divisor);
+        }
+    }
+
+    #[test]
+    fn test_logical_destination() {
+        let flat = LogicalDestination {
+            x2apic: false,
+            ldr: 0x02000000,
+            dfr: 0xffffffff,
+        };
+        assert!(flat.matches(0x03));
+        assert!(!flat.matches(0x01));
+
+        let cluster = LogicalDestination {
+            x2apic: false,
+            ldr: 0x21000000,
+            dfr: 0x0fffffff,
+        };
+        assert!(cluster.matches(0x23));
+        assert!(!cluster.matches(0x13));
+
+        let x2apic = LogicalDestination {
+            x2apic: true,
+            ldr: 0x00010004,
+            dfr: 0,
+        };
+        assert!(x2apic.matches(0x0001000c));
+        assert!(!x2apic.matches(0x0000000c));
+    }
 }
@@ -3,17 +3,22 @@ use crate::memory::{GuestAddressSpaceViewMut, GuestPhysAddr};
 use alloc::boxed::Box;
 use alloc::collections::btree_map::BTreeMap;
 use alloc::rc::Rc;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::cmp::Ordering;
 use core::convert::{TryFrom, TryInto};
 use core::fmt;
 use core::ops::RangeInclusive;
+use spin::Mutex;
 
 pub mod acpi;
 pub mod com;
 pub mod debug;
 pub mod dma;
+pub mod hpet;
 pub mod ignore;
+pub mod ioapic;
+pub mod irq;
 pub mod keyboard;
 pub mod lapic;
 pub mod pci;
@@ -138,6 +143,38 @@ impl DeviceMap {
         op.find_device_mut(self)
     }
 
+    /// The devices in the map (each device appears once)
+    pub fn devices(&self) -> Vec<&Box<dyn EmulatedDevice>> {
+        let mut devices: Vec<&Box<dyn EmulatedDevice>> = vec![];
+        for dev in self.portio_map.values().chain(self.memio_map.values()) {
+            if !devices.iter().any(|d| core::ptr::eq(*d, &**dev)) {
+                devices.push(&**dev);
+            }
+        }
+        devices
+    }
+
+    /// Reset every device in the map
+    pub fn reset_devices(&mut self) -> Result<()> {
+        // Devices are in the map once per region they service
+        let mut visited: Vec<*const Box<dyn EmulatedDevice>> = vec![];
+        for dev in self
+            .portio_map
+            .values_mut()
+            .chain(self.memio_map.values_mut())
+        {
+            let ptr = Rc::as_ptr(dev);
+            if visited.contains(&ptr) {
+                continue;
+            }
+            visited.push(ptr);
+
+            //NOTE: This is safe for the same reason as `find_device_mut`
+            unsafe { Rc::get_mut_unchecked(dev) }.reset()?;
+        }
+        Ok(())
+    }
+
     pub fn register_device(
         &mut self,
         dev: Box<dyn EmulatedDevice>,
@@ -186,6 +223,11 @@ impl DeviceMap {
 pub trait EmulatedDevice {
     fn services(&self) -> Vec<DeviceRegion>;
 
+    /// Return the device to its power-on state (as part of a VM reset)
+    fn reset(&mut self) -> Result<()> {
+        Ok(())
+    }
+
     fn on_mem_read(
         &mut self,
         _addr: GuestPhysAddr,
@@ -228,6 +270,54 @@ pub trait EmulatedDevice {
     }
 }
 
+// Allows a device to be shared with other parts of the VM (e.g., a PIC
+// that is also driven by `IrqLine`s) while still being in the `DeviceMap`
+impl<T: EmulatedDevice> EmulatedDevice for Arc<Mutex<T>> {
+    fn services(&self) -> Vec<DeviceRegion> {
+        self.lock().services()
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        self.lock().reset()
+    }
+
+    fn on_mem_read(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemReadRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.lock().on_mem_read(addr, data, space)
+    }
+
+    fn on_mem_write(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemWriteRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.lock().on_mem_write(addr, data, space)
+    }
+
+    fn on_port_read(
+        &mut self,
+        port: Port,
+        val: PortReadRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.lock().on_port_read(port, val, space)
+    }
+
+    fn on_port_write(
+        &mut self,
+        port: Port,
+        val: PortWriteRequest,
+        space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        self.lock().on_port_write(port, val, space)
+    }
+}
+
 #[derive(Debug)]
 pub enum PortReadRequest<'a> {
     OneByte(&'a mut [u8; 1]),
@@ -456,6 +546,40 @@ impl<'a> TryInto<u8> for MemWriteRequest<'a> {
     }
 }
 
+impl<'a> TryInto<u32> for MemWriteRequest<'a> {
+    type Error = Error;
+
+    fn try_into(self) -> Result<u32> {
+        if self.data.len() == 4 {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(self.data);
+            Ok(u32::from_be_bytes(bytes))
+        } else {
+            Err(Error::InvalidValue(format!(
+                "Value {} cannot be converted to u32",
+                self
+            )))
+        }
+    }
+}
+
+impl<'a> TryInto<u64> for MemWriteRequest<'a> {
+    type Error = Error;
+
+    fn try_into(self) -> Result<u64> {
+        if self.data.len() == 8 {
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(self.data);
+            Ok(u64::from_be_bytes(bytes))
+        } else {
+            Err(Error::InvalidValue(format!(
+                "Value {} cannot be converted to u64",
+                self
+            )))
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct MemReadRequest<'a> {
     data: &'a mut [u8],
@@ -469,6 +593,10 @@ impl<'a> MemReadRequest<'a> {
     pub fn as_slice(&self) -> &[u8] {
         self.data
     }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        self.data
+    }
 }
 
 impl<'a> fmt::Display for MemReadRequest<'a> {
@@ -482,13 +610,14 @@ mod test {
     use super::*;
     use crate::device::com::*;
     use crate::memory::{
-        GuestAddressSpace, GuestAddressSpaceViewMut, GuestPhysAddr,
+        GuestAddressSpace, GuestAddressSpaceViewMut, GuestPhysAddr, PageSize,
     };
     use core::convert::TryInto;
 
     fn define_test_view() -> GuestAddressSpaceViewMut<'static> {
-        let space: &'static mut GuestAddressSpace =
-            Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
+        let space: &'static mut GuestAddressSpace = Box::leak(Box::new(
+            GuestAddressSpace::new(PageSize::Size4K, 0).unwrap(),
+        ));
         GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space)
     }
 
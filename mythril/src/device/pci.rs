@@ -1,10 +1,14 @@
 use crate::device::{
-    DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
+    DeviceRegion, EmulatedDevice, MemReadRequest, MemWriteRequest, Port,
+    PortReadRequest, PortWriteRequest,
 };
 use crate::error::{Error, Result};
-use crate::memory::GuestAddressSpaceViewMut;
+use crate::memory::{GuestAddressSpaceViewMut, GuestPhysAddr};
+use crate::memory_layout::PCI_ECAM_SIZE;
+use crate::vm::VmLifecycle;
 use alloc::boxed::Box;
 use alloc::collections::btree_map::BTreeMap;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::convert::TryInto;
 use num_enum::TryFromPrimitive;
@@ -147,7 +151,10 @@ pub struct PciDevice {
 
 pub struct PciRootComplex {
     current_address: u32,
+    ecam_base: GuestPhysAddr,
     devices: BTreeMap<u16, PciDevice>,
+    reset_control: u8,
+    lifecycle: Arc<VmLifecycle>,
 }
 
 impl PciRootComplex {
@@ -156,7 +163,16 @@ impl PciRootComplex {
     const PCI_CONFIG_DATA: Port = 0xcfc;
     const PCI_CONFIG_DATA_MAX: Port = Self::PCI_CONFIG_DATA + 3;
 
-    pub fn new() -> Box<Self> {
+    // The ICH9 reset control register
+    const RESET_CONTROL: Port = 0xcf9;
+    const RESET_CONTROL_RST_CPU: u8 = 1 << 2;
+
+    /// Create the root complex, with the memory mapped configuration space
+    /// of bus 0 at `ecam_base`
+    pub fn new(
+        lifecycle: Arc<VmLifecycle>,
+        ecam_base: GuestPhysAddr,
+    ) -> Box<Self> {
         let mut devices = BTreeMap::new();
 
         let host_bridge = PciDevice {
@@ -185,7 +201,10 @@ impl PciRootComplex {
 
         Box::new(Self {
             current_address: 0,
+            ecam_base: ecam_base,
             devices: devices,
+            reset_control: 0,
+            lifecycle: lifecycle,
         })
     }
 }
@@ -200,8 +219,22 @@ impl EmulatedDevice for PciRootComplex {
                 Self::PCI_CONFIG_DATA..=Self::PCI_CONFIG_DATA_MAX,
             ),
             DeviceRegion::PortIo(Self::PCI_CONFIG_TYPE..=Self::PCI_CONFIG_TYPE),
+            DeviceRegion::PortIo(Self::RESET_CONTROL..=Self::RESET_CONTROL),
+            DeviceRegion::MemIo(
+                self.ecam_base
+                    ..=GuestPhysAddr::new(
+                        self.ecam_base.as_u64() + PCI_ECAM_SIZE - 1,
+                    ),
+            ),
         ]
     }
+
+    fn reset(&mut self) -> Result<()> {
+        self.current_address = 0;
+        self.reset_control = 0;
+        Ok(())
+    }
+
     fn on_port_read(
         &mut self,
         port: Port,
@@ -214,6 +247,7 @@ impl EmulatedDevice for PciRootComplex {
                 let addr = 0x80000000 | self.current_address;
                 val.copy_from_u32(addr);
             }
+            Self::RESET_CONTROL => val.copy_from_u32(self.reset_control as u32),
             Self::PCI_CONFIG_DATA..=Self::PCI_CONFIG_DATA_MAX => {
                 let bdf = ((self.current_address & 0xffff00) >> 8) as u16;
                 let register = (self.current_address & 0xff >> 2) as u8;
@@ -257,6 +291,17 @@ impl EmulatedDevice for PciRootComplex {
                 let addr: u32 = val.try_into()?;
                 self.current_address = addr & 0x7fffffffu32;
             }
+            Self::RESET_CONTROL => {
+                let val: u8 = val.try_into()?;
+
+                //FIXME: a soft reset (SYS_RST clear) should only reset the
+                //       processors, but both are treated as a full reset
+                if val & Self::RESET_CONTROL_RST_CPU != 0 {
+                    info!("Reset requested through the reset control register");
+                    self.lifecycle.reset();
+                }
+                self.reset_control = val & !Self::RESET_CONTROL_RST_CPU;
+            }
             _ => {
                 info!(
                     "Attempt to write to port=0x{:x} (addr=0x{:x}). Ignoring.",
@@ -266,18 +311,65 @@ impl EmulatedDevice for PciRootComplex {
         }
         Ok(())
     }
+
+    fn on_mem_read(
+        &mut self,
+        addr: GuestPhysAddr,
+        mut data: MemReadRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        // Each function has 4KB of configuration space, of which only the
+        // first 256 bytes are implemented
+        let offset = addr.as_u64() - self.ecam_base.as_u64();
+        let bdf = (offset >> 12) as u16;
+        let register = ((offset & 0xfff) >> 2) as u8;
+        let len = data.as_slice().len();
+        if len > 4 {
+            return Err(Error::InvalidValue(format!(
+                "Invalid PCI config space read of length {}",
+                len
+            )));
+        }
+
+        let res = match self.devices.get(&bdf) {
+            Some(device) if offset & 0xfff < 0x100 => {
+                device.config_space.read_register(register)
+                    >> ((offset & 0x3) * 8)
+            }
+            Some(_) => 0,
+            None => 0xffffffff,
+        };
+        let bytes = res.to_be_bytes();
+        data.as_mut_slice()
+            .copy_from_slice(&bytes[bytes.len() - len..]);
+        Ok(())
+    }
+
+    fn on_mem_write(
+        &mut self,
+        addr: GuestPhysAddr,
+        data: MemWriteRequest,
+        _space: GuestAddressSpaceViewMut,
+    ) -> Result<()> {
+        info!(
+            "Attempt to write to PCI config space at {:?} (val={}). Ignoring.",
+            addr, data
+        );
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::memory::{
-        GuestAddressSpace, GuestAddressSpaceViewMut, GuestPhysAddr,
+        GuestAddressSpace, GuestAddressSpaceViewMut, GuestPhysAddr, PageSize,
     };
 
     fn define_test_view() -> GuestAddressSpaceViewMut<'static> {
-        let space: &'static mut GuestAddressSpace =
-            Box::leak(Box::new(GuestAddressSpace::new().unwrap()));
+        let space: &'static mut GuestAddressSpace = Box::leak(Box::new(
+            GuestAddressSpace::new(PageSize::Size4K, 0).unwrap(),
+        ));
         GuestAddressSpaceViewMut::new(GuestPhysAddr::new(0), space)
     }
 
@@ -285,7 +377,10 @@ mod test {
         use core::convert::TryFrom;
 
         let view = define_test_view();
-        let mut complex = PciRootComplex::new();
+        let mut complex = PciRootComplex::new(
+            Arc::new(VmLifecycle::new(vec![])),
+            GuestPhysAddr::new(0xc000_0000),
+        );
         let addr = ((reg << 2) as u32).to_be_bytes();
         let request = PortWriteRequest::try_from(&addr[..]).unwrap();
         complex
@@ -307,6 +402,38 @@ mod test {
         assert_eq!(u32::from_be_bytes(buff), 0x29c08086);
     }
 
+    #[test]
+    fn test_ecam_register_read() {
+        let mut complex = PciRootComplex::new(
+            Arc::new(VmLifecycle::new(vec![])),
+            GuestPhysAddr::new(0xc000_0000),
+        );
+
+        // The device id of the ICH9 (device 1)
+        let view = define_test_view();
+        let mut buff = [0u8; 2];
+        complex
+            .on_mem_read(
+                GuestPhysAddr::new(0xc000_8002),
+                MemReadRequest::new(&mut buff),
+                view,
+            )
+            .unwrap();
+        assert_eq!(u16::from_be_bytes(buff), 0x2918);
+
+        // No device is present at device 2
+        let view = define_test_view();
+        let mut buff = [0u8; 4];
+        complex
+            .on_mem_read(
+                GuestPhysAddr::new(0xc001_0000),
+                MemReadRequest::new(&mut buff),
+                view,
+            )
+            .unwrap();
+        assert_eq!(u32::from_be_bytes(buff), 0xffffffff);
+    }
+
     #[test]
     fn test_half_register_read() {
         let view = define_test_view();
@@ -3,19 +3,294 @@ use crate::device::{
 };
 use crate::error::Result;
 use crate::memory::GuestAddressSpaceViewMut;
-use alloc::boxed::Box;
 use alloc::vec::Vec;
 use core::convert::TryInto;
 
-#[derive(Default, Debug)]
-pub struct PicState {
+#[derive(Debug, PartialEq)]
+enum InitState {
+    Ready,
+    Icw2,
+    Icw3,
+    Icw4,
+}
+
+/// The state of a single 8259 PIC
+#[derive(Debug)]
+struct PicState {
     imr: u8,
+    irr: u8,
+    isr: u8,
+
+    // The level of each input line the last time it changed, used to
+    // detect edges.
+    last_irr: u8,
+
+    // Edge/Level Control Register (set bits are level triggered)
+    elcr: u8,
+    elcr_mask: u8,
+
+    vector_base: u8,
+    init_state: InitState,
+    icw4_needed: bool,
+    single_mode: bool,
+    auto_eoi: bool,
+    rotate_on_auto_eoi: bool,
+    special_fully_nested: bool,
+    special_mask: bool,
+    poll: bool,
+    read_isr: bool,
+
+    // The irq with the lowest priority is (priority_add - 1) & 7
+    priority_add: u8,
+
+    is_master: bool,
+}
+
+impl PicState {
+    fn new(is_master: bool, elcr_mask: u8) -> Self {
+        Self {
+            imr: 0,
+            irr: 0,
+            isr: 0,
+            last_irr: 0,
+            elcr: 0,
+            elcr_mask,
+            vector_base: 0,
+            init_state: InitState::Ready,
+            icw4_needed: false,
+            single_mode: false,
+            auto_eoi: false,
+            rotate_on_auto_eoi: false,
+            special_fully_nested: false,
+            special_mask: false,
+            poll: false,
+            read_isr: false,
+            priority_add: 0,
+            is_master,
+        }
+    }
+
+    /// Reset the state in response to ICW1 (note that the ELCR is not reset)
+    fn init_reset(&mut self) {
+        self.last_irr = 0;
+        self.irr &= self.elcr;
+        self.imr = 0;
+        self.isr = 0;
+        self.priority_add = 0;
+        self.vector_base = 0;
+        self.read_isr = false;
+        self.poll = false;
+        self.special_mask = false;
+        self.init_state = InitState::Ready;
+        self.auto_eoi = false;
+        self.rotate_on_auto_eoi = false;
+        self.special_fully_nested = false;
+        self.icw4_needed = false;
+        self.single_mode = false;
+    }
+
+    /// Returns the priority (0 is highest) of the highest priority irq
+    /// in `mask`, or 8 if `mask` is empty.
+    fn priority(&self, mask: u8) -> u8 {
+        if mask == 0 {
+            return 8;
+        }
+        let mut priority = 0;
+        while mask & (1 << ((priority + self.priority_add) & 7)) == 0 {
+            priority += 1;
+        }
+        priority
+    }
+
+    /// Returns the irq that should be delivered to the processor (if any)
+    fn get_irq(&self) -> Option<u8> {
+        let priority = self.priority(self.irr & !self.imr);
+        if priority == 8 {
+            return None;
+        }
+
+        // In special fully nested mode, the master does not consider the
+        // cascaded slave to be in service, so additional interrupts from
+        // the slave can be delivered.
+        let mut in_service = self.isr;
+        if self.special_mask {
+            in_service &= !self.imr;
+        }
+        if self.special_fully_nested && self.is_master {
+            in_service &= !(1 << 2);
+        }
+
+        if priority < self.priority(in_service) {
+            Some((priority + self.priority_add) & 7)
+        } else {
+            None
+        }
+    }
+
+    fn set_irq(&mut self, irq: u8, level: bool) {
+        let mask = 1 << irq;
+        if self.elcr & mask != 0 {
+            if level {
+                self.irr |= mask;
+                self.last_irr |= mask;
+            } else {
+                self.irr &= !mask;
+                self.last_irr &= !mask;
+            }
+        } else if level {
+            // Edge triggered interrupts are only requested on a rising edge
+            if self.last_irr & mask == 0 {
+                self.irr |= mask;
+            }
+            self.last_irr |= mask;
+        } else {
+            self.last_irr &= !mask;
+        }
+    }
+
+    /// Perform the interrupt acknowledge cycle for the given irq
+    fn intack(&mut self, irq: u8) {
+        if self.auto_eoi {
+            if self.rotate_on_auto_eoi {
+                self.priority_add = (irq + 1) & 7;
+            }
+        } else {
+            self.isr |= 1 << irq;
+        }
+
+        // Level triggered interrupts remain requested until the line drops
+        if self.elcr & (1 << irq) == 0 {
+            self.irr &= !(1 << irq);
+        }
+    }
+
+    fn write_command(&mut self, val: u8) {
+        if val & 0x10 != 0 {
+            // ICW1
+            self.init_reset();
+            self.init_state = InitState::Icw2;
+            self.icw4_needed = val & 0x01 != 0;
+            self.single_mode = val & 0x02 != 0;
+            if val & 0x08 != 0 {
+                warn!("PIC: level triggered mode is not supported via ICW1");
+            }
+        } else if val & 0x08 != 0 {
+            // OCW3
+            if val & 0x04 != 0 {
+                self.poll = true;
+            }
+            if val & 0x02 != 0 {
+                self.read_isr = val & 0x01 != 0;
+            }
+            if val & 0x40 != 0 {
+                self.special_mask = val & 0x20 != 0;
+            }
+        } else {
+            // OCW2
+            match val >> 5 {
+                // Clear/Set rotate in automatic EOI mode
+                0b000 | 0b100 => self.rotate_on_auto_eoi = val >> 7 != 0,
+
+                // Non-specific EOI (optionally rotating)
+                cmd @ 0b001 | cmd @ 0b101 => {
+                    let priority = self.priority(self.isr);
+                    if priority != 8 {
+                        let irq = (priority + self.priority_add) & 7;
+                        self.isr &= !(1 << irq);
+                        if cmd == 0b101 {
+                            self.priority_add = (irq + 1) & 7;
+                        }
+                    }
+                }
+
+                // Specific EOI
+                0b011 => self.isr &= !(1 << (val & 7)),
+
+                // Set priority
+                0b110 => self.priority_add = ((val & 7) + 1) & 7,
+
+                // Rotate on specific EOI
+                0b111 => {
+                    let irq = val & 7;
+                    self.isr &= !(1 << irq);
+                    self.priority_add = (irq + 1) & 7;
+                }
+
+                // No operation
+                _ => (),
+            }
+        }
+    }
+
+    fn write_data(&mut self, val: u8) {
+        match self.init_state {
+            InitState::Ready => self.imr = val,
+            InitState::Icw2 => {
+                self.vector_base = val & 0xf8;
+                self.init_state = if !self.single_mode {
+                    InitState::Icw3
+                } else if self.icw4_needed {
+                    InitState::Icw4
+                } else {
+                    InitState::Ready
+                };
+            }
+            InitState::Icw3 => {
+                // The cascade configuration is fixed (the slave is always
+                // connected to IRQ2 of the master), so ICW3 is ignored.
+                self.init_state = if self.icw4_needed {
+                    InitState::Icw4
+                } else {
+                    InitState::Ready
+                };
+            }
+            InitState::Icw4 => {
+                self.special_fully_nested = val & 0x10 != 0;
+                self.auto_eoi = val & 0x02 != 0;
+                self.init_state = InitState::Ready;
+            }
+        }
+    }
+
+    fn read(&mut self, is_data: bool) -> u8 {
+        if self.poll {
+            self.poll = false;
+            match self.get_irq() {
+                Some(irq) => {
+                    self.intack(irq);
+                    irq | 0x80
+                }
+                None => 0,
+            }
+        } else if is_data {
+            self.imr
+        } else if self.read_isr {
+            self.isr
+        } else {
+            self.irr
+        }
+    }
 }
 
-#[derive(Default, Debug)]
+/// A pair of cascaded 8259 PICs (as found on a PC)
+///
+/// The slave PIC is connected to IRQ2 of the master, and the output of
+/// the master is connected to the guest BSP. Note that this is generally
+/// shared with the VM's `IrqLine`s through an `Arc<Mutex<Pic8259>>`.
+#[derive(Debug)]
 pub struct Pic8259 {
-    master_state: PicState,
-    slave_state: PicState,
+    master: PicState,
+    slave: PicState,
+}
+
+impl Default for Pic8259 {
+    fn default() -> Self {
+        Self {
+            // IRQ0, IRQ1, IRQ2, IRQ8 and IRQ13 are always edge triggered
+            master: PicState::new(true, 0xf8),
+            slave: PicState::new(false, 0xde),
+        }
+    }
 }
 
 impl Pic8259 {
@@ -23,15 +298,64 @@ impl Pic8259 {
     const PIC_MASTER_DATA: Port = Self::PIC_MASTER_COMMAND + 1;
     const PIC_SLAVE_COMMAND: Port = 0x00a0;
     const PIC_SLAVE_DATA: Port = Self::PIC_SLAVE_COMMAND + 1;
-    const PIC_ECLR_COMMAND: Port = 0x4d0;
-    const PIC_ECLR_DATA: Port = Self::PIC_ECLR_COMMAND + 1;
+    const PIC_ELCR_MASTER: Port = 0x4d0;
+    const PIC_ELCR_SLAVE: Port = Self::PIC_ELCR_MASTER + 1;
 
-    pub fn new() -> Box<Self> {
-        Box::new(Pic8259::default())
+    /// Set the level of one of the 16 legacy interrupt lines
+    pub fn set_irq(&mut self, irq: u8, level: bool) {
+        if irq < 8 {
+            self.master.set_irq(irq, level);
+        } else {
+            self.slave.set_irq(irq - 8, level);
+            self.update_cascade();
+        }
+    }
+
+    /// Returns true if the PIC is requesting an interrupt from the processor
+    pub fn has_interrupt(&self) -> bool {
+        self.master.get_irq().is_some()
+    }
+
+    /// Acknowledge the current interrupt and return its vector
+    ///
+    /// This is the equivalent of the processor's INTA cycle, so it should
+    /// only be called once the interrupt will actually be delivered. If no
+    /// interrupt is pending, the spurious vector (IRQ7) is returned.
+    pub fn acknowledge(&mut self) -> u8 {
+        match self.master.get_irq() {
+            Some(2) => {
+                let irq = match self.slave.get_irq() {
+                    Some(irq) => {
+                        self.slave.intack(irq);
+                        irq
+                    }
+                    None => 7,
+                };
+                self.update_cascade();
+                self.master.intack(2);
+                self.slave.vector_base + irq
+            }
+            Some(irq) => {
+                self.master.intack(irq);
+                self.master.vector_base + irq
+            }
+            None => self.master.vector_base + 7,
+        }
+    }
+
+    /// Propagate the output of the slave to IRQ2 of the master
+    fn update_cascade(&mut self) {
+        let level = self.slave.get_irq().is_some();
+        self.master.set_irq(2, level);
     }
 }
 
 impl EmulatedDevice for Pic8259 {
+    fn reset(&mut self) -> Result<()> {
+        *self = Self::default();
+        Ok(())
+    }
+
     fn services(&self) -> Vec<DeviceRegion> {
         vec![
             DeviceRegion::PortIo(
@@ -40,7 +364,7 @@ impl EmulatedDevice for Pic8259 {
             DeviceRegion::PortIo(
                 Self::PIC_SLAVE_COMMAND..=Self::PIC_SLAVE_DATA,
             ),
-            DeviceRegion::PortIo(Self::PIC_ECLR_COMMAND..=Self::PIC_ECLR_DATA),
+            DeviceRegion::PortIo(Self::PIC_ELCR_MASTER..=Self::PIC_ELCR_SLAVE),
         ]
     }
 
@@ -51,12 +375,20 @@ impl EmulatedDevice for Pic8259 {
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
         let data = match port {
-            Self::PIC_MASTER_DATA => self.master_state.imr,
-            Self::PIC_SLAVE_DATA => self.master_state.imr,
+            Self::PIC_MASTER_COMMAND => self.master.read(false),
+            Self::PIC_MASTER_DATA => self.master.read(true),
+            Self::PIC_SLAVE_COMMAND => self.slave.read(false),
+            Self::PIC_SLAVE_DATA => self.slave.read(true),
+            Self::PIC_ELCR_MASTER => self.master.elcr,
+            Self::PIC_ELCR_SLAVE => self.slave.elcr,
             _ => {
                 return Ok(());
             }
         };
+
+        // A poll may have acknowledged a slave interrupt
+        self.update_cascade();
+
         val.copy_from_u32(data as u32);
         Ok(())
     }
@@ -67,15 +399,124 @@ impl EmulatedDevice for Pic8259 {
         val: PortWriteRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
+        let val: u8 = val.try_into()?;
         match port {
-            Self::PIC_MASTER_DATA => {
-                self.master_state.imr = val.try_into()?;
+            Self::PIC_MASTER_COMMAND => self.master.write_command(val),
+            Self::PIC_MASTER_DATA => self.master.write_data(val),
+            Self::PIC_SLAVE_COMMAND => self.slave.write_command(val),
+            Self::PIC_SLAVE_DATA => self.slave.write_data(val),
+            Self::PIC_ELCR_MASTER => {
+                self.master.elcr = val & self.master.elcr_mask
             }
-            Self::PIC_SLAVE_DATA => {
-                self.master_state.imr = val.try_into()?;
+            Self::PIC_ELCR_SLAVE => {
+                self.slave.elcr = val & self.slave.elcr_mask
             }
             _ => (),
         }
+        self.update_cascade();
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Program the PICs the way linux does (vectors 0x30 and 0x38)
+    fn initialized_pic() -> Pic8259 {
+        let mut pic = Pic8259::default();
+        pic.master.write_command(0x11);
+        pic.master.write_data(0x30);
+        pic.master.write_data(1 << 2);
+        pic.master.write_data(0x01);
+        pic.slave.write_command(0x11);
+        pic.slave.write_data(0x38);
+        pic.slave.write_data(2);
+        pic.slave.write_data(0x01);
+        pic
+    }
+
+    #[test]
+    fn test_pic_init_sequence() {
+        let pic = initialized_pic();
+        assert_eq!(pic.master.init_state, InitState::Ready);
+        assert_eq!(pic.slave.init_state, InitState::Ready);
+        assert_eq!(pic.master.vector_base, 0x30);
+        assert_eq!(pic.slave.vector_base, 0x38);
+        assert!(!pic.has_interrupt());
+    }
+
+    #[test]
+    fn test_pic_edge_irq() {
+        let mut pic = initialized_pic();
+        pic.set_irq(0, true);
+        pic.set_irq(0, false);
+        assert!(pic.has_interrupt());
+        assert_eq!(pic.acknowledge(), 0x30);
+
+        // IRQ0 is now in service, so it should not be delivered again
+        // until the guest sends an EOI
+        assert!(!pic.has_interrupt());
+        pic.set_irq(0, true);
+        pic.set_irq(0, false);
+        assert!(!pic.has_interrupt());
+
+        pic.master.write_command(0x20);
+        assert!(pic.has_interrupt());
+        assert_eq!(pic.acknowledge(), 0x30);
+    }
+
+    #[test]
+    fn test_pic_priority_and_mask() {
+        let mut pic = initialized_pic();
+        pic.set_irq(4, true);
+        pic.set_irq(1, true);
+
+        // Lower numbered irqs have higher priority
+        assert_eq!(pic.acknowledge(), 0x31);
+        pic.master.write_command(0x20);
+
+        // Masked irqs are not delivered
+        pic.master.write_data(1 << 4);
+        assert!(!pic.has_interrupt());
+        pic.master.write_data(0);
+        assert_eq!(pic.acknowledge(), 0x34);
+
+        // Specific EOI for IRQ4
+        pic.master.write_command(0x60 | 4);
+        assert_eq!(pic.master.isr, 0);
+    }
+
+    #[test]
+    fn test_pic_cascade() {
+        let mut pic = initialized_pic();
+        pic.set_irq(8, true);
+        assert!(pic.has_interrupt());
+        assert_eq!(pic.acknowledge(), 0x38);
+        assert_eq!(pic.master.isr, 1 << 2);
+        assert_eq!(pic.slave.isr, 1);
+
+        pic.slave.write_command(0x20);
+        pic.master.write_command(0x20);
+        assert_eq!(pic.master.isr, 0);
+        assert_eq!(pic.slave.isr, 0);
+        assert!(!pic.has_interrupt());
+    }
+
+    #[test]
+    fn test_pic_level_triggered() {
+        let mut pic = initialized_pic();
+        pic.slave.elcr = 1 << 3;
+        pic.set_irq(11, true);
+        assert_eq!(pic.acknowledge(), 0x3b);
+        pic.slave.write_command(0x20);
+        pic.master.write_command(0x20);
+
+        // The line is still asserted, so the interrupt is requested again
+        pic.update_cascade();
+        assert!(pic.has_interrupt());
+
+        pic.set_irq(11, false);
+        assert!(!pic.has_interrupt());
+    }
+}
@@ -1,70 +1,433 @@
+use crate::device::irq::IrqLine;
 use crate::device::{
     DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
 };
-use crate::error::{Error, Result};
+use crate::error::Result;
 use crate::memory::GuestAddressSpaceViewMut;
 use crate::pit::*;
 use crate::time;
+use crate::vtime::GuestClock;
 
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::convert::TryFrom;
+use core::time::Duration;
 
-#[derive(Debug)]
-enum OperatingModeState {
-    Mode0 {
-        start_counter: Option<u16>,
-        timer: Option<time::TimerId>,
-        start_time: Option<time::Instant>,
-    },
-    Mode2 {
-        start_counter: Option<u16>,
-        timer: Option<time::TimerId>,
-        start_time: Option<time::Instant>,
-    },
+// The bits of port 0x61 (system control port B) that are owned by the PIT
+const PORT_B_T2GATE: u8 = 1 << 0;
+const PORT_B_REFRESH: u8 = 1 << 4;
+const PORT_B_T2OUT: u8 = 1 << 5;
+
+// The bits of port 0x61 that the guest can write (the timer 2 gate, the
+// speaker data enable, and the parity and channel check enables)
+const PORT_B_WRITABLE: u8 = 0x0f;
+
+// The DRAM refresh request toggles every 15.085us (18 PIT ticks)
+const REFRESH_PERIOD_TICKS: u64 = 18;
+
+/// The current guest time in PIT ticks
+fn pit_ticks(clock: &GuestClock) -> u64 {
+    ((clock.now_ns() as u128 * PIT_HZ as u128) / 1_000_000_000) as u64
 }
 
-#[derive(Debug)]
-enum AccessModeState {
-    LatchCount,
-    LoByte,
-    HiByte,
-    Word { lo_byte: Option<u8> },
+fn ticks_to_duration(ticks: u64) -> Duration {
+    Duration::from_nanos(
+        ((ticks as u128 * 1_000_000_000) / PIT_HZ as u128) as u64,
+    )
+}
+
+fn from_bcd(val: u16) -> u32 {
+    (0..4).rev().fold(0, |acc, digit| {
+        acc * 10 + ((val >> (digit * 4)) & 0xf) as u32
+    })
+}
+
+fn to_bcd(val: u32) -> u16 {
+    (0..4).fold(0, |acc, digit| {
+        acc | (((val / 10u32.pow(digit)) % 10) as u16) << (digit * 4)
+    })
 }
 
+/// A single counter of the PIT
+///
+/// Rather than counting down on every tick, the state of the counter is
+/// derived from the number of PIT ticks since it started counting.
 #[derive(Debug)]
-struct ChannelState {
-    mode: OperatingModeState,
-    access: AccessModeState,
+struct Counter {
+    mode: OperatingMode,
+    access: AccessMode,
+    bcd: bool,
+
+    // The initial count (1 to 0x10000, or 10000 in BCD mode)
+    count: u32,
+
+    // Whether a count has been written since the mode was set
+    loaded: bool,
+
+    // The low byte of a count being written in word access mode
+    pending_lsb: Option<u8>,
+
+    // Whether the next read in word access mode returns the high byte
+    read_msb: bool,
+
+    latched_count: Option<u16>,
+    latched_status: Option<u8>,
+    null_count: bool,
+    gate: bool,
+
+    // The tick the counter started counting down from `count`, which is
+    // None until a count is written (or the gate is triggered in modes 1
+    // and 5)
+    start: Option<u64>,
+
+    // The tick the gate went low, if it is still low
+    paused: Option<u64>,
 }
 
-impl Default for ChannelState {
-    fn default() -> Self {
+impl Counter {
+    fn new(gate: bool) -> Self {
         Self {
-            mode: OperatingModeState::Mode0 {
-                start_counter: None,
-                timer: None,
-                start_time: None,
+            mode: OperatingMode::Mode0,
+            access: AccessMode::LoByte,
+            bcd: false,
+            count: 0x10000,
+            loaded: false,
+            pending_lsb: None,
+            read_msb: false,
+            latched_count: None,
+            latched_status: None,
+            null_count: true,
+            gate: gate,
+            start: None,
+            paused: None,
+        }
+    }
+
+    /// Program the counter with a control word (which stops it until a
+    /// new count is written)
+    fn set_mode(&mut self, mode: OperatingMode, access: AccessMode, bcd: bool) {
+        let gate = self.gate;
+        let paused = self.paused;
+        *self = Self::new(gate);
+        self.mode = mode;
+        self.access = access;
+        self.bcd = bcd;
+        self.paused = paused;
+    }
+
+    /// The number of ticks the counter has been counting down
+    fn elapsed(&self, now: u64) -> Option<u64> {
+        // A low gate stops the count in every mode but 1 and 5 (where it
+        // only matters when it rises)
+        let end = match self.mode {
+            OperatingMode::Mode1 | OperatingMode::Mode5 => now,
+            _ => self.paused.unwrap_or(now),
+        };
+        self.start.map(|start| end.saturating_sub(start))
+    }
+
+    /// The value of the counting element
+    fn current(&self, now: u64) -> u16 {
+        let count = self.count as u64;
+        let modulus: u64 = if self.bcd { 10000 } else { 0x10000 };
+        let value = match (self.mode, self.elapsed(now)) {
+            (_, None) => count,
+            (OperatingMode::Mode2, Some(ticks)) => count - ticks % count,
+
+            // Mode 3 decrements by two, reloading every half period
+            (OperatingMode::Mode3, Some(ticks)) => count - (2 * ticks) % count,
+
+            // The other modes keep counting down after the terminal count
+            (_, Some(ticks)) => {
+                (count as i64 - ticks as i64).rem_euclid(modulus as i64) as u64
+            }
+        };
+        let value = (value % modulus) as u32;
+        if self.bcd {
+            to_bcd(value)
+        } else {
+            value as u16
+        }
+    }
+
+    /// The level of the OUT pin of the counter
+    fn output(&self, now: u64) -> bool {
+        let count = self.count as u64;
+        match (self.mode, self.elapsed(now)) {
+            (OperatingMode::Mode0, None) => false,
+            (_, None) => true,
+            (OperatingMode::Mode2, _) | (OperatingMode::Mode3, _)
+                if !self.gate =>
+            {
+                true
+            }
+            (OperatingMode::Mode0, Some(ticks))
+            | (OperatingMode::Mode1, Some(ticks)) => ticks >= count,
+            (OperatingMode::Mode2, Some(ticks)) => ticks % count != count - 1,
+            (OperatingMode::Mode3, Some(ticks)) => {
+                ticks % count < (count + 1) / 2
+            }
+            (OperatingMode::Mode4, Some(ticks))
+            | (OperatingMode::Mode5, Some(ticks)) => ticks != count,
+        }
+    }
+
+    fn status(&self, now: u64) -> u8 {
+        (self.output(now) as u8) << 7
+            | (self.null_count as u8) << 6
+            | (self.access as u8) << 4
+            | (self.mode as u8) << 1
+            | self.bcd as u8
+    }
+
+    fn latch_count(&mut self, now: u64) {
+        // A latched count is held until it is read
+        if self.latched_count.is_none() {
+            self.latched_count = Some(self.current(now));
+        }
+    }
+
+    fn latch_status(&mut self, now: u64) {
+        if self.latched_status.is_none() {
+            self.latched_status = Some(self.status(now));
+        }
+    }
+
+    fn read(&mut self, now: u64) -> u8 {
+        if let Some(status) = self.latched_status.take() {
+            return status;
+        }
+
+        let value = self.latched_count.unwrap_or_else(|| self.current(now));
+        let (byte, done) = match self.access {
+            AccessMode::LoByte => (value as u8, true),
+            AccessMode::HiByte => ((value >> 8) as u8, true),
+            _ => {
+                self.read_msb = !self.read_msb;
+                if self.read_msb {
+                    (value as u8, false)
+                } else {
+                    ((value >> 8) as u8, true)
+                }
+            }
+        };
+        if done {
+            self.latched_count = None;
+        }
+        byte
+    }
+
+    /// Write a byte of the count, returning true if the counter started
+    /// counting down from a new count
+    fn write(&mut self, val: u8, now: u64) -> bool {
+        let count = match self.access {
+            AccessMode::LoByte => val as u16,
+            AccessMode::HiByte => (val as u16) << 8,
+            _ => match self.pending_lsb.take() {
+                Some(lsb) => (val as u16) << 8 | lsb as u16,
+                None => {
+                    // Writing the first byte stops the count in mode 0
+                    if let OperatingMode::Mode0 = self.mode {
+                        self.start = None;
+                    }
+                    self.pending_lsb = Some(val);
+                    self.null_count = true;
+                    return false;
+                }
             },
-            access: AccessModeState::LoByte,
+        };
+
+        self.count = match (self.bcd, count) {
+            (true, 0) => 10000,
+            (true, count) => from_bcd(count),
+            (false, 0) => 0x10000,
+            (false, count) => count as u32,
+        };
+        self.loaded = true;
+
+        match self.mode {
+            // The new count is only used once the gate is triggered
+            OperatingMode::Mode1 | OperatingMode::Mode5 => {
+                self.null_count = true;
+                false
+            }
+            //NOTE: in modes 2 and 3 the new count should only be used at
+            //      the end of the current period, but it is used at once
+            _ => {
+                self.start = Some(now);
+                self.null_count = false;
+                true
+            }
+        }
+    }
+
+    fn set_gate(&mut self, gate: bool, now: u64) {
+        if gate == self.gate {
+            return;
+        }
+        self.gate = gate;
+        if !gate {
+            self.paused = Some(now);
+            return;
+        }
+
+        let paused = self.paused.take();
+        match self.mode {
+            // Counting resumes where it stopped
+            OperatingMode::Mode0 | OperatingMode::Mode4 => {
+                if let (Some(start), Some(paused)) = (self.start, paused) {
+                    self.start = Some(now - paused.saturating_sub(start));
+                }
+            }
+
+            // A rising edge (re)starts the count in the other modes
+            _ => {
+                if self.loaded {
+                    self.start = Some(now);
+                    self.null_count = false;
+                }
+            }
+        }
+    }
+
+    /// The number of ticks until the next rising edge of the output, and
+    /// whether the edges are periodic
+    fn next_edge(&self, now: u64) -> Option<(u64, bool)> {
+        let count = self.count as u64;
+        match (self.mode, self.elapsed(now)) {
+            (OperatingMode::Mode0, Some(ticks))
+            | (OperatingMode::Mode4, Some(ticks))
+                if ticks < count =>
+            {
+                Some((count - ticks, false))
+            }
+            (OperatingMode::Mode2, Some(_))
+            | (OperatingMode::Mode3, Some(_)) => Some((count, true)),
+            _ => None,
         }
     }
 }
 
-#[derive(Default, Debug)]
+/// An emulated 8254 programmable interval timer
+///
+/// The output of channel 0 raises IRQ0, channel 1 counts (without any
+/// effect) and channel 2 is gated by port 0x61, where its output can also
+/// be read.
+#[derive(Debug)]
 pub struct Pit8254 {
-    channel0: ChannelState,
-    // channel1 is not supported
-    channel2: ChannelState,
+    counters: [Counter; 3],
+
+    // The writable bits of port 0x61
+    port_b: u8,
+
+    // The output of channel 0 is connected to IRQ0
+    irq: IrqLine,
+    timer: Option<time::TimerId>,
+    clock: Arc<GuestClock>,
 }
 
 impl Pit8254 {
-    pub fn new() -> Box<Self> {
-        Box::new(Pit8254::default())
+    pub fn new(irq: IrqLine, clock: Arc<GuestClock>) -> Box<Self> {
+        Box::new(Pit8254 {
+            // Only the gate of channel 2 is connected (to port 0x61)
+            counters: [
+                Counter::new(true),
+                Counter::new(true),
+                Counter::new(false),
+            ],
+            port_b: 0,
+            irq: irq,
+            timer: None,
+            clock: clock,
+        })
+    }
+
+    fn cancel_timer(&mut self) {
+        if let Some(id) = self.timer.take() {
+            // The timer may have already expired, so ignore any error
+            let _ = time::cancel_timer(&id);
+        }
+    }
+
+    /// Set the timer that raises IRQ0 on the next rising edges of the
+    /// output of channel 0
+    fn update_timer(&mut self, now: u64) {
+        self.cancel_timer();
+        self.timer = match self.counters[0].next_edge(now) {
+            Some((ticks, true)) => Some(time::set_periodic_timer(
+                ticks_to_duration(ticks),
+                time::TimerInterruptType::Irq(self.irq.clone()),
+            )),
+            Some((ticks, false)) => Some(time::set_oneshot_timer(
+                ticks_to_duration(ticks),
+                time::TimerInterruptType::Irq(self.irq.clone()),
+            )),
+            None => None,
+        };
+    }
+
+    fn port_b(&self, now: u64) -> u8 {
+        let refresh = (now / REFRESH_PERIOD_TICKS) % 2 == 1;
+        let mut val = self.port_b;
+        if refresh {
+            val |= PORT_B_REFRESH;
+        }
+        if self.counters[2].output(now) {
+            val |= PORT_B_T2OUT;
+        }
+        val
+    }
+
+    fn write_control(&mut self, val: u8, now: u64) -> Result<()> {
+        let channel = Channel::try_from((val & 0b11000000) >> 6)?;
+        let access = AccessMode::try_from((val & 0b00110000) >> 4)?;
+
+        let index = match channel {
+            Channel::Channel0 => 0,
+            Channel::Channel1 => 1,
+            Channel::Channel2 => 2,
+            Channel::ReadBack => {
+                // Bits 1-3 select the counters, and the count and status
+                // are latched when bits 5 and 4 (respectively) are clear
+                for (i, counter) in self.counters.iter_mut().enumerate() {
+                    if val & (0b10 << i) == 0 {
+                        continue;
+                    }
+                    if val & 0b00100000 == 0 {
+                        counter.latch_count(now);
+                    }
+                    if val & 0b00010000 == 0 {
+                        counter.latch_status(now);
+                    }
+                }
+                return Ok(());
+            }
+        };
+
+        if let AccessMode::LatchCount = access {
+            self.counters[index].latch_count(now);
+            return Ok(());
+        }
+
+        let mode = OperatingMode::try_from((val & 0b00001110) >> 1)?;
+        self.counters[index].set_mode(mode, access, val & 0b1 != 0);
+        if index == 0 {
+            self.cancel_timer();
+        }
+        Ok(())
     }
 }
 
 impl EmulatedDevice for Pit8254 {
+    fn reset(&mut self) -> Result<()> {
+        self.cancel_timer();
+        self.counters =
+            [Counter::new(true), Counter::new(true), Counter::new(false)];
+        self.port_b = 0;
+        Ok(())
+    }
+
     fn services(&self) -> Vec<DeviceRegion> {
         vec![
             DeviceRegion::PortIo(PIT_COUNTER_0..=PIT_MODE_CONTROL),
@@ -78,33 +441,17 @@ impl EmulatedDevice for Pit8254 {
         mut val: PortReadRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
-        match port {
-            //FIXME: much of this 'PS2' handling is a hack. I'm not aware of
-            // a good source for exactly what's supposed to happen here.
-            PIT_PS2_CTRL_B => {
-                if let OperatingModeState::Mode0 {
-                    start_time,
-                    start_counter,
-                    ..
-                } = self.channel2.mode
-                {
-                    match (start_time, start_counter) {
-                        (Some(start_time), Some(start_counter)) => {
-                            let duration = time::now() - start_time;
-                            let ticks =
-                                duration.as_nanos() / PIT_NS_PER_TICK as u128;
-                            if ticks as u16 > start_counter {
-                                val.copy_from_u32(1 << 5);
-                            }
-                        }
-                        _ => (),
-                    }
-                }
+        let now = pit_ticks(&self.clock);
+        let byte = match port {
+            PIT_PS2_CTRL_B => self.port_b(now),
+            PIT_COUNTER_0..=PIT_COUNTER_2 => {
+                self.counters[(port - PIT_COUNTER_0) as usize].read(now)
             }
-            _ => {
-                info!("PIT read from unsupported port");
-            }
-        }
+
+            // The mode control register is write-only
+            _ => 0,
+        };
+        val.copy_from_u32(byte as u32);
         Ok(())
     }
 
@@ -114,156 +461,102 @@ impl EmulatedDevice for Pit8254 {
         val: PortWriteRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
+        let val = u8::try_from(val)?;
+        let now = pit_ticks(&self.clock);
         match port {
-            PIT_MODE_CONTROL => {
-                let val = u8::try_from(val)?;
-                let channel = (0b11000000 & val) >> 6;
-                let access = (0b00110000 & val) >> 4;
-                let operating = (0b00001110 & val) >> 1;
-
-                if val & 0b00000001 != 0 {
-                    return Err(Error::InvalidValue(
-                        "PIT BCD mode is not supported".into(),
-                    ));
+            PIT_MODE_CONTROL => self.write_control(val, now)?,
+            PIT_PS2_CTRL_B => {
+                //NOTE: there is no speaker, so the speaker data enable bit
+                //      is only stored
+                self.port_b = val & PORT_B_WRITABLE;
+                self.counters[2].set_gate(val & PORT_B_T2GATE != 0, now);
+            }
+            PIT_COUNTER_0..=PIT_COUNTER_2 => {
+                let index = (port - PIT_COUNTER_0) as usize;
+                let started = self.counters[index].write(val, now);
+
+                // Only channel 0 produces timer interrupts
+                if index == 0 && (started || self.counters[0].start.is_none()) {
+                    self.update_timer(now);
                 }
+            }
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+}
 
-                let operating_state = match operating {
-                    0b000 => OperatingModeState::Mode0 {
-                        start_counter: None,
-                        timer: None,
-                        start_time: None,
-                    },
-                    0b010 => OperatingModeState::Mode2 {
-                        start_counter: None,
-                        timer: None,
-                        start_time: None,
-                    },
-                    value => {
-                        return Err(Error::InvalidValue(format!(
-                            "Invalid PIT operating state '0x{:x}'",
-                            value
-                        )))
-                    }
-                };
-
-                let access_state = match access {
-                    0b00 => AccessModeState::LatchCount,
-                    0b01 => AccessModeState::LoByte,
-                    0b10 => AccessModeState::HiByte,
-                    0b11 => AccessModeState::Word { lo_byte: None },
-                    value => {
-                        return Err(Error::InvalidValue(format!(
-                            "Invalid PIT access state '0x{:x}'",
-                            value
-                        )))
-                    }
-                };
-
-                let channel_state = ChannelState {
-                    mode: operating_state,
-                    access: access_state,
-                };
-
-                let current_channel = match channel {
-                    0b00 => &mut self.channel0,
-                    0b10 => &mut self.channel2,
-                    value => {
-                        return Err(Error::InvalidValue(format!(
-                            "Invalid PIT channel '0x{:x}'",
-                            value
-                        )))
-                    }
-                };
+#[cfg(test)]
+mod test {
+    use super::*;
 
-                // Stop any running timers
-                match &current_channel.mode {
-                    OperatingModeState::Mode0 { ref timer, .. } => timer,
-                    OperatingModeState::Mode2 { ref timer, .. } => timer,
-                }
-                .as_ref()
-                .map(|id| time::cancel_timer(id));
+    fn programmed(mode: OperatingMode, access: AccessMode) -> Counter {
+        let mut counter = Counter::new(true);
+        counter.set_mode(mode, access, false);
+        counter
+    }
 
-                *current_channel = channel_state;
-            }
-            port @ PIT_COUNTER_0..=PIT_COUNTER_2 => {
-                let val = u8::try_from(val)?;
-                let channel_state = match port {
-                    PIT_COUNTER_0 => &mut self.channel0,
-                    PIT_COUNTER_1 => {
-                        return Err(Error::InvalidValue(format!(
-                            "Invalid PIT port '0x{:x}'",
-                            port
-                        )))
-                    }
-                    PIT_COUNTER_2 => &mut self.channel2,
-                    _ => unreachable!(),
-                };
-
-                let counter = match channel_state.access {
-                    AccessModeState::LoByte => val as u16,
-                    AccessModeState::HiByte => (val as u16) << 8,
-                    AccessModeState::Word { ref mut lo_byte } => {
-                        if let Some(lo_byte) = lo_byte {
-                            ((val as u16) << 8) | (*lo_byte as u16)
-                        } else {
-                            // We are just setting the low byte in word access mode.
-                            // There is nothing else to do, so return.
-                            *lo_byte = Some(val);
-                            return Ok(());
-                        }
-                    }
-                    _ => unreachable!(),
-                };
+    #[test]
+    fn test_mode0_count_down() {
+        let mut counter = programmed(OperatingMode::Mode0, AccessMode::Word);
+        assert!(!counter.write(0x00, 100));
+        assert!(counter.write(0x01, 100));
+        assert_eq!(counter.current(150), 0xce);
+        assert!(!counter.output(355));
+        assert!(counter.output(356));
 
-                if counter == 0 {
-                    warn!("PIT: ignoring counter set to 0");
-                    return Ok(());
-                }
+        // The counter wraps around after the terminal count
+        assert_eq!(counter.current(357), 0xffff);
+        assert_eq!(counter.next_edge(150), Some((0xce, false)));
+    }
 
-                let duration = core::time::Duration::from_nanos(
-                    PIT_NS_PER_TICK * counter as u64,
-                );
-
-                match channel_state.mode {
-                    OperatingModeState::Mode0 {
-                        ref mut start_counter,
-                        ref mut timer,
-                        ref mut start_time,
-                    } => {
-                        *start_counter = Some(counter);
-                        *start_time = Some(time::now());
-
-                        // Only channel 0 produces timer interrupts
-                        if port == PIT_COUNTER_0 {
-                            //FIXME: this value should be determined by the virtual
-                            // PIC/APIC. Currently use the vector linux has for IRQ0
-                            *timer =
-                                Some(time::set_oneshot_timer(duration, 48));
-                        }
-                    }
+    #[test]
+    fn test_mode3_square_wave() {
+        let mut counter = programmed(OperatingMode::Mode3, AccessMode::LoByte);
+        counter.write(10, 0);
+        assert!(counter.output(4));
+        assert!(!counter.output(5));
+        assert!(counter.output(10));
+        assert_eq!(counter.current(2), 6);
+        assert_eq!(counter.next_edge(3), Some((10, true)));
+    }
 
-                    OperatingModeState::Mode2 {
-                        ref mut start_counter,
-                        ref mut timer,
-                        ref mut start_time,
-                    } => {
-                        *start_counter = Some(counter);
-                        *start_time = Some(time::now());
-
-                        if port == PIT_COUNTER_0 {
-                            //FIXME: this value should be determined by the virtual
-                            // PIC/APIC. Currently use the vector linux has for IRQ0
-                            *timer =
-                                Some(time::set_periodic_timer(duration, 48));
-                        }
-                    }
-                };
-            }
-            _ => {
-                info!("PIT: write to unsupported port: 0x{:x}", port);
-            }
-        }
+    #[test]
+    fn test_latch_and_read_back() {
+        let mut counter = programmed(OperatingMode::Mode2, AccessMode::Word);
+        counter.write(0x34, 0);
+        counter.write(0x12, 0);
+        counter.latch_count(0x10);
+        counter.latch_status(0x10);
 
-        Ok(())
+        // A second latch does not replace the latched count
+        counter.latch_count(0x20);
+        assert_eq!(counter.read(0x30), 0b10110100);
+        assert_eq!(counter.read(0x30), 0x24);
+        assert_eq!(counter.read(0x30), 0x12);
+        assert_eq!(counter.read(0x30), 0x04);
+    }
+
+    #[test]
+    fn test_bcd_count() {
+        let mut counter = Counter::new(true);
+        counter.set_mode(OperatingMode::Mode0, AccessMode::Word, true);
+        counter.write(0x00, 0);
+        counter.write(0x10, 0);
+        assert_eq!(counter.count, 1000);
+        assert_eq!(counter.current(1), 0x0999);
+    }
+
+    #[test]
+    fn test_gate_triggers_mode1() {
+        let mut counter = Counter::new(false);
+        counter.set_mode(OperatingMode::Mode1, AccessMode::LoByte, false);
+        assert!(!counter.write(5, 0));
+        assert!(counter.output(10));
+
+        counter.set_gate(true, 10);
+        assert!(!counter.output(12));
+        assert_eq!(counter.current(12), 3);
+        assert!(counter.output(15));
     }
 }
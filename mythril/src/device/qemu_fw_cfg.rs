@@ -6,6 +6,7 @@ use crate::memory::{
     GuestAccess, GuestAddressSpaceViewMut, GuestPhysAddr, GuestVirtAddr,
     PrivilegeLevel,
 };
+use alloc::borrow::Cow;
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
@@ -116,9 +117,13 @@ impl From<RawFWCfgDmaAccess> for FWCfgDmaAccess {
     }
 }
 
+/// Builder for a `QemuFwCfg` device
+///
+/// The items can borrow the boot modules (e.g., the guest kernel), which
+/// are never freed, so large images are not copied onto the kernel heap.
 pub struct QemuFwCfgBuilder {
     file_info: Vec<FWCfgFile>,
-    data: BTreeMap<u16, Vec<u8>>,
+    data: BTreeMap<u16, Cow<'static, [u8]>>,
 }
 
 impl QemuFwCfgBuilder {
@@ -163,7 +168,7 @@ impl QemuFwCfgBuilder {
             );
         }
 
-        self.data.insert(FwCfgSelector::FILE_DIR, buffer);
+        self.data.insert(FwCfgSelector::FILE_DIR, buffer.into());
 
         Box::new(QemuFwCfg {
             selector: FwCfgSelector::SIGNATURE,
@@ -188,8 +193,9 @@ impl QemuFwCfgBuilder {
     pub fn add_file(
         &mut self,
         name: impl AsRef<str>,
-        data: &[u8],
+        data: impl Into<Cow<'static, [u8]>>,
     ) -> Result<()> {
+        let data = data.into();
         if name.as_ref().len() > FW_CFG_MAX_FILE_NAME {
             return Err(Error::InvalidValue(format!(
                 "qemu_fw_cfg: file name too long: {}",
@@ -214,22 +220,158 @@ impl QemuFwCfgBuilder {
         info.name[..name.len()].copy_from_slice(name);
 
         self.file_info.push(info);
-        self.data.insert(selector, data.to_vec());
+        self.data.insert(selector, data);
         Ok(())
     }
 
+    /// The contents of the file with the given name
+    #[cfg(test)]
+    pub fn file(&self, name: &str) -> Option<&[u8]> {
+        let info = self.file_info.iter().find(|info| {
+            info.name.starts_with(name.as_bytes())
+                && info.name.get(name.len()) == Some(&0)
+        })?;
+        self.data
+            .get(&u16::from_be(info.select))
+            .map(|data| &data[..])
+    }
+
     pub fn add_i32(&mut self, selector: u16, data: i32) {
-        self.data.insert(selector, data.to_le_bytes().to_vec());
+        self.data
+            .insert(selector, data.to_le_bytes().to_vec().into());
+    }
+
+    pub fn add_bytes(
+        &mut self,
+        selector: u16,
+        data: impl Into<Cow<'static, [u8]>>,
+    ) {
+        self.data.insert(selector, data.into());
+    }
+}
+
+// The size of each command of the 'etc/table-loader' file. See
+// hw/acpi/bios-linker-loader.c in QEMU for the format.
+pub const TABLE_LOADER_COMMAND_SIZE: usize = 128;
+
+#[repr(u32)]
+#[derive(Clone, Copy, Debug)]
+enum TableLoaderCommand {
+    Allocate = 1,
+    AddPointer = 2,
+    AddChecksum = 3,
+}
+
+/// The memory zone a `TableLoader` file is allocated in
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TableLoaderZone {
+    /// Anywhere in (32-bit) memory
+    High = 1,
+    /// The F segment (where the BIOS searches for the RSDP)
+    FSeg = 2,
+}
+
+/// The commands used by the BIOS to install a set of fw_cfg files (like
+/// ACPI tables) in guest memory
+///
+/// Pointers between the files are patched by the BIOS once it knows where
+/// each file is loaded, so they must initially contain the offset of the
+/// target in its file.
+#[derive(Default)]
+pub struct TableLoader {
+    commands: Vec<u8>,
+}
+
+impl TableLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn add_command(
+        &mut self,
+        command: TableLoaderCommand,
+        files: &[&str],
+        args: &[u8],
+    ) -> Result<()> {
+        let mut entry = vec![0u8; TABLE_LOADER_COMMAND_SIZE];
+        entry[..4].copy_from_slice(&(command as u32).to_le_bytes());
+
+        let mut offset = 4;
+        for file in files {
+            if file.len() > FW_CFG_MAX_FILE_NAME {
+                return Err(Error::InvalidValue(format!(
+                    "qemu_fw_cfg: file name too long: {}",
+                    file
+                )));
+            }
+            entry[offset..offset + file.len()].copy_from_slice(file.as_bytes());
+            offset += FW_CFG_MAX_FILE_NAME + 1;
+        }
+        entry[offset..offset + args.len()].copy_from_slice(args);
+
+        self.commands.extend_from_slice(&entry);
+        Ok(())
+    }
+
+    /// Allocate memory for `file` with the given alignment and load the
+    /// file into it
+    pub fn allocate(
+        &mut self,
+        file: &str,
+        align: u32,
+        zone: TableLoaderZone,
+    ) -> Result<()> {
+        let mut args = [0u8; 5];
+        args[..4].copy_from_slice(&align.to_le_bytes());
+        args[4] = zone as u8;
+        self.add_command(TableLoaderCommand::Allocate, &[file], &args)
+    }
+
+    /// Add the address of `src_file` to the `size` byte value at `offset`
+    /// in `dest_file`
+    pub fn add_pointer(
+        &mut self,
+        dest_file: &str,
+        src_file: &str,
+        offset: u32,
+        size: u8,
+    ) -> Result<()> {
+        let mut args = [0u8; 5];
+        args[..4].copy_from_slice(&offset.to_le_bytes());
+        args[4] = size;
+        self.add_command(
+            TableLoaderCommand::AddPointer,
+            &[dest_file, src_file],
+            &args,
+        )
+    }
+
+    /// Update the checksum at `offset` in `file`, which covers `length`
+    /// bytes starting at `start`
+    pub fn add_checksum(
+        &mut self,
+        file: &str,
+        offset: u32,
+        start: u32,
+        length: u32,
+    ) -> Result<()> {
+        let mut args = [0u8; 12];
+        args[..4].copy_from_slice(&offset.to_le_bytes());
+        args[4..8].copy_from_slice(&start.to_le_bytes());
+        args[8..].copy_from_slice(&length.to_le_bytes());
+        self.add_command(TableLoaderCommand::AddChecksum, &[file], &args)
     }
 
-    pub fn add_bytes(&mut self, selector: u16, data: &[u8]) {
-        self.data.insert(selector, data.to_vec());
+    /// The contents of the 'etc/table-loader' file
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.commands
     }
 }
 
 pub struct QemuFwCfg {
     selector: u16,
-    data: BTreeMap<u16, Vec<u8>>,
+    data: BTreeMap<u16, Cow<'static, [u8]>>,
     data_idx: usize,
     dma_addr: u64,
 }
@@ -316,6 +458,13 @@ impl QemuFwCfg {
 }
 
 impl EmulatedDevice for QemuFwCfg {
+    fn reset(&mut self) -> Result<()> {
+        self.selector = FwCfgSelector::SIGNATURE;
+        self.data_idx = 0;
+        self.dma_addr = 0;
+        Ok(())
+    }
+
     fn services(&self) -> Vec<DeviceRegion> {
         vec![
             DeviceRegion::PortIo(
@@ -419,4 +568,22 @@ mod test {
         assert!(selector >= FwCfgSelector::FILE_FIRST);
         assert!(selector <= FwCfgSelector::FILE_LAST);
     }
+
+    #[test]
+    fn test_table_loader_pointer() {
+        let mut loader = TableLoader::new();
+        loader
+            .add_pointer("etc/acpi/rsdp", "etc/acpi/tables", 24, 8)
+            .unwrap();
+        let bytes = loader.as_bytes();
+        assert_eq!(bytes.len(), TABLE_LOADER_COMMAND_SIZE);
+        assert_eq!(&bytes[..4], &2u32.to_le_bytes());
+        assert_eq!(&bytes[4..17], b"etc/acpi/rsdp");
+        assert_eq!(&bytes[60..75], b"etc/acpi/tables");
+        assert_eq!(&bytes[116..120], &24u32.to_le_bytes());
+        assert_eq!(bytes[120], 8);
+        assert!(loader
+            .allocate(&"x".repeat(56), 64, TableLoaderZone::High)
+            .is_err());
+    }
 }
@@ -1,9 +1,15 @@
+use crate::device::irq::IrqLine;
 use crate::device::{
     DeviceRegion, EmulatedDevice, Port, PortReadRequest, PortWriteRequest,
 };
-use crate::error::Result;
+use crate::error::{Error, Result};
 use crate::memory::GuestAddressSpaceViewMut;
+use crate::memory_layout::GuestMemoryLayout;
+use crate::rtc::*;
+use crate::time;
+use crate::vm::VmLifecycle;
 use alloc::boxed::Box;
+use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::convert::{TryFrom, TryInto};
 use num_enum::TryFromPrimitive;
@@ -61,57 +67,563 @@ enum CmosRegister {
     QemuMemAbove4GbLsb = 0x5b,
     QemuMemAbove4GbMmsb = 0x5c,
     QemuMemAbove4GbMsb = 0x5d,
+}
+
+impl CmosRegister {
+    /// Whether this is one of the registers holding the time and date
+    fn is_time(&self) -> bool {
+        match self {
+            CmosRegister::Seconds
+            | CmosRegister::Minutes
+            | CmosRegister::Hours
+            | CmosRegister::DayOfWeek
+            | CmosRegister::DayOfMonth
+            | CmosRegister::Month
+            | CmosRegister::Year
+            | CmosRegister::BcdCenturyDate => true,
+            _ => false,
+        }
+    }
 
-    // Not in any spec, used to represent unknown values
-    Unknown = 0xff,
+    fn is_alarm(&self) -> bool {
+        match self {
+            CmosRegister::SecondsAlarm
+            | CmosRegister::MinutesAlarm
+            | CmosRegister::HoursAlarm => true,
+            _ => false,
+        }
+    }
 }
 
+/// The size of the CMOS memory (including the clock registers)
+pub const NVRAM_SIZE: usize = 128;
+
+// The shutdown status that tells the BIOS to resume from S3 (by jumping to
+// the waking vector in the FACS) instead of booting
+const SHUTDOWN_STATUS_S3_RESUME: u8 = 0xfe;
+
+// The default register A (a 32.768kHz time base and a 1024Hz periodic
+// rate) and register B (24 hour BCD mode)
+const DEFAULT_STATUS_A: u8 = 0x26;
+const DEFAULT_STATUS_B: u8 = RTC_B_24HOUR;
+
+const RTC_A_RATE_MASK: u8 = 0x0f;
+
+const RTC_B_SET: u8 = 1 << 7;
+const RTC_B_PIE: u8 = 1 << 6;
+const RTC_B_AIE: u8 = 1 << 5;
+const RTC_B_UIE: u8 = 1 << 4;
+const RTC_B_SQWE: u8 = 1 << 3;
+
+// The flags of register C use the same bits as the enables in register B
+const RTC_C_IRQF: u8 = 1 << 7;
+const RTC_C_PF: u8 = RTC_B_PIE;
+const RTC_C_AF: u8 = RTC_B_AIE;
+const RTC_C_UF: u8 = RTC_B_UIE;
+
+// The valid RAM and time bit of register D
+const RTC_D_VRT: u8 = 1 << 7;
+
+// An alarm register with both top bits set matches any value
+const RTC_ALARM_DONT_CARE: u8 = 0xc0;
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+const SECS_PER_DAY: u64 = 86400;
+
+// UIP is set this long before each update of the time
+const UIP_NS: u64 = 244_000;
+
+/// An emulated MC146818 real time clock and its CMOS memory
+///
+/// The time and date advance from the configured base time, and IRQ8 is
+/// raised for the periodic, alarm and update-ended interrupts until the
+/// guest reads register C.
 pub struct CmosRtc {
-    addr: CmosRegister,
-    data: [u8; 256],
+    index: u8,
+    nmi_masked: bool,
+    data: [u8; NVRAM_SIZE],
+
+    // The guest time (in seconds since the UNIX epoch) at `base_ns`. The
+    // clock does not advance while register B has the SET bit.
+    base_time: u64,
+    base_ns: u64,
+
+    // The interrupt flags of register C, and when they were last updated
+    flags: u8,
+    flags_ns: u64,
+
+    irq: IrqLine,
+    timer: Option<time::TimerId>,
+    lifecycle: Arc<VmLifecycle>,
 }
 
 impl CmosRtc {
-    const RTC_ADDRESS: Port = 0x0070;
-    const RTC_DATA: Port = 0x0071;
+    /// Create a new `CmosRtc`
+    ///
+    /// # Arguments
+    ///
+    /// * `layout` - The memory layout reported to the BIOS
+    /// * `nvram` - The CMOS contents to restore (see `nvram`), if any
+    /// * `base_time` - The initial time (in seconds since the UNIX epoch)
+    /// * `irq` - The interrupt line of the RTC (IRQ8)
+    /// * `lifecycle` - The lifecycle of the VM
+    pub fn new(
+        layout: &GuestMemoryLayout,
+        nvram: Option<&[u8]>,
+        base_time: u64,
+        irq: IrqLine,
+        lifecycle: Arc<VmLifecycle>,
+    ) -> Result<Box<Self>> {
+        let mut data = [0u8; NVRAM_SIZE];
+        data[RTC_STATUS_A as usize] = DEFAULT_STATUS_A;
+        data[RTC_STATUS_B as usize] = DEFAULT_STATUS_B;
+        if let Some(nvram) = nvram {
+            Self::restore_nvram(&mut data, nvram)?;
+        }
+        Self::set_memory_registers(&mut data, layout);
 
-    pub fn new(mem: u64) -> Box<Self> {
-        Box::new(Self {
-            addr: CmosRegister::Seconds, // For now, just set the default reg as seconds
-            data: Self::default_register_values(mem),
-        })
+        let now = lifecycle.clock().now_ns();
+        Ok(Box::new(Self {
+            index: 0,
+            nmi_masked: false,
+            data: data,
+            base_time: base_time,
+            base_ns: now,
+            flags: 0,
+            flags_ns: now,
+            irq: irq,
+            timer: None,
+            lifecycle: lifecycle,
+        }))
     }
 
-    fn default_register_values(mem: u64) -> [u8; 256] {
-        //TODO: support memory above 4GB
+    /// The contents of the CMOS memory (which can be restored with `new`)
+    pub fn nvram(&mut self) -> [u8; NVRAM_SIZE] {
+        self.refresh_time(self.lifecycle.clock().now_ns());
+        self.data
+    }
 
-        let mut data = [0u8; 256];
+    /// The wall clock time (in nanoseconds since the UNIX epoch) when the
+    /// guest time was zero
+    ///
+    /// This is used as the boot time reported by kvmclock. It is `None` if
+    /// the time is too far in the future to be counted in nanoseconds.
+    pub fn boot_time_ns(&self) -> Option<u64> {
+        self.base_time
+            .checked_mul(NS_PER_SEC)
+            .map(|ns| ns.saturating_sub(self.base_ns))
+    }
 
-        let megs_under_4gb = mem & 0xfff;
-        // Subtrack 16 because it's really 'blocks_under_4gb_over_16mb'
-        // Shift by 4 because each 'block' is 64KiB
-        let blocks_under_4gb: u16 = ((megs_under_4gb - 16) << 4) as u16;
+    /// Whether the guest has disabled NMIs (with bit 7 of port 0x70)
+    pub fn nmi_masked(&self) -> bool {
+        self.nmi_masked
+    }
+
+    fn restore_nvram(data: &mut [u8; NVRAM_SIZE], nvram: &[u8]) -> Result<()> {
+        if nvram.len() != NVRAM_SIZE {
+            return Err(Error::InvalidValue(format!(
+                "Invalid CMOS NVRAM size {} (expected {})",
+                nvram.len(),
+                NVRAM_SIZE
+            )));
+        }
+
+        // The time comes from the clock, and registers C and D only hold
+        // status
+        for (i, byte) in nvram.iter().enumerate() {
+            match CmosRegister::try_from(i as u8) {
+                Ok(reg) if reg.is_time() => (),
+                Ok(CmosRegister::StatusRegisterC)
+                | Ok(CmosRegister::StatusRegisterD) => (),
+                _ => data[i] = *byte,
+            }
+        }
+        data[RTC_STATUS_B as usize] &= !RTC_B_SET;
+        Ok(())
+    }
+
+    fn set_memory_registers(
+        data: &mut [u8; NVRAM_SIZE],
+        layout: &GuestMemoryLayout,
+    ) {
+        // Subtract 16MB because it's really 'blocks_under_4gb_over_16mb'
+        // Shift by 16 because each 'block' is 64KiB
+        let blocks_under_4gb = core::cmp::min(
+            layout.ram_below_4gb().saturating_sub(16 << 20) >> 16,
+            0xffff,
+        );
+        let blocks_above_4gb = layout.ram_above_4gb() >> 16;
 
         let defaults = [
-            // The MSB of register D indicates the CMOS battery is working
-            (CmosRegister::StatusRegisterD, 0b10000000),
             (CmosRegister::QemuMemAbove16MbLsb, blocks_under_4gb as u8),
             (
                 CmosRegister::QemuMemAbove16MbMsb,
                 (blocks_under_4gb >> 8) as u8,
             ),
+            (CmosRegister::QemuMemAbove4GbLsb, blocks_above_4gb as u8),
+            (
+                CmosRegister::QemuMemAbove4GbMmsb,
+                (blocks_above_4gb >> 8) as u8,
+            ),
+            (
+                CmosRegister::QemuMemAbove4GbMsb,
+                (blocks_above_4gb >> 16) as u8,
+            ),
         ];
         for &(reg, val) in &defaults {
             data[reg as usize] = val
         }
-        data
+    }
+
+    fn status_b(&self) -> u8 {
+        self.data[RTC_STATUS_B as usize]
+    }
+
+    fn is_set(&self) -> bool {
+        self.status_b() & RTC_B_SET != 0
+    }
+
+    /// The guest time (in seconds since the UNIX epoch)
+    fn clock(&self, now: u64) -> u64 {
+        if self.is_set() {
+            self.base_time
+        } else {
+            self.base_time + now.saturating_sub(self.base_ns) / NS_PER_SEC
+        }
+    }
+
+    /// The number of nanoseconds since the last update of the time
+    fn ns_since_update(&self, now: u64) -> u64 {
+        now.saturating_sub(self.base_ns) % NS_PER_SEC
+    }
+
+    /// Encode a time or date register in the format of register B
+    fn encode(&self, val: u8) -> u8 {
+        if self.status_b() & RTC_B_BINARY != 0 {
+            val
+        } else {
+            to_bcd(val)
+        }
+    }
+
+    fn decode(&self, val: u8) -> u8 {
+        if self.status_b() & RTC_B_BINARY != 0 {
+            val
+        } else {
+            from_bcd(val)
+        }
+    }
+
+    fn encode_hours(&self, hour: u8) -> u8 {
+        if self.status_b() & RTC_B_24HOUR != 0 {
+            return self.encode(hour);
+        }
+        let pm = if hour >= 12 { RTC_HOURS_PM } else { 0 };
+        match hour % 12 {
+            0 => self.encode(12) | pm,
+            hour => self.encode(hour) | pm,
+        }
+    }
+
+    fn decode_hours(&self, val: u8) -> u8 {
+        if self.status_b() & RTC_B_24HOUR != 0 {
+            return self.decode(val);
+        }
+        let hour = self.decode(val & !RTC_HOURS_PM) % 12;
+        if val & RTC_HOURS_PM != 0 {
+            hour + 12
+        } else {
+            hour
+        }
+    }
+
+    /// Write the current time to the time and date registers
+    fn refresh_time(&mut self, now: u64) {
+        if self.is_set() {
+            return;
+        }
+        let date = DateTime::from_unix(self.clock(now));
+        let registers = [
+            (CmosRegister::Seconds, self.encode(date.second)),
+            (CmosRegister::Minutes, self.encode(date.minute)),
+            (CmosRegister::Hours, self.encode_hours(date.hour)),
+            (CmosRegister::DayOfWeek, self.encode(date.day_of_week())),
+            (CmosRegister::DayOfMonth, self.encode(date.day)),
+            (CmosRegister::Month, self.encode(date.month)),
+            (CmosRegister::Year, self.encode((date.year % 100) as u8)),
+            (
+                CmosRegister::BcdCenturyDate,
+                self.encode((date.year / 100) as u8),
+            ),
+        ];
+        for &(reg, val) in &registers {
+            self.data[reg as usize] = val;
+        }
+    }
+
+    /// The time in the time and date registers
+    fn register_time(&self) -> u64 {
+        let reg = |reg: CmosRegister| self.data[reg as usize];
+        DateTime {
+            year: self.decode(reg(CmosRegister::BcdCenturyDate)) as u32 * 100
+                + self.decode(reg(CmosRegister::Year)) as u32,
+            month: self.decode(reg(CmosRegister::Month)),
+            day: self.decode(reg(CmosRegister::DayOfMonth)),
+            hour: self.decode_hours(reg(CmosRegister::Hours)),
+            minute: self.decode(reg(CmosRegister::Minutes)),
+            second: self.decode(reg(CmosRegister::Seconds)),
+        }
+        .to_unix()
+    }
+
+    /// The period of the periodic interrupt (in nanoseconds)
+    fn periodic_ns(&self) -> Option<u64> {
+        // Rates 1 and 2 are the same as 8 and 9
+        let rate = match self.data[RTC_STATUS_A as usize] & RTC_A_RATE_MASK {
+            0 => return None,
+            rate @ 1..=2 => rate + 7,
+            rate => rate,
+        };
+        Some((NS_PER_SEC << rate) / 65536)
+    }
+
+    /// The values below `limit` (in increasing order) that match an alarm
+    /// register
+    fn alarm_values(&self, reg: CmosRegister, limit: u8) -> Vec<u8> {
+        let alarm = self.data[reg as usize];
+        (0..limit)
+            .filter(|&val| {
+                let encoded = match reg {
+                    CmosRegister::HoursAlarm => self.encode_hours(val),
+                    _ => self.encode(val),
+                };
+                alarm & RTC_ALARM_DONT_CARE == RTC_ALARM_DONT_CARE
+                    || alarm == encoded
+            })
+            .collect()
+    }
+
+    /// The first time after `time` (in seconds since the UNIX epoch) that
+    /// matches the alarm, if any
+    fn next_alarm(&self, time: u64) -> Option<u64> {
+        let hours = self.alarm_values(CmosRegister::HoursAlarm, 24);
+        let minutes = self.alarm_values(CmosRegister::MinutesAlarm, 60);
+        let seconds = self.alarm_values(CmosRegister::SecondsAlarm, 60);
+
+        // The first matching second of the day at or after `from`
+        let first_match = |from: u64| {
+            for &hour in hours.iter() {
+                for &minute in minutes.iter() {
+                    let start = hour as u64 * 3600 + minute as u64 * 60;
+                    if start + 59 < from {
+                        continue;
+                    }
+                    if let Some(&second) =
+                        seconds.iter().find(|&&s| start + s as u64 >= from)
+                    {
+                        return Some(start + second as u64);
+                    }
+                }
+            }
+            None
+        };
+
+        // The alarm only matches the time of day, so the next match is
+        // either later on the same day or on the next one
+        let next = time + 1;
+        let day = next - next % SECS_PER_DAY;
+        first_match(next % SECS_PER_DAY)
+            .map(|second| day + second)
+            .or_else(|| {
+                first_match(0).map(|second| day + SECS_PER_DAY + second)
+            })
+    }
+
+    /// Set the flags of the events that occurred since the last update, and
+    /// IRQF if any of them is enabled
+    fn update_flags(&mut self, now: u64) {
+        let last = self.flags_ns;
+        if now > last {
+            if let Some(period) = self.periodic_ns() {
+                if now / period > last / period {
+                    self.flags |= RTC_C_PF;
+                }
+            }
+
+            if !self.is_set() {
+                let (start, end) = (self.clock(last), self.clock(now));
+                if end > start {
+                    self.flags |= RTC_C_UF;
+                }
+
+                if self.next_alarm(start).map_or(false, |time| time <= end) {
+                    self.flags |= RTC_C_AF;
+                }
+            }
+            self.flags_ns = now;
+        }
+
+        let enabled = self.status_b() & (RTC_B_PIE | RTC_B_AIE | RTC_B_UIE);
+        if self.flags & enabled != 0 {
+            self.flags |= RTC_C_IRQF;
+        } else {
+            self.flags &= !RTC_C_IRQF;
+        }
+    }
+
+    /// The number of nanoseconds until the next enabled interrupt
+    fn next_interrupt(&self, now: u64) -> Option<u64> {
+        let status_b = self.status_b();
+        let mut delays = vec![];
+
+        if status_b & RTC_B_PIE != 0 {
+            if let Some(period) = self.periodic_ns() {
+                delays.push(period - now % period);
+            }
+        }
+
+        if !self.is_set() {
+            let to_update = NS_PER_SEC - self.ns_since_update(now);
+            if status_b & RTC_B_UIE != 0 {
+                delays.push(to_update);
+            }
+            if status_b & RTC_B_AIE != 0 {
+                let time = self.clock(now);
+                if let Some(alarm) = self.next_alarm(time) {
+                    delays.push(to_update + (alarm - time - 1) * NS_PER_SEC);
+                }
+            }
+        }
+        delays.into_iter().min()
+    }
+
+    /// Assert IRQ8 if an enabled interrupt is pending, or set the timer that
+    /// raises it for the next one
+    fn update_irq(&mut self, now: u64) {
+        if let Some(id) = self.timer.take() {
+            // The timer may have already expired, so ignore any error
+            let _ = time::cancel_timer(&id);
+        }
+
+        // IRQ8 remains asserted until register C is read
+        if self.flags & RTC_C_IRQF != 0 {
+            self.irq.raise();
+            return;
+        }
+
+        if let Some(delay) = self.next_interrupt(now) {
+            self.timer = Some(time::set_oneshot_timer(
+                core::time::Duration::from_nanos(delay),
+                time::TimerInterruptType::RaiseIrq(self.irq.clone()),
+            ));
+        }
+    }
+
+    fn read_register(&mut self, now: u64) -> u8 {
+        let index = self.index as usize;
+        match CmosRegister::try_from(self.index) {
+            Ok(CmosRegister::StatusRegisterA) => {
+                let val = self.data[index] & !RTC_A_UIP;
+                if !self.is_set()
+                    && self.ns_since_update(now) >= NS_PER_SEC - UIP_NS
+                {
+                    val | RTC_A_UIP
+                } else {
+                    val
+                }
+            }
+            Ok(CmosRegister::StatusRegisterC) => {
+                // Reading register C acknowledges the interrupt
+                self.update_flags(now);
+                let flags = self.flags;
+                self.flags = 0;
+                self.irq.lower();
+                self.update_irq(now);
+                flags
+            }
+            Ok(CmosRegister::StatusRegisterD) => RTC_D_VRT,
+            Ok(reg) if reg.is_time() => {
+                self.refresh_time(now);
+                self.data[index]
+            }
+            _ => self.data[index],
+        }
+    }
+
+    fn write_register(&mut self, val: u8, now: u64) {
+        let index = self.index as usize;
+        match CmosRegister::try_from(self.index) {
+            Ok(CmosRegister::StatusRegisterA) => {
+                self.update_flags(now);
+                self.data[index] = val & !RTC_A_UIP;
+                self.update_irq(now);
+            }
+            Ok(CmosRegister::StatusRegisterB) => {
+                self.update_flags(now);
+                self.refresh_time(now);
+                let time = self.clock(now);
+                let was_set = self.is_set();
+                self.data[index] = val;
+
+                // Setting SET stops the clock, and clearing it restarts the
+                // clock from the time written by the guest
+                match (was_set, self.is_set()) {
+                    (false, true) => self.base_time = time,
+                    (true, false) => {
+                        self.base_time = self.register_time();
+                        self.base_ns = now;
+                    }
+                    _ => (),
+                }
+                self.update_flags(now);
+                self.update_irq(now);
+            }
+            Ok(CmosRegister::StatusRegisterC)
+            | Ok(CmosRegister::StatusRegisterD) => {
+                // Status register C and D are read-only (but OVMF will attempt
+                // to write to them, so we must explicitly ignore the writes)
+            }
+            Ok(reg) if reg.is_time() || reg.is_alarm() => {
+                self.update_flags(now);
+                self.refresh_time(now);
+                self.data[index] = val;
+
+                // Writing the time while the clock runs restarts it
+                if reg.is_time() && !self.is_set() {
+                    self.base_time = self.register_time();
+                    self.base_ns = now;
+                }
+                self.update_irq(now);
+            }
+            _ => self.data[index] = val,
+        }
     }
 }
 
-//TODO: support the NMI masking stuff
 impl EmulatedDevice for CmosRtc {
+    fn reset(&mut self) -> Result<()> {
+        // The CMOS contents and the time survive a reset, but the interrupts
+        // are disabled
+        self.data[RTC_STATUS_B as usize] &=
+            !(RTC_B_PIE | RTC_B_AIE | RTC_B_UIE | RTC_B_SQWE);
+        self.flags = 0;
+        self.irq.lower();
+        if let Some(id) = self.timer.take() {
+            let _ = time::cancel_timer(&id);
+        }
+
+        // The BIOS must be told when the reset is a wake from S3
+        self.data[CmosRegister::ShutdownStatus as usize] =
+            if self.lifecycle.waking() {
+                SHUTDOWN_STATUS_S3_RESUME
+            } else {
+                0
+            };
+        Ok(())
+    }
+
     fn services(&self) -> Vec<DeviceRegion> {
-        vec![DeviceRegion::PortIo(Self::RTC_ADDRESS..=Self::RTC_DATA)]
+        vec![DeviceRegion::PortIo(RTC_ADDRESS..=RTC_DATA)]
     }
 
     fn on_port_read(
@@ -121,12 +633,11 @@ impl EmulatedDevice for CmosRtc {
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
         match port {
-            Self::RTC_ADDRESS => val.copy_from_u32(self.addr as u8 as u32),
-            Self::RTC_DATA => match self.addr {
-                addr => {
-                    val.copy_from_u32(self.data[addr as usize] as u32);
-                }
-            },
+            RTC_ADDRESS => val.copy_from_u32(self.index as u32),
+            RTC_DATA => {
+                let data = self.read_register(self.lifecycle.clock().now_ns());
+                val.copy_from_u32(data as u32);
+            }
             _ => unreachable!(),
         }
 
@@ -139,36 +650,181 @@ impl EmulatedDevice for CmosRtc {
         val: PortWriteRequest,
         _space: GuestAddressSpaceViewMut,
     ) -> Result<()> {
-        // For now, just ignore the NMI masking
         let val: u8 = val.try_into()?;
-        let val = val & 0x7f;
 
         match port {
-            Self::RTC_ADDRESS => {
+            RTC_ADDRESS => {
+                //NOTE: no NMI sources are emulated, so the mask is only
+                //      recorded
+                self.nmi_masked = val & RTC_NMI_DISABLE != 0;
+
                 // OVMF expects to be able to read pretty much any address
                 // (and just get zeros for meaningless ones)
-                self.addr = CmosRegister::try_from(val)
-                    .unwrap_or(CmosRegister::Unknown);
-            }
-            Self::RTC_DATA => {
-                match self.addr {
-                    CmosRegister::ShutdownStatus => {
-                        // It's not clear what's supposed to happen here, just ignore
-                        // it for now
-                    }
-                    CmosRegister::StatusRegisterD
-                    | CmosRegister::StatusRegisterC => {
-                        // Status register C and D are read-only (but OVMF will attempt
-                        // to write to them, so we must explicitly ignore the writes)
-                    }
-                    addr => {
-                        // For now, any other register write is just directly performed
-                        self.data[addr as usize] = val;
-                    }
-                }
+                self.index = val & !RTC_NMI_DISABLE;
+            }
+            RTC_DATA => {
+                self.write_register(val, self.lifecycle.clock().now_ns())
             }
             _ => unreachable!(),
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::ioapic::IoApic;
+    use crate::device::irq::IrqRouter;
+    use crate::device::pic::Pic8259;
+    use spin::Mutex;
+
+    // 2000-02-29 12:34:56 (a Tuesday)
+    const TEST_TIME: u64 = 951_827_696;
+
+    // The tests never assert the irq line, or set any timers
+    fn define_test_rtc() -> CmosRtc {
+        let router = IrqRouter::new(
+            Arc::new(Mutex::new(Pic8259::default())),
+            Arc::new(Mutex::new(IoApic::new(vec![]))),
+            vec![],
+            0,
+        );
+        let mut data = [0u8; NVRAM_SIZE];
+        data[RTC_STATUS_A as usize] = DEFAULT_STATUS_A;
+        data[RTC_STATUS_B as usize] = DEFAULT_STATUS_B;
+        CmosRtc {
+            index: 0,
+            nmi_masked: false,
+            data: data,
+            base_time: TEST_TIME,
+            base_ns: 0,
+            flags: 0,
+            flags_ns: 0,
+            irq: IrqLine::new(8, Arc::new(router)),
+            timer: None,
+            lifecycle: Arc::new(VmLifecycle::new(vec![])),
+        }
+    }
+
+    fn read(rtc: &mut CmosRtc, reg: CmosRegister, now: u64) -> u8 {
+        rtc.index = reg as u8;
+        rtc.read_register(now)
+    }
+
+    fn write(rtc: &mut CmosRtc, reg: CmosRegister, val: u8, now: u64) {
+        rtc.index = reg as u8;
+        rtc.write_register(val, now)
+    }
+
+    #[test]
+    fn test_rtc_clock() {
+        let mut rtc = define_test_rtc();
+        assert_eq!(read(&mut rtc, CmosRegister::Seconds, 0), 0x56);
+        assert_eq!(read(&mut rtc, CmosRegister::Seconds, 2 * NS_PER_SEC), 0x58);
+        assert_eq!(read(&mut rtc, CmosRegister::Hours, 0), 0x12);
+        assert_eq!(read(&mut rtc, CmosRegister::DayOfWeek, 0), 3);
+        assert_eq!(read(&mut rtc, CmosRegister::Month, 0), 0x02);
+        assert_eq!(read(&mut rtc, CmosRegister::BcdCenturyDate, 0), 0x20);
+
+        // Binary and 12 hour formats
+        rtc.data[RTC_STATUS_B as usize] = RTC_B_BINARY;
+        assert_eq!(read(&mut rtc, CmosRegister::Minutes, 0), 34);
+        assert_eq!(read(&mut rtc, CmosRegister::Hours, 0), 12 | RTC_HOURS_PM);
+
+        // UIP is set just before the time changes
+        assert_eq!(read(&mut rtc, CmosRegister::StatusRegisterA, 0), 0x26);
+        assert_eq!(
+            read(&mut rtc, CmosRegister::StatusRegisterA, NS_PER_SEC - 1000),
+            0x26 | RTC_A_UIP
+        );
+    }
+
+    #[test]
+    fn test_rtc_set_time() {
+        let mut rtc = define_test_rtc();
+        write(&mut rtc, CmosRegister::StatusRegisterB, 0x82, NS_PER_SEC);
+        write(&mut rtc, CmosRegister::Hours, 0x08, 2 * NS_PER_SEC);
+
+        // The clock is stopped until SET is cleared
+        assert_eq!(read(&mut rtc, CmosRegister::Seconds, 4 * NS_PER_SEC), 0x57);
+        write(
+            &mut rtc,
+            CmosRegister::StatusRegisterB,
+            0x02,
+            5 * NS_PER_SEC,
+        );
+        assert_eq!(read(&mut rtc, CmosRegister::Seconds, 6 * NS_PER_SEC), 0x58);
+        assert_eq!(read(&mut rtc, CmosRegister::Hours, 6 * NS_PER_SEC), 0x08);
+        assert_eq!(rtc.clock(6 * NS_PER_SEC), TEST_TIME - 4 * 3600 + 2);
+    }
+
+    #[test]
+    fn test_rtc_interrupt_flags() {
+        let mut rtc = define_test_rtc();
+        rtc.data[RTC_STATUS_B as usize] |= RTC_B_UIE;
+        rtc.data[CmosRegister::SecondsAlarm as usize] = 0x58;
+        rtc.data[CmosRegister::MinutesAlarm as usize] = RTC_ALARM_DONT_CARE;
+        rtc.data[CmosRegister::HoursAlarm as usize] = 0x12;
+
+        // A periodic interrupt (at 1024Hz), which is not enabled
+        rtc.update_flags(2_000_000);
+        assert_eq!(rtc.flags, RTC_C_PF);
+
+        rtc.update_flags(NS_PER_SEC + 1);
+        assert_eq!(rtc.flags, RTC_C_IRQF | RTC_C_PF | RTC_C_UF);
+
+        rtc.flags = 0;
+        rtc.update_flags(2 * NS_PER_SEC + 1);
+        assert_eq!(rtc.flags, RTC_C_IRQF | RTC_C_PF | RTC_C_UF | RTC_C_AF);
+        assert_eq!(
+            rtc.next_interrupt(2 * NS_PER_SEC + 1),
+            Some(NS_PER_SEC - 1)
+        );
+    }
+
+    #[test]
+    fn test_rtc_next_alarm() {
+        let mut rtc = define_test_rtc();
+        let day = TEST_TIME - TEST_TIME % SECS_PER_DAY;
+        rtc.data[CmosRegister::SecondsAlarm as usize] = 0x30;
+        rtc.data[CmosRegister::MinutesAlarm as usize] = RTC_ALARM_DONT_CARE;
+        rtc.data[CmosRegister::HoursAlarm as usize] = 0x12;
+        assert_eq!(rtc.next_alarm(day), Some(day + 12 * 3600 + 30));
+        assert_eq!(
+            rtc.next_alarm(day + 12 * 3600 + 30),
+            Some(day + 12 * 3600 + 90)
+        );
+
+        // Once the last match of the day has passed, the alarm is tomorrow
+        assert_eq!(
+            rtc.next_alarm(day + 12 * 3600 + 59 * 60 + 30),
+            Some(day + SECS_PER_DAY + 12 * 3600 + 30)
+        );
+
+        // An alarm that matches no time never fires
+        rtc.data[CmosRegister::SecondsAlarm as usize] = 0x60;
+        assert_eq!(rtc.next_alarm(day), None);
+    }
+
+    #[test]
+    fn test_rtc_boot_time() {
+        let mut rtc = define_test_rtc();
+        assert_eq!(rtc.boot_time_ns(), Some(TEST_TIME * NS_PER_SEC));
+        rtc.base_time = u64::MAX / NS_PER_SEC + 1;
+        assert_eq!(rtc.boot_time_ns(), None);
+    }
+
+    #[test]
+    fn test_rtc_restore_nvram() {
+        let mut nvram = [0xaa; NVRAM_SIZE];
+        nvram[RTC_STATUS_B as usize] = RTC_B_SET | RTC_B_BINARY;
+        let mut data = [0u8; NVRAM_SIZE];
+        CmosRtc::restore_nvram(&mut data, &nvram).unwrap();
+        assert_eq!(data[CmosRegister::Seconds as usize], 0);
+        assert_eq!(data[CmosRegister::SecondsAlarm as usize], 0xaa);
+        assert_eq!(data[RTC_STATUS_B as usize], RTC_B_BINARY);
+        assert_eq!(data[0x40], 0xaa);
+        assert!(CmosRtc::restore_nvram(&mut data, &nvram[..64]).is_err());
+    }
+}
@@ -1,30 +1,444 @@
-use crate::error::Result;
+use crate::emulate::pvclock;
+use crate::error::{Error, Result};
 use crate::{vcpu, vmexit};
+use alloc::vec::Vec;
+use core::str::FromStr;
+use raw_cpuid::CpuIdResult;
+
+const HYPERVISOR_LEAF_BASE: u32 = 0x40000000;
+const HYPERVISOR_LEAF_LAST: u32 = 0x400000ff;
+const EXTENDED_LEAF_BASE: u32 = 0x80000000;
+
+const DEFAULT_MAX_BASIC_LEAF: u32 = 0x1f;
+const DEFAULT_MAX_EXTENDED_LEAF: u32 = 0x80000008;
+
+// Reported in ebx, ecx and edx of leaf 0x40000000
+//
+// This is the KVM signature, so guests will use the kvmclock interface
+const HYPERVISOR_SIGNATURE: &[u8; 12] = b"KVMKVMKVM\0\0\0";
+
+const KVM_LEAF_FEATURES: u32 = 0x40000001;
+
+// Features that are not supported in the guest
+const LEAF1_ECX_DISABLED: u32 = (1 << 2) // DTES64
+    | (1 << 4) // DS-CPL
+    | (1 << 5) // VMX
+    | (1 << 6) // SMX
+    | (1 << 7) // EST
+    | (1 << 8) // TM2
+    | (1 << 15) // PDCM
+    | (1 << 26) // XSAVE
+    | (1 << 27); // OSXSAVE
+const LEAF1_EDX_DISABLED: u32 = (1 << 12) // MTRR
+    | (1 << 21) // DS
+    | (1 << 22) // ACPI
+    | (1 << 29) // TM
+    | (1 << 31); // PBE
+const LEAF7_EBX_DISABLED: u32 = (1 << 2) // SGX
+    | (1 << 25); // Intel PT
+
+// The speculation control MSRs (IA32_SPEC_CTRL, IA32_PRED_CMD,
+// IA32_FLUSH_CMD and IA32_ARCH_CAPABILITIES) are not emulated, so the guest
+// must not try to use them
+const LEAF7_EDX_DISABLED: u32 = (1 << 26) // IBRS and IBPB
+    | (1 << 27) // STIBP
+    | (1 << 28) // L1D_FLUSH
+    | (1 << 29) // ARCH_CAPABILITIES
+    | (1 << 31); // SSBD
+const LEAF_80000008_EBX_DISABLED: u32 = (1 << 12) // IBPB
+    | (1 << 14) // IBRS
+    | (1 << 15) // STIBP
+    | (1 << 24) // SSBD
+    | (1 << 25); // VIRT_SSBD
+
+// The virtual local APIC supports x2APIC mode and the TSC deadline timer,
+// regardless of the host.
+const LEAF1_ECX_ENABLED: u32 = (1 << 21) // x2APIC
+    | (1 << 24) // TSC-deadline
+    | (1 << 31); // Hypervisor
+const LEAF1_EDX_ENABLED: u32 = 1 << 28; // HTT
+
+// The APIC timer runs at a constant rate
+const LEAF6_EAX_ARAT: u32 = 1 << 2;
+
+fn native_cpuid(leaf: u32, subleaf: u32) -> CpuIdResult {
+    raw_cpuid::native_cpuid::cpuid_count(leaf, subleaf)
+}
+
+/// A CPUID output register
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CpuidRegister {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+impl FromStr for CpuidRegister {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "eax" => Ok(CpuidRegister::Eax),
+            "ebx" => Ok(CpuidRegister::Ebx),
+            "ecx" => Ok(CpuidRegister::Ecx),
+            "edx" => Ok(CpuidRegister::Edx),
+            _ => Err(Error::InvalidValue(format!(
+                "Invalid CPUID register '{}'",
+                s
+            ))),
+        }
+    }
+}
+
+/// A replacement of some bits of the result of a CPUID leaf
+///
+/// An override can be parsed from a string of the form
+/// `LEAF[.SUBLEAF]:REGISTER:VALUE:MASK`, where the bits set in `MASK` are
+/// taken from `VALUE`. For example, `0x7.0:ebx:0:0x20` hides AVX2.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpuidOverride {
+    /// The CPUID leaf (the value of eax)
+    pub leaf: u32,
+
+    /// The subleaf (the value of ecx), or `None` to match all subleaves
+    pub subleaf: Option<u32>,
+
+    /// The output register to modify
+    pub register: CpuidRegister,
+
+    /// The new value of the bits selected by `mask`
+    pub value: u32,
+
+    /// The bits of the register that are replaced
+    pub mask: u32,
+}
+
+impl CpuidOverride {
+    fn matches(&self, leaf: u32, subleaf: u32) -> bool {
+        self.leaf == leaf && self.subleaf.map_or(true, |s| s == subleaf)
+    }
+
+    fn apply(&self, res: &mut CpuIdResult) {
+        let reg = match self.register {
+            CpuidRegister::Eax => &mut res.eax,
+            CpuidRegister::Ebx => &mut res.ebx,
+            CpuidRegister::Ecx => &mut res.ecx,
+            CpuidRegister::Edx => &mut res.edx,
+        };
+        *reg = (*reg & !self.mask) | (self.value & self.mask);
+    }
+}
+
+fn parse_u32(s: &str) -> Result<u32> {
+    let val = if s.starts_with("0x") {
+        u32::from_str_radix(&s[2..], 16)
+    } else {
+        s.parse::<u32>()
+    };
+    val.map_err(|_| Error::InvalidValue(format!("Invalid integer '{}'", s)))
+}
+
+impl FromStr for CpuidOverride {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(':').map(|part| part.trim()).collect();
+        if parts.len() != 4 {
+            return Err(Error::InvalidValue(format!(
+                "Invalid CPUID override '{}'",
+                s
+            )));
+        }
+
+        let mut leaf = parts[0].splitn(2, '.');
+        Ok(CpuidOverride {
+            leaf: parse_u32(leaf.next().unwrap_or(""))?,
+            subleaf: leaf.next().map(parse_u32).transpose()?,
+            register: parts[1].parse()?,
+            value: parse_u32(parts[2])?,
+            mask: parse_u32(parts[3])?,
+        })
+    }
+}
+
+/// The CPUID values reported to the guests of a VM
+///
+/// The results are based on the host CPUID, with unsupported features
+/// hidden, the topology of the VM, and any additional overrides.
+#[derive(Clone, Debug)]
+pub struct CpuidPolicy {
+    nb_cpus: u32,
+    max_basic_leaf: u32,
+    max_extended_leaf: u32,
+    overrides: Vec<CpuidOverride>,
+}
+
+impl CpuidPolicy {
+    /// Create a new `CpuidPolicy` for a VM with `nb_cpus` vcpus
+    pub fn new(nb_cpus: u32) -> Self {
+        let mut policy = Self {
+            nb_cpus: nb_cpus.max(1),
+            max_basic_leaf: 0,
+            max_extended_leaf: 0,
+            overrides: vec![],
+        };
+        policy.set_max_basic_leaf(DEFAULT_MAX_BASIC_LEAF);
+        policy.set_max_extended_leaf(DEFAULT_MAX_EXTENDED_LEAF);
+        policy
+    }
+
+    /// Limit the highest basic leaf (this cannot exceed that of the host)
+    pub fn set_max_basic_leaf(&mut self, leaf: u32) {
+        self.max_basic_leaf = leaf.min(native_cpuid(0, 0).eax);
+    }
+
+    /// Limit the highest extended leaf (this cannot exceed that of the host)
+    pub fn set_max_extended_leaf(&mut self, leaf: u32) {
+        let native = native_cpuid(EXTENDED_LEAF_BASE, 0).eax;
+        self.max_extended_leaf = leaf.max(EXTENDED_LEAF_BASE).min(native);
+    }
+
+    /// Add an override that is applied after all other adjustments
+    ///
+    /// Later overrides take precedence over earlier ones.
+    pub fn add_override(&mut self, over: CpuidOverride) {
+        self.overrides.push(over);
+    }
+
+    /// Returns the result of CPUID with the given inputs for the vcpu with
+    /// the given (guest) APIC id
+    pub fn cpuid(&self, apic_id: u32, leaf: u32, subleaf: u32) -> CpuIdResult {
+        // Like Intel processors, invalid leaves return the highest basic leaf
+        let leaf = match leaf {
+            HYPERVISOR_LEAF_BASE..=HYPERVISOR_LEAF_LAST => leaf,
+            leaf if leaf >= EXTENDED_LEAF_BASE => {
+                if leaf > self.max_extended_leaf {
+                    self.max_basic_leaf
+                } else {
+                    leaf
+                }
+            }
+            leaf if leaf > self.max_basic_leaf => self.max_basic_leaf,
+            leaf => leaf,
+        };
+
+        let mut res = match leaf {
+            HYPERVISOR_LEAF_BASE..=HYPERVISOR_LEAF_LAST => {
+                self.hypervisor_leaf(leaf)
+            }
+            leaf => {
+                self.adjust(apic_id, leaf, subleaf, native_cpuid(leaf, subleaf))
+            }
+        };
+
+        for over in self.overrides.iter() {
+            if over.matches(leaf, subleaf) {
+                over.apply(&mut res);
+            }
+        }
+        res
+    }
+
+    fn hypervisor_leaf(&self, leaf: u32) -> CpuIdResult {
+        let sig = |i: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&HYPERVISOR_SIGNATURE[i * 4..(i + 1) * 4]);
+            u32::from_le_bytes(bytes)
+        };
+        match leaf {
+            HYPERVISOR_LEAF_BASE => CpuIdResult {
+                eax: KVM_LEAF_FEATURES,
+                ebx: sig(0),
+                ecx: sig(1),
+                edx: sig(2),
+            },
+            KVM_LEAF_FEATURES => CpuIdResult {
+                eax: pvclock::KVM_FEATURES,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            },
+            _ => CpuIdResult {
+                eax: 0,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            },
+        }
+    }
+
+    /// The number of APIC id bits used to identify the vcpus in the VM
+    fn core_id_bits(&self) -> u32 {
+        self.nb_cpus.next_power_of_two().trailing_zeros()
+    }
+
+    fn adjust(
+        &self,
+        apic_id: u32,
+        leaf: u32,
+        subleaf: u32,
+        mut res: CpuIdResult,
+    ) -> CpuIdResult {
+        let zero = CpuIdResult {
+            eax: 0,
+            ebx: 0,
+            ecx: 0,
+            edx: 0,
+        };
+
+        match leaf {
+            0x0 => res.eax = self.max_basic_leaf,
+            0x1 => {
+                res.ecx = (res.ecx & !LEAF1_ECX_DISABLED) | LEAF1_ECX_ENABLED;
+                res.edx = (res.edx & !LEAF1_EDX_DISABLED) | LEAF1_EDX_ENABLED;
+
+                // The APIC id and number of logical processors
+                res.ebx = (res.ebx & 0x0000ffff)
+                    | (apic_id << 24)
+                    | ((self.nb_cpus & 0xff) << 16);
+            }
+
+            // Each vcpu is reported as a core in a single package, with its
+            // own L1 and L2 caches.
+            0x4 if res.eax & 0x1f != 0 => {
+                let level = (res.eax >> 5) & 0b111;
+                let sharing = if level == 3 { self.nb_cpus - 1 } else { 0 };
+                res.eax = (res.eax & 0x3fff)
+                    | ((sharing & 0xfff) << 14)
+                    | (((self.nb_cpus - 1) & 0x3f) << 26);
+            }
+            0x6 => {
+                res = zero;
+                res.eax = LEAF6_EAX_ARAT;
+            }
+            0x7 if subleaf == 0 => {
+                res.ebx &= !LEAF7_EBX_DISABLED;
+                res.edx &= !LEAF7_EDX_DISABLED;
+            }
+
+            // Performance monitoring, XSAVE, SGX and PT are not supported
+            0xa | 0xd | 0x12 | 0x14 => res = zero,
+
+            0xb | 0x1f => {
+                let (shift, count, level_type) = match subleaf {
+                    0 => (0, 1, 1),
+                    1 => (self.core_id_bits(), self.nb_cpus, 2),
+                    _ => (0, 0, 0),
+                };
+                res.eax = shift;
+                res.ebx = count;
+                res.ecx = (level_type << 8) | (subleaf & 0xff);
+                res.edx = apic_id;
+            }
+            EXTENDED_LEAF_BASE => res.eax = self.max_extended_leaf,
+            0x80000008 => res.ebx &= !LEAF_80000008_EBX_DISABLED,
+            _ => (),
+        }
+        res
+    }
+}
 
 pub fn emulate_cpuid(
-    _vcpu: &mut vcpu::VCpu,
+    vcpu: &mut vcpu::VCpu,
     guest_cpu: &mut vmexit::GuestCpuState,
 ) -> Result<()> {
-    //FIXME: for now just use the actual cpuid
-    let mut res = raw_cpuid::native_cpuid::cpuid_count(
+    let res = vcpu.vm.read().config.cpuid().cpuid(
+        vcpu.id as u32,
         guest_cpu.rax as u32,
         guest_cpu.rcx as u32,
     );
 
-    if guest_cpu.rax as u32 == 1 {
-        // Disable MTRR
-        res.edx &= !(1 << 12);
-
-        // Disable XSAVE
-        res.ecx &= !(1 << 26);
-
-        // Hide hypervisor feature
-        res.ecx &= !(1 << 31);
-    }
-
     guest_cpu.rax = res.eax as u64 | (guest_cpu.rax & 0xffffffff00000000);
     guest_cpu.rbx = res.ebx as u64 | (guest_cpu.rbx & 0xffffffff00000000);
     guest_cpu.rcx = res.ecx as u64 | (guest_cpu.rcx & 0xffffffff00000000);
     guest_cpu.rdx = res.edx as u64 | (guest_cpu.rdx & 0xffffffff00000000);
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpuid_override() {
+        let over: CpuidOverride = "0x7.0:ebx:0:0x20".parse().unwrap();
+        assert_eq!(
+            over,
+            CpuidOverride {
+                leaf: 7,
+                subleaf: Some(0),
+                register: CpuidRegister::Ebx,
+                value: 0,
+                mask: 0x20,
+            }
+        );
+
+        let over: CpuidOverride = "1:ecx:0x1:1".parse().unwrap();
+        assert_eq!(over.subleaf, None);
+
+        assert!("1:ecx:0x1".parse::<CpuidOverride>().is_err());
+        assert!("1:esp:0x1:1".parse::<CpuidOverride>().is_err());
+    }
+
+    #[test]
+    fn test_cpuid_topology() {
+        let policy = CpuidPolicy::new(3);
+        if policy.max_basic_leaf < 0xb {
+            return;
+        }
+
+        let smt = policy.cpuid(2, 0xb, 0);
+        assert_eq!((smt.eax, smt.ebx, smt.ecx, smt.edx), (0, 1, 0x100, 2));
+
+        let core = policy.cpuid(2, 0xb, 1);
+        assert_eq!((core.eax, core.ebx, core.ecx, core.edx), (2, 3, 0x201, 2));
+
+        let invalid = policy.cpuid(2, 0xb, 2);
+        assert_eq!((invalid.ebx, invalid.ecx), (0, 2));
+    }
+
+    #[test]
+    fn test_cpuid_hypervisor_leaf() {
+        let mut policy = CpuidPolicy::new(1);
+        assert_ne!(policy.cpuid(0, 1, 0).ecx & (1 << 31), 0);
+
+        let res = policy.cpuid(0, HYPERVISOR_LEAF_BASE, 0);
+        assert_eq!(res.eax, KVM_LEAF_FEATURES);
+        assert_eq!(&res.ebx.to_le_bytes(), b"KVMK");
+        assert_eq!(&res.edx.to_le_bytes(), b"M\0\0\0");
+        assert_ne!(policy.cpuid(0, KVM_LEAF_FEATURES, 0).eax & (1 << 3), 0);
+
+        policy.add_override(CpuidOverride {
+            leaf: HYPERVISOR_LEAF_BASE,
+            subleaf: None,
+            register: CpuidRegister::Eax,
+            value: 0x40000000,
+            mask: 0xf,
+        });
+        assert_eq!(policy.cpuid(0, HYPERVISOR_LEAF_BASE, 3).eax, 0x40000000);
+    }
+
+    #[test]
+    fn test_cpuid_speculation_control() {
+        let policy = CpuidPolicy::new(1);
+        if policy.max_basic_leaf >= 0x7 {
+            assert_eq!(policy.cpuid(0, 0x7, 0).edx & LEAF7_EDX_DISABLED, 0);
+        }
+        if policy.max_extended_leaf >= 0x80000008 {
+            let res = policy.cpuid(0, 0x80000008, 0);
+            assert_eq!(res.ebx & LEAF_80000008_EBX_DISABLED, 0);
+        }
+    }
+
+    #[test]
+    fn test_cpuid_max_leaf() {
+        let mut policy = CpuidPolicy::new(1);
+        policy.set_max_basic_leaf(0x2);
+        assert_eq!(policy.cpuid(0, 0, 0).eax, 0x2);
+        let (clamped, leaf2) =
+            (policy.cpuid(0, 0x7, 0), policy.cpuid(0, 0x2, 0));
+        assert_eq!(clamped.eax, leaf2.eax);
+        assert_eq!(clamped.edx, leaf2.edx);
+    }
+}
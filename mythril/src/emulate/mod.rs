@@ -1,3 +1,5 @@
 pub mod cpuid;
 pub mod memio;
+pub mod msr;
 pub mod portio;
+pub mod pvclock;
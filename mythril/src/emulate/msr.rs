@@ -0,0 +1,275 @@
+use crate::device::lapic;
+use crate::emulate::pvclock;
+use crate::error::Result;
+use crate::{vcpu, vmexit};
+use alloc::collections::BTreeMap;
+
+const MSR_IA32_TSC: u32 = 0x10;
+const MSR_IA32_FEATURE_CONTROL: u32 = 0x3a;
+const MSR_IA32_TSC_ADJUST: u32 = 0x3b;
+const MSR_IA32_BIOS_SIGN_ID: u32 = 0x8b;
+const MSR_IA32_MTRRCAP: u32 = 0xfe;
+const MSR_IA32_SYSENTER_CS: u32 = 0x174;
+const MSR_IA32_SYSENTER_ESP: u32 = 0x175;
+const MSR_IA32_SYSENTER_EIP: u32 = 0x176;
+const MSR_IA32_MCG_CAP: u32 = 0x179;
+const MSR_IA32_MCG_STATUS: u32 = 0x17a;
+const MSR_IA32_MISC_ENABLE: u32 = 0x1a0;
+const MSR_IA32_MTRR_PHYSBASE0: u32 = 0x200;
+const MSR_IA32_MTRR_PHYSMASK7: u32 = 0x20f;
+const MSR_IA32_MTRR_FIX64K_00000: u32 = 0x250;
+const MSR_IA32_MTRR_FIX16K_80000: u32 = 0x258;
+const MSR_IA32_MTRR_FIX16K_A0000: u32 = 0x259;
+const MSR_IA32_MTRR_FIX4K_C0000: u32 = 0x268;
+const MSR_IA32_MTRR_FIX4K_F8000: u32 = 0x26f;
+const MSR_IA32_PAT: u32 = 0x277;
+const MSR_IA32_MTRR_DEF_TYPE: u32 = 0x2ff;
+const MSR_IA32_EFER: u32 = 0xc0000080;
+const MSR_IA32_STAR: u32 = 0xc0000081;
+const MSR_IA32_LSTAR: u32 = 0xc0000082;
+const MSR_IA32_CSTAR: u32 = 0xc0000083;
+const MSR_IA32_FMASK: u32 = 0xc0000084;
+const MSR_IA32_FS_BASE: u32 = 0xc0000100;
+const MSR_IA32_GS_BASE: u32 = 0xc0000101;
+const MSR_IA32_KERNEL_GS_BASE: u32 = 0xc0000102;
+const MSR_IA32_TSC_AUX: u32 = 0xc0000103;
+
+// 8 variable range MTRRs, fixed range MTRRs and write combining
+const MTRRCAP_VALUE: u64 = 8 | (1 << 8) | (1 << 10);
+
+// Fast strings enabled, BTS and PEBS unavailable
+const MISC_ENABLE_VALUE: u64 = 1 | (1 << 11) | (1 << 12);
+
+// Locked, with VMX disabled
+const FEATURE_CONTROL_VALUE: u64 = 1;
+
+const GP_VECTOR: u8 = 13;
+
+/// How guest accesses to an MSR are handled
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MsrPolicy {
+    /// Accesses go directly to the hardware MSR without a VM exit
+    ///
+    /// This is only used for MSRs that are switched by the VMCS on entry
+    /// and exit, or that are never used by the host (each core only runs
+    /// a single vcpu, so the guest may keep its value in the hardware).
+    PassThrough,
+
+    /// Reads go directly to the hardware MSR (with any adjustment applied
+    /// by the VMCS), but writes are emulated
+    ReadPassThrough,
+
+    /// Accesses are handled by the virtual local APIC
+    LocalApic,
+
+    /// Accesses are handled by the vcpu's kvmclock state
+    Pvclock,
+
+    /// Accesses use the value in the `VCpu`s `MsrTable`
+    Emulated,
+
+    /// Reads use the value in the `VCpu`s `MsrTable` and writes raise #GP
+    ReadOnly,
+
+    /// Accesses raise #GP
+    Unsupported,
+}
+
+fn msr_policy(msr: u32) -> MsrPolicy {
+    match msr {
+        MSR_IA32_EFER
+        | MSR_IA32_FS_BASE
+        | MSR_IA32_GS_BASE
+        | MSR_IA32_PAT
+        | MSR_IA32_SYSENTER_CS
+        | MSR_IA32_SYSENTER_ESP
+        | MSR_IA32_SYSENTER_EIP
+        | MSR_IA32_KERNEL_GS_BASE
+        | MSR_IA32_STAR
+        | MSR_IA32_LSTAR
+        | MSR_IA32_CSTAR
+        | MSR_IA32_FMASK
+        | MSR_IA32_TSC_AUX => MsrPolicy::PassThrough,
+
+        MSR_IA32_TSC => MsrPolicy::ReadPassThrough,
+
+        MSR_IA32_TSC_ADJUST
+        | MSR_IA32_BIOS_SIGN_ID
+        | MSR_IA32_MCG_STATUS
+        | MSR_IA32_MISC_ENABLE
+        | MSR_IA32_MTRR_DEF_TYPE
+        | MSR_IA32_MTRR_PHYSBASE0..=MSR_IA32_MTRR_PHYSMASK7
+        | MSR_IA32_MTRR_FIX64K_00000
+        | MSR_IA32_MTRR_FIX16K_80000
+        | MSR_IA32_MTRR_FIX16K_A0000
+        | MSR_IA32_MTRR_FIX4K_C0000..=MSR_IA32_MTRR_FIX4K_F8000 => {
+            MsrPolicy::Emulated
+        }
+
+        MSR_IA32_FEATURE_CONTROL | MSR_IA32_MTRRCAP | MSR_IA32_MCG_CAP => {
+            MsrPolicy::ReadOnly
+        }
+
+        msr if lapic::is_local_apic_msr(msr) => MsrPolicy::LocalApic,
+        msr if pvclock::is_pvclock_msr(msr) => MsrPolicy::Pvclock,
+        _ => MsrPolicy::Unsupported,
+    }
+}
+
+/// Initialize an MSR bitmap so that accesses to all MSRs exit, except
+/// those that are passed through to the hardware
+pub fn init_msr_bitmap(bitmap: &mut [u8; 4096]) {
+    // The bitmap contains read bitmaps for the low (0-0x1fff) and high
+    // (0xc0000000-0xc0001fff) MSRs, followed by the write bitmaps.
+    for (base, offset) in [(0, 0), (0xc0000000, 1024)].iter() {
+        for msr in *base..*base + 0x2000u32 {
+            let policy = msr_policy(msr);
+            let (byte, bit) = (*offset + (msr - *base) as usize / 8, msr % 8);
+            if policy != MsrPolicy::PassThrough
+                && policy != MsrPolicy::ReadPassThrough
+            {
+                bitmap[byte] |= 1 << bit;
+            }
+            if policy != MsrPolicy::PassThrough {
+                bitmap[2048 + byte] |= 1 << bit;
+            }
+        }
+    }
+}
+
+/// The virtualized MSRs of a `VCpu` that are emulated by the hypervisor
+pub struct MsrTable {
+    values: BTreeMap<u32, u64>,
+}
+
+impl MsrTable {
+    /// Create a new `MsrTable` with the power-up values of the MSRs
+    pub fn new() -> Self {
+        let mut values = BTreeMap::new();
+        values.insert(MSR_IA32_FEATURE_CONTROL, FEATURE_CONTROL_VALUE);
+        values.insert(MSR_IA32_MTRRCAP, MTRRCAP_VALUE);
+        values.insert(MSR_IA32_MISC_ENABLE, MISC_ENABLE_VALUE);
+        Self { values: values }
+    }
+
+    /// Read an emulated MSR (unset MSRs read as zero)
+    pub fn read(&self, msr: u32) -> u64 {
+        self.values.get(&msr).cloned().unwrap_or(0)
+    }
+
+    /// Write an emulated MSR
+    pub fn write(&mut self, msr: u32, val: u64) {
+        self.values.insert(msr, val);
+    }
+}
+
+impl Default for MsrTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn raise_gp(vcpu: &mut vcpu::VCpu) -> Result<()> {
+    vcpu.inject_exception(GP_VECTOR, Some(0))
+}
+
+/// Emulate a RDMSR instruction that caused a VM exit
+pub fn emulate_rdmsr(
+    vcpu: &mut vcpu::VCpu,
+    guest_cpu: &mut vmexit::GuestCpuState,
+) -> Result<()> {
+    let msr = guest_cpu.rcx as u32;
+    let val = match msr_policy(msr) {
+        MsrPolicy::LocalApic => match vcpu.local_apic.read_msr(msr) {
+            Ok(val) => val,
+            Err(e) => {
+                info!("rdmsr: {:?}", e);
+                return raise_gp(vcpu);
+            }
+        },
+        MsrPolicy::Pvclock => vcpu.pvclock.read_msr(msr),
+        MsrPolicy::Emulated | MsrPolicy::ReadOnly => vcpu.msrs.read(msr),
+        policy => {
+            info!("rdmsr: unsupported register 0x{:x} ({:?})", msr, policy);
+            return raise_gp(vcpu);
+        }
+    };
+
+    guest_cpu.rax = val & 0xffffffff;
+    guest_cpu.rdx = val >> 32;
+    vcpu.skip_emulated_instruction()
+}
+
+/// Emulate a WRMSR instruction that caused a VM exit
+pub fn emulate_wrmsr(
+    vcpu: &mut vcpu::VCpu,
+    guest_cpu: &mut vmexit::GuestCpuState,
+) -> Result<()> {
+    let msr = guest_cpu.rcx as u32;
+    let val = (guest_cpu.rdx << 32) | (guest_cpu.rax & 0xffffffff);
+    match msr_policy(msr) {
+        MsrPolicy::LocalApic => {
+            if let Err(e) = vcpu.local_apic.write_msr(msr, val) {
+                info!("wrmsr: {:?}", e);
+                return raise_gp(vcpu);
+            }
+        }
+        MsrPolicy::Pvclock => {
+            if let Err(e) = pvclock::write_msr(vcpu, msr, val) {
+                info!("wrmsr: {:?}", e);
+                return raise_gp(vcpu);
+            }
+        }
+        MsrPolicy::Emulated => vcpu.msrs.write(msr, val),
+
+        // Writing the TSC changes the TSC offset used by the vcpus
+        MsrPolicy::ReadPassThrough if msr == MSR_IA32_TSC => {
+            vcpu.set_tsc(val)?
+        }
+        policy => {
+            info!(
+                "wrmsr: unsupported write of 0x{:x} to 0x{:x} ({:?})",
+                val, msr, policy
+            );
+            return raise_gp(vcpu);
+        }
+    }
+    vcpu.skip_emulated_instruction()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn exits(bitmap: &[u8; 4096], offset: usize, msr: u32) -> bool {
+        let (byte, bit) = (offset + (msr & 0x1fff) as usize / 8, msr % 8);
+        bitmap[byte] & (1 << bit) != 0
+    }
+
+    #[test]
+    fn test_msr_bitmap() {
+        let mut bitmap = [0u8; 4096];
+        init_msr_bitmap(&mut bitmap);
+
+        // Reads and writes of the EFER are passed through
+        assert!(!exits(&bitmap, 1024, MSR_IA32_EFER));
+        assert!(!exits(&bitmap, 3072, MSR_IA32_EFER));
+
+        // Only writes to the TSC exit
+        assert!(!exits(&bitmap, 0, MSR_IA32_TSC));
+        assert!(exits(&bitmap, 2048, MSR_IA32_TSC));
+
+        assert!(exits(&bitmap, 0, lapic::MSR_IA32_APIC_BASE));
+        assert!(exits(&bitmap, 2048, MSR_IA32_MTRR_DEF_TYPE));
+        assert!(exits(&bitmap, 1024, 0xc0000104));
+    }
+
+    #[test]
+    fn test_msr_policy() {
+        assert_eq!(msr_policy(0x20c), MsrPolicy::Emulated);
+        assert_eq!(msr_policy(0x830), MsrPolicy::LocalApic);
+        assert_eq!(msr_policy(MSR_IA32_MTRRCAP), MsrPolicy::ReadOnly);
+        assert_eq!(msr_policy(0x4b564d01), MsrPolicy::Pvclock);
+        assert_eq!(msr_policy(0x4b564d02), MsrPolicy::Unsupported);
+    }
+}
@@ -0,0 +1,276 @@
+//! Support for the KVM paravirtual clock (kvmclock)
+//!
+//! The guest registers a `pvclock_vcpu_time_info` structure for each vcpu
+//! with MSR_KVM_SYSTEM_TIME_NEW. The hypervisor fills it with a guest TSC
+//! value, the guest time at that TSC and the scale to convert TSC ticks to
+//! nanoseconds, so the guest can compute the time from RDTSC alone.
+
+use crate::error::{Error, Result};
+use crate::memory::GuestPhysAddr;
+use crate::vcpu;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+pub const MSR_KVM_WALL_CLOCK_NEW: u32 = 0x4b564d00;
+pub const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b564d01;
+
+/// The features reported in eax of CPUID leaf 0x40000001
+pub const KVM_FEATURES: u32 = (1 << 3) // KVM_FEATURE_CLOCKSOURCE2
+    | (1 << 24); // KVM_FEATURE_CLOCKSOURCE_STABLE_BIT
+
+// Bit 0 of MSR_KVM_SYSTEM_TIME_NEW enables the updates of the structure
+const SYSTEM_TIME_ENABLE: u64 = 1 << 0;
+
+// The guest TSC is the same on every vcpu, and they all share one clock
+// snapshot (see `GuestClock::snapshot`), so the guest does not need to keep
+// the time monotonic across them
+const PVCLOCK_TSC_STABLE_BIT: u8 = 1 << 0;
+
+const NS_PER_SEC: u64 = 1_000_000_000;
+
+// The sizes of `pvclock_vcpu_time_info` and `pvclock_wall_clock`
+const SYSTEM_TIME_SIZE: u64 = 32;
+const WALL_CLOCK_SIZE: u64 = 12;
+
+/// Whether an MSR is part of the kvmclock interface
+pub fn is_pvclock_msr(msr: u32) -> bool {
+    msr == MSR_KVM_WALL_CLOCK_NEW || msr == MSR_KVM_SYSTEM_TIME_NEW
+}
+
+/// Returns the multiplier (a 32 bit fraction) and shift that convert
+/// ticks of the given frequency to nanoseconds
+///
+/// This is the same calculation as `kvm_get_time_scale` in Linux.
+fn time_scale(frequency: u64) -> (u32, i8) {
+    // The loops below never end for a zero frequency
+    if frequency == 0 {
+        return (0, 0);
+    }
+
+    let mut scaled = NS_PER_SEC;
+    let mut ticks = frequency;
+    let mut shift = 0i8;
+
+    while ticks > scaled * 2 || ticks >> 32 != 0 {
+        ticks >>= 1;
+        shift -= 1;
+    }
+
+    let mut ticks = ticks as u32;
+    while ticks as u64 <= scaled || scaled >> 32 != 0 {
+        if scaled >> 32 != 0 || ticks & (1 << 31) != 0 {
+            scaled >>= 1;
+        } else {
+            ticks <<= 1;
+        }
+        shift += 1;
+    }
+
+    (((scaled << 32) / ticks as u64) as u32, shift)
+}
+
+/// The `pvclock_vcpu_time_info` structure shared with the guest
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct VcpuTimeInfo {
+    version: u32,
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+}
+
+impl VcpuTimeInfo {
+    fn new(version: u32, tsc: u64, system_time: u64, frequency: u64) -> Self {
+        let (mul, shift) = time_scale(frequency);
+        Self {
+            version: version,
+            tsc_timestamp: tsc,
+            system_time: system_time,
+            tsc_to_system_mul: mul,
+            tsc_shift: shift,
+            flags: PVCLOCK_TSC_STABLE_BIT,
+        }
+    }
+
+    fn as_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        bytes[0..4].copy_from_slice(&self.version.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.tsc_timestamp.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.system_time.to_le_bytes());
+        bytes[24..28].copy_from_slice(&self.tsc_to_system_mul.to_le_bytes());
+        bytes[28] = self.tsc_shift as u8;
+        bytes[29] = self.flags;
+        bytes
+    }
+
+    /// The guest time at `tsc`, as computed by the guest
+    #[cfg(test)]
+    fn time_at(&self, tsc: u64) -> u64 {
+        let delta = tsc - self.tsc_timestamp;
+        let delta = if self.tsc_shift < 0 {
+            delta >> -self.tsc_shift
+        } else {
+            delta << self.tsc_shift
+        };
+        self.system_time
+            + ((delta as u128 * self.tsc_to_system_mul as u128) >> 32) as u64
+    }
+}
+
+/// The kvmclock state of a `VCpu`
+#[derive(Default)]
+pub struct PvClock {
+    wall_clock: u64,
+    system_time: u64,
+
+    // The version of the last update of the vcpu's time info (the guest
+    // retries a read if this changes)
+    version: u32,
+}
+
+impl PvClock {
+    /// Read one of the kvmclock MSRs
+    pub fn read_msr(&self, msr: u32) -> u64 {
+        match msr {
+            MSR_KVM_WALL_CLOCK_NEW => self.wall_clock,
+            _ => self.system_time,
+        }
+    }
+}
+
+/// Check that a structure of `size` bytes at `addr` is in guest RAM
+fn check_guest_ram(vcpu: &vcpu::VCpu, addr: u64, size: u64) -> Result<()> {
+    let end = addr.checked_add(size - 1).ok_or_else(|| {
+        Error::InvalidValue(format!("Invalid kvmclock address 0x{:x}", addr))
+    })?;
+
+    let vm = vcpu.vm.read();
+    vm.guest_space.find_host_frame(GuestPhysAddr::new(addr))?;
+    vm.guest_space.find_host_frame(GuestPhysAddr::new(end))?;
+    Ok(())
+}
+
+/// Write a structure that starts with a version field to guest memory
+///
+/// The version is odd while the structure is being updated, so the guest
+/// knows to retry any read that overlaps the update.
+fn write_versioned(
+    vcpu: &mut vcpu::VCpu,
+    addr: GuestPhysAddr,
+    version: u32,
+    bytes: &mut [u8],
+) -> Result<()> {
+    let vm = vcpu.vm.clone();
+    let mut vm = vm.write();
+    let space = &mut vm.guest_space;
+
+    space.write_phys_bytes(addr, &(version | 1).to_le_bytes())?;
+    compiler_fence(Ordering::SeqCst);
+    bytes[0..4].copy_from_slice(&(version | 1).to_le_bytes());
+    space.write_phys_bytes(addr, bytes)?;
+    compiler_fence(Ordering::SeqCst);
+    space.write_phys_bytes(addr, &(version + 2).to_le_bytes())
+}
+
+/// Write the time info of a vcpu, if it has enabled kvmclock
+///
+/// This must be done whenever the guest TSC changes. The address was
+/// checked when the guest enabled kvmclock, so a failure here only
+/// disables the clock instead of stopping the vcpu.
+pub fn update_system_time(vcpu: &mut vcpu::VCpu) {
+    let msr = vcpu.pvclock.system_time;
+    if msr & SYSTEM_TIME_ENABLE == 0 {
+        return;
+    }
+
+    let clock = vcpu.clock().clone();
+    let snapshot = clock.snapshot();
+    let version = vcpu.pvclock.version;
+    let info = VcpuTimeInfo::new(
+        version,
+        snapshot.tsc,
+        snapshot.ns,
+        clock.frequency(),
+    );
+    match write_versioned(
+        vcpu,
+        GuestPhysAddr::new(msr & !SYSTEM_TIME_ENABLE),
+        version,
+        &mut info.as_bytes(),
+    ) {
+        Ok(()) => vcpu.pvclock.version = version + 2,
+        Err(e) => {
+            warn!("Disabling kvmclock for vcpu {}: {:?}", vcpu.id, e);
+            vcpu.pvclock.system_time &= !SYSTEM_TIME_ENABLE;
+        }
+    }
+}
+
+/// Write the `pvclock_wall_clock` structure (the wall clock time when the
+/// guest time was zero) to the given address
+fn update_wall_clock(vcpu: &mut vcpu::VCpu, addr: u64) -> Result<()> {
+    // Without an RTC (or with a time that does not fit), the guest is told
+    // it booted at the epoch
+    let rtc = vcpu.vm.read().config.rtc().cloned();
+    let boot_time = rtc.and_then(|rtc| rtc.lock().boot_time_ns()).unwrap_or(0);
+
+    let mut bytes = [0u8; 12];
+    bytes[4..8]
+        .copy_from_slice(&((boot_time / NS_PER_SEC) as u32).to_le_bytes());
+    bytes[8..12]
+        .copy_from_slice(&((boot_time % NS_PER_SEC) as u32).to_le_bytes());
+
+    //NOTE: the guest only reads this once, so its version is not tracked
+    write_versioned(vcpu, GuestPhysAddr::new(addr), 0, &mut bytes)
+}
+
+/// Emulate a write to one of the kvmclock MSRs
+///
+/// An address outside of guest RAM is rejected (and the MSR left
+/// unchanged), so the caller can inject a #GP like KVM does.
+pub fn write_msr(vcpu: &mut vcpu::VCpu, msr: u32, val: u64) -> Result<()> {
+    match msr {
+        MSR_KVM_WALL_CLOCK_NEW => {
+            check_guest_ram(vcpu, val, WALL_CLOCK_SIZE)?;
+            vcpu.pvclock.wall_clock = val;
+            update_wall_clock(vcpu, val)
+        }
+        _ => {
+            if val & SYSTEM_TIME_ENABLE != 0 {
+                let addr = val & !SYSTEM_TIME_ENABLE;
+                check_guest_ram(vcpu, addr, SYSTEM_TIME_SIZE)?;
+            }
+            vcpu.pvclock.system_time = val;
+            update_system_time(vcpu);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_time_scale() {
+        assert_eq!(time_scale(1_000_000_000), (1 << 31, 1));
+        assert_eq!(time_scale(0), (0, 0));
+
+        for frequency in [2_500_000_000, 1_193_182, 3_000_000_000u64].iter() {
+            let info = VcpuTimeInfo::new(0, 1000, 5000, *frequency);
+            let ns = info.time_at(1000 + frequency) - 5000;
+            assert!(ns.max(NS_PER_SEC) - ns.min(NS_PER_SEC) <= 1, "{}", ns);
+        }
+    }
+
+    #[test]
+    fn test_time_info_layout() {
+        let info = VcpuTimeInfo::new(2, 0x1122, 0x3344, 1_000_000_000);
+        let bytes = info.as_bytes();
+        assert_eq!(&bytes[0..4], &[2, 0, 0, 0]);
+        assert_eq!(&bytes[8..10], &[0x22, 0x11]);
+        assert_eq!(&bytes[16..18], &[0x44, 0x33]);
+        assert_eq!(&bytes[24..28], &[0, 0, 0, 0x80]);
+        assert_eq!((bytes[28], bytes[29]), (1, PVCLOCK_TSC_STABLE_BIT));
+    }
+}
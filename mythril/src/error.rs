@@ -111,6 +111,9 @@ fn panic_handler(info: &core::panic::PanicInfo) -> ! {
         }
     }
 
+    // Show what the guest on this core (if any) was doing
+    crate::exitstats::dump_trace();
+
     loop {
         unsafe {
             // Try to at least keep CPU from running at 100%
@@ -0,0 +1,271 @@
+//! Statistics and tracing of VM exits
+//!
+//! Each core running a `VCpu` counts the exits of that vcpu by reason (and
+//! the I/O ports and MMIO pages accessed by the guest), along with the
+//! cycles spent handling them. A VM can also be configured to keep a trace
+//! of the most recent exits of each vcpu (with `exit_trace = <n>`), which
+//! is shown if the hypervisor panics.
+
+use crate::vmexit::ExitInformation;
+use crate::{declare_per_core, get_per_core, get_per_core_mut};
+use alloc::collections::vec_deque::VecDeque;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+declare_per_core! {
+    static mut EXIT_STATS: Option<ExitStats> = None;
+}
+
+// Set once any core has initialized its statistics
+static STATS_READY: AtomicBool = AtomicBool::new(false);
+
+// The most MMIO pages counted separately (a guest can touch any number of
+// them, so the later ones are only counted together)
+const MAX_MMIO_PAGES: usize = 64;
+
+const PAGE_MASK: u64 = !0xfff;
+
+/// The number of exits and the cycles spent handling them
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ExitCounter {
+    pub count: u64,
+    pub cycles: u64,
+}
+
+impl ExitCounter {
+    fn add(&mut self, cycles: u64) {
+        self.count += 1;
+        self.cycles += cycles;
+    }
+
+    /// The average number of cycles spent handling an exit
+    pub fn average_cycles(&self) -> u64 {
+        if self.count == 0 {
+            0
+        } else {
+            self.cycles / self.count
+        }
+    }
+}
+
+/// An exit recorded in the trace
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceEntry {
+    pub reason: &'static str,
+    pub rip: u64,
+    pub qualification: u64,
+}
+
+/// The exit statistics of a `VCpu`
+pub struct ExitStats {
+    total: ExitCounter,
+    reasons: BTreeMap<&'static str, ExitCounter>,
+    ports: BTreeMap<u16, u64>,
+    mmio: BTreeMap<u64, u64>,
+
+    // The MMIO exits to pages beyond the first `MAX_MMIO_PAGES`
+    mmio_other: u64,
+
+    // The most recent exits (oldest first)
+    trace: VecDeque<TraceEntry>,
+    trace_size: usize,
+}
+
+impl ExitStats {
+    /// Create empty statistics, keeping a trace of `trace_size` exits
+    pub fn new(trace_size: usize) -> Self {
+        Self {
+            total: ExitCounter::default(),
+            reasons: BTreeMap::new(),
+            ports: BTreeMap::new(),
+            mmio: BTreeMap::new(),
+            mmio_other: 0,
+            trace: VecDeque::with_capacity(trace_size),
+            trace_size: trace_size,
+        }
+    }
+
+    /// Returns true if the recent exits are traced
+    pub fn is_tracing(&self) -> bool {
+        self.trace_size > 0
+    }
+
+    /// Count a handled exit
+    pub fn record(&mut self, info: &ExitInformation, cycles: u64) {
+        self.total.add(cycles);
+        self.reasons.entry(info.name()).or_default().add(cycles);
+        match info {
+            ExitInformation::IoInstruction(io) => {
+                *self.ports.entry(io.port).or_default() += 1;
+            }
+            ExitInformation::EptViolation(ept) => {
+                self.record_mmio(ept.guest_phys_addr.as_u64())
+            }
+            _ => (),
+        }
+    }
+
+    fn record_mmio(&mut self, addr: u64) {
+        let page = addr & PAGE_MASK;
+        if let Some(count) = self.mmio.get_mut(&page) {
+            *count += 1;
+        } else if self.mmio.len() < MAX_MMIO_PAGES {
+            self.mmio.insert(page, 1);
+        } else {
+            self.mmio_other += 1;
+        }
+    }
+
+    /// Add an exit to the trace (dropping the oldest one if it is full)
+    pub fn trace(&mut self, entry: TraceEntry) {
+        if !self.is_tracing() {
+            return;
+        }
+        if self.trace.len() == self.trace_size {
+            self.trace.pop_front();
+        }
+        self.trace.push_back(entry);
+    }
+
+    /// The total number of exits and cycles
+    pub fn total(&self) -> ExitCounter {
+        self.total
+    }
+
+    /// Format the statistics for display
+    pub fn summary(&self) -> String {
+        let mut out = format!(
+            "{} exits, {} cycles/exit\n",
+            self.total.count,
+            self.total.average_cycles()
+        );
+        for (reason, counter) in self.reasons.iter() {
+            out += &format!(
+                "  {}: {} ({} cycles/exit)\n",
+                reason,
+                counter.count,
+                counter.average_cycles()
+            );
+        }
+        for (port, count) in self.ports.iter() {
+            out += &format!("  port 0x{:x}: {}\n", port, count);
+        }
+        for (page, count) in self.mmio.iter() {
+            out += &format!("  mmio page 0x{:x}: {}\n", page, count);
+        }
+        if self.mmio_other > 0 {
+            out += &format!("  mmio other pages: {}\n", self.mmio_other);
+        }
+        out
+    }
+
+    /// Format the trace for display (oldest first)
+    pub fn format_trace(&self) -> String {
+        if !self.is_tracing() {
+            return "exit tracing is disabled\n".into();
+        }
+        let mut out = format!("last {} exits:\n", self.trace.len());
+        for entry in self.trace.iter() {
+            out += &format!(
+                "  {} rip=0x{:x} qualification=0x{:x}\n",
+                entry.reason, entry.rip, entry.qualification
+            );
+        }
+        out
+    }
+}
+
+/// Initialize the exit statistics of the current core
+pub fn init_exit_stats(trace_size: usize) {
+    *get_per_core_mut!(EXIT_STATS) = Some(ExitStats::new(trace_size));
+    STATS_READY.store(true, Ordering::Release);
+}
+
+/// Get the exit statistics of the current core (if it runs a `VCpu`)
+pub fn get_exit_stats() -> Option<&'static ExitStats> {
+    get_per_core!(EXIT_STATS).as_ref()
+}
+
+/// Get a mutable reference to the exit statistics of the current core
+pub fn get_exit_stats_mut() -> Option<&'static mut ExitStats> {
+    get_per_core_mut!(EXIT_STATS).as_mut()
+}
+
+/// Show the exit trace of the current core (used when panicking)
+pub fn dump_trace() {
+    // The per-core data may not be usable before any vcpu has started
+    if !STATS_READY.load(Ordering::Acquire) {
+        return;
+    }
+    if let Some(stats) = get_exit_stats() {
+        if stats.is_tracing() {
+            error!("{}", stats.format_trace());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn entry(rip: u64) -> TraceEntry {
+        TraceEntry {
+            reason: "CpuId",
+            rip: rip,
+            qualification: 0,
+        }
+    }
+
+    #[test]
+    fn test_exit_counts() {
+        let mut stats = ExitStats::new(0);
+        stats.record(&ExitInformation::CpuId, 100);
+        stats.record(&ExitInformation::CpuId, 300);
+        stats.record(&ExitInformation::Hlt, 200);
+        assert_eq!(
+            stats.total(),
+            ExitCounter {
+                count: 3,
+                cycles: 600
+            }
+        );
+        assert_eq!(stats.reasons["CpuId"].average_cycles(), 200);
+        assert_eq!(stats.reasons["Hlt"].count, 1);
+    }
+
+    #[test]
+    fn test_mmio_counts() {
+        let mut stats = ExitStats::new(0);
+        stats.record_mmio(0xfee00300);
+        stats.record_mmio(0xfee00310);
+        assert_eq!(stats.mmio[&0xfee00000], 2);
+
+        // Once the map is full, only the pages already in it are counted
+        // separately
+        for page in 1..MAX_MMIO_PAGES as u64 {
+            stats.record_mmio(page << 12);
+        }
+        stats.record_mmio(0xfec00000);
+        stats.record_mmio(0xfee00000);
+        assert_eq!(stats.mmio.len(), MAX_MMIO_PAGES);
+        assert_eq!(stats.mmio[&0xfee00000], 3);
+        assert_eq!(stats.mmio_other, 1);
+    }
+
+    #[test]
+    fn test_exit_trace() {
+        let mut stats = ExitStats::new(2);
+        stats.trace(entry(1));
+        stats.trace(entry(2));
+        stats.trace(entry(3));
+        let rips: Vec<u64> = stats.trace.iter().map(|e| e.rip).collect();
+        assert_eq!(rips, vec![2, 3]);
+
+        // Without a trace size, nothing is kept
+        let mut stats = ExitStats::new(0);
+        stats.trace(entry(1));
+        assert!(stats.trace.is_empty());
+    }
+}
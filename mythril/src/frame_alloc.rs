@@ -0,0 +1,429 @@
+//! The host physical frame allocator
+//!
+//! Guest memory (and the EPT tables that map it) is allocated from the
+//! usable regions of the host memory map instead of the kernel heap. Each
+//! region is a pool of 4KB frames tracked with a bitmap, and belongs to the
+//! NUMA node given by the ACPI SRAT (or node 0 if there is no SRAT).
+//! Allocations are made from the requested node when it has room, and from
+//! any other node otherwise.
+
+use crate::acpi::srat::SRAT;
+use crate::boot_info::MemoryRegion;
+use crate::error::{Error, Result};
+use crate::memory::{HostPhysAddr, HostPhysFrame, PageSize};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const FRAME_SIZE: u64 = HostPhysFrame::SIZE as u64;
+
+// Low memory holds the AP startup code and BIOS data structures
+const MIN_USABLE_ADDR: u64 = 1024 * 1024;
+
+//FIXME: the host page tables only identity map the first 4GB, so the
+//       frames above that cannot be used yet
+const MAX_USABLE_ADDR: u64 = 4 * 1024 * 1024 * 1024;
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) / align * align
+}
+
+/// A contiguous range of frames on one NUMA node
+struct FramePool {
+    start: u64,
+    frames: usize,
+    free: usize,
+    node: u32,
+
+    // One bit per frame, set if the frame is in use
+    bitmap: Vec<u64>,
+}
+
+impl FramePool {
+    fn new(start: u64, end: u64, node: u32) -> Option<Self> {
+        let start = align_up(start, FRAME_SIZE);
+        let end = end & !(FRAME_SIZE - 1);
+        if start >= end {
+            return None;
+        }
+        let frames = ((end - start) / FRAME_SIZE) as usize;
+        Some(Self {
+            start: start,
+            frames: frames,
+            free: frames,
+            node: node,
+            bitmap: vec![0; (frames + 63) / 64],
+        })
+    }
+
+    fn end(&self) -> u64 {
+        self.start + self.frames as u64 * FRAME_SIZE
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set_used(&mut self, index: usize, used: bool) {
+        if used {
+            self.bitmap[index / 64] |= 1 << (index % 64);
+            self.free -= 1;
+        } else {
+            self.bitmap[index / 64] &= !(1 << (index % 64));
+            self.free += 1;
+        }
+    }
+
+    fn reserve(&mut self, start: u64, end: u64) {
+        let start = core::cmp::max(start, self.start);
+        let end = core::cmp::min(end, self.end());
+        if start >= end {
+            return;
+        }
+        let first = ((start - self.start) / FRAME_SIZE) as usize;
+        let last = ((end - self.start + FRAME_SIZE - 1) / FRAME_SIZE) as usize;
+        for index in first..last {
+            if !self.is_used(index) {
+                self.set_used(index, true);
+            }
+        }
+    }
+
+    fn allocate(&mut self, count: usize, align: u64) -> Option<u64> {
+        if count == 0 || count > self.free {
+            return None;
+        }
+
+        // Frame numbers (not indices in the pool) must be aligned
+        let align = align / FRAME_SIZE;
+        let first_frame = self.start / FRAME_SIZE;
+        let mut index = (align_up(first_frame, align) - first_frame) as usize;
+        while index + count <= self.frames {
+            match (index..index + count).rev().find(|i| self.is_used(*i)) {
+                Some(used) => {
+                    let next = first_frame + used as u64 + 1;
+                    index = (align_up(next, align) - first_frame) as usize;
+                }
+                None => {
+                    for i in index..index + count {
+                        self.set_used(i, true);
+                    }
+                    return Some(self.start + index as u64 * FRAME_SIZE);
+                }
+            }
+        }
+        None
+    }
+
+    fn free(&mut self, addr: u64, count: usize) -> Result<()> {
+        let index = ((addr - self.start) / FRAME_SIZE) as usize;
+        if index + count > self.frames {
+            return Err(Error::InvalidValue(format!(
+                "Frames 0x{:x}+{} are outside of their pool",
+                addr, count
+            )));
+        }
+        if (index..index + count).any(|i| !self.is_used(i)) {
+            return Err(Error::InvalidValue(format!(
+                "Frames 0x{:x}+{} are not allocated",
+                addr, count
+            )));
+        }
+        for i in index..index + count {
+            self.set_used(i, false);
+        }
+        Ok(())
+    }
+}
+
+/// An allocator of host physical frames
+pub struct FrameAllocator {
+    pools: Vec<FramePool>,
+
+    // The usable memory above MAX_USABLE_ADDR, which is not allocated
+    skipped: u64,
+
+    // The NUMA node of each core, by APIC ID
+    core_nodes: BTreeMap<u32, u32>,
+}
+
+impl FrameAllocator {
+    pub fn new() -> Self {
+        Self {
+            pools: vec![],
+            skipped: 0,
+            core_nodes: BTreeMap::new(),
+        }
+    }
+
+    /// Create an allocator for the usable memory in `memory_map` (except
+    /// the `reserved` memory), assigning it to NUMA nodes using the SRAT
+    pub fn from_memory_map(
+        memory_map: &[MemoryRegion],
+        reserved: &[MemoryRegion],
+        srat: Option<&SRAT>,
+    ) -> Self {
+        let mut allocator = Self::new();
+
+        let mut affinities = srat.map_or(vec![], |srat| srat.memory.clone());
+        affinities.sort_by_key(|affinity| affinity.base);
+
+        for region in memory_map.iter() {
+            let start = core::cmp::max(region.start.as_u64(), MIN_USABLE_ADDR);
+            let end = core::cmp::min(region.end.as_u64(), MAX_USABLE_ADDR);
+            allocator.skipped += region
+                .end
+                .as_u64()
+                .saturating_sub(core::cmp::max(start, MAX_USABLE_ADDR));
+
+            // Memory that is not described by the SRAT is put on node 0
+            let mut cursor = start;
+            for affinity in affinities.iter() {
+                let node_start = core::cmp::max(affinity.base, cursor);
+                let node_end =
+                    core::cmp::min(affinity.base + affinity.length, end);
+                if node_start >= node_end {
+                    continue;
+                }
+                allocator.add_region(cursor, node_start, 0);
+                allocator.add_region(node_start, node_end, affinity.domain);
+                cursor = node_end;
+            }
+            allocator.add_region(cursor, end, 0);
+        }
+
+        for region in reserved.iter() {
+            allocator.reserve(region.start.as_u64(), region.end.as_u64());
+        }
+
+        if let Some(srat) = srat {
+            for processor in srat.processors.iter() {
+                allocator.set_core_node(processor.apic_id, processor.domain);
+            }
+        }
+        allocator
+    }
+
+    /// Add the memory from `start` to `end` on the given NUMA node
+    pub fn add_region(&mut self, start: u64, end: u64, node: u32) {
+        if let Some(pool) = FramePool::new(start, end, node) {
+            self.pools.push(pool);
+        }
+    }
+
+    /// Mark the memory from `start` to `end` as in use (so it will never
+    /// be allocated)
+    pub fn reserve(&mut self, start: u64, end: u64) {
+        for pool in self.pools.iter_mut() {
+            pool.reserve(start, end);
+        }
+    }
+
+    pub fn set_core_node(&mut self, apic_id: u32, node: u32) {
+        self.core_nodes.insert(apic_id, node);
+    }
+
+    /// The NUMA node of the core with the given APIC ID
+    pub fn core_node(&self, apic_id: u32) -> u32 {
+        self.core_nodes.get(&apic_id).copied().unwrap_or(0)
+    }
+
+    /// The number of frames that are not allocated
+    pub fn free_frames(&self) -> usize {
+        self.pools.iter().map(|pool| pool.free).sum()
+    }
+
+    /// The number of bytes of usable memory that cannot be allocated (as
+    /// the host does not map it)
+    pub fn skipped_bytes(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Allocate `count` contiguous frames aligned to `align`, preferably
+    /// from the given NUMA node
+    pub fn allocate(
+        &mut self,
+        count: usize,
+        align: PageSize,
+        node: u32,
+    ) -> Result<HostPhysAddr> {
+        let (local, remote): (Vec<_>, Vec<_>) =
+            self.pools.iter_mut().partition(|pool| pool.node == node);
+        local
+            .into_iter()
+            .chain(remote.into_iter())
+            .find_map(|pool| pool.allocate(count, align.bytes()))
+            .map(HostPhysAddr::new)
+            .ok_or_else(|| {
+                Error::AllocError(format!(
+                    "Unable to allocate {} frames ({:?} aligned)",
+                    count, align
+                ))
+            })
+    }
+
+    /// Free `count` frames previously allocated at `addr`
+    pub fn free(&mut self, addr: HostPhysAddr, count: usize) -> Result<()> {
+        let addr = addr.as_u64();
+        self.pools
+            .iter_mut()
+            .find(|pool| addr >= pool.start && addr < pool.end())
+            .ok_or_else(|| {
+                Error::InvalidValue(format!(
+                    "Address 0x{:x} is not in a frame pool",
+                    addr
+                ))
+            })?
+            .free(addr, count)
+    }
+}
+
+static FRAME_ALLOCATOR: Mutex<Option<FrameAllocator>> = Mutex::new(None);
+
+/// Start allocating frames with the given allocator
+pub fn init(allocator: FrameAllocator) {
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+}
+
+/// The NUMA node of the core with the given APIC ID
+pub fn node_of_core(apic_id: u32) -> u32 {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .map_or(0, |allocator| allocator.core_node(apic_id))
+}
+
+/// The number of bytes that can still be allocated
+#[cfg(not(test))]
+pub fn free_bytes() -> u64 {
+    FRAME_ALLOCATOR.lock().as_ref().map_or(0, |allocator| {
+        (allocator.free_frames() * HostPhysFrame::SIZE) as u64
+    })
+}
+
+// Tests allocate from the heap, which has no fixed size
+#[cfg(test)]
+pub fn free_bytes() -> u64 {
+    u64::MAX
+}
+
+/// Allocate `count` zeroed, contiguous frames aligned to `align`,
+/// preferably from the given NUMA node
+#[cfg(not(test))]
+pub fn allocate_frames(
+    count: usize,
+    align: PageSize,
+    node: u32,
+) -> Result<HostPhysFrame> {
+    let addr = FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .ok_or_else(|| {
+            Error::AllocError("The frame allocator is not ready".into())
+        })?
+        .allocate(count, align, node)?;
+    unsafe {
+        core::ptr::write_bytes(
+            addr.as_u64() as *mut u8,
+            0,
+            count * HostPhysFrame::SIZE,
+        );
+    }
+    HostPhysFrame::from_start_address(addr)
+}
+
+// Tests have no host memory map, so frames come from the heap (and are
+// never freed)
+#[cfg(test)]
+pub fn allocate_frames(
+    count: usize,
+    align: PageSize,
+    _node: u32,
+) -> Result<HostPhysFrame> {
+    let layout = alloc::alloc::Layout::from_size_align(
+        count * HostPhysFrame::SIZE,
+        align.bytes() as usize,
+    )
+    .map_err(|_| Error::AllocError("Invalid frame layout".into()))?;
+    let ptr = unsafe { alloc::alloc::alloc_zeroed(layout) };
+    if ptr.is_null() {
+        return Err(Error::AllocError("Unable to allocate frames".into()));
+    }
+    HostPhysFrame::from_start_address(HostPhysAddr::new(ptr as u64))
+}
+
+/// Free `count` frames allocated with `allocate_frames`
+#[cfg(not(test))]
+pub fn free_frames(frame: HostPhysFrame, count: usize) -> Result<()> {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .ok_or_else(|| {
+            Error::AllocError("The frame allocator is not ready".into())
+        })?
+        .free(frame.start_address(), count)
+}
+
+#[cfg(test)]
+pub fn free_frames(_frame: HostPhysFrame, _count: usize) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    #[test]
+    fn test_allocate_aligned_frames() {
+        let mut allocator = FrameAllocator::new();
+        allocator.add_region(MB + 0x1000, 8 * MB, 0);
+        allocator.reserve(2 * MB, 2 * MB + 1);
+
+        let addr = allocator.allocate(1, PageSize::Size4K, 0).unwrap();
+        assert_eq!(addr.as_u64(), MB + 0x1000);
+
+        // The frame at 2MB is reserved, so the first 2MB page is at 4MB
+        let addr = allocator.allocate(512, PageSize::Size2M, 0).unwrap();
+        assert_eq!(addr.as_u64(), 4 * MB);
+        assert!(allocator.allocate(512, PageSize::Size2M, 0).is_ok());
+        assert!(allocator.allocate(512, PageSize::Size2M, 0).is_err());
+
+        allocator.free(addr, 512).unwrap();
+        assert!(allocator.free(addr, 512).is_err());
+        assert_eq!(allocator.allocate(512, PageSize::Size2M, 0).unwrap(), addr);
+    }
+
+    #[test]
+    fn test_allocate_from_node() {
+        let mut allocator = FrameAllocator::new();
+        allocator.add_region(MB, 2 * MB, 0);
+        allocator.add_region(2 * MB, 3 * MB, 1);
+        allocator.set_core_node(4, 1);
+
+        let node = allocator.core_node(4);
+        let addr = allocator.allocate(1, PageSize::Size4K, node).unwrap();
+        assert_eq!(addr.as_u64(), 2 * MB);
+
+        // Once a node is full, other nodes are used
+        allocator.allocate(255, PageSize::Size4K, node).unwrap();
+        let addr = allocator.allocate(1, PageSize::Size4K, node).unwrap();
+        assert_eq!(addr.as_u64(), MB);
+        assert_eq!(allocator.free_frames(), 255);
+    }
+
+    #[test]
+    fn test_skip_high_memory() {
+        let memory_map = [MemoryRegion {
+            start: HostPhysAddr::new(MB),
+            end: HostPhysAddr::new(MAX_USABLE_ADDR + 2 * MB),
+        }];
+        let allocator = FrameAllocator::from_memory_map(&memory_map, &[], None);
+        assert_eq!(
+            allocator.free_frames() as u64,
+            (MAX_USABLE_ADDR - MB) / FRAME_SIZE
+        );
+        assert_eq!(allocator.skipped_bytes(), 2 * MB);
+    }
+}
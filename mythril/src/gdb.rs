@@ -0,0 +1,842 @@
+//! A GDB remote serial protocol stub for debugging guests
+//!
+//! A VM configured with `gdb = "comN"` stops its BSP after the first guest
+//! instruction and waits for GDB to connect to that host serial port:
+//!
+//! ```text
+//! (gdb) set architecture i386:x86-64
+//! (gdb) target remote /dev/ttyS1
+//! ```
+//!
+//! The stub can read and write the registers and memory (by linear address)
+//! of the guest, single-step it using the monitor trap flag and set up to
+//! four hardware breakpoints using the debug registers. Requests for software
+//! breakpoints are also served by the debug registers, so the stub never
+//! modifies guest memory to insert a breakpoint.
+//!
+//! Only the BSP of the VM is stopped, the other vcpus continue to run.
+//!
+//! While the stub is attached, guest accesses to the debug registers exit
+//! and use shadow registers, so the guest cannot remove the debugger's
+//! breakpoints (but the guest's own hardware breakpoints never fire).
+
+use crate::error::Result;
+use crate::memory::{self, GuestAccess, GuestVirtAddr, PrivilegeLevel};
+use crate::serial::SerialPort;
+use crate::{vcpu, vmcs, vmexit};
+use alloc::vec::Vec;
+use core::cmp;
+use core::convert::TryInto;
+use core::mem;
+
+/// The vector of the debug exception (#DB)
+pub const DEBUG_VECTOR: u8 = 1;
+
+const MAX_PACKET_SIZE: usize = 4096;
+const NUM_BREAKPOINTS: usize = 4;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const RFLAGS_RF: u64 = 1 << 16;
+const DR6_RESERVED: u64 = 0xffff0ff0;
+const DR7_RESERVED: u64 = 1 << 10;
+
+// The bits of DR6 that report the cause of a debug exception (B0-B3, BD and
+// BS), which are also in the exit qualification of a #DB exit
+const DR6_STATUS: u64 = 0x600f;
+
+/// Why the guest was stopped for the debugger
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    /// A single step completed
+    Step,
+
+    /// One of the debugger's breakpoints was hit
+    Breakpoint,
+
+    /// GDB asked for the running guest to be stopped
+    Interrupt,
+}
+
+impl StopReason {
+    fn signal(&self) -> u8 {
+        match self {
+            StopReason::Interrupt => SIGINT,
+            StopReason::Step | StopReason::Breakpoint => SIGTRAP,
+        }
+    }
+}
+
+/// Input received from GDB
+#[derive(Debug, PartialEq)]
+enum Input {
+    /// A packet with a valid checksum (without the framing)
+    Packet(Vec<u8>),
+
+    /// A packet with an invalid checksum
+    Corrupt,
+
+    /// A request to stop the running guest (i.e., Ctrl-C)
+    Interrupt,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ReadState {
+    Idle,
+    Data,
+    Escape,
+    Checksum(Option<u8>),
+}
+
+/// Decodes the packets sent by GDB, one byte at a time
+struct PacketReader {
+    state: ReadState,
+    data: Vec<u8>,
+    checksum: u8,
+}
+
+impl PacketReader {
+    fn new() -> Self {
+        Self {
+            state: ReadState::Idle,
+            data: vec![],
+            checksum: 0,
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> Option<Input> {
+        match self.state {
+            ReadState::Idle => match byte {
+                b'$' => {
+                    self.data.clear();
+                    self.checksum = 0;
+                    self.state = ReadState::Data;
+                }
+                0x03 => return Some(Input::Interrupt),
+
+                // Acknowledgments are ignored, there is nothing to resend
+                // over a serial line that does not lose data.
+                _ => (),
+            },
+            ReadState::Data => match byte {
+                b'#' => self.state = ReadState::Checksum(None),
+                b'}' => {
+                    self.checksum = self.checksum.wrapping_add(byte);
+                    self.state = ReadState::Escape;
+                }
+                _ => {
+                    self.checksum = self.checksum.wrapping_add(byte);
+                    self.data.push(byte);
+                }
+            },
+            ReadState::Escape => {
+                self.checksum = self.checksum.wrapping_add(byte);
+                self.data.push(byte ^ 0x20);
+                self.state = ReadState::Data;
+            }
+            ReadState::Checksum(None) => {
+                self.state = ReadState::Checksum(Some(byte))
+            }
+            ReadState::Checksum(Some(high)) => {
+                self.state = ReadState::Idle;
+                let valid = match (hex_value(high), hex_value(byte)) {
+                    (Some(high), Some(low)) => {
+                        (high << 4 | low) == self.checksum
+                    }
+                    _ => false,
+                };
+                return Some(if valid {
+                    Input::Packet(mem::replace(&mut self.data, vec![]))
+                } else {
+                    Input::Corrupt
+                });
+            }
+        }
+        None
+    }
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter()
+        .try_fold(0u64, |val, c| Some(val << 4 | hex_value(*c)? as u64))
+}
+
+fn decode_hex(s: &[u8]) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    s.chunks(2)
+        .map(|pair| Some(hex_value(pair[0])? << 4 | hex_value(pair[1])?))
+        .collect()
+}
+
+fn encode_hex(out: &mut Vec<u8>, bytes: &[u8]) {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+    for byte in bytes {
+        out.push(DIGITS[(byte >> 4) as usize]);
+        out.push(DIGITS[(byte & 0xf) as usize]);
+    }
+}
+
+fn frame_packet(data: &[u8]) -> Vec<u8> {
+    let checksum = data.iter().fold(0u8, |sum, c| sum.wrapping_add(*c));
+    let mut packet = Vec::with_capacity(data.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(data);
+    packet.push(b'#');
+    encode_hex(&mut packet, &[checksum]);
+    packet
+}
+
+fn split_args(args: &[u8], sep: u8) -> Option<(&[u8], &[u8])> {
+    let pos = args.iter().position(|c| *c == sep)?;
+    Some((&args[..pos], &args[pos + 1..]))
+}
+
+fn parse_resume_addr(args: &[u8]) -> Option<Option<u64>> {
+    if args.is_empty() {
+        Some(None)
+    } else {
+        parse_hex(args).map(Some)
+    }
+}
+
+/// The subset of the GDB commands supported by the stub
+#[derive(Debug, PartialEq)]
+enum Command {
+    StopReason,
+    ReadRegisters,
+    WriteRegisters(Vec<u8>),
+    ReadMemory { addr: u64, len: usize },
+    WriteMemory { addr: u64, data: Vec<u8> },
+    Continue(Option<u64>),
+    Step(Option<u64>),
+    InsertBreakpoint(u64),
+    RemoveBreakpoint(u64),
+    SetThread,
+    QuerySupported,
+    QueryAttached,
+    Detach,
+    Kill,
+    Unsupported,
+}
+
+impl Command {
+    /// Parse a packet received from GDB (returns None if it is malformed)
+    fn parse(packet: &[u8]) -> Option<Self> {
+        let (kind, args) = match packet.split_first() {
+            Some((kind, args)) => (*kind, args),
+            None => return Some(Command::Unsupported),
+        };
+
+        Some(match kind {
+            b'?' => Command::StopReason,
+            b'g' => Command::ReadRegisters,
+            b'G' => Command::WriteRegisters(decode_hex(args)?),
+            b'm' => {
+                let (addr, len) = split_args(args, b',')?;
+                Command::ReadMemory {
+                    addr: parse_hex(addr)?,
+                    len: parse_hex(len)? as usize,
+                }
+            }
+            b'M' => {
+                let (addr, rest) = split_args(args, b',')?;
+                let (len, data) = split_args(rest, b':')?;
+                let data = decode_hex(data)?;
+                if data.len() as u64 != parse_hex(len)? {
+                    return None;
+                }
+                Command::WriteMemory {
+                    addr: parse_hex(addr)?,
+                    data: data,
+                }
+            }
+            b'c' => Command::Continue(parse_resume_addr(args)?),
+            b's' => Command::Step(parse_resume_addr(args)?),
+            b'Z' | b'z' => {
+                let (ty, rest) = split_args(args, b',')?;
+
+                // Watchpoints are not supported, so GDB will fall back to
+                // single-stepping the guest
+                if ty != b"0" && ty != b"1" {
+                    return Some(Command::Unsupported);
+                }
+                let (addr, _kind) = split_args(rest, b',')?;
+                let addr = parse_hex(addr)?;
+                if kind == b'Z' {
+                    Command::InsertBreakpoint(addr)
+                } else {
+                    Command::RemoveBreakpoint(addr)
+                }
+            }
+            b'H' => Command::SetThread,
+            b'D' => Command::Detach,
+            b'k' => Command::Kill,
+            b'q' if args.starts_with(b"Supported") => Command::QuerySupported,
+            b'q' if args.starts_with(b"Attached") => Command::QueryAttached,
+            _ => Command::Unsupported,
+        })
+    }
+}
+
+const REGISTERS_SIZE: usize = 16 * 8 + 8 + 4 + 6 * 4;
+
+/// The guest registers in the order of GDB's x86-64 'g' packet
+///
+/// The x87 and SSE registers are not included, which GDB reports as
+/// unavailable.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Registers {
+    /// rax, rbx, rcx, rdx, rsi, rdi, rbp, rsp and r8-r15
+    gprs: [u64; 16],
+    rip: u64,
+    eflags: u32,
+    /// cs, ss, ds, es, fs and gs
+    segments: [u32; 6],
+}
+
+impl Registers {
+    fn read(
+        vmcs: &vmcs::ActiveVmcs,
+        guest_cpu: &vmexit::GuestCpuState,
+    ) -> Result<Self> {
+        Ok(Self {
+            gprs: [
+                guest_cpu.rax,
+                guest_cpu.rbx,
+                guest_cpu.rcx,
+                guest_cpu.rdx,
+                guest_cpu.rsi,
+                guest_cpu.rdi,
+                guest_cpu.rbp,
+                vmcs.read_field(vmcs::VmcsField::GuestRsp)?,
+                guest_cpu.r8,
+                guest_cpu.r9,
+                guest_cpu.r10,
+                guest_cpu.r11,
+                guest_cpu.r12,
+                guest_cpu.r13,
+                guest_cpu.r14,
+                guest_cpu.r15,
+            ],
+            rip: vmcs.read_field(vmcs::VmcsField::GuestRip)?,
+            eflags: vmcs.read_field(vmcs::VmcsField::GuestRflags)? as u32,
+            segments: [
+                vmcs.read_field(vmcs::VmcsField::GuestCsSelector)? as u32,
+                vmcs.read_field(vmcs::VmcsField::GuestSsSelector)? as u32,
+                vmcs.read_field(vmcs::VmcsField::GuestDsSelector)? as u32,
+                vmcs.read_field(vmcs::VmcsField::GuestEsSelector)? as u32,
+                vmcs.read_field(vmcs::VmcsField::GuestFsSelector)? as u32,
+                vmcs.read_field(vmcs::VmcsField::GuestGsSelector)? as u32,
+            ],
+        })
+    }
+
+    fn write(
+        &self,
+        vmcs: &mut vmcs::ActiveVmcs,
+        guest_cpu: &mut vmexit::GuestCpuState,
+    ) -> Result<()> {
+        guest_cpu.rax = self.gprs[0];
+        guest_cpu.rbx = self.gprs[1];
+        guest_cpu.rcx = self.gprs[2];
+        guest_cpu.rdx = self.gprs[3];
+        guest_cpu.rsi = self.gprs[4];
+        guest_cpu.rdi = self.gprs[5];
+        guest_cpu.rbp = self.gprs[6];
+        vmcs.write_field(vmcs::VmcsField::GuestRsp, self.gprs[7])?;
+        guest_cpu.r8 = self.gprs[8];
+        guest_cpu.r9 = self.gprs[9];
+        guest_cpu.r10 = self.gprs[10];
+        guest_cpu.r11 = self.gprs[11];
+        guest_cpu.r12 = self.gprs[12];
+        guest_cpu.r13 = self.gprs[13];
+        guest_cpu.r14 = self.gprs[14];
+        guest_cpu.r15 = self.gprs[15];
+        vmcs.write_field(vmcs::VmcsField::GuestRip, self.rip)?;
+        vmcs.write_field(vmcs::VmcsField::GuestRflags, self.eflags as u64)?;
+
+        //TODO: support changing the segment selectors (this requires
+        //      loading the descriptors from the guest GDT)
+        Ok(())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(REGISTERS_SIZE);
+        for reg in self.gprs.iter() {
+            bytes.extend_from_slice(&reg.to_le_bytes());
+        }
+        bytes.extend_from_slice(&self.rip.to_le_bytes());
+        bytes.extend_from_slice(&self.eflags.to_le_bytes());
+        for seg in self.segments.iter() {
+            bytes.extend_from_slice(&seg.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Decode the registers from a 'G' packet (any registers after the
+    /// segment selectors are ignored)
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < REGISTERS_SIZE {
+            return None;
+        }
+        let u64_at = |offset: usize| {
+            u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+        };
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+        };
+
+        let mut regs = Self::default();
+        for (i, reg) in regs.gprs.iter_mut().enumerate() {
+            *reg = u64_at(i * 8);
+        }
+        regs.rip = u64_at(16 * 8);
+        regs.eflags = u32_at(17 * 8);
+        for (i, seg) in regs.segments.iter_mut().enumerate() {
+            *seg = u32_at(17 * 8 + 4 + i * 4);
+        }
+        Some(regs)
+    }
+}
+
+unsafe fn write_debug_address(idx: usize, addr: u64) {
+    match idx {
+        0 => llvm_asm!("mov $0, %dr0" :: "r"(addr) :: "volatile"),
+        1 => llvm_asm!("mov $0, %dr1" :: "r"(addr) :: "volatile"),
+        2 => llvm_asm!("mov $0, %dr2" :: "r"(addr) :: "volatile"),
+        3 => llvm_asm!("mov $0, %dr3" :: "r"(addr) :: "volatile"),
+        _ => unreachable!(),
+    }
+}
+
+fn read_memory(vcpu: &vcpu::VCpu, addr: u64, len: usize) -> Result<Vec<u8>> {
+    let mut vm = vcpu.vm.write();
+    let addr = GuestVirtAddr::new(addr, &vcpu.vmcs)?;
+    let view = memory::GuestAddressSpaceViewMut::from_vmcs(
+        &vcpu.vmcs,
+        &mut vm.guest_space,
+    )?;
+    view.read_bytes(addr, len, GuestAccess::Read(PrivilegeLevel(0)))
+}
+
+fn write_memory(vcpu: &vcpu::VCpu, addr: u64, data: &[u8]) -> Result<()> {
+    let mut vm = vcpu.vm.write();
+    let addr = GuestVirtAddr::new(addr, &vcpu.vmcs)?;
+    let mut view = memory::GuestAddressSpaceViewMut::from_vmcs(
+        &vcpu.vmcs,
+        &mut vm.guest_space,
+    )?;
+    view.write_bytes(addr, data, GuestAccess::Write(PrivilegeLevel(0)))
+}
+
+/// A GDB stub for a `VCpu`, connected over a host serial port
+pub struct GdbStub {
+    port: SerialPort,
+    reader: PacketReader,
+    breakpoints: [Option<u64>; NUM_BREAKPOINTS],
+    stop_reason: StopReason,
+
+    /// True if GDB is waiting for the guest to stop
+    running: bool,
+    stepping: bool,
+
+    // The values of DR0-3, DR6 and DR7 seen by the guest
+    guest_dr: [u64; 4],
+    guest_dr6: u64,
+    guest_dr7: u64,
+}
+
+impl GdbStub {
+    /// Create a stub that communicates over the host serial port at `port`
+    pub fn new(port: u16) -> Self {
+        Self {
+            port: SerialPort::new(port),
+            reader: PacketReader::new(),
+            breakpoints: [None; NUM_BREAKPOINTS],
+            stop_reason: StopReason::Step,
+            running: false,
+            stepping: false,
+            guest_dr: [0; 4],
+            guest_dr6: DR6_RESERVED,
+            guest_dr7: DR7_RESERVED,
+        }
+    }
+
+    /// Configure the VMCS so that the guest stops for the debugger after
+    /// executing its first instruction
+    pub fn attach(&mut self, vmcs: &mut vmcs::ActiveVmcs) -> Result<()> {
+        let bitmap = vmcs.read_field(vmcs::VmcsField::ExceptionBitmap)?;
+        vmcs.write_field(
+            vmcs::VmcsField::ExceptionBitmap,
+            bitmap | 1 << DEBUG_VECTOR,
+        )?;
+
+        // The debug registers belong to the debugger from now on
+        self.guest_dr7 = vmcs.read_field(vmcs::VmcsField::GuestDr7)?;
+        let field = vmcs.read_field(vmcs::VmcsField::CpuBasedVmExecControl)?;
+        vmcs.write_field(
+            vmcs::VmcsField::CpuBasedVmExecControl,
+            field | vmcs::CpuBasedCtrlFlags::MOV_DR_EXITING.bits(),
+        )?;
+        self.set_stepping(vmcs, true)
+    }
+
+    /// Emulate a guest MOV to or from a debug register
+    ///
+    /// DR4 and DR5 must already be translated to DR6 and DR7.
+    pub fn emulate_mov_dr(
+        &mut self,
+        vmcs: &mut vmcs::ActiveVmcs,
+        guest_cpu: &mut vmexit::GuestCpuState,
+        info: &vmexit::DrInformation,
+    ) -> Result<()> {
+        let reg = match info.dr_num {
+            0..=3 => &mut self.guest_dr[info.dr_num as usize],
+            6 => &mut self.guest_dr6,
+            _ => &mut self.guest_dr7,
+        };
+        if info.read {
+            info.register.write(*reg, vmcs, guest_cpu)
+        } else {
+            *reg = info.register.read(vmcs, guest_cpu)?;
+            Ok(())
+        }
+    }
+
+    /// Record the cause of a debug exception that is passed on to the
+    /// guest in its DR6 (`qualification` is the exit qualification of the
+    /// #DB exit)
+    pub fn set_debug_status(&mut self, qualification: u64) {
+        self.guest_dr6 = (self.guest_dr6 & !0xf) | (qualification & DR6_STATUS);
+    }
+
+    /// Returns true if the guest is being single-stepped
+    pub fn is_stepping(&self) -> bool {
+        self.stepping
+    }
+
+    /// Returns true if GDB has asked for the running guest to be stopped
+    pub fn interrupt_requested(&mut self) -> bool {
+        let mut requested = false;
+        while let Some(byte) = self.port.try_read() {
+            match self.reader.push(byte) {
+                Some(Input::Interrupt) => requested = true,
+
+                // A debugger is connecting after the previous one detached.
+                // The packet is not acknowledged, so GDB will send it again
+                // once the guest is stopped.
+                Some(Input::Packet(_)) if !self.running => requested = true,
+                _ => (),
+            }
+        }
+        requested
+    }
+
+    /// Returns true if the debug exception that caused the current exit
+    /// was raised by one of the debugger's breakpoints
+    pub fn is_breakpoint_hit(&self, vmcs: &vmcs::ActiveVmcs) -> Result<bool> {
+        // Bits 0-3 of the exit qualification are the B0-B3 bits of DR6
+        let qualification =
+            vmcs.read_field(vmcs::VmcsField::ExitQualification)?;
+        Ok(self
+            .breakpoints
+            .iter()
+            .enumerate()
+            .any(|(i, bp)| bp.is_some() && qualification & (1 << i) != 0))
+    }
+
+    /// Stop the guest and process debugger commands until GDB resumes it
+    pub fn stop(
+        &mut self,
+        vcpu: &mut vcpu::VCpu,
+        guest_cpu: &mut vmexit::GuestCpuState,
+        reason: StopReason,
+    ) -> Result<()> {
+        self.stop_reason = reason;
+        if self.running {
+            self.running = false;
+            let reply = self.stop_reply();
+            self.send_packet(&reply);
+        }
+
+        loop {
+            let byte = self.port.read();
+            let packet = match self.reader.push(byte) {
+                Some(Input::Packet(packet)) => packet,
+                Some(Input::Corrupt) => {
+                    self.port.write(b'-');
+                    continue;
+                }
+                _ => continue,
+            };
+            self.port.write(b'+');
+
+            let command = match Command::parse(&packet) {
+                Some(command) => command,
+                None => {
+                    self.send_packet(b"E01");
+                    continue;
+                }
+            };
+
+            match command {
+                Command::Continue(addr) => {
+                    return self.resume(vcpu, addr, false);
+                }
+                Command::Step(addr) => return self.resume(vcpu, addr, true),
+                Command::Detach | Command::Kill => {
+                    info!("Debugger detached from vcpu {}", vcpu.id);
+                    if command == Command::Detach {
+                        self.send_packet(b"OK");
+                    }
+                    self.breakpoints = [None; NUM_BREAKPOINTS];
+                    self.update_breakpoints(&mut vcpu.vmcs)?;
+                    self.resume(vcpu, None, false)?;
+                    self.running = false;
+                    return Ok(());
+                }
+                command => {
+                    let reply =
+                        self.handle_command(vcpu, guest_cpu, command)?;
+                    self.send_packet(&reply);
+                }
+            }
+        }
+    }
+
+    fn handle_command(
+        &mut self,
+        vcpu: &mut vcpu::VCpu,
+        guest_cpu: &mut vmexit::GuestCpuState,
+        command: Command,
+    ) -> Result<Vec<u8>> {
+        let mut reply = vec![];
+        match command {
+            Command::StopReason => reply = self.stop_reply(),
+            Command::ReadRegisters => {
+                let regs = Registers::read(&vcpu.vmcs, guest_cpu)?;
+                encode_hex(&mut reply, &regs.to_bytes());
+            }
+            Command::WriteRegisters(bytes) => {
+                match Registers::from_bytes(&bytes) {
+                    Some(regs) => {
+                        regs.write(&mut vcpu.vmcs, guest_cpu)?;
+                        reply.extend_from_slice(b"OK");
+                    }
+                    None => reply.extend_from_slice(b"E01"),
+                }
+            }
+            Command::ReadMemory { addr, len } => {
+                // Each byte is encoded as two hex digits
+                let len = cmp::min(len, MAX_PACKET_SIZE / 2);
+                match read_memory(vcpu, addr, len) {
+                    Ok(bytes) => encode_hex(&mut reply, &bytes),
+                    Err(_) => reply.extend_from_slice(b"E14"),
+                }
+            }
+            Command::WriteMemory { addr, data } => {
+                match write_memory(vcpu, addr, &data) {
+                    Ok(()) => reply.extend_from_slice(b"OK"),
+                    Err(_) => reply.extend_from_slice(b"E14"),
+                }
+            }
+            Command::InsertBreakpoint(addr) => {
+                if self.breakpoints.contains(&Some(addr)) {
+                    reply.extend_from_slice(b"OK");
+                } else {
+                    match self.breakpoints.iter().position(Option::is_none) {
+                        Some(slot) => {
+                            self.breakpoints[slot] = Some(addr);
+                            self.update_breakpoints(&mut vcpu.vmcs)?;
+                            reply.extend_from_slice(b"OK");
+                        }
+                        None => reply.extend_from_slice(b"E28"),
+                    }
+                }
+            }
+            Command::RemoveBreakpoint(addr) => {
+                for bp in self.breakpoints.iter_mut() {
+                    if *bp == Some(addr) {
+                        *bp = None;
+                    }
+                }
+                self.update_breakpoints(&mut vcpu.vmcs)?;
+                reply.extend_from_slice(b"OK");
+            }
+            Command::SetThread => reply.extend_from_slice(b"OK"),
+            Command::QuerySupported => reply.extend_from_slice(
+                format!("PacketSize={:x}", MAX_PACKET_SIZE).as_bytes(),
+            ),
+            Command::QueryAttached => reply.extend_from_slice(b"1"),
+
+            // An empty reply tells GDB the command is not supported
+            _ => (),
+        }
+        Ok(reply)
+    }
+
+    fn resume(
+        &mut self,
+        vcpu: &mut vcpu::VCpu,
+        addr: Option<u64>,
+        step: bool,
+    ) -> Result<()> {
+        if let Some(addr) = addr {
+            vcpu.vmcs.write_field(vmcs::VmcsField::GuestRip, addr)?;
+        }
+
+        // Don't fault on the breakpoint the guest is stopped at again
+        if self.stop_reason == StopReason::Breakpoint {
+            let rflags = vcpu.vmcs.read_field(vmcs::VmcsField::GuestRflags)?;
+            vcpu.vmcs.write_field(
+                vmcs::VmcsField::GuestRflags,
+                rflags | RFLAGS_RF,
+            )?;
+        }
+
+        self.set_stepping(&mut vcpu.vmcs, step)?;
+        self.running = true;
+        Ok(())
+    }
+
+    fn set_stepping(
+        &mut self,
+        vmcs: &mut vmcs::ActiveVmcs,
+        step: bool,
+    ) -> Result<()> {
+        let field = vmcs.read_field(vmcs::VmcsField::CpuBasedVmExecControl)?;
+        let mtf = vmcs::CpuBasedCtrlFlags::MONITOR_TRAP_FLAG.bits();
+        vmcs.write_field(
+            vmcs::VmcsField::CpuBasedVmExecControl,
+            if step { field | mtf } else { field & !mtf },
+        )?;
+        self.stepping = step;
+        Ok(())
+    }
+
+    fn update_breakpoints(&self, vmcs: &mut vmcs::ActiveVmcs) -> Result<()> {
+        let mut dr7 = DR7_RESERVED;
+        for (i, bp) in self.breakpoints.iter().enumerate() {
+            if let Some(addr) = bp {
+                // The guest DR0-3 are not switched on VM entry, so they are
+                // loaded directly (the host never uses them). A zero R/W and
+                // LEN field means break on instruction execution.
+                unsafe { write_debug_address(i, *addr) };
+                dr7 |= 1 << (i * 2 + 1);
+            }
+        }
+        vmcs.write_field(vmcs::VmcsField::GuestDr7, dr7)
+    }
+
+    fn stop_reply(&self) -> Vec<u8> {
+        format!("S{:02x}", self.stop_reason.signal()).into_bytes()
+    }
+
+    fn send_packet(&mut self, data: &[u8]) {
+        self.port.write_bytes(&frame_packet(data));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_all(reader: &mut PacketReader, bytes: &[u8]) -> Vec<Input> {
+        bytes.iter().filter_map(|b| reader.push(*b)).collect()
+    }
+
+    #[test]
+    fn test_packet_reader() {
+        let mut reader = PacketReader::new();
+        assert_eq!(
+            read_all(&mut reader, b"+$g#67"),
+            vec![Input::Packet(b"g".to_vec())]
+        );
+        assert_eq!(read_all(&mut reader, b"$g#68"), vec![Input::Corrupt]);
+        assert_eq!(read_all(&mut reader, b"\x03"), vec![Input::Interrupt]);
+
+        // Escaped bytes are included in the checksum as sent
+        assert_eq!(
+            read_all(&mut reader, b"$X}\x03#d8"),
+            vec![Input::Packet(b"X#".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_frame_packet() {
+        assert_eq!(frame_packet(b"OK"), b"$OK#9a".to_vec());
+        assert_eq!(frame_packet(b""), b"$#00".to_vec());
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(
+            Command::parse(b"m7c00,10"),
+            Some(Command::ReadMemory {
+                addr: 0x7c00,
+                len: 0x10
+            })
+        );
+        assert_eq!(
+            Command::parse(b"Mffff8000,2:90cc"),
+            Some(Command::WriteMemory {
+                addr: 0xffff8000,
+                data: vec![0x90, 0xcc]
+            })
+        );
+        assert_eq!(Command::parse(b"Mffff8000,3:90cc"), None);
+        assert_eq!(Command::parse(b"c"), Some(Command::Continue(None)));
+        assert_eq!(Command::parse(b"s1000"), Some(Command::Step(Some(0x1000))));
+        assert_eq!(
+            Command::parse(b"Z1,ffffffff81000000,1"),
+            Some(Command::InsertBreakpoint(0xffffffff81000000))
+        );
+        assert_eq!(
+            Command::parse(b"z0,1000,1"),
+            Some(Command::RemoveBreakpoint(0x1000))
+        );
+        assert_eq!(Command::parse(b"Z2,1000,4"), Some(Command::Unsupported));
+        assert_eq!(
+            Command::parse(b"qSupported:multiprocess+"),
+            Some(Command::QuerySupported)
+        );
+        assert_eq!(Command::parse(b"mzz,1"), None);
+        assert_eq!(Command::parse(b"vCont?"), Some(Command::Unsupported));
+    }
+
+    #[test]
+    fn test_registers_bytes() {
+        let mut regs = Registers::default();
+        regs.gprs[0] = 0x1122334455667788;
+        regs.gprs[15] = 0xffff;
+        regs.rip = 0xffffffff81000000;
+        regs.eflags = 0x246;
+        regs.segments[0] = 0x10;
+
+        let bytes = regs.to_bytes();
+        assert_eq!(bytes.len(), REGISTERS_SIZE);
+        assert_eq!(bytes[0], 0x88);
+        assert_eq!(&bytes[16 * 8 + 8..16 * 8 + 12], &[0x46, 0x02, 0, 0]);
+
+        // GDB may send more registers than the stub uses
+        let mut long = bytes.clone();
+        long.extend_from_slice(&[0u8; 16]);
+        assert_eq!(Registers::from_bytes(&long), Some(regs));
+        assert_eq!(Registers::from_bytes(&bytes[1..]), None);
+    }
+}
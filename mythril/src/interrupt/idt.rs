@@ -147,7 +147,6 @@ macro_rules! interrupt_fn_impl {
      }
 }
 
-#[allow(unused_macros)]
 macro_rules! interrupt_fn {
     ($name:ident, $stack:ident, $func:block) => {
         interrupt_fn_impl!(
@@ -189,11 +188,19 @@ fault_fn!(zero_division_handler, state, {
     panic!("Divide by zero handler (rip=0x{:x})", state.rip);
 });
 
+// The timer and kick interrupts normally cause VM exits, so they only
+// reach the host while a core waits for one (see `wait_for_interrupt`)
+interrupt_fn!(wakeup_handler, _state, {
+    crate::apic::get_local_apic_mut().eoi();
+});
+
 pub unsafe fn init() {
     IDT[0].set_func(zero_division_handler);
     IDT[2].set_func(nmi_handler);
     IDT[13].set_func(protection_fault_handler);
     IDT[14].set_func(page_fault_handler);
+    IDT[crate::time::TIMER_VECTOR as usize].set_func(wakeup_handler);
+    IDT[crate::vcpu::KICK_VECTOR as usize].set_func(wakeup_handler);
 
     ap_init();
 }
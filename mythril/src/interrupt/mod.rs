@@ -7,3 +7,11 @@ pub unsafe fn enable_interrupts() {
 pub unsafe fn disable_interrupts() {
     llvm_asm!("cli" :::: "volatile");
 }
+
+/// Halt the core until the next interrupt, leaving interrupts disabled
+///
+/// An interrupt that is already pending ends the wait immediately, as STI
+/// only takes effect after the HLT.
+pub unsafe fn wait_for_interrupt() {
+    llvm_asm!("sti; hlt; cli" :::: "volatile");
+}
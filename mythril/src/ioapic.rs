@@ -460,6 +460,36 @@ impl IoRedTblEntry {
         Ok(entry)
     }
 
+    /// The interrupt vector.
+    pub fn vector(&self) -> u8 {
+        self.vector
+    }
+
+    /// The action the APIC should take on signal.
+    pub fn delivery_mode(&self) -> DeliveryMode {
+        self.delivery_mode
+    }
+
+    /// The interpretation of the destination field.
+    pub fn destination_mode(&self) -> DestinationMode {
+        self.destination_mode
+    }
+
+    /// Type of signal on interrupt pin.
+    pub fn trigger_mode(&self) -> TriggerMode {
+        self.trigger_mode
+    }
+
+    /// Whether the interrupt signal is masked.
+    pub fn interrupt_mask(&self) -> bool {
+        self.interrupt_mask
+    }
+
+    /// The destination APIC ID or logical set of processors.
+    pub fn destination(&self) -> u8 {
+        self.destination
+    }
+
     /// Perform basic validity checks found in the table from section 3.2.4
     /// in the I/O APIC specification.
     fn validate(&self) -> Result<()> {
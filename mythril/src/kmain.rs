@@ -2,9 +2,10 @@ use crate::acpi;
 use crate::ap;
 use crate::apic;
 use crate::boot_info::BootInfo;
-use crate::device;
+use crate::config;
+use crate::console;
+use crate::frame_alloc;
 use crate::interrupt;
-use crate::linux;
 use crate::logger;
 use crate::memory;
 use crate::multiboot2;
@@ -12,12 +13,11 @@ use crate::percore;
 use crate::time;
 use crate::vcpu;
 use crate::vm;
+use crate::vmx;
 
 use alloc::collections::BTreeMap;
-use alloc::sync::Arc;
 use alloc::vec::Vec;
-use log::{debug, info};
-use spin::RwLock;
+use log::{debug, info, warn};
 
 extern "C" {
     static AP_STARTUP_ADDR: u16;
@@ -26,109 +26,6 @@ extern "C" {
     static mut AP_READY: u8;
 }
 
-// Temporary helper function to create a vm for a single core
-fn default_vm(
-    core: usize,
-    mem: u64,
-    info: &BootInfo,
-) -> Arc<RwLock<vm::VirtualMachine>> {
-    let mut config = vm::VirtualMachineConfig::new(vec![core as u8], mem);
-
-    // FIXME: When `map_bios` may return an error, log the error.
-    config.map_bios("seabios.bin".into()).unwrap_or(());
-
-    let device_map = config.device_map();
-    device_map
-        .register_device(device::acpi::AcpiRuntime::new(0xb000).unwrap())
-        .unwrap();
-    device_map
-        .register_device(device::com::ComDevice::new(core as u64, 0x3F8))
-        .unwrap();
-    device_map
-        .register_device(device::com::ComDevice::new(core as u64, 0x2F8))
-        .unwrap();
-    device_map
-        .register_device(device::com::ComDevice::new(core as u64, 0x3E8))
-        .unwrap();
-    device_map
-        .register_device(device::com::ComDevice::new(core as u64, 0x2E8))
-        .unwrap();
-    device_map
-        .register_device(device::debug::DebugPort::new(core as u64, 0x402))
-        .unwrap();
-    device_map
-        .register_device(device::vga::VgaController::new())
-        .unwrap();
-    device_map
-        .register_device(device::dma::Dma8237::new())
-        .unwrap();
-    device_map
-        .register_device(device::ignore::IgnoredDevice::new())
-        .unwrap();
-    device_map
-        .register_device(device::pci::PciRootComplex::new())
-        .unwrap();
-    device_map
-        .register_device(device::pic::Pic8259::new())
-        .unwrap();
-    device_map
-        .register_device(device::keyboard::Keyboard8042::new())
-        .unwrap();
-    device_map
-        .register_device(device::pit::Pit8254::new())
-        .unwrap();
-    device_map
-        .register_device(device::pos::ProgrammableOptionSelect::new())
-        .unwrap();
-    device_map
-        .register_device(device::rtc::CmosRtc::new(mem))
-        .unwrap();
-
-    //TODO: this should actually be per-vcpu
-    device_map
-        .register_device(device::lapic::LocalApic::new())
-        .unwrap();
-
-    let mut fw_cfg_builder = device::qemu_fw_cfg::QemuFwCfgBuilder::new();
-
-    // The 'linuxboot' file is an option rom that loads the linux kernel
-    // via qemu_fw_cfg
-    fw_cfg_builder
-        .add_file(
-            "genroms/linuxboot_dma.bin",
-            info.find_module("linuxboot_dma.bin").unwrap().data(),
-        )
-        .unwrap();
-
-    // Passing the bootorder file automatically selects the option rom
-    // as the default boot device
-    fw_cfg_builder
-        .add_file(
-            "bootorder",
-            "/rom@genroms/linuxboot_dma.bin\nHALT".as_bytes(),
-        )
-        .unwrap();
-
-    linux::load_linux(
-        "kernel",
-        "initramfs",
-        core::concat!(
-            "rodata=0 nopti disableapic acpi=off ",
-            "earlyprintk=serial,0x3f8,115200 ",
-            "console=ttyS0 debug nokaslr noapic mitigations=off ",
-            "root=/dev/ram0 rdinit=/init\0"
-        )
-        .as_bytes(),
-        mem,
-        &mut fw_cfg_builder,
-        info,
-    )
-    .unwrap();
-    device_map.register_device(fw_cfg_builder.build()).unwrap();
-
-    vm::VirtualMachine::new(config, info).expect("Failed to create vm")
-}
-
 #[no_mangle]
 pub extern "C" fn ap_entry(_ap_data: &ap::ApData) -> ! {
     unsafe { interrupt::idt::ap_init() };
@@ -200,16 +97,69 @@ unsafe fn kmain(mut boot_info: BootInfo) -> ! {
     percore::init_sections(apic_ids.len())
         .expect("Failed to initialize per-core sections");
 
-    let mut map = BTreeMap::new();
-    for apic_id in apic_ids.iter() {
-        map.insert(
-            *apic_id as usize,
-            default_vm(*apic_id as usize, 256, &boot_info),
+    // Guest memory is allocated from the host memory that is not used by
+    // the hypervisor, preferably on the NUMA node of the VM's cores
+    let srat_sdt = rsdt.find_entry(b"SRAT").ok();
+    let srat = srat_sdt
+        .as_ref()
+        .map(acpi::srat::SRAT::new)
+        .transpose()
+        .expect("Failed to parse SRAT");
+    let frame_allocator = frame_alloc::FrameAllocator::from_memory_map(
+        &boot_info.memory_map,
+        &boot_info.reserved,
+        srat.as_ref(),
+    );
+    info!(
+        "Frame allocator: {}MB free",
+        (frame_allocator.free_frames() * memory::HostPhysFrame::SIZE) >> 20
+    );
+    if frame_allocator.skipped_bytes() > 0 {
+        warn!(
+            "Frame allocator: skipping {}MB of memory above 4GB",
+            frame_allocator.skipped_bytes() >> 20
         );
     }
+    frame_alloc::init(frame_allocator);
+
+    // If no configuration was provided, run a default VM on every core
+    let user_config = config::UserConfig::from_boot_info(&boot_info)
+        .expect("Failed to read mythril configuration")
+        .unwrap_or_else(|| config::UserConfig {
+            vms: apic_ids
+                .iter()
+                .map(|apic_id| config::UserVmConfig {
+                    cpus: vec![*apic_id as u8],
+                    ..Default::default()
+                })
+                .collect(),
+        });
+    user_config
+        .check_cpus(&apic_ids)
+        .expect("Invalid mythril configuration");
+
+    let mut map = BTreeMap::new();
+    let mut vms = vec![];
+    for (vmid, vm_config) in user_config.vms.iter().enumerate() {
+        let mut config = vm_config
+            .build(vmid as u64, &boot_info)
+            .expect("Failed to build vm configuration");
+        config.set_max_ept_page_size(vmx::Vmx::max_ept_page_size());
+        let vm = vm::VirtualMachine::new(config, &boot_info)
+            .expect("Failed to create vm");
+
+        // Each core used by the VM runs one of its vcpus
+        for cpu in vm_config.cpus.iter() {
+            map.insert(*cpu as usize, vm.clone());
+        }
+        vms.push(vm);
+    }
 
     vm::VM_MAP = Some(map);
 
+    // Share the host serial port between the guest consoles
+    console::init(&vms);
+
     debug!("AP_STARTUP address: 0x{:x}", AP_STARTUP_ADDR);
 
     for (idx, apic_id) in apic_ids.into_iter().enumerate() {
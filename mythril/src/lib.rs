@@ -28,9 +28,14 @@ pub mod ap;
 /// Support for the local APIC.
 pub mod apic;
 pub mod boot_info;
+pub mod config;
+pub mod console;
 pub mod device;
 pub mod emulate;
 pub mod error;
+pub mod exitstats;
+pub mod frame_alloc;
+pub mod gdb;
 mod global_alloc;
 pub mod interrupt;
 pub mod ioapic;
@@ -38,10 +43,14 @@ pub mod kmain;
 pub mod linux;
 pub mod logger;
 pub mod memory;
+pub mod memory_layout;
+pub mod monitor;
 pub mod multiboot2;
 pub mod percore;
 pub mod pit;
 mod registers;
+pub mod rtc;
+pub mod serial;
 pub mod time;
 pub mod tsc;
 pub mod vcpu;
@@ -49,3 +58,4 @@ pub mod vm;
 pub mod vmcs;
 mod vmexit;
 pub mod vmx;
+pub mod vtime;
@@ -1,6 +1,7 @@
 use crate::boot_info::BootInfo;
 use crate::device::qemu_fw_cfg::{FwCfgSelector, QemuFwCfgBuilder};
 use crate::error::{Error, Result};
+use crate::memory_layout::GuestMemoryLayout;
 use bitflags::bitflags;
 use byteorder::{ByteOrder, LittleEndian};
 
@@ -20,11 +21,11 @@ pub fn load_linux(
     kernel_name: impl AsRef<str>,
     initramfs_name: impl AsRef<str>,
     cmdline: &[u8],
-    memory: u64,
+    layout: &GuestMemoryLayout,
     builder: &mut QemuFwCfgBuilder,
     info: &BootInfo,
 ) -> Result<()> {
-    let mut kernel = info
+    let kernel = info
         .find_module(kernel_name.as_ref())
         .ok_or_else(|| {
             Error::InvalidValue(format!(
@@ -32,8 +33,7 @@ pub fn load_linux(
                 kernel_name.as_ref()
             ))
         })?
-        .data()
-        .to_vec();
+        .data();
     let initramfs = info
         .find_module(initramfs_name.as_ref())
         .ok_or_else(|| {
@@ -85,22 +85,39 @@ pub fn load_linux(
         0x37ffffff
     };
 
-    // Don't position the initramfs above the available memory
-    if (memory < 4 * 1024) && (initrd_max as u64 >= (memory << 20)) {
-        initrd_max = (memory << 20) as u32 - 1;
+    // Don't position the initramfs above the memory below the PCI hole
+    if initrd_max as u64 >= layout.ram_below_4gb() {
+        initrd_max = (layout.ram_below_4gb() as u32).saturating_sub(1);
+    }
+
+    let setup_size = match kernel[0x1f1] {
+        // For legacy compat, setup size 0 is really 4 sectors
+        0 => 4 + 1,
+        size => size + 1,
+    } as i32
+        * 512;
+
+    if setup_size as usize > kernel.len() {
+        return Err(Error::InvalidValue(
+            "Invalid kernel header (setup size > kernel size)".into(),
+        ));
     }
 
+    // Only the setup code (which contains the header) is modified, so the
+    // rest of the kernel is passed to the guest without copying it
+    let mut setup = kernel[..setup_size as usize].to_vec();
+
     builder.add_i32(FwCfgSelector::CMDLINE_ADDR, cmdline_addr);
-    builder.add_bytes(FwCfgSelector::CMDLINE_DATA, cmdline);
+    builder.add_bytes(FwCfgSelector::CMDLINE_DATA, cmdline.to_vec());
     //TODO: this should be NULL terminated
     builder.add_i32(FwCfgSelector::CMDLINE_SIZE, cmdline.len() as i32);
 
     if protocol >= 0x202 {
-        LittleEndian::write_i32(&mut kernel[0x228..0x228 + 4], cmdline_addr);
+        LittleEndian::write_i32(&mut setup[0x228..0x228 + 4], cmdline_addr);
     } else {
-        LittleEndian::write_u16(&mut kernel[0x20..0x20 + 2], 0xa33f);
+        LittleEndian::write_u16(&mut setup[0x20..0x20 + 2], 0xa33f);
         LittleEndian::write_i16(
-            &mut kernel[0x22..0x22 + 2],
+            &mut setup[0x22..0x22 + 2],
             (cmdline_addr - real_addr) as i16,
         );
     }
@@ -110,14 +127,14 @@ pub fn load_linux(
     // loader type
     // TODO: change this from QEMU probably
     if protocol >= 0x200 {
-        kernel[0x210] = 0xB0;
+        setup[0x210] = 0xB0;
     }
 
     // Heap
     if protocol >= 0x201 {
-        kernel[0x211] |= 0x80;
+        setup[0x211] |= 0x80;
         LittleEndian::write_i16(
-            &mut kernel[0x224..0x224 + 2],
+            &mut setup[0x224..0x224 + 2],
             (cmdline_addr - real_addr - 0x200) as i16,
         );
     }
@@ -140,24 +157,12 @@ pub fn load_linux(
     builder.add_i32(FwCfgSelector::INITRD_ADDR, initrd_addr);
     builder.add_i32(FwCfgSelector::INITRD_SIZE, initramfs.len() as i32);
     builder.add_bytes(FwCfgSelector::INITRD_DATA, initramfs);
-    LittleEndian::write_i32(&mut kernel[0x218..0x218 + 4], initrd_addr);
+    LittleEndian::write_i32(&mut setup[0x218..0x218 + 4], initrd_addr);
     LittleEndian::write_i32(
-        &mut kernel[0x21c..0x21c + 4],
+        &mut setup[0x21c..0x21c + 4],
         initramfs.len() as i32,
     );
 
-    let setup_size = match kernel[0x1f1] {
-        // For legacy compat, setup size 0 is really 4 sectors
-        0 => 4 + 1,
-        size => size + 1,
-    } as i32
-        * 512;
-
-    if setup_size as usize > kernel.len() {
-        return Err(Error::InvalidValue(
-            "Invalid kernel header (setup size > kernel size)".into(),
-        ));
-    }
     let kernel_size = kernel.len() as i32 - setup_size;
 
     builder.add_i32(FwCfgSelector::KERNEL_ADDR, prot_addr);
@@ -167,8 +172,7 @@ pub fn load_linux(
 
     builder.add_i32(FwCfgSelector::SETUP_ADDR, real_addr);
     builder.add_i32(FwCfgSelector::SETUP_SIZE, setup_size);
-    builder
-        .add_bytes(FwCfgSelector::SETUP_DATA, &kernel[..setup_size as usize]);
+    builder.add_bytes(FwCfgSelector::SETUP_DATA, setup);
 
     info!("CMDLINE_ADDR: 0x{:x}", cmdline_addr);
     info!("CMDLINE_SIZE: 0x{:x}", cmdline.len());
@@ -1,4 +1,5 @@
 use crate::error::{Error, Result};
+use crate::frame_alloc;
 use crate::vmcs;
 use alloc::boxed::Box;
 use alloc::vec::Vec;
@@ -248,8 +249,43 @@ impl HostPhysFrame {
     }
 }
 
+/// The size of a page mapped by the EPT
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PageSize {
+    Size4K,
+    Size2M,
+    Size1G,
+}
+
+impl PageSize {
+    pub fn bytes(&self) -> u64 {
+        match self {
+            PageSize::Size4K => 4096,
+            PageSize::Size2M => 2 * 1024 * 1024,
+            PageSize::Size1G => 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// What the EPT maps at a guest physical address
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum EptMapping {
+    /// Nothing is mapped in the aligned region of this size around the
+    /// address
+    Unmapped(PageSize),
+
+    /// The address is in a page of this size starting at the host address
+    Mapped(HostPhysAddr, PageSize),
+}
+
 pub struct GuestAddressSpace {
     root: Box<EptPml4Table>,
+
+    // The largest pages that may be used to map new memory
+    max_page_size: PageSize,
+
+    // The NUMA node that new memory (and EPT tables) are allocated from
+    node: u32,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -263,19 +299,36 @@ pub enum GuestAccess {
 }
 
 impl GuestAddressSpace {
-    pub fn new() -> Result<Self> {
+    /// Create an empty address space that uses pages up to `max_page_size`
+    /// for new memory (see `Vmx::max_ept_page_size`), which is allocated
+    /// from the given NUMA node when possible
+    pub fn new(max_page_size: PageSize, node: u32) -> Result<Self> {
         Ok(GuestAddressSpace {
             root: Box::new(EptPml4Table::default()),
+            max_page_size: max_page_size,
+            node: node,
         })
     }
 
+    /// The NUMA node that new memory is allocated from
+    pub fn node(&self) -> u32 {
+        self.node
+    }
+
     pub fn map_frame(
         &mut self,
         guest_addr: GuestPhysAddr,
         host_frame: HostPhysFrame,
         readonly: bool,
     ) -> Result<()> {
-        map_guest_memory(&mut self.root, guest_addr, host_frame, readonly)
+        map_guest_memory(
+            &mut self.root,
+            guest_addr,
+            host_frame.start_address(),
+            PageSize::Size4K,
+            readonly,
+            self.node,
+        )
     }
 
     pub fn map_new_frame(
@@ -283,12 +336,103 @@ impl GuestAddressSpace {
         guest_addr: GuestPhysAddr,
         readonly: bool,
     ) -> Result<()> {
-        let page = Box::into_raw(Box::new(Raw4kPage::default()));
         let page =
-            HostPhysFrame::from_start_address(HostPhysAddr::new(page as u64))?;
+            frame_alloc::allocate_frames(1, PageSize::Size4K, self.node)?;
         self.map_frame(guest_addr, page, readonly)
     }
 
+    /// Map newly allocated memory over the `size` bytes at `guest_addr`
+    ///
+    /// The largest possible pages are used, and any part of the region that
+    /// is already mapped is left unchanged.
+    pub fn map_new_region(
+        &mut self,
+        guest_addr: GuestPhysAddr,
+        size: u64,
+        readonly: bool,
+    ) -> Result<()> {
+        let end = guest_addr.as_u64() + size;
+        let mut addr = guest_addr.as_u64();
+        let mut max_page_size = self.max_page_size;
+        while addr < end {
+            let unmapped = match self.mapping(GuestPhysAddr::new(addr)) {
+                EptMapping::Unmapped(size) => size,
+                EptMapping::Mapped(_, size) => {
+                    addr = (addr & !(size.bytes() - 1)) + size.bytes();
+                    continue;
+                }
+            };
+
+            let mut page_size = PageSize::Size4K;
+            for size in [PageSize::Size1G, PageSize::Size2M].iter() {
+                if *size <= max_page_size
+                    && *size <= unmapped
+                    && addr % size.bytes() == 0
+                    && addr + size.bytes() <= end
+                {
+                    page_size = *size;
+                    break;
+                }
+            }
+
+            match page_size {
+                PageSize::Size4K => {
+                    self.map_new_frame(GuestPhysAddr::new(addr), readonly)?
+                }
+
+                // If there is no free, aligned large page, fall back to the
+                // next smaller size
+                size => match alloc_large_page(size, self.node) {
+                    Some(host_addr) => map_guest_memory(
+                        &mut self.root,
+                        GuestPhysAddr::new(addr),
+                        host_addr,
+                        size,
+                        readonly,
+                        self.node,
+                    )?,
+                    None if size == PageSize::Size1G => {
+                        max_page_size = PageSize::Size2M;
+                        continue;
+                    }
+                    None => {
+                        max_page_size = PageSize::Size4K;
+                        continue;
+                    }
+                },
+            }
+            addr += page_size.bytes();
+        }
+        Ok(())
+    }
+
+    /// Change whether the guest may write to the frame at `guest_addr`,
+    /// splitting any large page that contains it
+    ///
+    /// NOTE: the EPT translations cached by the processor are not
+    ///       invalidated, so this should be done before the VM is started.
+    pub fn set_frame_readonly(
+        &mut self,
+        guest_addr: GuestPhysAddr,
+        readonly: bool,
+    ) -> Result<()> {
+        let ept_pte = self.find_pte_mut(guest_addr)?;
+        let mut flags = ept_pte.flags();
+        flags.set(EptTableFlags::WRITE_ACCESS, !readonly);
+        ept_pte.set_flags(flags);
+        Ok(())
+    }
+
+    /// Remove the mapping of the frame at `guest_addr` (so that it can be
+    /// mapped differently), splitting any large page that contains it
+    ///
+    /// NOTE: like `set_frame_readonly`, this does not invalidate cached
+    ///       translations, and the frame is not freed.
+    pub fn unmap_frame(&mut self, guest_addr: GuestPhysAddr) -> Result<()> {
+        self.find_pte_mut(guest_addr)?.set_unused();
+        Ok(())
+    }
+
     pub fn eptp(&self) -> u64 {
         // //TODO: check available memory types
         (&*self.root as *const _ as u64) | (4 - 1) << 3 | 6
@@ -349,40 +493,88 @@ impl GuestAddressSpace {
         Ok(GuestPhysAddr::new(translated_vaddr))
     }
 
-    //FIXME this ignores read/write/exec permissions and 2MB/1GB pages (and lots of other stuff)
+    //FIXME this ignores read/write/exec permissions (and lots of other stuff)
     pub fn find_host_frame(
         &self,
         addr: GuestPhysAddr,
     ) -> Result<HostPhysFrame> {
+        match self.mapping(addr) {
+            EptMapping::Mapped(base, size) => {
+                let offset = addr.as_u64() & (size.bytes() - 1);
+                HostPhysFrame::from_start_address(HostPhysAddr::new(
+                    base.as_u64() + (offset & !0xfff),
+                ))
+            }
+            EptMapping::Unmapped(_) => Err(Error::InvalidValue(format!(
+                "No EPT mapping for {:?}",
+                addr
+            ))),
+        }
+    }
+
+    fn mapping(&self, addr: GuestPhysAddr) -> EptMapping {
         let ept_pml4e = &self.root[addr.p4_index()];
         if ept_pml4e.is_unused() {
-            return Err(Error::InvalidValue(
-                "No PML4 entry for GuestPhysAddr".into(),
-            ));
+            return EptMapping::Unmapped(PageSize::Size1G);
         }
         let ept_pdpt =
             ept_pml4e.addr().as_u64() as *const EptPageDirectoryPointerTable;
         let ept_pdpe = unsafe { &(*ept_pdpt)[addr.p3_index()] };
         if ept_pdpe.is_unused() {
-            return Err(Error::InvalidValue(
-                "No PDP entry for GuestPhysAddr".into(),
-            ));
+            return EptMapping::Unmapped(PageSize::Size1G);
+        } else if ept_pdpe.is_large_page() {
+            return EptMapping::Mapped(ept_pdpe.addr(), PageSize::Size1G);
         }
         let ept_pdt = ept_pdpe.addr().as_u64() as *const EptPageDirectory;
         let ept_pde = unsafe { &(*ept_pdt)[addr.p2_index()] };
         if ept_pde.is_unused() {
-            return Err(Error::InvalidValue(
-                "No PD entry for GuestPhysAddr".into(),
-            ));
+            return EptMapping::Unmapped(PageSize::Size2M);
+        } else if ept_pde.is_large_page() {
+            return EptMapping::Mapped(ept_pde.addr(), PageSize::Size2M);
         }
         let ept_pt = ept_pde.addr().as_u64() as *const EptPageTable;
         let ept_pte = unsafe { &(*ept_pt)[addr.p1_index()] };
         if ept_pte.is_unused() {
-            return Err(Error::InvalidValue(
-                "No PT entry for GuestPhysAddr".into(),
-            ));
+            EptMapping::Unmapped(PageSize::Size4K)
+        } else {
+            EptMapping::Mapped(ept_pte.addr(), PageSize::Size4K)
         }
-        HostPhysFrame::from_start_address(ept_pte.addr())
+    }
+
+    // Find the page table entry of a mapped frame, splitting large pages
+    fn find_pte_mut(
+        &mut self,
+        addr: GuestPhysAddr,
+    ) -> Result<&mut EptPageTableEntry> {
+        let not_mapped =
+            || Error::InvalidValue(format!("No EPT mapping for {:?}", addr));
+        let node = self.node;
+
+        let ept_pml4e = &mut self.root[addr.p4_index()];
+        if ept_pml4e.is_unused() {
+            return Err(not_mapped());
+        }
+        let ept_pdpt =
+            ept_pml4e.addr().as_u64() as *mut EptPageDirectoryPointerTable;
+        let ept_pdpe = unsafe { &mut (*ept_pdpt)[addr.p3_index()] };
+        if ept_pdpe.is_unused() {
+            return Err(not_mapped());
+        } else if ept_pdpe.is_large_page() {
+            split_huge_page(ept_pdpe, node)?;
+        }
+        let ept_pdt = ept_pdpe.addr().as_u64() as *mut EptPageDirectory;
+        let ept_pde = unsafe { &mut (*ept_pdt)[addr.p2_index()] };
+        if ept_pde.is_unused() {
+            return Err(not_mapped());
+        } else if ept_pde.is_large_page() {
+            split_large_page(ept_pde, node)?;
+        }
+        let ept_pt = ept_pde.addr().as_u64() as *mut EptPageTable;
+        let ept_pte = unsafe { &mut (*ept_pt)[addr.p1_index()] };
+        if ept_pte.is_unused() {
+            return Err(not_mapped());
+        }
+        Ok(ept_pte)
     }
 
     pub fn frame_iter(
@@ -432,6 +624,26 @@ impl GuestAddressSpace {
         Ok(out)
     }
 
+    /// Write to guest physical memory (regardless of the guest paging)
+    pub fn write_phys_bytes(
+        &mut self,
+        addr: GuestPhysAddr,
+        mut bytes: &[u8],
+    ) -> Result<()> {
+        let mut addr = addr.as_u64();
+        while !bytes.is_empty() {
+            let mut frame = self.find_host_frame(GuestPhysAddr::new(addr))?;
+            let offset = (addr % HostPhysFrame::SIZE as u64) as usize;
+            let count =
+                core::cmp::min(bytes.len(), HostPhysFrame::SIZE - offset);
+            let array = unsafe { frame.as_mut_array() };
+            array[offset..offset + count].copy_from_slice(&bytes[..count]);
+            bytes = &bytes[count..];
+            addr += count as u64;
+        }
+        Ok(())
+    }
+
     pub fn write_bytes(
         &mut self,
         cr3: GuestPhysAddr,
@@ -536,6 +748,14 @@ where
             .borrow_mut()
             .write_bytes(self.cr3, addr, bytes, access)
     }
+
+    pub fn write_phys_bytes(
+        &mut self,
+        addr: GuestPhysAddr,
+        bytes: &[u8],
+    ) -> Result<()> {
+        self.space.borrow_mut().write_phys_bytes(addr, bytes)
+    }
 }
 
 impl<T> Deref for GuestAddressSpaceWrapper<T>
@@ -638,6 +858,24 @@ impl EptTableEntry {
     pub fn set_flags(&mut self, flags: EptTableFlags) {
         self.entry = self.addr().as_u64() | flags.bits();
     }
+
+    /// Returns true if the entry maps a 2MB or 1GB page (instead of
+    /// referencing another table)
+    pub fn is_large_page(&self) -> bool {
+        self.flags().contains(EptTableFlags::LARGE_PAGE)
+    }
+
+    pub fn mem_type(&self) -> EptMemoryType {
+        EptMemoryType::try_from(((self.entry & (0b111 << 3)) >> 3) as u8)
+            .expect("Invalid EPT memory type")
+    }
+
+    /// Set the memory type of a large page (this must be zero for entries
+    /// that reference a table)
+    pub fn set_mem_type(&mut self, mem_type: EptMemoryType) {
+        self.entry &= !(0b111u64 << 3);
+        self.entry |= (mem_type as u64) << 3;
+    }
 }
 
 #[derive(Copy, Clone, TryFromPrimitive)]
@@ -678,25 +916,25 @@ impl EptPageTableEntry {
     }
 
     pub fn mem_type(&self) -> EptMemoryType {
-        EptMemoryType::try_from(((self.entry & (0b111 << 5)) >> 5) as u8)
+        EptMemoryType::try_from(((self.entry & (0b111 << 3)) >> 3) as u8)
             .expect("Invalid EPT memory type")
     }
 
     pub fn set_addr(&mut self, addr: HostPhysAddr, flags: EptTableFlags) {
         assert!(addr.is_frame_aligned());
         self.entry =
-            (addr.as_u64()) | flags.bits() | ((self.mem_type() as u64) << 5);
+            (addr.as_u64()) | flags.bits() | ((self.mem_type() as u64) << 3);
     }
 
     pub fn set_flags(&mut self, flags: EptTableFlags) {
         self.entry = self.addr().as_u64()
             | flags.bits()
-            | ((self.mem_type() as u64) << 5);
+            | ((self.mem_type() as u64) << 3);
     }
 
     pub fn set_mem_type(&mut self, mem_type: EptMemoryType) {
-        self.entry &= !(0b111u64 << 5);
-        self.entry |= ((mem_type as u8) << 5) as u64;
+        self.entry &= !(0b111u64 << 3);
+        self.entry |= (mem_type as u64) << 3;
     }
 }
 
@@ -707,6 +945,7 @@ bitflags! {
         const WRITE_ACCESS =         1 << 1;
         const PRIV_EXEC_ACCESS =     1 << 2;
         const IGNORE_PAT =           1 << 6;
+        const LARGE_PAGE =           1 << 7;
         const ACCESSED =             1 << 8;
         const DIRTY =                1 << 9;
         const USERMODE_EXEC_ACCESS = 1 << 10;
@@ -723,63 +962,217 @@ pub type EptPageDirectoryPointerTable = EptTable<EptPageDirectoryPointerEntry>;
 pub type EptPageDirectory = EptTable<EptPageDirectoryEntry>;
 pub type EptPageTable = EptTable<EptPageTableEntry>;
 
+fn leaf_flags(readonly: bool) -> EptTableFlags {
+    let mut flags = EptTableFlags::READ_ACCESS
+        | EptTableFlags::PRIV_EXEC_ACCESS
+        | EptTableFlags::USERMODE_EXEC_ACCESS
+        | EptTableFlags::IGNORE_PAT;
+    if !readonly {
+        flags |= EptTableFlags::WRITE_ACCESS;
+    }
+    flags
+}
+
+fn table_flags() -> EptTableFlags {
+    EptTableFlags::READ_ACCESS
+        | EptTableFlags::WRITE_ACCESS
+        | EptTableFlags::PRIV_EXEC_ACCESS
+        | EptTableFlags::USERMODE_EXEC_ACCESS
+}
+
+fn duplicate_mapping(guest_addr: GuestPhysAddr) -> Error {
+    Error::DuplicateMapping(format!(
+        "Duplicate mapping for address 0x{:x}",
+        guest_addr.as_u64()
+    ))
+}
+
+// Allocate zeroed host memory for a 2MB or 1GB page
+fn alloc_large_page(size: PageSize, node: u32) -> Option<HostPhysAddr> {
+    let frames = (size.bytes() / PageSize::Size4K.bytes()) as usize;
+    frame_alloc::allocate_frames(frames, size, node)
+        .ok()
+        .map(|frame| frame.start_address())
+}
+
+// Allocate an empty EPT table
+fn alloc_table<T>(node: u32) -> Result<*mut T> {
+    let frame = frame_alloc::allocate_frames(1, PageSize::Size4K, node)?;
+    Ok(frame.start_address().as_u64() as *mut T)
+}
+
+// Replace a 1GB page with a page directory of 2MB pages mapping the same
+// memory
+fn split_huge_page(
+    ept_pdpe: &mut EptPageDirectoryPointerEntry,
+    node: u32,
+) -> Result<()> {
+    let base = ept_pdpe.addr().as_u64();
+    let ept_pdt = alloc_table::<EptPageDirectory>(node)?;
+    for (i, ept_pde) in unsafe { (*ept_pdt).entries.iter_mut() }.enumerate() {
+        ept_pde.set_addr(
+            HostPhysAddr::new(base + i as u64 * PageSize::Size2M.bytes()),
+            ept_pdpe.flags(),
+        );
+        ept_pde.set_mem_type(ept_pdpe.mem_type());
+    }
+    ept_pdpe.set_addr(HostPhysAddr::new(ept_pdt as u64), table_flags());
+    Ok(())
+}
+
+// Replace a 2MB page with a page table of 4KB pages mapping the same memory
+fn split_large_page(
+    ept_pde: &mut EptPageDirectoryEntry,
+    node: u32,
+) -> Result<()> {
+    let base = ept_pde.addr().as_u64();
+    let ept_pt = alloc_table::<EptPageTable>(node)?;
+    for (i, ept_pte) in unsafe { (*ept_pt).entries.iter_mut() }.enumerate() {
+        ept_pte.set_mem_type(ept_pde.mem_type());
+        ept_pte.set_addr(
+            HostPhysAddr::new(base + i as u64 * PageSize::Size4K.bytes()),
+            ept_pde.flags() - EptTableFlags::LARGE_PAGE,
+        );
+    }
+    ept_pde.set_addr(HostPhysAddr::new(ept_pt as u64), table_flags());
+    Ok(())
+}
+
 fn map_guest_memory(
     guest_ept_base: &mut EptPml4Table,
     guest_addr: GuestPhysAddr,
-    host_frame: HostPhysFrame,
+    host_addr: HostPhysAddr,
+    size: PageSize,
     readonly: bool,
+    node: u32,
 ) -> Result<()> {
-    let default_flags = EptTableFlags::READ_ACCESS
-        | EptTableFlags::WRITE_ACCESS
-        | EptTableFlags::PRIV_EXEC_ACCESS
-        | EptTableFlags::USERMODE_EXEC_ACCESS;
-
     let ept_pml4e = &mut guest_ept_base[guest_addr.p4_index()];
     if ept_pml4e.is_unused() {
-        let ept_pdpt_frame =
-            Box::into_raw(Box::new(EptPageDirectoryPointerTable::default()));
+        let ept_pdpt_frame = alloc_table::<EptPageDirectoryPointerTable>(node)?;
         let ept_pdpt_addr = HostPhysAddr::new(ept_pdpt_frame as u64);
-        ept_pml4e.set_addr(ept_pdpt_addr, default_flags);
+        ept_pml4e.set_addr(ept_pdpt_addr, table_flags());
     }
 
     let ept_pdpt =
         ept_pml4e.addr().as_u64() as *mut EptPageDirectoryPointerTable;
     let ept_pdpe = unsafe { &mut (*ept_pdpt)[guest_addr.p3_index()] };
-    if ept_pdpe.is_unused() {
-        let ept_pdt_frame =
-            Box::into_raw(Box::new(EptPageDirectory::default()));
+    if size == PageSize::Size1G {
+        if !ept_pdpe.is_unused() {
+            return Err(duplicate_mapping(guest_addr));
+        }
+        ept_pdpe.set_addr(
+            host_addr,
+            leaf_flags(readonly) | EptTableFlags::LARGE_PAGE,
+        );
+        ept_pdpe.set_mem_type(EptMemoryType::WriteBack);
+        return Ok(());
+    } else if ept_pdpe.is_large_page() {
+        return Err(duplicate_mapping(guest_addr));
+    } else if ept_pdpe.is_unused() {
+        let ept_pdt_frame = alloc_table::<EptPageDirectory>(node)?;
         let ept_pdt_addr = HostPhysAddr::new(ept_pdt_frame as u64);
-        ept_pdpe.set_addr(ept_pdt_addr, default_flags);
+        ept_pdpe.set_addr(ept_pdt_addr, table_flags());
     }
 
     let ept_pdt = ept_pdpe.addr().as_u64() as *mut EptPageDirectory;
     let ept_pde = unsafe { &mut (*ept_pdt)[guest_addr.p2_index()] };
-    if ept_pde.is_unused() {
-        let ept_pt_frame = Box::into_raw(Box::new(EptPageTable::default()));
+    if size == PageSize::Size2M {
+        if !ept_pde.is_unused() {
+            return Err(duplicate_mapping(guest_addr));
+        }
+        ept_pde.set_addr(
+            host_addr,
+            leaf_flags(readonly) | EptTableFlags::LARGE_PAGE,
+        );
+        ept_pde.set_mem_type(EptMemoryType::WriteBack);
+        return Ok(());
+    } else if ept_pde.is_large_page() {
+        return Err(duplicate_mapping(guest_addr));
+    } else if ept_pde.is_unused() {
+        let ept_pt_frame = alloc_table::<EptPageTable>(node)?;
         let ept_pt_addr = HostPhysAddr::new(ept_pt_frame as u64);
-        ept_pde.set_addr(ept_pt_addr, default_flags);
+        ept_pde.set_addr(ept_pt_addr, table_flags());
     }
 
     let ept_pt = ept_pde.addr().as_u64() as *mut EptPageTable;
     let ept_pte = unsafe { &mut (*ept_pt)[guest_addr.p1_index()] };
 
     if !ept_pte.is_unused() {
-        return Err(Error::DuplicateMapping(format!(
-            "Duplicate mapping for address 0x{:x}",
-            guest_addr.as_u64()
-        )));
+        return Err(duplicate_mapping(guest_addr));
     }
 
-    let mut page_flags = EptTableFlags::READ_ACCESS
-        | EptTableFlags::PRIV_EXEC_ACCESS
-        | EptTableFlags::USERMODE_EXEC_ACCESS
-        | EptTableFlags::IGNORE_PAT;
-    if !readonly {
-        page_flags |= EptTableFlags::WRITE_ACCESS;
-    }
-
-    ept_pte.set_addr(host_frame.start_address(), page_flags);
+    ept_pte.set_addr(host_addr, leaf_flags(readonly));
     ept_pte.set_mem_type(EptMemoryType::WriteBack);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MB: u64 = 1024 * 1024;
+
+    #[test]
+    fn test_map_large_pages() {
+        let mut space = GuestAddressSpace::new(PageSize::Size2M, 0).unwrap();
+        let page = Box::into_raw(Box::new(Raw4kPage::default()));
+        let frame =
+            HostPhysFrame::from_start_address(HostPhysAddr::new(page as u64))
+                .unwrap();
+        space
+            .map_frame(GuestPhysAddr::new(0x1000), frame, false)
+            .unwrap();
+        space
+            .map_new_region(GuestPhysAddr::new(0), 4 * MB, false)
+            .unwrap();
+
+        // The first 2MB already had a table, so it uses 4KB pages
+        assert_eq!(
+            space.mapping(GuestPhysAddr::new(0x1000)),
+            EptMapping::Mapped(frame.start_address(), PageSize::Size4K)
+        );
+        let base = match space.mapping(GuestPhysAddr::new(2 * MB + 0x3000)) {
+            EptMapping::Mapped(base, PageSize::Size2M) => base,
+            mapping => panic!("Unexpected mapping {:?}", mapping),
+        };
+        assert_eq!(
+            space
+                .find_host_frame(GuestPhysAddr::new(2 * MB + 0x3010))
+                .unwrap()
+                .start_address(),
+            HostPhysAddr::new(base.as_u64() + 0x3000)
+        );
+        assert!(space.find_host_frame(GuestPhysAddr::new(4 * MB)).is_err());
+        assert!(space
+            .map_frame(GuestPhysAddr::new(3 * MB), frame, false)
+            .is_err());
+    }
+
+    #[test]
+    fn test_split_large_page() {
+        let mut space = GuestAddressSpace::new(PageSize::Size2M, 0).unwrap();
+        space
+            .map_new_region(GuestPhysAddr::new(0), 2 * MB, false)
+            .unwrap();
+        let before = space.find_host_frame(GuestPhysAddr::new(0x5000)).unwrap();
+
+        space
+            .set_frame_readonly(GuestPhysAddr::new(0x4000), true)
+            .unwrap();
+        space.unmap_frame(GuestPhysAddr::new(0x6000)).unwrap();
+
+        // The rest of the page still maps the same memory
+        assert_eq!(
+            space.mapping(GuestPhysAddr::new(0x5000)),
+            EptMapping::Mapped(before.start_address(), PageSize::Size4K)
+        );
+        assert_eq!(
+            space.mapping(GuestPhysAddr::new(0x6000)),
+            EptMapping::Unmapped(PageSize::Size4K)
+        );
+        let pte = space.find_pte_mut(GuestPhysAddr::new(0x4000)).unwrap();
+        assert!(!pte.flags().contains(EptTableFlags::WRITE_ACCESS));
+        assert_eq!(pte.mem_type() as u8, EptMemoryType::WriteBack as u8);
+    }
+}
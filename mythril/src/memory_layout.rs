@@ -0,0 +1,247 @@
+//! The layout of guest physical memory
+//!
+//! Guest RAM starts at address zero and runs up to the PCI hole (3GB by
+//! default). The hole extends to 4GB and holds the MMIO windows of the
+//! emulated devices and the PCI BARs, so any remaining RAM is placed above
+//! 4GB. The same layout is reported to the guest through the CMOS, the
+//! fw_cfg `etc/e820` file and ACPI.
+
+use crate::error::{Error, Result};
+use crate::memory::GuestPhysAddr;
+use alloc::vec::Vec;
+
+/// The default start of the PCI hole
+pub const DEFAULT_PCI_HOLE: u64 = 0xc000_0000;
+
+/// The lowest allowed start of the PCI hole
+pub const MIN_PCI_HOLE: u64 = 0x0100_0000;
+
+/// The highest allowed start of the PCI hole (leaving room for PCI BARs)
+pub const MAX_PCI_HOLE: u64 = 0xe000_0000;
+
+/// The size of the PCI Express configuration space (ECAM) window at the
+/// start of the PCI hole (enough for bus 0)
+pub const PCI_ECAM_SIZE: u64 = 0x10_0000;
+
+/// The guest physical address of the IOAPIC registers
+pub const IOAPIC_BASE: u64 = 0xfec0_0000;
+
+/// The guest physical address of the HPET registers
+pub const HPET_BASE: u64 = 0xfed0_0000;
+
+/// The guest physical address of the local APIC registers
+pub const LOCAL_APIC_BASE: u64 = 0xfee0_0000;
+
+/// The start of the RAM above the PCI hole
+pub const HIGH_MEMORY_BASE: u64 = 0x1_0000_0000;
+
+// The size of the windows of the IOAPIC, HPET and local APIC
+const DEVICE_WINDOW_SIZE: u64 = 0x1000;
+
+// See 'Address Range Types' in the ACPI specification
+const E820_RAM: u32 = 1;
+const E820_RESERVED: u32 = 2;
+
+/// The use of a region of guest physical memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegionKind {
+    Ram,
+    Mmio,
+}
+
+/// A region of guest physical memory
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GuestRegion {
+    pub name: &'static str,
+    pub kind: RegionKind,
+    pub start: GuestPhysAddr,
+    pub size: u64,
+}
+
+impl GuestRegion {
+    fn new(
+        name: &'static str,
+        kind: RegionKind,
+        start: u64,
+        size: u64,
+    ) -> Self {
+        Self {
+            name: name,
+            kind: kind,
+            start: GuestPhysAddr::new(start),
+            size: size,
+        }
+    }
+
+    /// The address after the end of the region
+    pub fn end(&self) -> u64 {
+        self.start.as_u64() + self.size
+    }
+}
+
+/// Where RAM and MMIO windows are placed in guest physical memory
+#[derive(Clone, Debug, PartialEq)]
+pub struct GuestMemoryLayout {
+    ram_below_4gb: u64,
+    ram_above_4gb: u64,
+    pci_hole: u64,
+}
+
+impl GuestMemoryLayout {
+    /// Create the layout of a VM with `memory` MB of RAM and a PCI hole
+    /// starting at `pci_hole`
+    pub fn new(memory: u64, pci_hole: u64) -> Result<Self> {
+        if pci_hole < MIN_PCI_HOLE
+            || pci_hole > MAX_PCI_HOLE
+            || pci_hole % (1024 * 1024) != 0
+        {
+            return Err(Error::InvalidValue(format!(
+                "Invalid PCI hole start 0x{:x}",
+                pci_hole
+            )));
+        }
+        let ram = memory << 20;
+        let ram_below_4gb = core::cmp::min(ram, pci_hole);
+        Ok(Self {
+            ram_below_4gb: ram_below_4gb,
+            ram_above_4gb: ram - ram_below_4gb,
+            pci_hole: pci_hole,
+        })
+    }
+
+    /// The total amount of RAM (in bytes)
+    pub fn ram_size(&self) -> u64 {
+        self.ram_below_4gb + self.ram_above_4gb
+    }
+
+    /// The amount of RAM below the PCI hole (in bytes)
+    pub fn ram_below_4gb(&self) -> u64 {
+        self.ram_below_4gb
+    }
+
+    /// The amount of RAM above 4GB (in bytes)
+    pub fn ram_above_4gb(&self) -> u64 {
+        self.ram_above_4gb
+    }
+
+    /// The start of the PCI hole
+    pub fn pci_hole(&self) -> u64 {
+        self.pci_hole
+    }
+
+    /// The RAM regions of the VM
+    pub fn ram(&self) -> Vec<GuestRegion> {
+        let mut ram = vec![GuestRegion::new(
+            "ram",
+            RegionKind::Ram,
+            0,
+            self.ram_below_4gb,
+        )];
+        if self.ram_above_4gb > 0 {
+            ram.push(GuestRegion::new(
+                "high ram",
+                RegionKind::Ram,
+                HIGH_MEMORY_BASE,
+                self.ram_above_4gb,
+            ));
+        }
+        ram
+    }
+
+    /// The MMIO windows in the PCI hole
+    pub fn mmio_windows(&self) -> Vec<GuestRegion> {
+        vec![
+            self.pci_ecam(),
+            self.pci_window(),
+            GuestRegion::new(
+                "ioapic",
+                RegionKind::Mmio,
+                IOAPIC_BASE,
+                DEVICE_WINDOW_SIZE,
+            ),
+            GuestRegion::new(
+                "hpet",
+                RegionKind::Mmio,
+                HPET_BASE,
+                DEVICE_WINDOW_SIZE,
+            ),
+            GuestRegion::new(
+                "lapic",
+                RegionKind::Mmio,
+                LOCAL_APIC_BASE,
+                DEVICE_WINDOW_SIZE,
+            ),
+        ]
+    }
+
+    /// The PCI Express configuration space (ECAM) window
+    pub fn pci_ecam(&self) -> GuestRegion {
+        GuestRegion::new(
+            "pci ecam",
+            RegionKind::Mmio,
+            self.pci_hole,
+            PCI_ECAM_SIZE,
+        )
+    }
+
+    /// The window that 32-bit PCI BARs are placed in
+    pub fn pci_window(&self) -> GuestRegion {
+        let start = self.pci_hole + PCI_ECAM_SIZE;
+        GuestRegion::new("pci", RegionKind::Mmio, start, IOAPIC_BASE - start)
+    }
+
+    /// The contents of the fw_cfg `etc/e820` file
+    ///
+    /// Each entry is a little endian 64-bit address and length, followed by
+    /// a 32-bit type.
+    pub fn e820_table(&self) -> Vec<u8> {
+        let mut table = vec![];
+        let regions = self.ram().into_iter().chain(self.mmio_windows());
+        for region in regions.filter(|region| region.size > 0) {
+            let kind = match region.kind {
+                RegionKind::Ram => E820_RAM,
+
+                // The PCI window is left as a gap, so the guest can place BARs
+                // in it
+                RegionKind::Mmio if region.name == "pci" => continue,
+                RegionKind::Mmio => E820_RESERVED,
+            };
+            table.extend_from_slice(&region.start.as_u64().to_le_bytes());
+            table.extend_from_slice(&region.size.to_le_bytes());
+            table.extend_from_slice(&kind.to_le_bytes());
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_layout_with_high_memory() {
+        let layout = GuestMemoryLayout::new(4096, DEFAULT_PCI_HOLE).unwrap();
+        assert_eq!(layout.ram_below_4gb(), 0xc000_0000);
+        assert_eq!(layout.ram_above_4gb(), 0x4000_0000);
+        assert_eq!(layout.ram()[1].start, GuestPhysAddr::new(0x1_0000_0000));
+        assert_eq!(layout.pci_window().end(), IOAPIC_BASE);
+
+        // Two RAM entries, the ECAM window and the three device windows
+        let e820 = layout.e820_table();
+        assert_eq!(e820.len(), 6 * 20);
+        assert_eq!(&e820[20..28], &0x1_0000_0000u64.to_le_bytes());
+        assert_eq!(&e820[36..40], &E820_RAM.to_le_bytes());
+        assert_eq!(&e820[40..48], &DEFAULT_PCI_HOLE.to_le_bytes());
+        assert_eq!(&e820[56..60], &E820_RESERVED.to_le_bytes());
+        assert_eq!(&e820[60..68], &IOAPIC_BASE.to_le_bytes());
+    }
+
+    #[test]
+    fn test_layout_below_pci_hole() {
+        let layout = GuestMemoryLayout::new(256, DEFAULT_PCI_HOLE).unwrap();
+        assert_eq!(layout.ram_below_4gb(), 256 << 20);
+        assert_eq!(layout.ram().len(), 1);
+        assert!(GuestMemoryLayout::new(256, 0xf000_0000).is_err());
+        assert!(GuestMemoryLayout::new(256, 0xc000_1000).is_err());
+    }
+}
@@ -0,0 +1,436 @@
+//! An interactive monitor for inspecting and controlling the hypervisor
+//!
+//! The monitor is entered from the host console with `Ctrl-A m` (see the
+//! `console` module). The commands that refer to a vcpu (`regs` and `vmcs`)
+//! or to guest memory (`x`) act on the VM whose console has the focus.
+//!
+//! The state of a vcpu (and the timers of its core) can only be read on the
+//! core running it, so those commands ask the vcpu to print its state the
+//! next time it exits.
+
+use crate::device::DeviceRegion;
+use crate::error::{Error, Result};
+use crate::logger;
+use crate::memory::{GuestPhysAddr, HostPhysFrame};
+use crate::vcpu::MonitorRequest;
+use crate::vm::VirtualMachine;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use spin::RwLock;
+
+const PROMPT: &str = "(mythril) ";
+
+const HELP: &str = "\
+vms                 list the VMs\r
+vcpus               list the vcpus of each VM\r
+regs <vcpu>         show the registers of a vcpu of the focused VM\r
+vmcs <vcpu>         show the VMCS of a vcpu of the focused VM\r
+x/<n> <gpa>         dump n bytes of memory of the focused VM\r
+devices <vm>        list the devices of a VM\r
+pause <vm>          pause a VM\r
+resume <vm>         resume a paused VM\r
+reset <vm>          reset a VM\r
+powerbtn <vm>       press the ACPI power button of a VM\r
+gpe <vm> <n>        signal ACPI general purpose event n of a VM\r
+nvram <vm>          dump the CMOS memory of a VM\r
+exits <vm>          show the exit statistics of each vcpu of a VM\r
+trace <vm>          show the recent exits of each vcpu of a VM\r
+timers              show the timers of each core\r
+quit                return to the VM console\r
+";
+
+/// The largest number of bytes that can be dumped with `x`
+const MAX_DUMP_SIZE: u64 = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Command {
+    Help,
+    Vms,
+    Vcpus,
+    Registers(usize),
+    Vmcs(usize),
+    Examine { count: u64, addr: u64 },
+    Devices(usize),
+    Pause(usize),
+    Resume(usize),
+    Reset(usize),
+    PowerButton(usize),
+    Gpe { vmid: usize, gpe: u8 },
+    Nvram(usize),
+    Exits(usize),
+    Trace(usize),
+    Timers,
+    Quit,
+}
+
+fn parse_number(s: &str) -> Result<u64> {
+    let res = if s.starts_with("0x") {
+        u64::from_str_radix(&s[2..], 16)
+    } else {
+        u64::from_str_radix(s, 10)
+    };
+    res.map_err(|_| Error::InvalidValue(format!("Invalid number '{}'", s)))
+}
+
+impl Command {
+    /// Parse a command line, returning `None` if it is empty
+    fn parse(line: &str) -> Result<Option<Self>> {
+        let mut words = line.split_whitespace();
+        let name = match words.next() {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let args: Vec<&str> = words.collect();
+
+        let arg = |index: usize| -> Result<u64> {
+            let arg = args.get(index).ok_or_else(|| {
+                Error::InvalidValue(format!("'{}' needs an argument", name))
+            })?;
+            parse_number(arg)
+        };
+
+        let cmd = match name {
+            "help" | "?" => Command::Help,
+            "vms" => Command::Vms,
+            "vcpus" => Command::Vcpus,
+            "regs" => Command::Registers(arg(0)? as usize),
+            "vmcs" => Command::Vmcs(arg(0)? as usize),
+            "devices" => Command::Devices(arg(0)? as usize),
+            "pause" => Command::Pause(arg(0)? as usize),
+            "resume" => Command::Resume(arg(0)? as usize),
+            "reset" => Command::Reset(arg(0)? as usize),
+            "powerbtn" => Command::PowerButton(arg(0)? as usize),
+            "gpe" => Command::Gpe {
+                vmid: arg(0)? as usize,
+                gpe: u8::try_from(arg(1)?).map_err(|_| {
+                    Error::InvalidValue(format!("Invalid GPE '{}'", args[1]))
+                })?,
+            },
+            "nvram" => Command::Nvram(arg(0)? as usize),
+            "exits" => Command::Exits(arg(0)? as usize),
+            "trace" => Command::Trace(arg(0)? as usize),
+            "timers" => Command::Timers,
+            "quit" | "q" => Command::Quit,
+            name if name.starts_with("x/") => Command::Examine {
+                count: parse_number(&name[2..])?,
+                addr: arg(0)?,
+            },
+            name => {
+                return Err(Error::InvalidValue(format!(
+                    "Unknown command '{}'",
+                    name
+                )))
+            }
+        };
+        Ok(Some(cmd))
+    }
+}
+
+/// Read guest physical memory
+fn read_guest_memory(
+    vm: &VirtualMachine,
+    addr: u64,
+    len: u64,
+) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    let mut addr = addr;
+    let end = addr.checked_add(len).ok_or_else(|| {
+        Error::InvalidValue(format!(
+            "0x{:x} bytes at 0x{:x} is beyond the end of memory",
+            len, addr
+        ))
+    })?;
+    while addr < end {
+        let frame = vm.guest_space.find_host_frame(GuestPhysAddr::new(addr))?;
+        let offset = (addr % HostPhysFrame::SIZE as u64) as usize;
+        let count =
+            core::cmp::min(end - addr, (HostPhysFrame::SIZE - offset) as u64);
+        let array = unsafe { frame.as_array() };
+        out.extend_from_slice(&array[offset..offset + count as usize]);
+        addr += count;
+    }
+    Ok(out)
+}
+
+/// The hypervisor monitor
+pub struct Monitor {
+    // The VMs, indexed by vmid
+    vms: Vec<Arc<RwLock<VirtualMachine>>>,
+    line: String,
+}
+
+impl Monitor {
+    pub fn new(vms: Vec<Arc<RwLock<VirtualMachine>>>) -> Self {
+        Self {
+            vms: vms,
+            line: String::new(),
+        }
+    }
+
+    /// Start a monitor session
+    pub fn enter(&mut self) {
+        self.line.clear();
+        logger::write_console(&format!(
+            "\r\n[monitor] type 'help' for the commands\r\n{}",
+            PROMPT
+        ));
+    }
+
+    /// Handle a byte of input from the host console
+    ///
+    /// Returns true if the session has ended.
+    pub fn input(&mut self, byte: u8, focus: usize) -> bool {
+        match byte {
+            b'\r' | b'\n' => {
+                logger::write_console("\r\n");
+                let line = core::mem::replace(&mut self.line, String::new());
+                match Command::parse(&line) {
+                    Ok(Some(Command::Quit)) => return true,
+                    Ok(Some(cmd)) => {
+                        if let Err(e) = self.execute(cmd, focus) {
+                            logger::write_console(&format!(
+                                "error: {:?}\r\n",
+                                e
+                            ));
+                        }
+                    }
+                    Ok(None) => (),
+                    Err(e) => {
+                        logger::write_console(&format!("error: {:?}\r\n", e))
+                    }
+                }
+                logger::write_console(PROMPT);
+            }
+
+            // Backspace or delete
+            0x08 | 0x7f => {
+                if self.line.pop().is_some() {
+                    logger::write_console("\x08 \x08");
+                }
+            }
+            0x20..=0x7e => {
+                self.line.push(byte as char);
+                let mut echo = [0u8; 1];
+                logger::write_console((byte as char).encode_utf8(&mut echo));
+            }
+            _ => (),
+        }
+        false
+    }
+
+    fn vm(&self, vmid: usize) -> Result<&Arc<RwLock<VirtualMachine>>> {
+        self.vms
+            .get(vmid)
+            .ok_or_else(|| Error::InvalidValue(format!("No VM {}", vmid)))
+    }
+
+    fn request(
+        &self,
+        vmid: usize,
+        vcpu: usize,
+        request: MonitorRequest,
+    ) -> Result<()> {
+        let vm = self.vm(vmid)?.read();
+        let mailbox = vm.config.mailboxes().get(vcpu).ok_or_else(|| {
+            Error::InvalidValue(format!("No vcpu {} in VM {}", vcpu, vmid))
+        })?;
+        mailbox.request(request);
+        Ok(())
+    }
+
+    fn request_all(&self, vmid: usize, request: MonitorRequest) -> Result<()> {
+        let vcpus = self.vm(vmid)?.read().config.cpus().len();
+        for vcpu in 0..vcpus {
+            self.request(vmid, vcpu, request)?;
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, cmd: Command, focus: usize) -> Result<()> {
+        let mut out = String::new();
+        match cmd {
+            Command::Help => out += HELP,
+            Command::Vms => {
+                for (vmid, vm) in self.vms.iter().enumerate() {
+                    let vm = vm.read();
+                    out += &format!(
+                        "vm {}: {} vcpus, {}MB, {:?}{}\r\n",
+                        vmid,
+                        vm.config.cpus().len(),
+                        vm.config.memory(),
+                        vm.config.lifecycle().state(),
+                        if vmid == focus { " (focused)" } else { "" }
+                    );
+                }
+            }
+            Command::Vcpus => {
+                for (vmid, vm) in self.vms.iter().enumerate() {
+                    let vm = vm.read();
+                    for (id, core) in vm.config.cpus().iter().enumerate() {
+                        out += &format!(
+                            "vm {} vcpu {}: core {}\r\n",
+                            vmid, id, core
+                        );
+                    }
+                }
+            }
+            Command::Registers(vcpu) => {
+                self.request(focus, vcpu, MonitorRequest::Registers)?
+            }
+            Command::Vmcs(vcpu) => {
+                self.request(focus, vcpu, MonitorRequest::Vmcs)?
+            }
+            Command::Examine { count, addr } => {
+                if count > MAX_DUMP_SIZE {
+                    return Err(Error::InvalidValue(format!(
+                        "At most {} bytes can be dumped",
+                        MAX_DUMP_SIZE
+                    )));
+                }
+                let bytes =
+                    read_guest_memory(&self.vm(focus)?.read(), addr, count)?;
+                for (i, chunk) in bytes.chunks(16).enumerate() {
+                    out += &format!("0x{:08x}:", addr + i as u64 * 16);
+                    for byte in chunk {
+                        out += &format!(" {:02x}", byte);
+                    }
+                    out += "\r\n";
+                }
+            }
+            Command::Devices(vmid) => {
+                let mut vm = self.vm(vmid)?.write();
+                for dev in vm.config.device_map().devices() {
+                    out += " ";
+                    for region in dev.services() {
+                        out += &match region {
+                            DeviceRegion::PortIo(ports) => format!(
+                                " port 0x{:x}-0x{:x}",
+                                ports.start(),
+                                ports.end()
+                            ),
+                            DeviceRegion::MemIo(addrs) => format!(
+                                " mmio 0x{:x}-0x{:x}",
+                                addrs.start().as_u64(),
+                                addrs.end().as_u64()
+                            ),
+                        };
+                    }
+                    out += "\r\n";
+                }
+            }
+            Command::Pause(vmid) => {
+                self.vm(vmid)?.read().config.lifecycle().pause()
+            }
+            Command::Resume(vmid) => {
+                self.vm(vmid)?.read().config.lifecycle().resume()
+            }
+            Command::Reset(vmid) => {
+                self.vm(vmid)?.read().config.lifecycle().reset()
+            }
+            Command::PowerButton(vmid) => {
+                let acpi = self.vm(vmid)?.read().config.acpi().cloned();
+                match acpi {
+                    Some(acpi) => acpi.lock().press_power_button(),
+                    None => out += "the VM has no ACPI device\r\n",
+                }
+            }
+            Command::Gpe { vmid, gpe } => {
+                let acpi = self.vm(vmid)?.read().config.acpi().cloned();
+                match acpi {
+                    Some(acpi) => acpi.lock().raise_gpe(gpe)?,
+                    None => out += "the VM has no ACPI device\r\n",
+                }
+            }
+            Command::Nvram(vmid) => {
+                let rtc = self.vm(vmid)?.read().config.rtc().cloned();
+                match rtc {
+                    Some(rtc) => {
+                        let nvram = rtc.lock().nvram();
+                        for (i, chunk) in nvram.chunks(16).enumerate() {
+                            out += &format!("0x{:02x}:", i * 16);
+                            for byte in chunk {
+                                out += &format!(" {:02x}", byte);
+                            }
+                            out += "\r\n";
+                        }
+                    }
+                    None => out += "the VM has no RTC\r\n",
+                }
+            }
+            Command::Exits(vmid) => {
+                self.request_all(vmid, MonitorRequest::ExitStats)?
+            }
+            Command::Trace(vmid) => {
+                self.request_all(vmid, MonitorRequest::ExitTrace)?
+            }
+            Command::Timers => {
+                for vmid in 0..self.vms.len() {
+                    self.request_all(vmid, MonitorRequest::Timers)?;
+                }
+            }
+            Command::Quit => (),
+        }
+        logger::write_console(&out);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::boot_info::BootInfo;
+    use crate::memory_layout::{self, GuestMemoryLayout};
+    use crate::vm::VirtualMachineConfig;
+
+    #[test]
+    fn test_read_guest_memory_overflow() {
+        let layout =
+            GuestMemoryLayout::new(0, memory_layout::DEFAULT_PCI_HOLE).unwrap();
+        let config = VirtualMachineConfig::new(vec![1], layout);
+        let vm = VirtualMachine::new(config, &BootInfo::default()).unwrap();
+        assert!(read_guest_memory(&vm.read(), u64::MAX, 16).is_err());
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(Command::parse("  ").unwrap(), None);
+        assert_eq!(Command::parse("vms").unwrap(), Some(Command::Vms));
+        assert_eq!(
+            Command::parse("regs 1").unwrap(),
+            Some(Command::Registers(1))
+        );
+        assert_eq!(
+            Command::parse("x/32 0x7c00").unwrap(),
+            Some(Command::Examine {
+                count: 32,
+                addr: 0x7c00
+            })
+        );
+        assert_eq!(
+            Command::parse("reset 0x2").unwrap(),
+            Some(Command::Reset(2))
+        );
+        assert_eq!(Command::parse("trace 1").unwrap(), Some(Command::Trace(1)));
+        assert_eq!(
+            Command::parse("powerbtn 0").unwrap(),
+            Some(Command::PowerButton(0))
+        );
+        assert_eq!(
+            Command::parse("gpe 0 3").unwrap(),
+            Some(Command::Gpe { vmid: 0, gpe: 3 })
+        );
+        assert_eq!(Command::parse("nvram 1").unwrap(), Some(Command::Nvram(1)));
+    }
+
+    #[test]
+    fn test_parse_invalid_commands() {
+        assert!(Command::parse("regs").is_err());
+        assert!(Command::parse("pause vm0").is_err());
+        assert!(Command::parse("x/ 0x1000").is_err());
+        assert!(Command::parse("reboot").is_err());
+        assert!(Command::parse("gpe 0").is_err());
+        assert!(Command::parse("gpe 0 0x103").is_err());
+    }
+}
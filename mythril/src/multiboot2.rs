@@ -1,5 +1,5 @@
 use crate::acpi;
-use crate::boot_info::{self, BootInfo};
+use crate::boot_info::{self, BootInfo, MemoryRegion};
 use crate::global_alloc;
 use crate::memory::HostPhysAddr;
 use alloc::vec::Vec;
@@ -16,6 +16,11 @@ pub fn header_location() -> (u32, u32) {
     unsafe { (MULTIBOOT2_HEADER_START, MULTIBOOT2_HEADER_END) }
 }
 
+// Guest memory comes from the frame allocator and the boot images are
+// passed to the guests in place (see `QemuFwCfgBuilder`), so the kernel
+// heap only needs room for the hypervisor's own data structures
+const KERNEL_HEAP_SIZE: u64 = 64 * 1024 * 1024;
+
 fn setup_global_alloc_region(info: &multiboot2::BootInformation) -> (u64, u64) {
     let mem_tag = info
         .memory_map_tag()
@@ -72,7 +77,7 @@ fn setup_global_alloc_region(info: &multiboot2::BootInformation) -> (u64, u64) {
         .max_by(|left, right| (left.1 - left.0).cmp(&(right.1 - right.0)))
         .expect("No largest region");
 
-    if largest_region.0 > max_excluded.1 {
+    let (start, end) = if largest_region.0 > max_excluded.1 {
         largest_region
     } else if max_excluded.1 > largest_region.0
         && max_excluded.1 < largest_region.1
@@ -80,7 +85,10 @@ fn setup_global_alloc_region(info: &multiboot2::BootInformation) -> (u64, u64) {
         (max_excluded.1, largest_region.1)
     } else {
         panic!("Unable to find suitable global alloc region")
-    }
+    };
+
+    // The rest of the region is left to the frame allocator
+    (start, core::cmp::min(end, start + KERNEL_HEAP_SIZE))
 }
 
 pub fn early_init_multiboot2(addr: HostPhysAddr) -> BootInfo {
@@ -106,6 +114,34 @@ pub fn early_init_multiboot2(addr: HostPhysAddr) -> BootInfo {
         })
         .collect::<Vec<_>>();
 
+    let memory_map = multiboot_info
+        .memory_map_tag()
+        .expect("Missing multiboot memory map tag")
+        .memory_areas()
+        .map(|area| MemoryRegion::new(area.start_address(), area.end_address()))
+        .collect::<Vec<_>>();
+
+    // The frame allocator must not use the memory that is already in use
+    let mut reserved = vec![
+        MemoryRegion::new(alloc_region.0, alloc_region.1),
+        MemoryRegion::new(
+            multiboot_info.start_address() as u64,
+            multiboot_info.end_address() as u64,
+        ),
+    ];
+    reserved.extend(multiboot_info.module_tags().map(|module| {
+        MemoryRegion::new(
+            module.start_address() as u64,
+            module.end_address() as u64,
+        )
+    }));
+    let sections_tag = multiboot_info
+        .elf_sections_tag()
+        .expect("Missing multiboot elf sections tag");
+    reserved.extend(sections_tag.sections().map(|section| {
+        MemoryRegion::new(section.start_address(), section.end_address())
+    }));
+
     let rsdp = multiboot_info
         .rsdp_v2_tag()
         .filter(|tag| tag.checksum_is_valid())
@@ -140,5 +176,7 @@ pub fn early_init_multiboot2(addr: HostPhysAddr) -> BootInfo {
     BootInfo {
         modules: modules,
         rsdp: rsdp,
+        memory_map: memory_map,
+        reserved: reserved,
     }
 }
@@ -0,0 +1,190 @@
+//! Support for the MC146818 compatible real time clock in the CMOS
+//!
+//! This contains the register definitions and date conversions shared by
+//! the host driver (used to read the wall clock time at boot) and the
+//! emulated `CmosRtc`.
+
+use crate::device::Port;
+
+use x86::io::{inb, outb};
+
+pub const RTC_ADDRESS: Port = 0x0070;
+pub const RTC_DATA: Port = 0x0071;
+
+/// Writing this bit to the address port disables NMIs
+pub const RTC_NMI_DISABLE: u8 = 1 << 7;
+
+pub const RTC_SECONDS: u8 = 0x00;
+pub const RTC_MINUTES: u8 = 0x02;
+pub const RTC_HOURS: u8 = 0x04;
+pub const RTC_DAY_OF_MONTH: u8 = 0x07;
+pub const RTC_MONTH: u8 = 0x08;
+pub const RTC_YEAR: u8 = 0x09;
+pub const RTC_STATUS_A: u8 = 0x0a;
+pub const RTC_STATUS_B: u8 = 0x0b;
+pub const RTC_CENTURY: u8 = 0x32;
+
+/// Register A: an update is in progress (the time must not be read)
+pub const RTC_A_UIP: u8 = 1 << 7;
+
+/// Register B: the time and date are in binary (instead of BCD)
+pub const RTC_B_BINARY: u8 = 1 << 2;
+
+/// Register B: the hours are in 24 hour format (instead of 12 hour)
+pub const RTC_B_24HOUR: u8 = 1 << 1;
+
+/// The PM bit of the hours register in 12 hour format
+pub const RTC_HOURS_PM: u8 = 1 << 7;
+
+pub fn from_bcd(val: u8) -> u8 {
+    (val >> 4) * 10 + (val & 0xf)
+}
+
+pub fn to_bcd(val: u8) -> u8 {
+    ((val / 10) << 4) | (val % 10)
+}
+
+/// A calendar date and time (in UTC)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DateTime {
+    pub year: u32,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Convert a number of seconds since the UNIX epoch
+    pub fn from_unix(secs: u64) -> Self {
+        // See http://howardhinnant.github.io/date_algorithms.html
+        let days = (secs / 86400) as i64 + 719468;
+        let era = days / 146097;
+        let day_of_era = days - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524
+            - day_of_era / 146096)
+            / 365;
+        let day_of_year = day_of_era
+            - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+        let secs_of_day = secs % 86400;
+        Self {
+            year: year as u32,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs_of_day / 3600) as u8,
+            minute: (secs_of_day / 60 % 60) as u8,
+            second: (secs_of_day % 60) as u8,
+        }
+    }
+
+    /// The number of seconds since the UNIX epoch
+    pub fn to_unix(&self) -> u64 {
+        let year = self.year as i64 - (self.month <= 2) as i64;
+        let era = year / 400;
+        let year_of_era = year - era * 400;
+        let mp = (self.month as i64 + 9) % 12;
+        let day_of_year = (153 * mp + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4
+            - year_of_era / 100
+            + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        days.max(0) as u64 * 86400
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    /// The day of the week, from 1 (Sunday) to 7 (Saturday)
+    pub fn day_of_week(&self) -> u8 {
+        // The epoch was a Thursday
+        ((self.to_unix() / 86400 + 4) % 7 + 1) as u8
+    }
+}
+
+unsafe fn read_register(reg: u8) -> u8 {
+    outb(RTC_ADDRESS, reg);
+    inb(RTC_DATA)
+}
+
+unsafe fn read_date_time() -> DateTime {
+    while read_register(RTC_STATUS_A) & RTC_A_UIP != 0 {}
+
+    let status_b = read_register(RTC_STATUS_B);
+    let decode = |val: u8| {
+        if status_b & RTC_B_BINARY != 0 {
+            val
+        } else {
+            from_bcd(val)
+        }
+    };
+
+    let hours = read_register(RTC_HOURS);
+    let mut hour = decode(hours & !RTC_HOURS_PM);
+    if status_b & RTC_B_24HOUR == 0 {
+        hour %= 12;
+        if hours & RTC_HOURS_PM != 0 {
+            hour += 12;
+        }
+    }
+
+    // Not every RTC has a century register
+    let century = match decode(read_register(RTC_CENTURY)) {
+        0 => 20,
+        century => century as u32,
+    };
+
+    //NOTE: this assumes the host RTC is in UTC
+    DateTime {
+        year: century * 100 + decode(read_register(RTC_YEAR)) as u32,
+        month: decode(read_register(RTC_MONTH)),
+        day: decode(read_register(RTC_DAY_OF_MONTH)),
+        hour: hour,
+        minute: decode(read_register(RTC_MINUTES)),
+        second: decode(read_register(RTC_SECONDS)),
+    }
+}
+
+/// Read the current time of the host RTC (in seconds since the UNIX epoch)
+pub fn read_host_time() -> u64 {
+    // Read the time until two consecutive reads match, in case an update
+    // started while it was being read
+    let mut time = unsafe { read_date_time() };
+    loop {
+        let next = unsafe { read_date_time() };
+        if next == time {
+            return time.to_unix();
+        }
+        time = next;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_date_time_conversion() {
+        let date = DateTime::from_unix(951_827_696);
+        assert_eq!(
+            date,
+            DateTime {
+                year: 2000,
+                month: 2,
+                day: 29,
+                hour: 12,
+                minute: 34,
+                second: 56,
+            }
+        );
+        assert_eq!(date.to_unix(), 951_827_696);
+        assert_eq!(date.day_of_week(), 3);
+        assert_eq!(DateTime::from_unix(0).day_of_week(), 5);
+    }
+}
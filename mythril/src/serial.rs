@@ -0,0 +1,94 @@
+//! A polled driver for the host's 16550 compatible serial ports
+//!
+//! This is used by host-side services that need to receive data (like the
+//! GDB stub), unlike the logger which only ever writes to COM1.
+
+use x86::io::{inb, outb};
+
+const DATA: u16 = 0;
+const DLL: u16 = 0;
+const IER: u16 = 1;
+const DLH: u16 = 1;
+const FCR: u16 = 2;
+const LCR: u16 = 3;
+const MCR: u16 = 4;
+const LSR: u16 = 5;
+
+const LCR_DLAB: u8 = 1 << 7;
+const LCR_8N1: u8 = 0b11;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+/// The host port used for the hypervisor log
+pub const LOG_PORT_BASE: u16 = 0x3f8;
+
+/// Get the base I/O port of a host COM port from its name (e.g., "com2")
+pub fn com_port_base(name: &str) -> Option<u16> {
+    match name {
+        "com1" => Some(0x3f8),
+        "com2" => Some(0x2f8),
+        "com3" => Some(0x3e8),
+        "com4" => Some(0x2e8),
+        _ => None,
+    }
+}
+
+/// A host serial port, used without interrupts
+pub struct SerialPort {
+    base: u16,
+}
+
+impl SerialPort {
+    /// Initialize the serial port at `base` for 115200 baud, 8N1
+    pub fn new(base: u16) -> Self {
+        unsafe {
+            outb(base + IER, 0x00);
+            outb(base + LCR, LCR_DLAB);
+            outb(base + DLL, 0x01);
+            outb(base + DLH, 0x00);
+            outb(base + LCR, LCR_8N1);
+
+            // Enable and clear the FIFOs
+            outb(base + FCR, 0xc7);
+
+            // Set DTR and RTS, but leave OUT2 clear so no IRQ is raised
+            outb(base + MCR, 0x03);
+        }
+        Self { base: base }
+    }
+
+    /// Read a byte from the port, if one has been received
+    pub fn try_read(&mut self) -> Option<u8> {
+        unsafe {
+            if inb(self.base + LSR) & LSR_DATA_READY != 0 {
+                Some(inb(self.base + DATA))
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Wait until a byte is received from the port
+    pub fn read(&mut self) -> u8 {
+        loop {
+            if let Some(byte) = self.try_read() {
+                return byte;
+            }
+        }
+    }
+
+    /// Write a byte to the port (waiting for the transmitter to be ready)
+    pub fn write(&mut self, byte: u8) {
+        unsafe {
+            while inb(self.base + LSR) & LSR_THR_EMPTY == 0 {}
+            outb(self.base + DATA, byte);
+        }
+    }
+
+    /// Write a sequence of bytes to the port
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write(*byte);
+        }
+    }
+}
@@ -7,16 +7,18 @@
 //! abstract system clock, counter, and timer information.
 
 use crate::apic;
+use crate::device::irq::IrqLine;
 use crate::error::Result;
 use crate::tsc;
-use crate::vcpu;
 use crate::{declare_per_core, get_per_core, get_per_core_mut};
 
 use alloc::{collections::BTreeMap, vec};
 use core::ops::{Add, AddAssign, Sub, SubAssign};
 use core::time::Duration;
 
-const TIMER_VECTOR: u8 = 32;
+/// The host vector of the local APIC timer, which is armed for the next
+/// timer in the current core's `TimerWheel`
+pub const TIMER_VECTOR: u8 = 32;
 
 //TODO: should this just be stored as a VCPU member?
 declare_per_core! {
@@ -55,7 +57,8 @@ pub fn is_global_time_ready() -> bool {
     unsafe { TIME_SRC.is_some() }
 }
 
-fn frequency() -> u64 {
+/// The frequency of the global system `TimeSource` (in ticks per second)
+pub fn frequency() -> u64 {
     unsafe {
         TIME_SRC
             .as_ref()
@@ -72,6 +75,16 @@ fn frequency() -> u64 {
 #[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Debug)]
 pub struct Instant(pub u64);
 
+impl Instant {
+    /// The time from `earlier` to this instant, or zero if `earlier` is
+    /// later than this instant
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        let ticks = self.0.saturating_sub(earlier.0);
+        let ns = (ticks as u128 * 1_000_000_000) / frequency() as u128;
+        Duration::from_nanos(ns as u64)
+    }
+}
+
 impl Add<Duration> for Instant {
     type Output = Self;
 
@@ -126,16 +139,32 @@ enum TimerMode {
     Periodic,
 }
 
+/// The guest interrupt generated when a timer expires
+#[derive(Clone)]
+pub enum TimerInterruptType {
+    /// Inject the given vector directly into the guest
+    Vector(u8),
+
+    /// Pulse a guest interrupt line
+    Irq(IrqLine),
+
+    /// Assert a (level triggered) guest interrupt line. The device that
+    /// created the timer is responsible for deasserting it.
+    RaiseIrq(IrqLine),
+
+    /// Fire the timer of the local APIC of the `VCpu` on the current core
+    LocalApicTimer,
+}
+
 /// A one-shot or periodic timer that has not not yet been started
 pub struct ReadyTimer {
     duration: Duration,
     mode: TimerMode,
 
-    // The interrupt vector to deliver to the guest when this timer
-    // expires.
+    // The interrupt to deliver to the guest when this timer expires.
     // TODO: Not all timers represent interrupts to deliver to the guest,
     // so we will need to make this more abstract.
-    vector: u8,
+    interrupt: TimerInterruptType,
 }
 
 /// A started one-shot or periodic timer
@@ -143,25 +172,25 @@ pub struct RunningTimer {
     duration: Duration,
     mode: TimerMode,
     started: Instant,
-    vector: u8,
+    interrupt: TimerInterruptType,
 }
 
 impl ReadyTimer {
     /// Create a new one-shot timer.
-    pub fn one_shot(duration: Duration, vector: u8) -> Self {
+    pub fn one_shot(duration: Duration, interrupt: TimerInterruptType) -> Self {
         Self {
             duration: duration,
             mode: TimerMode::OneShot,
-            vector: vector,
+            interrupt: interrupt,
         }
     }
 
     /// Create a new periodic timer.
-    pub fn periodic(period: Duration, vector: u8) -> Self {
+    pub fn periodic(period: Duration, interrupt: TimerInterruptType) -> Self {
         Self {
             duration: period,
             mode: TimerMode::Periodic,
-            vector: vector,
+            interrupt: interrupt,
         }
     }
 
@@ -171,7 +200,7 @@ impl ReadyTimer {
             duration: self.duration,
             mode: self.mode,
             started: now(),
-            vector: self.vector,
+            interrupt: self.interrupt,
         }
     }
 
@@ -190,7 +219,7 @@ impl RunningTimer {
         ReadyTimer {
             duration: self.duration,
             mode: self.mode,
-            vector: self.vector,
+            interrupt: self.interrupt,
         }
     }
 
@@ -281,7 +310,7 @@ impl TimerWheel {
     /// expired and will reset any periodic timers.
     pub fn expire_elapsed_timers(
         &mut self,
-    ) -> Result<vec::Vec<(u8, vcpu::InjectedInterruptType)>> {
+    ) -> Result<vec::Vec<TimerInterruptType>> {
         let mut interrupts = vec![];
         let elapsed_oneshots = self
             .timers
@@ -296,11 +325,9 @@ impl TimerWheel {
             .collect::<vec::Vec<_>>();
 
         for id in elapsed_oneshots {
-            interrupts.push((
-                self.timers[&id].vector,
-                vcpu::InjectedInterruptType::ExternalInterrupt,
-            ));
-            self.timers.remove(&id);
+            if let Some(timer) = self.timers.remove(&id) {
+                interrupts.push(timer.interrupt);
+            }
         }
 
         for (_, timer) in self
@@ -308,10 +335,7 @@ impl TimerWheel {
             .iter_mut()
             .filter(|(_, timer)| timer.elapsed() && timer.is_periodic())
         {
-            interrupts.push((
-                timer.vector,
-                vcpu::InjectedInterruptType::ExternalInterrupt,
-            ));
+            interrupts.push(timer.interrupt.clone());
             timer.reset();
         }
 
@@ -319,16 +343,15 @@ impl TimerWheel {
         Ok(interrupts)
     }
 
-    fn update_interrupt_timer(&mut self) {
-        let soonest = self
-            .timers
-            .values()
-            .map(|timer| (timer.elapses_at(), timer.vector))
-            .min();
+    /// The time the next timer in this wheel elapses (if there are any)
+    pub fn next_expiration(&self) -> Option<Instant> {
+        self.timers.values().map(|timer| timer.elapses_at()).min()
+    }
 
+    fn update_interrupt_timer(&mut self) {
         // TODO: we should only actually reset this if the new time
         // is sooner than the last time we set
-        if let Some((when, _)) = soonest {
+        if let Some(when) = self.next_expiration() {
             unsafe {
                 apic::get_local_apic_mut()
                     .schedule_interrupt(when, TIMER_VECTOR);
@@ -398,19 +421,19 @@ pub fn cancel_timer(id: &TimerId) -> Result<()> {
 /// Set a one shot timer on this core
 pub fn set_oneshot_timer(
     duration: core::time::Duration,
-    vector: u8,
+    interrupt: TimerInterruptType,
 ) -> TimerId {
     let wheel = unsafe { get_timer_wheel_mut() };
-    let timer = ReadyTimer::one_shot(duration, vector);
+    let timer = ReadyTimer::one_shot(duration, interrupt);
     wheel.register_timer(timer)
 }
 
 /// Set a periodic timer on this core
 pub fn set_periodic_timer(
     interval: core::time::Duration,
-    vector: u8,
+    interrupt: TimerInterruptType,
 ) -> TimerId {
     let wheel = unsafe { get_timer_wheel_mut() };
-    let timer = ReadyTimer::periodic(interval, vector);
+    let timer = ReadyTimer::periodic(interval, interrupt);
     wheel.register_timer(timer)
 }
@@ -14,7 +14,8 @@ struct TscTimeSource {
     frequency: u64,
 }
 
-unsafe fn read_tsc() -> u64 {
+/// Read the TSC of the current core
+pub unsafe fn read_tsc() -> u64 {
     x86::time::rdtsc()
 }
 
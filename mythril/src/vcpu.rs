@@ -1,19 +1,28 @@
 use crate::apic;
+use crate::console;
+use crate::device::lapic::{LocalApic, LogicalDestination};
+use crate::device::pic::Pic8259;
 use crate::emulate;
 use crate::error::{self, Error, Result};
+use crate::exitstats;
+use crate::gdb;
+use crate::interrupt;
+use crate::logger;
 use crate::memory::Raw4kPage;
 use crate::percore;
 use crate::registers::{GdtrBase, IdtrBase};
 use crate::time;
-use crate::vm::VirtualMachine;
+use crate::vm::{VirtualMachine, VmLifecycle, VmState};
+use crate::vtime::GuestClock;
 use crate::{vm, vmcs, vmexit, vmx};
 use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
+use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::mem;
 use core::pin::Pin;
-use spin::RwLock;
+use spin::{Mutex, RwLock};
 use x86::controlregs::{cr0, cr3, cr4};
 use x86::msr;
 
@@ -37,8 +46,18 @@ pub fn mp_entry_point() -> ! {
             .as_ref()
             .unwrap()
             .get(&apic::get_local_apic().id())
-            .expect("Failed to find VM for core")
-            .clone()
+            .cloned()
+    };
+
+    // Cores that are not assigned to any VM have nothing to do
+    let vm = match vm {
+        Some(vm) => vm,
+        None => {
+            info!("No VM for core {}, halting", apic::get_local_apic().id());
+            loop {
+                unsafe { llvm_asm!("hlt" :::: "volatile") };
+            }
+        }
     };
     let vcpu = VCpu::new(vm.clone()).expect("Failed to create vcpu");
     vcpu.launch().expect("Failed to launch vm")
@@ -56,6 +75,175 @@ pub enum InjectedInterruptType {
     OtherEvent = 7,
 }
 
+/// The host vector used to force a core out of the guest
+///
+/// The resulting external interrupt exit acknowledges the vector, so it is
+/// only handled by the host when the core is waiting in the host (see
+/// `interrupt::wait_for_interrupt`).
+pub const KICK_VECTOR: u8 = 33;
+
+/// How a halted `VCpu` re-enters the guest
+#[derive(Debug, PartialEq)]
+enum HaltedEntry {
+    /// Resume the guest to handle the injected event
+    Wake,
+    /// Stay in the HLT state
+    Halt,
+    /// Expire the elapsed timers first, as they may wake the guest
+    ExpireTimers,
+}
+
+/// Decide how a halted vcpu re-enters the guest, given the VM entry
+/// interruption information and the time the next timer elapses
+///
+/// A timer that elapses while the vcpu is halted causes a VM exit (the host
+/// local APIC timer is armed for it), but one that has already elapsed must
+/// be expired before the VM entry, or the vcpu could miss its interrupt.
+fn halted_entry(
+    entry_info: u64,
+    next_timer: Option<time::Instant>,
+    now: time::Instant,
+) -> HaltedEntry {
+    if entry_info & 0x80000000 != 0 {
+        HaltedEntry::Wake
+    } else if next_timer.map_or(false, |when| when < now) {
+        HaltedEntry::ExpireTimers
+    } else {
+        HaltedEntry::Halt
+    }
+}
+
+/// How often an idle vcpu (halted, or in a stopped VM) checks the console
+/// for input
+const CONSOLE_POLL_INTERVAL: core::time::Duration =
+    core::time::Duration::from_millis(10);
+
+/// The time to wake a halted vcpu to poll the console, if its next timer
+/// will not wake it before the poll is due
+///
+/// The host UART does not raise interrupts, so without this an idle guest
+/// would never see its console input (nor would the monitor).
+fn console_poll_deadline(
+    next_timer: Option<time::Instant>,
+    poll_due: time::Instant,
+) -> Option<time::Instant> {
+    match next_timer {
+        Some(when) if when <= poll_due => None,
+        _ => Some(poll_due),
+    }
+}
+
+/// The vector of the invalid opcode exception (#UD)
+const UD_VECTOR: u8 = 6;
+
+/// CR4.DE (debugging extensions), which makes DR4 and DR5 reserved
+const CR4_DE: u64 = 1 << 3;
+
+/// Force the core with the given APIC id to exit its guest
+///
+/// This allows the `VCpu` on that core to notice newly pending interrupts.
+pub fn kick_vcpu(host_apic_id: u32) {
+    if apic::get_local_apic().id() == host_apic_id as usize {
+        // The current vcpu will check for interrupts before resuming
+        return;
+    }
+    unsafe { apic::get_local_apic_mut() }.send_ipi(
+        host_apic_id,
+        apic::DstShorthand::NoShorthand,
+        apic::TriggerMode::Edge,
+        apic::Level::Assert,
+        apic::DstMode::Physical,
+        apic::DeliveryMode::Fixed,
+        KICK_VECTOR,
+    );
+}
+
+/// An interrupt sent to a `VCpu` by one of the VM's interrupt controllers
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InterruptMessage {
+    /// A maskable interrupt with the given vector
+    Fixed { vector: u8, level_triggered: bool },
+    /// A non-maskable interrupt
+    Nmi,
+}
+
+/// A request from the hypervisor monitor
+///
+/// Most of the state of a `VCpu` can only be read on the core running it,
+/// so the `VCpu` handles these requests itself when it next exits.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MonitorRequest {
+    /// Show the guest registers
+    Registers,
+    /// Show the contents of the VMCS
+    Vmcs,
+    /// Show the timers of the core running the `VCpu`
+    Timers,
+    /// Show the exit statistics of the `VCpu`
+    ExitStats,
+    /// Show the most recent exits of the `VCpu`
+    ExitTrace,
+}
+
+/// Interrupts that have been sent to a `VCpu`, but not yet processed by it
+///
+/// This is shared between the `VCpu` and the devices that send it
+/// interrupts (which may be running on other cores).
+pub struct VCpuMailbox {
+    host_apic_id: u32,
+    messages: Mutex<Vec<InterruptMessage>>,
+    requests: Mutex<Vec<MonitorRequest>>,
+    logical_destination: Mutex<LogicalDestination>,
+}
+
+impl VCpuMailbox {
+    /// Create a mailbox for the `VCpu` running on the given core
+    pub fn new(host_apic_id: u32) -> Self {
+        Self {
+            host_apic_id: host_apic_id,
+            messages: Mutex::new(vec![]),
+            requests: Mutex::new(vec![]),
+            logical_destination: Mutex::new(LogicalDestination::default()),
+        }
+    }
+
+    /// Send an interrupt to the `VCpu`
+    pub fn post(&self, message: InterruptMessage) {
+        self.messages.lock().push(message);
+        kick_vcpu(self.host_apic_id);
+    }
+
+    /// Send a request from the monitor to the `VCpu`
+    pub fn request(&self, request: MonitorRequest) {
+        self.requests.lock().push(request);
+        kick_vcpu(self.host_apic_id);
+    }
+
+    /// The host APIC id of the core running the `VCpu`
+    pub fn host_apic_id(&self) -> u32 {
+        self.host_apic_id
+    }
+
+    /// The logical destination configuration of the `VCpu`s local APIC
+    pub fn logical_destination(&self) -> LogicalDestination {
+        *self.logical_destination.lock()
+    }
+
+    /// Update the logical destination configuration (this should only be
+    /// called by the `VCpu`s local APIC)
+    pub fn set_logical_destination(&self, dest: LogicalDestination) {
+        *self.logical_destination.lock() = dest;
+    }
+
+    fn take_messages(&self) -> Vec<InterruptMessage> {
+        mem::replace(&mut *self.messages.lock(), vec![])
+    }
+
+    fn take_requests(&self) -> Vec<MonitorRequest> {
+        mem::replace(&mut *self.requests.lock(), vec![])
+    }
+}
+
 /// A virtual CPU.
 ///
 /// Each `VCpu` will be executed on a particular physical core, and is
@@ -64,9 +252,22 @@ pub enum InjectedInterruptType {
 /// ultimate handling will occur within an emulated device in the `VirtualMachine`'s
 /// `DeviceMap`)
 pub struct VCpu {
+    /// The index of this `VCpu` in its VM (this is also the guest APIC id)
+    pub id: usize,
     pub vm: Arc<RwLock<VirtualMachine>>,
     pub vmcs: vmcs::ActiveVmcs,
     pending_interrupts: BTreeMap<u8, InjectedInterruptType>,
+    mailbox: Arc<VCpuMailbox>,
+    pub local_apic: LocalApic,
+    pub msrs: emulate::msr::MsrTable,
+    pub pvclock: emulate::pvclock::PvClock,
+    pic: Arc<Mutex<Pic8259>>,
+    debugger: Option<gdb::GdbStub>,
+    lifecycle: Arc<VmLifecycle>,
+    /// The number of VM resets this `VCpu` has performed
+    resets: u64,
+    /// The generation of the `GuestClock` TSC controls in the VMCS
+    tsc_generation: Option<u64>,
     stack: Vec<u8>,
 }
 
@@ -77,6 +278,37 @@ impl VCpu {
     /// address on to the per-core host stack so it can be retrieved on
     /// VMEXIT.
     pub fn new(vm: Arc<RwLock<VirtualMachine>>) -> Result<Pin<Box<Self>>> {
+        let host_apic_id = apic::get_local_apic().id();
+        let pic = vm.read().config.pic().clone();
+        let id = vm
+            .read()
+            .config
+            .cpus()
+            .iter()
+            .position(|cpu| *cpu as usize == host_apic_id)
+            .ok_or_else(|| {
+                Error::InvalidValue(format!(
+                    "Core {} is not assigned to this VM",
+                    host_apic_id
+                ))
+            })?;
+        let mailbox = vm.read().config.mailboxes()[id].clone();
+        let lifecycle = vm.read().config.lifecycle().clone();
+        let resets = lifecycle.status().1;
+        let local_apic = {
+            let vm = vm.read();
+            LocalApic::new(
+                id as u32,
+                vm.config.cpus().to_vec(),
+                vm.config.mailboxes().to_vec(),
+                vm.config.ioapic().clone(),
+                lifecycle.clock().clone(),
+            )
+        };
+
+        let trace_size = vm.read().config.exit_trace_size();
+        exitstats::init_exit_stats(trace_size);
+
         let vmx = vmx::Vmx::enable()?;
         let vmcs = vmcs::Vmcs::new()?.activate(vmx)?;
 
@@ -84,10 +316,20 @@ impl VCpu {
         let stack = vec![0u8; 1024 * 1024];
 
         let mut vcpu = Box::pin(Self {
+            id: id,
             vm: vm,
             vmcs: vmcs,
             stack: stack,
             pending_interrupts: BTreeMap::new(),
+            mailbox: mailbox,
+            local_apic: local_apic,
+            msrs: emulate::msr::MsrTable::new(),
+            pvclock: emulate::pvclock::PvClock::default(),
+            pic: pic,
+            debugger: None,
+            lifecycle: lifecycle,
+            resets: resets,
+            tsc_generation: None,
         });
 
         // All VCpus in a VM must share the same address space (except for the
@@ -106,11 +348,37 @@ impl VCpu {
 
         Self::initialize_host_vmcs(&mut vcpu.vmcs, stack_base)?;
         Self::initialize_guest_vmcs(&mut vcpu.vmcs)?;
-        Self::initialize_ctrl_vmcs(&mut vcpu.vmcs)?;
+        let tsc_scaling = vcpu.lifecycle.clock().tsc_controls().multiplier;
+        Self::initialize_ctrl_vmcs(&mut vcpu.vmcs, tsc_scaling.is_some())?;
+        vcpu.sync_tsc()?;
+
+        // The GDB stub (if any) debugs the BSP of the VM
+        let gdb_port = if vcpu.is_bsp() {
+            vcpu.vm.read().config.gdb_port()
+        } else {
+            None
+        };
+        if let Some(port) = gdb_port {
+            info!("Waiting for GDB on host port 0x{:x}", port);
+            let mut debugger = gdb::GdbStub::new(port);
+            debugger.attach(&mut vcpu.vmcs)?;
+            vcpu.debugger = Some(debugger);
+        }
+
+        // Only the BSP begins executing at the reset vector. The APs wait
+        // until the guest starts them with an INIT-SIPI sequence.
+        if !vcpu.is_bsp() {
+            vcpu.enter_wait_for_sipi()?;
+        }
 
         Ok(vcpu)
     }
 
+    /// Returns true if this is the bootstrap processor of the guest
+    pub fn is_bsp(&self) -> bool {
+        self.id == 0
+    }
+
     pub fn inject_interrupt(
         &mut self,
         vector: u8,
@@ -119,6 +387,29 @@ impl VCpu {
         self.pending_interrupts.insert(vector, kind);
     }
 
+    /// Inject a hardware exception on the next VM entry
+    ///
+    /// Unlike interrupts, exceptions are delivered immediately (regardless
+    /// of whether the guest is interruptible).
+    pub fn inject_exception(
+        &mut self,
+        vector: u8,
+        error_code: Option<u32>,
+    ) -> Result<()> {
+        let mut info = 0x80000000
+            | vector as u64
+            | ((InjectedInterruptType::HardwareException as u64) << 8);
+        if let Some(code) = error_code {
+            info |= 1 << 11;
+            self.vmcs.write_field(
+                vmcs::VmcsField::VmEntryExceptionErrorCode,
+                code as u64,
+            )?;
+        }
+        self.vmcs
+            .write_field(vmcs::VmcsField::VmEntryIntrInfoField, info)
+    }
+
     /// Begin execution in the guest context for this core
     pub fn launch(self: Pin<Box<Self>>) -> Result<!> {
         let rflags = unsafe { vmlaunch_wrapper() };
@@ -178,6 +469,9 @@ impl VCpu {
         vmcs.write_field(vmcs::VmcsField::HostIa32Efer, unsafe {
             msr::rdmsr(msr::IA32_EFER)
         })?;
+        vmcs.write_field(vmcs::VmcsField::HostIa32Pat, unsafe {
+            msr::rdmsr(msr::IA32_PAT)
+        })?;
 
         vmcs.write_field(
             vmcs::VmcsField::HostRip,
@@ -236,6 +530,7 @@ impl VCpu {
 
         //TODO: get actual EFER (use MSR for vt-x v1)
         vmcs.write_field(vmcs::VmcsField::GuestIa32Efer, 0x00)?;
+        vmcs.write_field(vmcs::VmcsField::GuestIa32Pat, 0x0007040600070406)?;
 
         let (guest_cr0, guest_cr4) = {
             let mut cr0_fixed0 =
@@ -269,24 +564,37 @@ impl VCpu {
         Ok(())
     }
 
-    fn initialize_ctrl_vmcs(vmcs: &mut vmcs::ActiveVmcs) -> Result<()> {
+    fn initialize_ctrl_vmcs(
+        vmcs: &mut vmcs::ActiveVmcs,
+        tsc_scaling: bool,
+    ) -> Result<()> {
         vmcs.write_with_fixed(
             vmcs::VmcsField::CpuBasedVmExecControl,
             (vmcs::CpuBasedCtrlFlags::UNCOND_IO_EXITING
+                | vmcs::CpuBasedCtrlFlags::HLT_EXITING
+                | vmcs::CpuBasedCtrlFlags::USE_TSC_OFFSETING
+                | vmcs::CpuBasedCtrlFlags::CR8_LOAD_EXITING
+                | vmcs::CpuBasedCtrlFlags::CR8_STORE_EXITING
                 | vmcs::CpuBasedCtrlFlags::ACTIVATE_MSR_BITMAP
                 | vmcs::CpuBasedCtrlFlags::ACTIVATE_SECONDARY_CONTROLS)
                 .bits(),
             msr::IA32_VMX_PROCBASED_CTLS,
         )?;
 
+        let mut secondary = vmcs::SecondaryExecFlags::VIRTUALIZE_APIC_ACCESSES
+            | vmcs::SecondaryExecFlags::ENABLE_EPT
+            | vmcs::SecondaryExecFlags::ENABLE_VPID
+            | vmcs::SecondaryExecFlags::ENABLE_INVPCID
+            | vmcs::SecondaryExecFlags::UNRESTRICTED_GUEST;
+
+        // Scaling is only enabled when the guest TSC frequency differs from
+        // the host's, as not every processor supports it
+        if tsc_scaling {
+            secondary |= vmcs::SecondaryExecFlags::TSC_SCALING;
+        }
         vmcs.write_with_fixed(
             vmcs::VmcsField::SecondaryVmExecControl,
-            (vmcs::SecondaryExecFlags::VIRTUALIZE_APIC_ACCESSES
-                | vmcs::SecondaryExecFlags::ENABLE_EPT
-                | vmcs::SecondaryExecFlags::ENABLE_VPID
-                | vmcs::SecondaryExecFlags::ENABLE_INVPCID
-                | vmcs::SecondaryExecFlags::UNRESTRICTED_GUEST)
-                .bits(),
+            secondary.bits(),
             msr::IA32_VMX_PROCBASED_CTLS2,
         )?;
 
@@ -312,6 +620,8 @@ impl VCpu {
             (vmcs::VmExitCtrlFlags::IA32E_MODE
                 | vmcs::VmExitCtrlFlags::LOAD_HOST_EFER
                 | vmcs::VmExitCtrlFlags::SAVE_GUEST_EFER
+                | vmcs::VmExitCtrlFlags::LOAD_HOST_PAT
+                | vmcs::VmExitCtrlFlags::SAVE_GUEST_PAT
                 | vmcs::VmExitCtrlFlags::ACK_INTR_ON_EXIT)
                 .bits(),
             msr::IA32_VMX_EXIT_CTLS,
@@ -319,11 +629,17 @@ impl VCpu {
 
         vmcs.write_with_fixed(
             vmcs::VmcsField::VmEntryControls,
-            vmcs::VmEntryCtrlFlags::LOAD_GUEST_EFER.bits(),
+            (vmcs::VmEntryCtrlFlags::LOAD_GUEST_EFER
+                | vmcs::VmEntryCtrlFlags::LOAD_GUEST_PAT)
+                .bits(),
             msr::IA32_VMX_ENTRY_CTLS,
         )?;
 
         let msr_bitmap = Box::into_raw(Box::new(Raw4kPage::default()));
+
+        emulate::msr::init_msr_bitmap(unsafe {
+            &mut *(msr_bitmap as *mut [u8; 4096])
+        });
         vmcs.write_field(vmcs::VmcsField::MsrBitmap, msr_bitmap as u64)?;
 
         // Do not VMEXIT on any exceptions
@@ -345,7 +661,255 @@ impl VCpu {
         Ok(())
     }
 
-    fn skip_emulated_instruction(&mut self) -> Result<()> {
+    fn enter_wait_for_sipi(&mut self) -> Result<()> {
+        let misc = unsafe { msr::rdmsr(msr::IA32_VMX_MISC) };
+        if misc & (1 << 8) == 0 {
+            return Err(Error::NotSupported);
+        }
+
+        self.vmcs.write_field(
+            vmcs::VmcsField::GuestActivityState,
+            vmcs::ActivityState::WaitForSipi as u64,
+        )
+    }
+
+    /// Return the guest state of this `VCpu` to the state following INIT
+    fn reset_guest_state(
+        &mut self,
+        guest_cpu: &mut vmexit::GuestCpuState,
+    ) -> Result<()> {
+        Self::initialize_guest_vmcs(&mut self.vmcs)?;
+
+        // The guest may have been in long mode, so clear the IA-32e mode
+        // entry control that was saved on exit.
+        self.vmcs.write_with_fixed(
+            vmcs::VmcsField::VmEntryControls,
+            (vmcs::VmEntryCtrlFlags::LOAD_GUEST_EFER
+                | vmcs::VmEntryCtrlFlags::LOAD_GUEST_PAT)
+                .bits(),
+            msr::IA32_VMX_ENTRY_CTLS,
+        )?;
+
+        let field = self
+            .vmcs
+            .read_field(vmcs::VmcsField::CpuBasedVmExecControl)?;
+        self.vmcs.write_field(
+            vmcs::VmcsField::CpuBasedVmExecControl,
+            field & !vmcs::CpuBasedCtrlFlags::INTERRUPT_WINDOW_EXITING.bits(),
+        )?;
+        self.pending_interrupts.clear();
+        self.local_apic.reset();
+
+        *guest_cpu = vmexit::GuestCpuState {
+            cr2: 0,
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: 0,
+            r11: 0,
+            r10: 0,
+            r9: 0,
+            r8: 0,
+            rbp: 0,
+            rdi: 0,
+            rsi: 0,
+            rdx: 0,
+            rcx: 0,
+            rbx: 0,
+            rax: 0,
+            vcpu: guest_cpu.vcpu,
+        };
+
+        Ok(())
+    }
+
+    /// Return this `VCpu` to its power-on state as part of a VM reset
+    fn power_on_reset(
+        &mut self,
+        guest_cpu: &mut vmexit::GuestCpuState,
+    ) -> Result<()> {
+        self.reset_guest_state(guest_cpu)?;
+        self.msrs = emulate::msr::MsrTable::new();
+        self.pvclock = emulate::pvclock::PvClock::default();
+
+        // Drop any interrupts sent before the reset, including one that
+        // was about to be injected
+        self.mailbox.take_messages();
+        self.vmcs
+            .write_field(vmcs::VmcsField::VmEntryIntrInfoField, 0)?;
+
+        if !self.is_bsp() {
+            self.enter_wait_for_sipi()?;
+        }
+        Ok(())
+    }
+
+    /// Update the TSC offset and multiplier in the VMCS if the guest TSC
+    /// has changed (e.g., because the VM was paused or the TSC written)
+    fn sync_tsc(&mut self) -> Result<()> {
+        let controls = self.lifecycle.clock().tsc_controls();
+        if self.tsc_generation == Some(controls.generation) {
+            return Ok(());
+        }
+
+        self.vmcs
+            .write_field(vmcs::VmcsField::TscOffset, controls.offset)?;
+        if let Some(multiplier) = controls.multiplier {
+            self.vmcs
+                .write_field(vmcs::VmcsField::TscMultiplier, multiplier)?;
+        }
+        self.tsc_generation = Some(controls.generation);
+
+        // The kvmclock time info is only valid for a single TSC offset
+        emulate::pvclock::update_system_time(self);
+        Ok(())
+    }
+
+    /// The virtual clock of this vcpu's VM
+    pub fn clock(&self) -> &Arc<GuestClock> {
+        self.lifecycle.clock()
+    }
+
+    /// Set the guest TSC (of every vcpu in the VM)
+    ///
+    /// The other vcpus use the new TSC from their next VM exit.
+    pub fn set_tsc(&mut self, val: u64) -> Result<()> {
+        self.lifecycle.clock().set_tsc(val);
+        self.sync_tsc()
+    }
+
+    /// Follow any change to the state of the VM
+    ///
+    /// This resets the vcpu if the VM was reset since it last ran and
+    /// waits here while the VM is not running.
+    fn sync_lifecycle(
+        &mut self,
+        guest_cpu: &mut vmexit::GuestCpuState,
+    ) -> Result<()> {
+        loop {
+            let (state, resets) = self.lifecycle.status();
+            if resets != self.resets {
+                info!("Resetting vcpu {}", self.id);
+                self.power_on_reset(guest_cpu)?;
+                self.resets = resets;
+
+                // The BSP is responsible for resetting the shared devices
+                if self.is_bsp() {
+                    self.vm.write().config.device_map().reset_devices()?;
+                    self.lifecycle.finish_reset(resets);
+                }
+                continue;
+            }
+
+            if state == VmState::Running {
+                return self.sync_tsc();
+            }
+
+            // The monitor must remain usable while the VM is stopped
+            console::poll_input();
+            self.handle_monitor_requests(guest_cpu)?;
+
+            // Halt the core until the VM changes state (which kicks the
+            // vcpus), a monitor request or the next console poll. The timer
+            // wheel re-arms the timer once the VM runs again.
+            unsafe {
+                apic::get_local_apic_mut().schedule_interrupt(
+                    time::now() + CONSOLE_POLL_INTERVAL,
+                    time::TIMER_VECTOR,
+                );
+                interrupt::wait_for_interrupt();
+            }
+        }
+    }
+
+    fn handle_monitor_requests(
+        &mut self,
+        guest_cpu: &vmexit::GuestCpuState,
+    ) -> Result<()> {
+        for request in self.mailbox.take_requests() {
+            let out = match request {
+                MonitorRequest::Registers => {
+                    self.format_registers(guest_cpu)?
+                }
+                MonitorRequest::Vmcs => format!("{}", self.vmcs),
+                MonitorRequest::ExitStats => exitstats::get_exit_stats()
+                    .map_or("no exit statistics\n".into(), |s| s.summary()),
+                MonitorRequest::ExitTrace => exitstats::get_exit_stats()
+                    .map_or("no exit statistics\n".into(), |s| {
+                        s.format_trace()
+                    }),
+                MonitorRequest::Timers => {
+                    let now = time::now();
+                    let mut out =
+                        format!("core {}:\n", self.mailbox.host_apic_id());
+                    for timer in time::get_timer_wheel().iter() {
+                        let at = timer.elapses_at();
+                        let sign = if at < now { "-" } else { "+" };
+                        out += &format!(
+                            "  {}{}us{}\n",
+                            sign,
+                            (at - now).as_micros(),
+                            if timer.is_periodic() {
+                                " (periodic)"
+                            } else {
+                                ""
+                            }
+                        );
+                    }
+                    out
+                }
+            };
+            logger::write_console(&format!("vcpu {}: {}", self.id, out));
+        }
+        Ok(())
+    }
+
+    fn format_registers(
+        &self,
+        guest_cpu: &vmexit::GuestCpuState,
+    ) -> Result<String> {
+        let field = |field| self.vmcs.read_field(field);
+        let mut out = format!(
+            "RIP=0x{:x} RSP=0x{:x} RFLAGS=0x{:x}\n",
+            field(vmcs::VmcsField::GuestRip)?,
+            field(vmcs::VmcsField::GuestRsp)?,
+            field(vmcs::VmcsField::GuestRflags)?
+        );
+        out += &format!(
+            "  RAX=0x{:x} RBX=0x{:x} RCX=0x{:x} RDX=0x{:x}\n",
+            guest_cpu.rax, guest_cpu.rbx, guest_cpu.rcx, guest_cpu.rdx
+        );
+        out += &format!(
+            "  RSI=0x{:x} RDI=0x{:x} RBP=0x{:x}\n",
+            guest_cpu.rsi, guest_cpu.rdi, guest_cpu.rbp
+        );
+        out += &format!(
+            "  R8=0x{:x} R9=0x{:x} R10=0x{:x} R11=0x{:x}\n",
+            guest_cpu.r8, guest_cpu.r9, guest_cpu.r10, guest_cpu.r11
+        );
+        out += &format!(
+            "  R12=0x{:x} R13=0x{:x} R14=0x{:x} R15=0x{:x}\n",
+            guest_cpu.r12, guest_cpu.r13, guest_cpu.r14, guest_cpu.r15
+        );
+        out += &format!(
+            "  CR0=0x{:x} CR2=0x{:x} CR3=0x{:x} CR4=0x{:x} EFER=0x{:x}\n",
+            field(vmcs::VmcsField::GuestCr0)?,
+            guest_cpu.cr2,
+            field(vmcs::VmcsField::GuestCr3)?,
+            field(vmcs::VmcsField::GuestCr4)?,
+            field(vmcs::VmcsField::GuestIa32Efer)?
+        );
+        out += &format!(
+            "  CS=0x{:x} (base=0x{:x}) activity={}\n",
+            field(vmcs::VmcsField::GuestCsSelector)?,
+            field(vmcs::VmcsField::GuestCsBase)?,
+            field(vmcs::VmcsField::GuestActivityState)?
+        );
+        Ok(out)
+    }
+
+    /// Advance the guest past the instruction that caused the current exit
+    pub fn skip_emulated_instruction(&mut self) -> Result<()> {
         let mut rip = self.vmcs.read_field(vmcs::VmcsField::GuestRip)?;
         rip += self
             .vmcs
@@ -367,24 +931,240 @@ impl VCpu {
         &mut self,
         guest_cpu: &mut vmexit::GuestCpuState,
         exit: vmexit::ExitReason,
+    ) -> Result<()> {
+        let start = unsafe { x86::time::rdtsc() };
+
+        if let Some(stats) = exitstats::get_exit_stats_mut() {
+            if stats.is_tracing() {
+                stats.trace(exitstats::TraceEntry {
+                    reason: exit.info.name(),
+                    rip: self.vmcs.read_field(vmcs::VmcsField::GuestRip)?,
+                    qualification: self
+                        .vmcs
+                        .read_field(vmcs::VmcsField::ExitQualification)?,
+                });
+            }
+        }
+
+        let info = exit.info.clone();
+        let res = self.process_vmexit(guest_cpu, exit);
+
+        //NOTE: this includes any time the vcpu spent stopped (e.g., while
+        //      the VM was paused or the debugger was active)
+        let cycles = unsafe { x86::time::rdtsc() } - start;
+        if let Some(stats) = exitstats::get_exit_stats_mut() {
+            stats.record(&info, cycles);
+        }
+        res
+    }
+
+    fn process_vmexit(
+        &mut self,
+        guest_cpu: &mut vmexit::GuestCpuState,
+        exit: vmexit::ExitReason,
     ) -> Result<()> {
         // Process the exit reason
         self.handle_vmexit_impl(guest_cpu, exit.clone())?;
 
-        // Always check for expired timers
-        unsafe {
-            for (vec, kind) in
-                time::get_timer_wheel_mut().expire_elapsed_timers()?
-            {
-                self.inject_interrupt(vec, kind);
+        // Give an attached debugger the chance to stop the guest
+        let interrupted = match self.debugger {
+            Some(ref mut debugger) => debugger.interrupt_requested(),
+            None => false,
+        };
+        if interrupted {
+            self.debug_stop(guest_cpu, gdb::StopReason::Interrupt)?;
+        }
+
+        // The VM may have been paused, shut down or reset
+        self.sync_lifecycle(guest_cpu)?;
+
+        // Deliver any input from the host console to the guest serial port
+        // (or the monitor)
+        console::poll_input();
+        self.handle_monitor_requests(guest_cpu)?;
+
+        loop {
+            self.expire_timers()?;
+
+            // Interrupts cannot be delivered while waiting for a SIPI, so
+            // leave them pending until the guest starts this vcpu.
+            let activity =
+                self.vmcs.read_field(vmcs::VmcsField::GuestActivityState)?;
+            if activity == vmcs::ActivityState::WaitForSipi as u64 {
+                return Ok(());
+            }
+
+            self.deliver_interrupts()?;
+            if activity != vmcs::ActivityState::Hlt as u64 {
+                return Ok(());
+            }
+
+            // A halted vcpu only wakes up to handle an event. Otherwise, it
+            // re-enters the guest in the HLT state, which halts this core
+            // until the next host interrupt (the local APIC timer, armed for
+            // the next timer in the wheel or the next console poll, or a
+            // kick from another core).
+            let entry_info = self
+                .vmcs
+                .read_field(vmcs::VmcsField::VmEntryIntrInfoField)?;
+            let next_timer = time::get_timer_wheel().next_expiration();
+            let now = time::now();
+            match halted_entry(entry_info, next_timer, now) {
+                HaltedEntry::Wake => {
+                    return self.vmcs.write_field(
+                        vmcs::VmcsField::GuestActivityState,
+                        vmcs::ActivityState::Active as u64,
+                    );
+                }
+                HaltedEntry::Halt => {
+                    // The timer wheel re-arms the timer for its own timers
+                    // when they are registered or expire
+                    let poll_due = now + CONSOLE_POLL_INTERVAL;
+                    if let Some(when) =
+                        console_poll_deadline(next_timer, poll_due)
+                    {
+                        unsafe {
+                            apic::get_local_apic_mut()
+                                .schedule_interrupt(when, time::TIMER_VECTOR);
+                        }
+                    }
+                    return Ok(());
+                }
+
+                // A timer elapsed while this exit was handled, so its
+                // interrupt may wake the vcpu
+                HaltedEntry::ExpireTimers => continue,
             }
         }
+    }
 
-        // If there are no pending interrupts, we're done
-        if self.pending_interrupts.is_empty() {
-            return Ok(());
+    /// Deliver the interrupts of any elapsed timers on this core
+    fn expire_timers(&mut self) -> Result<()> {
+        let interrupts =
+            unsafe { time::get_timer_wheel_mut().expire_elapsed_timers()? };
+        for interrupt in interrupts {
+            match interrupt {
+                time::TimerInterruptType::Vector(vector) => self
+                    .inject_interrupt(
+                        vector,
+                        InjectedInterruptType::ExternalInterrupt,
+                    ),
+                time::TimerInterruptType::Irq(line) => line.pulse(),
+                time::TimerInterruptType::RaiseIrq(line) => line.raise(),
+                time::TimerInterruptType::LocalApicTimer => {
+                    self.local_apic.timer_expired()
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Accept the interrupts sent to this vcpu and inject the next pending
+    /// one (if the guest can take it)
+    fn deliver_interrupts(&mut self) -> Result<()> {
+        for message in self.mailbox.take_messages() {
+            match message {
+                InterruptMessage::Fixed {
+                    vector,
+                    level_triggered,
+                } => {
+                    self.local_apic.accept_interrupt(vector, level_triggered);
+                }
+                InterruptMessage::Nmi => self.inject_interrupt(
+                    2,
+                    InjectedInterruptType::NonMaskableInterrupt,
+                ),
+            }
+        }
+
+        self.inject_pending_interrupts()
+    }
+
+    /// Put the guest in the HLT state after it executes HLT
+    fn halt(&mut self) -> Result<()> {
+        self.skip_emulated_instruction()?;
+
+        // The blocking from a preceding STI (as in 'sti; hlt') or MOV SS
+        // ends with the HLT, so interrupts can be delivered to wake the
+        // guest
+        let interruptibility = self
+            .vmcs
+            .read_field(vmcs::VmcsField::GuestInterruptibilityInfo)?;
+        self.vmcs.write_field(
+            vmcs::VmcsField::GuestInterruptibilityInfo,
+            interruptibility
+                & !(vmcs::InterruptibilityState::STI_BLOCKING
+                    | vmcs::InterruptibilityState::MOV_SS_BLOCKING)
+                    .bits(),
+        )?;
+
+        self.vmcs.write_field(
+            vmcs::VmcsField::GuestActivityState,
+            vmcs::ActivityState::Hlt as u64,
+        )
+    }
+
+    /// Stop the guest until the attached debugger (if any) resumes it
+    fn debug_stop(
+        &mut self,
+        guest_cpu: &mut vmexit::GuestCpuState,
+        reason: gdb::StopReason,
+    ) -> Result<()> {
+        // The debugger needs access to the whole vcpu while it is stopped
+        match self.debugger.take() {
+            Some(mut debugger) => {
+                let res = debugger.stop(self, guest_cpu, reason);
+                self.debugger = Some(debugger);
+                res
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Returns true if the PIC is requesting an interrupt from this vcpu
+    ///
+    /// The output of the PIC is only connected to the BSP.
+    fn pic_has_interrupt(&self) -> bool {
+        self.is_bsp()
+            && self.local_apic.accepts_pic_interrupts()
+            && self.pic.lock().has_interrupt()
+    }
+
+    fn has_pending_interrupts(&self) -> bool {
+        !self.pending_interrupts.is_empty()
+            || self.local_apic.pending_vector().is_some()
+            || self.pic_has_interrupt()
+    }
+
+    fn inject_pending_interrupts(&mut self) -> Result<()> {
+        let has_pending = self.has_pending_interrupts();
+
+        // If the exit occurred during the delivery of an event, that event
+        // must be delivered before anything else.
+        let vectoring = self
+            .vmcs
+            .read_field(vmcs::VmcsField::IdtVectoringInfoField)?;
+        if vectoring & 0x80000000 != 0 {
+            self.reinject_event(vectoring)?;
+            return self.set_immediate_exit(has_pending);
         }
 
+        // An exception raised while handling this exit takes priority too
+        let entry_info = self
+            .vmcs
+            .read_field(vmcs::VmcsField::VmEntryIntrInfoField)?;
+        if entry_info & 0x80000000 != 0 {
+            return self.set_immediate_exit(has_pending);
+        }
+
+        // Interrupts are held while single-stepping, so that a step does
+        // not end up in an interrupt handler
+        if self.debugger.as_ref().map_or(false, |d| d.is_stepping()) {
+            return self.set_immediate_exit(false);
+        }
+
+        // If the guest is not currently interruptible, set the interrupt window exiting
+        // and exit. Otherwise, ensure that it is disabled.
         let interruptibility = vmcs::InterruptibilityState::from_bits(
             self.vmcs
                 .read_field(vmcs::VmcsField::GuestInterruptibilityInfo)?,
@@ -392,21 +1172,20 @@ impl VCpu {
         .ok_or_else(|| {
             Error::InvalidValue("Invalid interruptibility state".into())
         })?;
-
         let rflags = self.vmcs.read_field(vmcs::VmcsField::GuestRflags)?;
+        let interruptible =
+            interruptibility.is_empty() && rflags & 0b1000000000 != 0;
 
-        // If the guest is not currently interruptible, set the interrupt window exiting
-        // and exit. Otherwise, ensure that it is disabled.
         let field = self
             .vmcs
             .read_field(vmcs::VmcsField::CpuBasedVmExecControl)?;
-        if !interruptibility.is_empty() || rflags & 0b1000000000 == 0 {
+        if has_pending && !interruptible {
             self.vmcs.write_field(
                 vmcs::VmcsField::CpuBasedVmExecControl,
                 field
                     | vmcs::CpuBasedCtrlFlags::INTERRUPT_WINDOW_EXITING.bits(),
             )?;
-            return Ok(());
+            return self.set_immediate_exit(false);
         } else {
             self.vmcs.write_field(
                 vmcs::VmcsField::CpuBasedVmExecControl,
@@ -415,15 +1194,65 @@ impl VCpu {
             )?;
         }
 
+        // If there are no pending interrupts, we're done
+        if !has_pending {
+            return self.set_immediate_exit(false);
+        }
+
         // At this point, we must have at least one pending interrupt, and the guest
-        // can accept interrupts, so do the injection.
-        if let Some(pending) = self.pending_interrupts.pop_first() {
+        // can accept interrupts, so do the injection. Interrupts from the local
+        // APIC and PIC are only acknowledged once they will actually be delivered.
+        let (vector, kind) = match self.pending_interrupts.pop_first() {
+            Some(pending) => pending,
+            None => (
+                match self.local_apic.acknowledge() {
+                    Some(vector) => vector,
+                    None => self.pic.lock().acknowledge(),
+                },
+                InjectedInterruptType::ExternalInterrupt,
+            ),
+        };
+        self.vmcs.write_field(
+            vmcs::VmcsField::VmEntryIntrInfoField,
+            0x80000000 | vector as u64 | ((kind as u64) << 8),
+        )?;
+
+        let has_pending = self.has_pending_interrupts();
+        self.set_immediate_exit(has_pending)
+    }
+
+    /// Re-inject an event whose delivery was interrupted by a VMEXIT
+    fn reinject_event(&mut self, vectoring: u64) -> Result<()> {
+        // Bit 11 indicates that the event has an error code
+        if vectoring & (1 << 11) != 0 {
+            let code = self
+                .vmcs
+                .read_field(vmcs::VmcsField::IdtVectoringErrorCode)?;
             self.vmcs.write_field(
-                vmcs::VmcsField::VmEntryIntrInfoField,
-                0x80000000 | pending.0 as u64 | ((pending.1 as u64) << 8),
+                vmcs::VmcsField::VmEntryExceptionErrorCode,
+                code,
             )?;
         }
 
+        // Software interrupts and exceptions need the instruction length
+        match (vectoring >> 8) & 0b111 {
+            4 | 5 | 6 => {
+                let len = self
+                    .vmcs
+                    .read_field(vmcs::VmcsField::VmExitInstructionLen)?;
+                self.vmcs
+                    .write_field(vmcs::VmcsField::VmEntryInstructionLen, len)?;
+            }
+            _ => (),
+        }
+
+        self.vmcs.write_field(
+            vmcs::VmcsField::VmEntryIntrInfoField,
+            vectoring & 0x80000fff,
+        )
+    }
+
+    fn set_immediate_exit(&mut self, enable: bool) -> Result<()> {
         // If there are still pending interrupts, we need to exit immediately,
         // so set the vmx-preemption timer to 0. According to the docs, this will
         // still do the event injection:
@@ -437,7 +1266,7 @@ impl VCpu {
         let field = self
             .vmcs
             .read_field(vmcs::VmcsField::PinBasedVmExecControl)?;
-        if !self.pending_interrupts.is_empty() {
+        if enable {
             self.vmcs
                 .write_field(vmcs::VmcsField::VmxPreemptionTimerValue, 0)?;
             self.vmcs.write_field(
@@ -502,6 +1331,20 @@ impl VCpu {
                             op
                         ),
                     },
+                    // CR8 is an alias for the TPR of the local APIC
+                    8 => match info.access_type {
+                        vmexit::CrAccessType::MovToCr => {
+                            let reg = info.register.unwrap();
+                            let val = reg.read(&self.vmcs, guest_cpu)?;
+                            self.local_apic.set_cr8(val);
+                        }
+                        vmexit::CrAccessType::MovFromCr => {
+                            let reg = info.register.unwrap();
+                            let val = self.local_apic.cr8();
+                            reg.write(val, &mut self.vmcs, guest_cpu)?;
+                        }
+                        op => panic!("Unsupported cr8 operation: {:?}", op),
+                    },
                     _ => {
                         return Err(Error::InvalidValue(format!(
                             "Unsupported CR number access"
@@ -524,21 +1367,97 @@ impl VCpu {
                 emulate::memio::handle_ept_violation(self, guest_cpu, info)?;
                 self.skip_emulated_instruction()?;
             }
+            vmexit::ExitInformation::RdMsr => {
+                emulate::msr::emulate_rdmsr(self, guest_cpu)?;
+            }
             vmexit::ExitInformation::WrMsr => {
-                info!(
-                    "wrmsr: {:x}:{:x} to register 0x{:x}",
-                    guest_cpu.rdx as u32,
-                    guest_cpu.rax as u32,
-                    guest_cpu.rcx as u32
-                );
+                emulate::msr::emulate_wrmsr(self, guest_cpu)?;
             }
             vmexit::ExitInformation::ExternalInterrupt(_info) => unsafe {
-                // FIXME: For now, the only external interrupt would be the
-                // timers we setup in the vPIT, so we can just ack them. In
-                // the future this will not be true.
+                // FIXME: For now, the only external interrupts are the
+                // timers we setup and vcpu kicks, so we can just ack them.
+                // In the future this will not be true.
                 apic::get_local_apic_mut().eoi();
             },
             vmexit::ExitInformation::InterruptWindow => {}
+            vmexit::ExitInformation::Hlt => self.halt()?,
+            vmexit::ExitInformation::MonitorTrapFlag => {
+                self.debug_stop(guest_cpu, gdb::StopReason::Step)?;
+            }
+
+            // Debug exceptions only exit when a debugger is attached
+            vmexit::ExitInformation::NonMaskableInterrupt(info)
+                if info.vector == gdb::DEBUG_VECTOR =>
+            {
+                let hit = match self.debugger {
+                    Some(ref debugger) => {
+                        debugger.is_breakpoint_hit(&self.vmcs)?
+                    }
+                    None => false,
+                };
+                if hit {
+                    self.debug_stop(guest_cpu, gdb::StopReason::Breakpoint)?;
+                } else {
+                    let qualification = self
+                        .vmcs
+                        .read_field(vmcs::VmcsField::ExitQualification)?;
+                    if let Some(ref mut debugger) = self.debugger {
+                        debugger.set_debug_status(qualification);
+                    }
+                    self.inject_exception(gdb::DEBUG_VECTOR, None)?;
+                }
+            }
+
+            // Debug register accesses only exit when a debugger is attached
+            vmexit::ExitInformation::MovDr(mut info) => {
+                if info.dr_num == 4 || info.dr_num == 5 {
+                    let cr4 =
+                        self.vmcs.read_field(vmcs::VmcsField::GuestCr4)?;
+                    if cr4 & CR4_DE != 0 {
+                        return self.inject_exception(UD_VECTOR, None);
+                    }
+                    info.dr_num += 2;
+                }
+                if let Some(ref mut debugger) = self.debugger {
+                    debugger.emulate_mov_dr(
+                        &mut self.vmcs,
+                        guest_cpu,
+                        &info,
+                    )?;
+                }
+                self.skip_emulated_instruction()?;
+            }
+            vmexit::ExitInformation::TripleFault => {
+                warn!("vcpu {} triple faulted, resetting the VM", self.id);
+                self.lifecycle.reset();
+            }
+            vmexit::ExitInformation::InitSignal => {
+                info!("vcpu {} received INIT", self.id);
+                self.reset_guest_state(guest_cpu)?;
+                if !self.is_bsp() {
+                    self.enter_wait_for_sipi()?;
+                }
+            }
+            vmexit::ExitInformation::StartUpIpi => {
+                // The SIPI vector is the page number where the AP will start
+                // executing in real mode.
+                let vector =
+                    self.vmcs.read_field(vmcs::VmcsField::ExitQualification)?
+                        & 0xff;
+                info!("vcpu {} received SIPI (vector=0x{:x})", self.id, vector);
+
+                self.vmcs.write_field(
+                    vmcs::VmcsField::GuestCsSelector,
+                    vector << 8,
+                )?;
+                self.vmcs
+                    .write_field(vmcs::VmcsField::GuestCsBase, vector << 12)?;
+                self.vmcs.write_field(vmcs::VmcsField::GuestRip, 0)?;
+                self.vmcs.write_field(
+                    vmcs::VmcsField::GuestActivityState,
+                    vmcs::ActivityState::Active as u64,
+                )?;
+            }
             _ => {
                 info!("{}", self.vmcs);
                 panic!("No handler for exit reason: {:?}", exit);
@@ -548,3 +1467,48 @@ impl VCpu {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_halted_entry() {
+        let now = time::Instant(1000);
+        let injected = 0x80000000 | 0x30;
+        assert_eq!(halted_entry(injected, None, now), HaltedEntry::Wake);
+        assert_eq!(
+            halted_entry(injected, Some(time::Instant(10)), now),
+            HaltedEntry::Wake
+        );
+
+        // Without an event, the vcpu stays halted until the next timer
+        assert_eq!(halted_entry(0, None, now), HaltedEntry::Halt);
+        assert_eq!(
+            halted_entry(0, Some(time::Instant(2000)), now),
+            HaltedEntry::Halt
+        );
+
+        // A timer that has already elapsed must be expired first
+        assert_eq!(
+            halted_entry(0, Some(time::Instant(999)), now),
+            HaltedEntry::ExpireTimers
+        );
+    }
+
+    #[test]
+    fn test_console_poll_deadline() {
+        let poll_due = time::Instant(1000);
+        assert_eq!(console_poll_deadline(None, poll_due), Some(poll_due));
+        assert_eq!(
+            console_poll_deadline(Some(time::Instant(2000)), poll_due),
+            Some(poll_due)
+        );
+
+        // The timer wakes the vcpu in time for the poll
+        assert_eq!(
+            console_poll_deadline(Some(time::Instant(500)), poll_due),
+            None
+        );
+    }
+}
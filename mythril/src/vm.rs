@@ -1,31 +1,208 @@
 use crate::boot_info::BootInfo;
+use crate::device::acpi::AcpiRuntime;
+use crate::device::com::ComDevice;
+use crate::device::ioapic::IoApic;
+use crate::device::irq::{IrqLine, IrqRouter};
+use crate::device::pic::Pic8259;
+use crate::device::rtc::CmosRtc;
 use crate::device::{
     DeviceMap, MemReadRequest, MemWriteRequest, Port, PortReadRequest,
     PortWriteRequest,
 };
+use crate::emulate::cpuid::CpuidPolicy;
 use crate::error::{Error, Result};
-use crate::memory::{
-    self, GuestAddressSpace, GuestPhysAddr, HostPhysAddr, HostPhysFrame,
-    Raw4kPage,
-};
-use crate::vcpu;
-use alloc::boxed::Box;
+use crate::frame_alloc;
+use crate::memory::{self, GuestAddressSpace, GuestPhysAddr, PageSize};
+use crate::memory_layout::GuestMemoryLayout;
+use crate::vcpu::{self, VCpuMailbox};
+use crate::vtime::GuestClock;
 use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use spin::RwLock;
+use spin::{Mutex, RwLock};
 
 pub static mut VM_MAP: Option<BTreeMap<usize, Arc<RwLock<VirtualMachine>>>> =
     None;
 
+/// The lifecycle state of a `VirtualMachine`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VmState {
+    /// The guest is executing normally
+    Running,
+
+    /// The vcpus are stopped until the VM is resumed
+    Paused,
+
+    /// The guest has entered the S3 sleep state, and the vcpus are stopped
+    /// until a wake event
+    Suspended,
+
+    /// The guest has powered off (it can only be restarted with a reset)
+    Shutdown,
+
+    /// The vcpus and devices are returning to their power-on state
+    Reset,
+}
+
+/// Tracks the lifecycle state of a `VirtualMachine`
+///
+/// This is shared between the `VCpu`s of the VM and the devices that can
+/// power off or reset the machine. Each `VCpu` applies a change of state
+/// the next time it exits the guest (the vcpus are kicked to ensure this
+/// happens promptly).
+///
+/// The lifecycle also owns the `GuestClock` of the VM, so the guest time
+/// stops whenever the VM stops running.
+pub struct VmLifecycle {
+    cpus: Vec<u8>,
+    clock: Arc<GuestClock>,
+
+    // The current state, the number of resets requested so far and whether
+    // the last reset is a wake from S3
+    state: Mutex<(VmState, u64, bool)>,
+}
+
+impl VmLifecycle {
+    /// Create the lifecycle of a VM running on the given cores (by APIC id)
+    pub fn new(cpus: Vec<u8>) -> Self {
+        Self {
+            cpus: cpus,
+            clock: Arc::new(GuestClock::new()),
+            state: Mutex::new((VmState::Running, 0, false)),
+        }
+    }
+
+    /// The virtual time of the VM
+    pub fn clock(&self) -> &Arc<GuestClock> {
+        &self.clock
+    }
+
+    /// The current state of the VM
+    pub fn state(&self) -> VmState {
+        self.state.lock().0
+    }
+
+    /// The current state of the VM and the number of resets requested
+    ///
+    /// Each `VCpu` compares the reset count to the last one it saw, to
+    /// know when it must reset itself.
+    pub fn status(&self) -> (VmState, u64) {
+        let state = self.state.lock();
+        (state.0, state.1)
+    }
+
+    /// Whether the current (or last) reset is a wake from S3, in which case
+    /// the firmware must resume the guest instead of booting it
+    pub fn waking(&self) -> bool {
+        self.state.lock().2
+    }
+
+    /// Stop the vcpus of a running VM until `resume` is called
+    pub fn pause(&self) {
+        let mut state = self.state.lock();
+        if state.0 == VmState::Running {
+            state.0 = VmState::Paused;
+            self.clock.stop();
+            drop(state);
+            self.kick_vcpus();
+        }
+    }
+
+    /// Continue executing a paused VM
+    pub fn resume(&self) {
+        let mut state = self.state.lock();
+        if state.0 == VmState::Paused {
+            state.0 = VmState::Running;
+            self.clock.start();
+            drop(state);
+            self.kick_vcpus();
+        }
+    }
+
+    /// Stop the vcpus of a VM that has entered the S3 sleep state
+    pub fn suspend(&self) {
+        let mut state = self.state.lock();
+        if state.0 == VmState::Running {
+            state.0 = VmState::Suspended;
+            self.clock.stop();
+            drop(state);
+            self.kick_vcpus();
+        }
+    }
+
+    /// Wake a suspended VM
+    ///
+    /// The vcpus restart from the BIOS reset vector, like on real hardware,
+    /// and the firmware then jumps to the waking vector of the guest.
+    pub fn wake(&self) {
+        let mut state = self.state.lock();
+        if state.0 == VmState::Suspended {
+            state.0 = VmState::Reset;
+            state.1 += 1;
+            state.2 = true;
+            self.clock.start();
+            drop(state);
+            self.kick_vcpus();
+        }
+    }
+
+    /// Power off the VM (e.g., following an ACPI S5 request)
+    pub fn shutdown(&self) {
+        self.state.lock().0 = VmState::Shutdown;
+        self.clock.stop();
+        self.kick_vcpus();
+    }
+
+    /// Reset the VM, so it restarts from the BIOS reset vector
+    pub fn reset(&self) {
+        let mut state = self.state.lock();
+        state.0 = VmState::Reset;
+        state.1 += 1;
+        state.2 = false;
+
+        // Like on real hardware, the TSC restarts from zero
+        self.clock.start();
+        self.clock.set_tsc(0);
+        drop(state);
+        self.kick_vcpus();
+    }
+
+    /// Mark the given reset as complete (called by the BSP once the
+    /// devices have been reset)
+    pub fn finish_reset(&self, count: u64) {
+        let mut state = self.state.lock();
+        if state.0 == VmState::Reset && state.1 == count {
+            state.0 = VmState::Running;
+        }
+    }
+
+    fn kick_vcpus(&self) {
+        for cpu in self.cpus.iter() {
+            vcpu::kick_vcpu(*cpu as u32);
+        }
+    }
+}
+
 /// A configuration for a `VirtualMachine`
 pub struct VirtualMachineConfig {
-    _cpus: Vec<u8>,
+    cpus: Vec<u8>,
     images: Vec<(String, GuestPhysAddr)>,
     bios: Option<String>,
     devices: DeviceMap,
-    memory: u64, // in MB
+    pic: Arc<Mutex<Pic8259>>,
+    ioapic: Arc<Mutex<IoApic>>,
+    irq_router: Arc<IrqRouter>,
+    mailboxes: Vec<Arc<VCpuMailbox>>,
+    cpuid: CpuidPolicy,
+    gdb_port: Option<u16>,
+    lifecycle: Arc<VmLifecycle>,
+    com_ports: Vec<Arc<Mutex<ComDevice>>>,
+    acpi: Option<Arc<Mutex<AcpiRuntime>>>,
+    rtc: Option<Arc<Mutex<CmosRtc>>>,
+    exit_trace_size: usize,
+    max_ept_page_size: PageSize,
+    layout: GuestMemoryLayout,
 }
 
 impl VirtualMachineConfig {
@@ -34,14 +211,43 @@ impl VirtualMachineConfig {
     /// # Arguments
     ///
     /// * `cpus` - A list of the cores used by the VM (by APIC id)
-    /// * `memory` - The amount of VM memory (in MB)
-    pub fn new(cpus: Vec<u8>, memory: u64) -> VirtualMachineConfig {
+    /// * `layout` - The layout of the VM's physical memory
+    pub fn new(
+        cpus: Vec<u8>,
+        layout: GuestMemoryLayout,
+    ) -> VirtualMachineConfig {
+        let mailboxes: Vec<_> = cpus
+            .iter()
+            .map(|cpu| Arc::new(VCpuMailbox::new(*cpu as u32)))
+            .collect();
+        let pic = Arc::new(Mutex::new(Pic8259::default()));
+        let ioapic = Arc::new(Mutex::new(IoApic::new(mailboxes.clone())));
+        let irq_router = IrqRouter::new(
+            pic.clone(),
+            ioapic.clone(),
+            mailboxes.clone(),
+            cpus.first().cloned().unwrap_or(0) as u32,
+        );
+        let cpuid = CpuidPolicy::new(cpus.len() as u32);
+        let lifecycle = Arc::new(VmLifecycle::new(cpus.clone()));
         VirtualMachineConfig {
-            _cpus: cpus,
+            cpus: cpus,
             images: vec![],
             devices: DeviceMap::default(),
+            pic: pic,
+            ioapic: ioapic,
+            irq_router: Arc::new(irq_router),
+            mailboxes: mailboxes,
+            cpuid: cpuid,
+            gdb_port: None,
+            lifecycle: lifecycle,
+            com_ports: vec![],
+            acpi: None,
+            rtc: None,
+            exit_trace_size: 0,
+            max_ept_page_size: PageSize::Size4K,
             bios: None,
-            memory: memory,
+            layout: layout,
         }
     }
 
@@ -70,6 +276,147 @@ impl VirtualMachineConfig {
         Ok(())
     }
 
+    /// The cores used by the VM (by APIC id)
+    ///
+    /// The index of a core in this list is the APIC id of the corresponding
+    /// `VCpu` in the guest, so the first core runs the guest BSP.
+    pub fn cpus(&self) -> &[u8] {
+        &self.cpus
+    }
+
+    /// The amount of VM memory (in MB)
+    pub fn memory(&self) -> u64 {
+        self.layout.ram_size() >> 20
+    }
+
+    /// The layout of the VM's physical memory
+    pub fn layout(&self) -> &GuestMemoryLayout {
+        &self.layout
+    }
+
+    /// The VM's (legacy) PIC
+    ///
+    /// This is shared by the `IrqLine`s of the VM and the guest BSP, which
+    /// will receive the interrupts from the PIC.
+    pub fn pic(&self) -> &Arc<Mutex<Pic8259>> {
+        &self.pic
+    }
+
+    /// The VM's IOAPIC
+    pub fn ioapic(&self) -> &Arc<Mutex<IoApic>> {
+        &self.ioapic
+    }
+
+    /// The interrupt mailbox of each `VCpu` (indexed by guest APIC id)
+    pub fn mailboxes(&self) -> &[Arc<VCpuMailbox>] {
+        &self.mailboxes
+    }
+
+    /// The CPUID values reported to the guest
+    pub fn cpuid(&self) -> &CpuidPolicy {
+        &self.cpuid
+    }
+
+    /// Modify the CPUID values reported to the guest
+    pub fn cpuid_mut(&mut self) -> &mut CpuidPolicy {
+        &mut self.cpuid
+    }
+
+    /// The host serial port used by the GDB stub of the guest BSP, if any
+    pub fn gdb_port(&self) -> Option<u16> {
+        self.gdb_port
+    }
+
+    /// Debug the guest BSP with the GDB stub on the given host serial port
+    pub fn set_gdb_port(&mut self, port: u16) {
+        self.gdb_port = Some(port);
+    }
+
+    /// The number of recent exits traced for each `VCpu` (zero if the exits
+    /// are not traced)
+    pub fn exit_trace_size(&self) -> usize {
+        self.exit_trace_size
+    }
+
+    /// Trace the most recent `size` exits of each `VCpu`
+    pub fn set_exit_trace_size(&mut self, size: usize) {
+        self.exit_trace_size = size;
+    }
+
+    /// The largest pages used to map the guest RAM in the EPT
+    pub fn max_ept_page_size(&self) -> PageSize {
+        self.max_ept_page_size
+    }
+
+    /// Map the guest RAM with pages up to `size` (this should be the
+    /// largest size supported by the processor, see
+    /// `Vmx::max_ept_page_size`)
+    pub fn set_max_ept_page_size(&mut self, size: PageSize) {
+        self.max_ept_page_size = size;
+    }
+
+    /// The lifecycle state of the VM
+    ///
+    /// This is shared by the `VCpu`s and the devices that can power off or
+    /// reset the VM.
+    pub fn lifecycle(&self) -> &Arc<VmLifecycle> {
+        &self.lifecycle
+    }
+
+    /// The emulated serial ports of the VM (in the order they were added)
+    ///
+    /// The host console sends its input to the first of these.
+    pub fn com_ports(&self) -> &[Arc<Mutex<ComDevice>>] {
+        &self.com_ports
+    }
+
+    /// Add an emulated serial port that can receive input from the host
+    ///
+    /// The port must also be registered in the `DeviceMap`.
+    pub fn add_com_port(&mut self, com: Arc<Mutex<ComDevice>>) {
+        self.com_ports.push(com);
+    }
+
+    /// The ACPI fixed hardware of the VM, if it has any
+    ///
+    /// The host uses this to inject events like power button presses.
+    pub fn acpi(&self) -> Option<&Arc<Mutex<AcpiRuntime>>> {
+        self.acpi.as_ref()
+    }
+
+    /// Set the ACPI fixed hardware of the VM
+    ///
+    /// The device must also be registered in the `DeviceMap`.
+    pub fn set_acpi(&mut self, acpi: Arc<Mutex<AcpiRuntime>>) {
+        self.acpi = Some(acpi);
+    }
+
+    /// The RTC of the VM, if it has one
+    ///
+    /// The host uses this to dump the CMOS memory.
+    pub fn rtc(&self) -> Option<&Arc<Mutex<CmosRtc>>> {
+        self.rtc.as_ref()
+    }
+
+    /// Set the RTC of the VM
+    ///
+    /// The device must also be registered in the `DeviceMap`.
+    pub fn set_rtc(&mut self, rtc: Arc<Mutex<CmosRtc>>) {
+        self.rtc = Some(rtc);
+    }
+
+    /// Connect a new device to the interrupt line `irq` of the VM
+    ///
+    /// Each call returns a separate source, so devices can share a line.
+    pub fn irq_line(&self, irq: u8) -> IrqLine {
+        IrqLine::new(irq, self.irq_router.clone())
+    }
+
+    /// The router for the interrupts of the VM's devices (including MSIs)
+    pub fn irq_router(&self) -> &Arc<IrqRouter> {
+        &self.irq_router
+    }
+
     /// Access the configurations `DeviceMap`
     pub fn device_map(&mut self) -> &mut DeviceMap {
         &mut self.devices
@@ -195,11 +542,12 @@ impl VirtualMachine {
         space: &mut GuestAddressSpace,
     ) -> Result<()> {
         for (i, chunk) in image.chunks(4096 as usize).enumerate() {
-            let frame_ptr =
-                Box::into_raw(Box::new(Raw4kPage::default())) as *mut u8;
-            let frame = HostPhysFrame::from_start_address(HostPhysAddr::new(
-                frame_ptr as u64,
-            ))?;
+            let frame = frame_alloc::allocate_frames(
+                1,
+                PageSize::Size4K,
+                space.node(),
+            )?;
+            let frame_ptr = frame.start_address().as_u64() as *mut u8;
             let chunk_ptr = chunk.as_ptr();
             unsafe {
                 core::ptr::copy_nonoverlapping(
@@ -263,7 +611,21 @@ impl VirtualMachine {
         config: &VirtualMachineConfig,
         info: &BootInfo,
     ) -> Result<GuestAddressSpace> {
-        let mut guest_space = GuestAddressSpace::new()?;
+        // Allocate the guest memory near the VM's BSP
+        let node = config
+            .cpus()
+            .first()
+            .map_or(0, |cpu| frame_alloc::node_of_core(*cpu as u32));
+        let ram_size = config.layout.ram_size();
+        if ram_size > frame_alloc::free_bytes() {
+            return Err(Error::AllocError(format!(
+                "VM needs {}MB of memory, but only {}MB of host memory is free",
+                ram_size >> 20,
+                frame_alloc::free_bytes() >> 20
+            )));
+        }
+        let mut guest_space =
+            GuestAddressSpace::new(config.max_ept_page_size(), node)?;
 
         // First map the bios
         if let Some(ref bios) = config.bios {
@@ -275,15 +637,9 @@ impl VirtualMachine {
             Self::map_image(&image.0, &image.1, &mut guest_space, info)?;
         }
 
-        // Iterate over each page
-        for i in 0..(config.memory << 8) {
-            match guest_space.map_new_frame(
-                memory::GuestPhysAddr::new((i as u64 * 4096) as u64),
-                false,
-            ) {
-                Ok(_) | Err(Error::DuplicateMapping(_)) => continue,
-                Err(e) => return Err(e),
-            }
+        // Then fill in the rest of the guest RAM (around the PCI hole)
+        for region in config.layout.ram() {
+            guest_space.map_new_region(region.start, region.size, false)?;
         }
 
         Ok(guest_space)
@@ -298,7 +654,50 @@ mod test {
     fn test_vm_creation() {
         let info = BootInfo::default();
 
-        let config = VirtualMachineConfig::new(vec![1], 0);
+        let layout =
+            GuestMemoryLayout::new(0, crate::memory_layout::DEFAULT_PCI_HOLE)
+                .unwrap();
+        let config = VirtualMachineConfig::new(vec![1], layout);
         VirtualMachine::new(config, &info).unwrap();
     }
+
+    #[test]
+    fn test_lifecycle() {
+        let lifecycle = VmLifecycle::new(vec![]);
+        assert_eq!(lifecycle.status(), (VmState::Running, 0));
+
+        // The guest time stops while the VM is paused
+        lifecycle.pause();
+        assert_eq!(lifecycle.state(), VmState::Paused);
+        let tsc = lifecycle.clock().tsc();
+        assert_eq!(lifecycle.clock().tsc(), tsc);
+        lifecycle.resume();
+        assert_eq!(lifecycle.state(), VmState::Running);
+
+        // A shut down VM cannot be resumed, only reset
+        lifecycle.shutdown();
+        lifecycle.resume();
+        assert_eq!(lifecycle.state(), VmState::Shutdown);
+        lifecycle.reset();
+        assert_eq!(lifecycle.status(), (VmState::Reset, 1));
+
+        // Only the latest reset completes the reset
+        lifecycle.reset();
+        lifecycle.finish_reset(1);
+        assert_eq!(lifecycle.state(), VmState::Reset);
+        lifecycle.finish_reset(2);
+        assert_eq!(lifecycle.status(), (VmState::Running, 2));
+        assert!(!lifecycle.waking());
+
+        // Waking from S3 is a reset that the firmware can tell apart
+        lifecycle.wake();
+        assert_eq!(lifecycle.state(), VmState::Running);
+        lifecycle.suspend();
+        assert_eq!(lifecycle.state(), VmState::Suspended);
+        lifecycle.pause();
+        assert_eq!(lifecycle.state(), VmState::Suspended);
+        lifecycle.wake();
+        assert_eq!(lifecycle.status(), (VmState::Reset, 3));
+        assert!(lifecycle.waking());
+    }
 }
@@ -285,6 +285,16 @@ bitflags! {
     }
 }
 
+/// The guest activity state (see Section 24.4.2)
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u64)]
+pub enum ActivityState {
+    Active = 0,
+    Hlt = 1,
+    Shutdown = 2,
+    WaitForSipi = 3,
+}
+
 fn vmcs_write_with_fixed(
     field: VmcsField,
     value: u64,
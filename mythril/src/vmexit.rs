@@ -104,7 +104,7 @@ pub enum ExitInformation {
     VmxOff,
     VmxOn,
     CrAccess(CrInformation),
-    MovDr,
+    MovDr(DrInformation),
     IoInstruction(IoInstructionInformation),
     RdMsr,
     WrMsr,
@@ -139,6 +139,88 @@ pub enum ExitInformation {
     Xrstors,
 }
 
+impl ExitInformation {
+    /// The name of the exit reason (without the extended information)
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExitInformation::NonMaskableInterrupt(_) => "NonMaskableInterrupt",
+            ExitInformation::ExternalInterrupt(_) => "ExternalInterrupt",
+            ExitInformation::TripleFault => "TripleFault",
+            ExitInformation::InitSignal => "InitSignal",
+            ExitInformation::StartUpIpi => "StartUpIpi",
+            ExitInformation::IoSystemManagementInterrupt => {
+                "IoSystemManagementInterrupt"
+            }
+            ExitInformation::OtherSystemManagementInterrupt => {
+                "OtherSystemManagementInterrupt"
+            }
+            ExitInformation::InterruptWindow => "InterruptWindow",
+            ExitInformation::NonMaskableInterruptWindow => {
+                "NonMaskableInterruptWindow"
+            }
+            ExitInformation::TaskSwitch => "TaskSwitch",
+            ExitInformation::CpuId => "CpuId",
+            ExitInformation::GetSec => "GetSec",
+            ExitInformation::Hlt => "Hlt",
+            ExitInformation::Invd => "Invd",
+            ExitInformation::InvlPg => "InvlPg",
+            ExitInformation::Rdpmc => "Rdpmc",
+            ExitInformation::Rdtsc => "Rdtsc",
+            ExitInformation::Rsm => "Rsm",
+            ExitInformation::VmCall => "VmCall",
+            ExitInformation::VmClear => "VmClear",
+            ExitInformation::VmLaunch => "VmLaunch",
+            ExitInformation::VmPtrLd => "VmPtrLd",
+            ExitInformation::VmPtrRst => "VmPtrRst",
+            ExitInformation::VmRead => "VmRead",
+            ExitInformation::VmResume => "VmResume",
+            ExitInformation::VmWrite => "VmWrite",
+            ExitInformation::VmxOff => "VmxOff",
+            ExitInformation::VmxOn => "VmxOn",
+            ExitInformation::CrAccess(_) => "CrAccess",
+            ExitInformation::MovDr(_) => "MovDr",
+            ExitInformation::IoInstruction(_) => "IoInstruction",
+            ExitInformation::RdMsr => "RdMsr",
+            ExitInformation::WrMsr => "WrMsr",
+            ExitInformation::VmEntryInvalidGuestState => {
+                "VmEntryInvalidGuestState"
+            }
+            ExitInformation::VmEntryMsrLoad => "VmEntryMsrLoad",
+            ExitInformation::Mwait => "Mwait",
+            ExitInformation::MonitorTrapFlag => "MonitorTrapFlag",
+            ExitInformation::Monitor => "Monitor",
+            ExitInformation::Pause => "Pause",
+            ExitInformation::VmEntryMachineCheck => "VmEntryMachineCheck",
+            ExitInformation::TprBelowThreshold => "TprBelowThreshold",
+            ExitInformation::ApicAccess => "ApicAccess",
+            ExitInformation::VirtualEio => "VirtualEio",
+            ExitInformation::AccessGdtridtr => "AccessGdtridtr",
+            ExitInformation::AccessLdtrTr => "AccessLdtrTr",
+            ExitInformation::EptViolation(_) => "EptViolation",
+            ExitInformation::EptMisconfigure => "EptMisconfigure",
+            ExitInformation::InvEpt => "InvEpt",
+            ExitInformation::Rdtscp => "Rdtscp",
+            ExitInformation::VmxPreemptionTimerExpired => {
+                "VmxPreemptionTimerExpired"
+            }
+            ExitInformation::Invvpid => "Invvpid",
+            ExitInformation::Wbinvd => "Wbinvd",
+            ExitInformation::Xsetbv => "Xsetbv",
+            ExitInformation::ApicWrite => "ApicWrite",
+            ExitInformation::RdRand => "RdRand",
+            ExitInformation::Invpcid => "Invpcid",
+            ExitInformation::VmFunc => "VmFunc",
+            ExitInformation::Encls => "Encls",
+            ExitInformation::RdSeed => "RdSeed",
+            ExitInformation::PageModificationLogFull => {
+                "PageModificationLogFull"
+            }
+            ExitInformation::Xsaves => "Xsaves",
+            ExitInformation::Xrstors => "Xrstors",
+        }
+    }
+}
+
 impl ExitReason {
     fn from_active_vmcs(vmcs: &mut vmcs::ActiveVmcs) -> Result<Self> {
         let reason = vmcs.read_field(vmcs::VmcsField::VmExitReason)?;
@@ -180,7 +262,9 @@ impl ExitReason {
             28 => ExitInformation::CrAccess(CrInformation::from_active_vmcs(
                 vmcs,
             )?),
-            29 => ExitInformation::MovDr,
+            29 => {
+                ExitInformation::MovDr(DrInformation::from_active_vmcs(vmcs)?)
+            }
             30 => ExitInformation::IoInstruction(
                 IoInstructionInformation::from_active_vmcs(vmcs)?,
             ),
@@ -468,6 +552,28 @@ impl ExtendedExitInformation for CrInformation {
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct DrInformation {
+    pub dr_num: u8,
+
+    /// True for a MOV from the debug register (false for a MOV to it)
+    pub read: bool,
+    pub register: MovCrRegister,
+}
+
+impl ExtendedExitInformation for DrInformation {
+    fn from_active_vmcs(vmcs: &vmcs::ActiveVmcs) -> Result<Self> {
+        let qualifier = vmcs.read_field(vmcs::VmcsField::ExitQualification)?;
+        Ok(DrInformation {
+            dr_num: (qualifier & 0b111) as u8,
+            read: qualifier & (1 << 4) != 0,
+            register: MovCrRegister::try_from(
+                ((qualifier & 0xf00) >> 8) as u8,
+            )?,
+        })
+    }
+}
+
 bitflags! {
     pub struct ExitReasonFlags: u64 {
         const ENCLAVE_MODE =        1 << 27;
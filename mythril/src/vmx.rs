@@ -1,5 +1,5 @@
 use crate::error::{self, Error, Result};
-use crate::memory::Raw4kPage;
+use crate::memory::{PageSize, Raw4kPage};
 use alloc::boxed::Box;
 use raw_cpuid::CpuId;
 use x86::msr;
@@ -76,4 +76,29 @@ impl Vmx {
     pub fn revision() -> u32 {
         unsafe { msr::rdmsr(msr::IA32_VMX_BASIC) as u32 }
     }
+
+    /// The largest pages the processor supports in the EPT
+    pub fn max_ept_page_size() -> PageSize {
+        const EPT_2MB_PAGES: u64 = 1 << 16;
+        const EPT_1GB_PAGES: u64 = 1 << 17;
+
+        let cap = unsafe { msr::rdmsr(msr::IA32_VMX_EPT_VPID_CAP) };
+        if cap & EPT_1GB_PAGES != 0 {
+            PageSize::Size1G
+        } else if cap & EPT_2MB_PAGES != 0 {
+            PageSize::Size2M
+        } else {
+            PageSize::Size4K
+        }
+    }
+
+    /// Whether the processor supports TSC scaling (a secondary processor
+    /// based control)
+    pub fn supports_tsc_scaling() -> bool {
+        const TSC_SCALING: u64 = 1 << 25;
+
+        // The controls that may be set are in the high 32 bits
+        let ctls2 = unsafe { msr::rdmsr(msr::IA32_VMX_PROCBASED_CTLS2) };
+        (ctls2 >> 32) & TSC_SCALING != 0
+    }
 }
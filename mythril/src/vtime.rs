@@ -0,0 +1,312 @@
+//! # Virtual time
+//!
+//! Each VM has a `GuestClock` that provides the guest TSC and the time used
+//! by the emulated devices. The guest TSC starts at zero when the VM is
+//! created, runs at the same (optionally scaled) rate on every vcpu, and
+//! both the TSC and the device time stop while the VM is not running.
+
+use crate::time;
+use crate::tsc;
+use spin::Mutex;
+
+// The VMX TSC multiplier is a fixed point value with 48 fractional bits
+const TSC_MULTIPLIER_SHIFT: u32 = 48;
+
+const NS_PER_SEC: u128 = 1_000_000_000;
+
+/// The TSC offset and multiplier a vcpu must use to present the guest TSC
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TscControls {
+    /// The value added to the (scaled) host TSC
+    pub offset: u64,
+
+    /// The TSC multiplier, if the guest TSC frequency differs from the
+    /// host's
+    pub multiplier: Option<u64>,
+
+    /// Incremented each time the offset or multiplier changes
+    pub generation: u64,
+}
+
+/// A guest TSC value and the guest time (in nanoseconds) at that TSC
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClockSnapshot {
+    /// The guest TSC
+    pub tsc: u64,
+
+    /// The time the VM had been running at that TSC
+    pub ns: u64,
+}
+
+#[derive(Debug)]
+struct ClockState {
+    // The host TSC when the device time was zero (advanced by any time the
+    // clock was stopped)
+    origin: u64,
+
+    // The host TSC when the clock was stopped, if it is stopped
+    stopped: Option<u64>,
+
+    tsc: TscControls,
+
+    // The snapshot shared by the vcpus, and the TSC generation it is for
+    snapshot: Option<(u64, ClockSnapshot)>,
+}
+
+impl ClockState {
+    fn scale(&self, host_tsc: u64) -> u64 {
+        match self.tsc.multiplier {
+            Some(multiplier) => {
+                ((host_tsc as u128 * multiplier as u128)
+                    >> TSC_MULTIPLIER_SHIFT) as u64
+            }
+            None => host_tsc,
+        }
+    }
+
+    /// The host TSC the guest time is currently based on
+    fn host_tsc(&self, host_tsc: u64) -> u64 {
+        self.stopped.unwrap_or(host_tsc)
+    }
+
+    fn guest_tsc(&self, host_tsc: u64) -> u64 {
+        self.scale(self.host_tsc(host_tsc))
+            .wrapping_add(self.tsc.offset)
+    }
+
+    fn set_guest_tsc(&mut self, val: u64, host_tsc: u64) {
+        self.tsc.offset = val.wrapping_sub(self.scale(self.host_tsc(host_tsc)));
+        self.tsc.generation += 1;
+    }
+}
+
+/// The virtual time of a VM
+#[derive(Debug)]
+pub struct GuestClock {
+    state: Mutex<ClockState>,
+}
+
+impl GuestClock {
+    /// Create a new `GuestClock`, with a guest TSC that starts at zero
+    pub fn new() -> Self {
+        Self::new_at(unsafe { tsc::read_tsc() })
+    }
+
+    fn new_at(host_tsc: u64) -> Self {
+        Self {
+            state: Mutex::new(ClockState {
+                origin: host_tsc,
+                stopped: None,
+                tsc: TscControls {
+                    offset: 0u64.wrapping_sub(host_tsc),
+                    multiplier: None,
+                    generation: 0,
+                },
+                snapshot: None,
+            }),
+        }
+    }
+
+    /// Present a guest TSC with the given frequency (in Hz), using TSC
+    /// scaling
+    ///
+    /// This must be called before the vcpus are created, as they only
+    /// enable TSC scaling if the clock needs it.
+    pub fn set_frequency(&self, frequency: u64, host_frequency: u64) {
+        self.set_frequency_at(frequency, host_frequency, unsafe {
+            tsc::read_tsc()
+        })
+    }
+
+    fn set_frequency_at(
+        &self,
+        frequency: u64,
+        host_frequency: u64,
+        host_tsc: u64,
+    ) {
+        let mut state = self.state.lock();
+        let tsc = state.guest_tsc(host_tsc);
+        state.tsc.multiplier = if frequency == host_frequency {
+            None
+        } else {
+            Some(
+                (((frequency as u128) << TSC_MULTIPLIER_SHIFT)
+                    / host_frequency as u128) as u64,
+            )
+        };
+
+        // The guest TSC continues from its current value
+        state.set_guest_tsc(tsc, host_tsc);
+    }
+
+    /// The frequency of the guest TSC (in Hz)
+    pub fn frequency(&self) -> u64 {
+        let state = self.state.lock();
+        state.scale(time::frequency())
+    }
+
+    /// The TSC offset and multiplier the vcpus must use
+    pub fn tsc_controls(&self) -> TscControls {
+        self.state.lock().tsc
+    }
+
+    /// The current value of the guest TSC
+    pub fn tsc(&self) -> u64 {
+        self.state.lock().guest_tsc(unsafe { tsc::read_tsc() })
+    }
+
+    /// Set the guest TSC (on all of the vcpus)
+    pub fn set_tsc(&self, val: u64) {
+        self.state
+            .lock()
+            .set_guest_tsc(val, unsafe { tsc::read_tsc() })
+    }
+
+    /// The number of nanoseconds until the guest TSC reaches `deadline`
+    pub fn ns_until_tsc(&self, deadline: u64) -> u64 {
+        let ticks = deadline.saturating_sub(self.tsc());
+        ((ticks as u128 * NS_PER_SEC) / self.frequency() as u128) as u64
+    }
+
+    fn elapsed_at(&self, host_tsc: u64) -> u64 {
+        let state = self.state.lock();
+        state.host_tsc(host_tsc).saturating_sub(state.origin)
+    }
+
+    /// The time the VM has been running (in nanoseconds)
+    ///
+    /// Emulated devices should use this instead of `time::now`, so they
+    /// do not see time pass while the VM is paused.
+    pub fn now_ns(&self) -> u64 {
+        let elapsed = self.elapsed_at(unsafe { tsc::read_tsc() });
+        ((elapsed as u128 * NS_PER_SEC) / time::frequency() as u128) as u64
+    }
+
+    /// The guest TSC and time at a single point
+    ///
+    /// Every caller gets the same snapshot until the TSC offset or
+    /// multiplier changes, so the vcpus all compute the same time from a
+    /// given TSC value (as kvmclock requires).
+    pub fn snapshot(&self) -> ClockSnapshot {
+        self.snapshot_at(unsafe { tsc::read_tsc() }, time::frequency())
+    }
+
+    fn snapshot_at(&self, host_tsc: u64, host_frequency: u64) -> ClockSnapshot {
+        let mut state = self.state.lock();
+        let generation = state.tsc.generation;
+        match state.snapshot {
+            Some((gen, snapshot)) if gen == generation => snapshot,
+            _ => {
+                // Both values come from the same host TSC read
+                let elapsed =
+                    state.host_tsc(host_tsc).saturating_sub(state.origin);
+                let snapshot = ClockSnapshot {
+                    tsc: state.guest_tsc(host_tsc),
+                    ns: ((elapsed as u128 * NS_PER_SEC)
+                        / host_frequency as u128)
+                        as u64,
+                };
+                state.snapshot = Some((generation, snapshot));
+                snapshot
+            }
+        }
+    }
+
+    /// Stop the guest time (e.g., while the VM is paused)
+    pub fn stop(&self) {
+        self.stop_at(unsafe { tsc::read_tsc() })
+    }
+
+    fn stop_at(&self, host_tsc: u64) {
+        let mut state = self.state.lock();
+        if state.stopped.is_none() {
+            state.stopped = Some(host_tsc);
+        }
+    }
+
+    /// Restart a stopped guest time from where it stopped
+    pub fn start(&self) {
+        self.start_at(unsafe { tsc::read_tsc() })
+    }
+
+    fn start_at(&self, host_tsc: u64) {
+        let mut state = self.state.lock();
+        if state.stopped.is_some() {
+            let tsc = state.guest_tsc(host_tsc);
+            let stopped = state.stopped.take().unwrap_or(host_tsc);
+            state.origin += host_tsc.saturating_sub(stopped);
+            state.set_guest_tsc(tsc, host_tsc);
+        }
+    }
+}
+
+impl Default for GuestClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_guest_tsc_offset() {
+        let clock = GuestClock::new_at(1000);
+        let state = clock.state.lock();
+        assert_eq!(state.guest_tsc(1000), 0);
+        assert_eq!(state.guest_tsc(1500), 500);
+    }
+
+    #[test]
+    fn test_stopped_clock() {
+        let clock = GuestClock::new_at(1000);
+        clock.stop_at(1500);
+        assert_eq!(clock.elapsed_at(3000), 500);
+        assert_eq!(clock.state.lock().guest_tsc(3000), 500);
+
+        // Restarting the clock continues from where it stopped
+        clock.start_at(3000);
+        assert_eq!(clock.elapsed_at(3100), 600);
+        assert_eq!(clock.state.lock().guest_tsc(3100), 600);
+        assert_eq!(clock.tsc_controls().generation, 1);
+    }
+
+    #[test]
+    fn test_scaled_tsc() {
+        let clock = GuestClock::new_at(0);
+        clock.set_frequency_at(1_000_000_000, 2_000_000_000, 1000);
+        let mut state = clock.state.lock();
+        assert_eq!(state.tsc.multiplier, Some(1 << 47));
+        assert_eq!(state.guest_tsc(1000), 1000);
+        assert_eq!(state.guest_tsc(3000), 2000);
+
+        state.set_guest_tsc(0, 3000);
+        assert_eq!(state.guest_tsc(5000), 1000);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let clock = GuestClock::new_at(1000);
+        let snapshot = clock.snapshot_at(3000, 1_000_000_000);
+        assert_eq!(
+            snapshot,
+            ClockSnapshot {
+                tsc: 2000,
+                ns: 2000
+            }
+        );
+
+        // The other vcpus get the same snapshot, until the TSC changes
+        assert_eq!(clock.snapshot_at(5000, 1_000_000_000), snapshot);
+        clock.stop_at(6000);
+        clock.start_at(7000);
+        assert_eq!(
+            clock.snapshot_at(8000, 1_000_000_000),
+            ClockSnapshot {
+                tsc: 6000,
+                ns: 6000
+            }
+        );
+    }
+}
@@ -17,6 +17,7 @@ cp seabios/out/bios.bin _isofiles/boot/seabios.bin
 cp linux/arch/x86_64/boot/bzImage _isofiles/boot/vmlinuz
 cp scripts/linuxboot_dma.bin _isofiles/boot/linuxboot_dma.bin
 cp scripts/initramfs _isofiles/boot/initramfs
+cp scripts/mythril.cfg _isofiles/boot/mythril.cfg
 cp "$1" _isofiles/boot/mythril.bin
 
 # Explicitly avoid using grub efi for now